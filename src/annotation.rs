@@ -0,0 +1,78 @@
+//! Comments around included content that say where it came from, e.g.
+//! `<!-- begin include: header.html -->` ... `<!-- end include: header.html -->`, for debugging
+//! generated files.
+use std::fmt;
+use std::path::Path;
+use std::str::FromStr;
+
+/// The comment syntax used for annotations
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub enum Annotation {
+    /// Picked from the extension of the generated file, falling back to `# ...`
+    #[default]
+    Auto,
+    /// A template with `{}` where the text goes, e.g. `/* {} */`. A template without `{}` is
+    /// used as a line comment prefix, e.g. `//`.
+    Template(String),
+}
+
+impl Annotation {
+    /// Wraps included content in begin and end comments naming `include`, written in the comment
+    /// syntax for `output`, the file being generated
+    pub(crate) fn wrap(&self, content: &str, include: &str, output: &Path) -> String {
+        let template = match self {
+            Annotation::Auto => template_for_path(output).to_string(),
+            Annotation::Template(template) if template.contains("{}") => template.clone(),
+            Annotation::Template(prefix) => format!("{} {{}}", prefix),
+        };
+        let comment = |text: String| template.replacen("{}", &text, 1);
+        let mut annotated = comment(format!("begin include: {}", include));
+        annotated.push('\n');
+        annotated.push_str(content);
+        if !content.is_empty() && !content.ends_with('\n') {
+            annotated.push('\n');
+        }
+        annotated.push_str(&comment(format!("end include: {}", include)));
+        annotated
+    }
+}
+
+/// The comment template for a file, from its extension
+fn template_for_path(path: &Path) -> &'static str {
+    let extension = path
+        .extension()
+        .and_then(|e| e.to_str())
+        .unwrap_or_default()
+        .to_ascii_lowercase();
+    match extension.as_str() {
+        "html" | "htm" | "xml" | "svg" | "md" | "markdown" | "vue" => "<!-- {} -->",
+        "rs" | "c" | "h" | "cc" | "cpp" | "cxx" | "hpp" | "java" | "js" | "mjs" | "cjs" | "ts"
+        | "go" | "kt" | "cs" | "swift" | "scss" | "less" | "jsonc" => "// {}",
+        "css" => "/* {} */",
+        "sql" | "lua" | "hs" | "elm" => "-- {}",
+        "tex" | "sty" => "% {}",
+        "ini" => "; {}",
+        _ => "# {}",
+    }
+}
+
+impl FromStr for Annotation {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "" => Err("the annotation style can't be empty".to_string()),
+            "auto" => Ok(Annotation::Auto),
+            template => Ok(Annotation::Template(template.to_string())),
+        }
+    }
+}
+
+impl fmt::Display for Annotation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Annotation::Auto => write!(f, "auto"),
+            Annotation::Template(template) => write!(f, "{}", template),
+        }
+    }
+}
@@ -0,0 +1,118 @@
+//! Reading members of zip and tar archives for includes like `snippets.zip!header.txt`, with the
+//! `archive-includes` feature.
+//!
+//! Zip members can be stored or deflated. Tar archives can be plain or gzipped (`.tar.gz` or
+//! `.tgz`). Members are read through the `zip`, `tar` and `flate2` crates, and no more than a set
+//! size is decompressed, so an archive from an untrusted source can't exhaust memory.
+use std::fs::File;
+use std::io::{self, BufReader, Read, Seek};
+use std::path::Path;
+
+use flate2::read::GzDecoder;
+use tar::EntryType;
+use zip::ZipArchive;
+
+/// The extensions of the archives an include path can point into
+const ARCHIVE_EXTENSIONS: [&str; 4] = [".zip", ".tar", ".tar.gz", ".tgz"];
+
+/// How many bytes of a member are decompressed at most, unless `max_include_size` is set
+const MAX_MEMBER_SIZE: u64 = 64 * 1024 * 1024;
+
+/// Splits an include path into the archive and the member inside it, so
+/// `snippets.zip!header.txt` becomes (`snippets.zip`, `header.txt`). The `!` must follow an
+/// archive extension.
+pub(crate) fn split(include_path: &str) -> Option<(&str, &str)> {
+    include_path
+        .match_indices('!')
+        .map(|(index, _)| (&include_path[..index], &include_path[index + 1..]))
+        .find(|(archive, member)| {
+            let archive = archive.to_ascii_lowercase();
+            !member.is_empty()
+                && ARCHIVE_EXTENSIONS.iter().any(|extension| {
+                    archive.len() > extension.len() && archive.ends_with(extension)
+                })
+        })
+}
+
+/// Reads the bytes of `member` from the zip or tar archive at `path`, decompressing no more than
+/// `max_size` bytes (64 MiB if it isn't set). A member that isn't there is a `NotFound` error, and
+/// an archive that can't be read or a member that is too large is `InvalidData`.
+pub(crate) fn read_member(path: &Path, member: &str, max_size: Option<u64>) -> io::Result<Vec<u8>> {
+    let max_size = max_size.unwrap_or(MAX_MEMBER_SIZE);
+    let mut file = BufReader::new(File::open(path)?);
+    let mut magic = [0; 2];
+    let magic_length = file.read(&mut magic)?;
+    file.rewind()?;
+    let member = member.trim_start_matches("./");
+    let found = match &magic[..magic_length] {
+        b"PK" => zip_member(file, member, max_size)?,
+        [0x1f, 0x8b] => tar_member(GzDecoder::new(file), member, max_size)?,
+        _ => tar_member(file, member, max_size)?,
+    };
+    found.ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("{:?} is not in the archive", member),
+        )
+    })
+}
+
+fn corrupt(e: impl std::fmt::Display) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("corrupt archive: {}", e),
+    )
+}
+
+/// Reads a member's content, failing once it goes over `max_size` bytes
+fn read_limited(member: impl Read, name: &str, max_size: u64) -> io::Result<Vec<u8>> {
+    let mut content = Vec::new();
+    member
+        .take(max_size.saturating_add(1))
+        .read_to_end(&mut content)
+        .map_err(corrupt)?;
+    if content.len() as u64 > max_size {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "{:?} is over the maximum size of {} bytes when decompressed",
+                name, max_size
+            ),
+        ));
+    }
+    Ok(content)
+}
+
+/// Finds a member through the zip's central directory. Its checksum is checked as it is read.
+fn zip_member(file: impl Read + Seek, member: &str, max_size: u64) -> io::Result<Option<Vec<u8>>> {
+    let mut archive = ZipArchive::new(file).map_err(corrupt)?;
+    let Some(index) = (0..archive.len()).find(|&index| {
+        archive
+            .name_for_index(index)
+            .is_some_and(|name| name.trim_start_matches("./") == member)
+    }) else {
+        return Ok(None);
+    };
+    let entry = archive.by_index(index).map_err(corrupt)?;
+    if !entry.is_file() {
+        return Ok(None);
+    }
+    read_limited(entry, member, max_size).map(Some)
+}
+
+/// Finds a member by walking the tar's entries, following GNU long name and pax path headers
+fn tar_member(data: impl Read, member: &str, max_size: u64) -> io::Result<Option<Vec<u8>>> {
+    let mut archive = tar::Archive::new(data);
+    for entry in archive.entries().map_err(corrupt)? {
+        let entry = entry.map_err(corrupt)?;
+        let regular_file = matches!(
+            entry.header().entry_type(),
+            EntryType::Regular | EntryType::Continuous
+        );
+        let path = entry.path().map_err(corrupt)?;
+        if regular_file && path.to_string_lossy().trim_start_matches("./") == member {
+            return read_limited(entry, member, max_size).map(Some);
+        }
+    }
+    Ok(None)
+}
@@ -0,0 +1,237 @@
+//! The include map saved between runs, so incremental builds know what each file includes
+//! without scanning it.
+//!
+//! The cache is a JSON object mapping each included file to the files that include it, all as
+//! absolute paths. With `--hash-cache` a second file maps each source and include to a hash of
+//! its content.
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File};
+use std::io;
+use std::iter::Peekable;
+use std::path::{Path, PathBuf};
+use std::str::Chars;
+use std::sync::Mutex;
+
+use simple_include::{quote, slashed};
+
+pub const CACHE_FILE_NAME: &str = ".simple-include-cache.json";
+pub const HASHES_FILE_NAME: &str = ".simple-include-hashes.json";
+
+/// Reads the cache, returning an empty map if it doesn't exist
+pub fn load(path: &Path) -> io::Result<HashMap<PathBuf, HashSet<PathBuf>>> {
+    let content = match fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(HashMap::new()),
+        Err(e) => return Err(e),
+    };
+    parse(&content).ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{:?} is not a valid include cache", slashed(path)),
+        )
+    })
+}
+
+pub fn save(path: &Path, included_files: &HashMap<PathBuf, HashSet<PathBuf>>) -> io::Result<()> {
+    let mut entries: Vec<(String, Vec<String>)> = included_files
+        .iter()
+        .map(|(included, includers)| {
+            let mut includers: Vec<String> = includers
+                .iter()
+                .map(|includer| quote(&includer.to_string_lossy()))
+                .collect();
+            includers.sort();
+            (quote(&included.to_string_lossy()), includers)
+        })
+        .collect();
+    entries.sort();
+    let entries: Vec<String> = entries
+        .into_iter()
+        .map(|(included, includers)| format!("  {}: [{}]", included, includers.join(", ")))
+        .collect();
+    fs::write(path, format!("{{\n{}\n}}\n", entries.join(",\n")))
+}
+
+/// Inverts the cache into what each file includes, keeping only files whose cached includes all
+/// still exist
+pub fn includes_by_file(
+    included_files: &HashMap<PathBuf, HashSet<PathBuf>>,
+) -> HashMap<PathBuf, Vec<PathBuf>> {
+    let mut includes: HashMap<PathBuf, Vec<PathBuf>> = HashMap::new();
+    for (included, includers) in included_files {
+        for includer in includers {
+            includes
+                .entry(includer.clone())
+                .or_default()
+                .push(included.clone());
+        }
+    }
+    includes.retain(|file, includes| file.exists() && includes.iter().all(|i| i.exists()));
+    includes
+}
+
+/// The content hashes of the last run, and of this one as files are checked
+#[derive(Default)]
+pub struct Hashes {
+    previous: HashMap<PathBuf, String>,
+    current: Mutex<HashMap<PathBuf, String>>,
+    forgotten: Mutex<HashSet<PathBuf>>,
+}
+
+impl Hashes {
+    /// Reads the hashes of the last run, starting with none if there are none
+    pub fn load(path: &Path) -> io::Result<Hashes> {
+        let previous = match fs::read_to_string(path) {
+            Ok(content) => parse_hashes(&content).ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("{:?} is not a valid hash cache", slashed(path)),
+                )
+            })?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => HashMap::new(),
+            Err(e) => return Err(e),
+        };
+        Ok(Hashes {
+            previous,
+            ..Hashes::default()
+        })
+    }
+
+    /// Whether the file has the same content as in the last run. Its hash is kept for the next
+    /// run, and a file that can't be read has changed.
+    pub fn unchanged(&self, path: &Path) -> bool {
+        let known = self.current.lock().unwrap().get(path).cloned();
+        let hash = match known {
+            Some(hash) => hash,
+            None => match hash_file(path) {
+                Ok(hash) => {
+                    self.current
+                        .lock()
+                        .unwrap()
+                        .insert(path.to_path_buf(), hash.clone());
+                    hash
+                }
+                Err(_) => return false,
+            },
+        };
+        self.previous.get(path) == Some(&hash)
+    }
+
+    /// Drops the hash of a file that failed, so it is processed again next time
+    pub fn forget(&self, path: &Path) {
+        self.current.lock().unwrap().remove(path);
+        self.forgotten.lock().unwrap().insert(path.to_path_buf());
+    }
+
+    /// Writes the hashes of the files checked in this run, along with those of the last run for
+    /// files this run didn't check and that still exist
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let forgotten = self.forgotten.lock().unwrap();
+        let mut hashes: HashMap<&PathBuf, &String> = self
+            .previous
+            .iter()
+            .filter(|(file, _)| !forgotten.contains(*file) && file.exists())
+            .collect();
+        let current = self.current.lock().unwrap();
+        hashes.extend(current.iter());
+        let mut entries: Vec<String> = hashes
+            .into_iter()
+            .map(|(file, hash)| format!("  {}: {}", quote(&file.to_string_lossy()), quote(hash)))
+            .collect();
+        entries.sort();
+        fs::write(path, format!("{{\n{}\n}}\n", entries.join(",\n")))
+    }
+}
+
+/// The BLAKE3 hash of a file's content, in hex
+fn hash_file(path: &Path) -> io::Result<String> {
+    let mut hasher = blake3::Hasher::new();
+    hasher.update_reader(File::open(path)?)?;
+    Ok(hasher.finalize().to_hex().to_string())
+}
+
+/// Parses an object of strings, the shape of the hash cache
+fn parse_hashes(content: &str) -> Option<HashMap<PathBuf, String>> {
+    let mut chars = content.chars().peekable();
+    let mut hashes = HashMap::new();
+    expect(&mut chars, '{')?;
+    if !consume(&mut chars, '}') {
+        loop {
+            let file = parse_string(&mut chars)?;
+            expect(&mut chars, ':')?;
+            hashes.insert(PathBuf::from(file), parse_string(&mut chars)?);
+            if consume(&mut chars, '}') {
+                break;
+            }
+            expect(&mut chars, ',')?;
+        }
+    }
+    skip_whitespace(&mut chars);
+    chars.peek().is_none().then_some(hashes)
+}
+
+/// Parses an object of string arrays, the shape of the include cache
+fn parse(content: &str) -> Option<HashMap<PathBuf, HashSet<PathBuf>>> {
+    let mut chars = content.chars().peekable();
+    let mut included_files = HashMap::new();
+    expect(&mut chars, '{')?;
+    if !consume(&mut chars, '}') {
+        loop {
+            let included = parse_string(&mut chars)?;
+            expect(&mut chars, ':')?;
+            expect(&mut chars, '[')?;
+            let mut includers = HashSet::new();
+            if !consume(&mut chars, ']') {
+                loop {
+                    includers.insert(PathBuf::from(parse_string(&mut chars)?));
+                    if consume(&mut chars, ']') {
+                        break;
+                    }
+                    expect(&mut chars, ',')?;
+                }
+            }
+            included_files.insert(PathBuf::from(included), includers);
+            if consume(&mut chars, '}') {
+                break;
+            }
+            expect(&mut chars, ',')?;
+        }
+    }
+    skip_whitespace(&mut chars);
+    chars.peek().is_none().then_some(included_files)
+}
+
+fn skip_whitespace(chars: &mut Peekable<Chars>) {
+    while chars.next_if(|c| c.is_whitespace()).is_some() {}
+}
+
+/// Consumes `expected` (after any whitespace) if it is next
+fn consume(chars: &mut Peekable<Chars>, expected: char) -> bool {
+    skip_whitespace(chars);
+    chars.next_if_eq(&expected).is_some()
+}
+
+fn expect(chars: &mut Peekable<Chars>, expected: char) -> Option<()> {
+    consume(chars, expected).then_some(())
+}
+
+fn parse_string(chars: &mut Peekable<Chars>) -> Option<String> {
+    expect(chars, '"')?;
+    let mut s = String::new();
+    loop {
+        match chars.next()? {
+            '"' => return Some(s),
+            '\\' => match chars.next()? {
+                'u' => {
+                    let hex: String = chars.take(4).collect();
+                    s.push(char::from_u32(u32::from_str_radix(&hex, 16).ok()?)?);
+                }
+                'n' => s.push('\n'),
+                't' => s.push('\t'),
+                'r' => s.push('\r'),
+                c => s.push(c),
+            },
+            c => s.push(c),
+        }
+    }
+}
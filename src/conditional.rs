@@ -0,0 +1,63 @@
+//! Conditional regions, between an `--if FLAG` line and an `--endif` line, that are only kept if
+//! the flag is defined. Regions can nest, and each file's markers must match within that file.
+use std::path::Path;
+
+use crate::{IncludeError, IncludeOptions};
+
+/// The conditional regions open at the current line of a file
+#[derive(Debug, Default)]
+pub(crate) struct Conditionals {
+    /// For each open region, outermost first: the line its `--if` is on, the `--if` line itself
+    /// and whether the region is kept
+    open: Vec<(usize, String, bool)>,
+}
+
+impl Conditionals {
+    /// Whether `line` (the 1-based `line_number` of `file`) should be dropped, because it is a
+    /// marker or is inside a region that isn't kept. An `--endif` without an `--if` is an error.
+    pub(crate) fn drop_line(
+        &mut self,
+        line: &str,
+        line_number: usize,
+        file: &Path,
+        options: &IncludeOptions,
+    ) -> Result<bool, IncludeError> {
+        let marker = line.trim();
+        let flag = marker
+            .strip_prefix(options.if_marker.as_str())
+            .filter(|rest| rest.starts_with(char::is_whitespace))
+            .map(str::trim);
+        if let Some(flag) = flag {
+            let kept = self.is_kept() && options.is_defined(flag);
+            self.open.push((line_number, marker.to_string(), kept));
+            return Ok(true);
+        }
+        if marker == options.endif_marker {
+            if self.open.pop().is_none() {
+                return Err(IncludeError::UnmatchedConditional {
+                    file: file.to_path_buf(),
+                    line: line_number,
+                    marker: marker.to_string(),
+                });
+            }
+            return Ok(true);
+        }
+        Ok(!self.is_kept())
+    }
+
+    /// Checks that every region was closed by the end of the file
+    pub(crate) fn finish(self, file: &Path) -> Result<(), IncludeError> {
+        match self.open.into_iter().next_back() {
+            Some((line, marker, _)) => Err(IncludeError::UnmatchedConditional {
+                file: file.to_path_buf(),
+                line,
+                marker,
+            }),
+            None => Ok(()),
+        }
+    }
+
+    fn is_kept(&self) -> bool {
+        self.open.last().is_none_or(|(_, _, kept)| *kept)
+    }
+}
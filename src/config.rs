@@ -0,0 +1,232 @@
+//! Settings read from a `simple-include.toml` file.
+//!
+//! Each key is the name of a command line option (`max_depth` or `max-depth` for
+//! `--max-depth`), and is turned into the equivalent arguments so clap parses and validates it
+//! the same way. Options given on the command line take precedence over the file.
+//!
+//! A `.si-config` file in the same format can set a few options for the files in its directory and
+//! the directories below it, e.g. a different include prefix for one subtree.
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use clap::parser::ValueSource;
+use clap::{ArgAction, ArgMatches, Command};
+use log::warn;
+use serde::Deserialize;
+use simple_include::{quote, slashed, IncludeOptions};
+
+pub const CONFIG_FILE_NAME: &str = "simple-include.toml";
+pub const DIR_CONFIG_FILE_NAME: &str = ".si-config";
+
+/// A value in the config file
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(untagged, expecting = "expected a string, integer, boolean or array")]
+pub enum Value {
+    String(String),
+    Integer(i64),
+    Boolean(bool),
+    Array(Vec<Value>),
+}
+
+/// The settings in a `simple-include.toml` file, each under the name of its command line option
+#[derive(Debug, Deserialize)]
+#[serde(transparent)]
+struct Config {
+    settings: BTreeMap<String, Value>,
+}
+
+/// The settings in a `.si-config` file, which can only set the options that affect how directives
+/// are read. Keys can be written with `-` or `_`.
+#[derive(Debug, Deserialize)]
+struct DirConfig {
+    include: Option<Strings>,
+    #[serde(alias = "include-suffix")]
+    include_suffix: Option<String>,
+    #[serde(alias = "comment-marker")]
+    comment_marker: Option<String>,
+    #[serde(alias = "root-prefix")]
+    root_prefix: Option<String>,
+    #[serde(alias = "strip-lines")]
+    strip_lines: Option<Strings>,
+    feature: Option<Strings>,
+    /// Any other keys, which are warned about
+    #[serde(flatten)]
+    other: BTreeMap<String, toml::Value>,
+}
+
+/// A string, or an array of them
+#[derive(Debug, Deserialize)]
+#[serde(untagged, expecting = "expected a string or an array of strings")]
+enum Strings {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl From<Strings> for Vec<String> {
+    fn from(strings: Strings) -> Self {
+        match strings {
+            Strings::One(s) => vec![s],
+            Strings::Many(strings) => strings,
+        }
+    }
+}
+
+/// Finds the config file in the first source directory, then the current directory
+pub fn find(srcs: &[String]) -> Option<PathBuf> {
+    srcs.first()
+        .map(|src| Path::new(src).join(CONFIG_FILE_NAME))
+        .into_iter()
+        .chain(std::iter::once(PathBuf::from(CONFIG_FILE_NAME)))
+        .find(|path| path.is_file())
+}
+
+/// Reads the config file and converts it to command line arguments, skipping options that
+/// `matches` got from the command line. Unknown keys are warned about and ignored.
+pub fn to_args(path: &Path, command: &Command, matches: &ArgMatches) -> io::Result<Vec<String>> {
+    let content = fs::read_to_string(path)?;
+    let config: Config = toml::from_str(&content).map_err(|e| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "Error in config file {:?}: {}",
+                slashed(path),
+                e.to_string().trim_end()
+            ),
+        )
+    })?;
+    let mut args = Vec::new();
+    for (key, value) in config.settings {
+        let id = key.replace('-', "_");
+        let Some(arg) = command
+            .get_arguments()
+            .find(|arg| arg.get_id() == id.as_str() && arg.get_long().is_some())
+        else {
+            warn!(
+                "Unknown key {:?} in config file {:?}, ignoring it",
+                key,
+                slashed(path)
+            );
+            continue;
+        };
+        if matches.value_source(&id) == Some(ValueSource::CommandLine) {
+            continue;
+        }
+        let long = format!("--{}", arg.get_long().unwrap());
+        let values = match value {
+            Value::Array(values) => values,
+            value => vec![value],
+        };
+        for value in values {
+            match value {
+                Value::Boolean(true) if !arg.get_action().takes_values() => args.push(long.clone()),
+                Value::Boolean(false) if !arg.get_action().takes_values() => {}
+                // A count like `verbose = 2` is the flag repeated
+                Value::Integer(count) if matches!(arg.get_action(), ArgAction::Count) => {
+                    args.extend((0..count).map(|_| long.clone()))
+                }
+                Value::String(s) => args.extend([long.clone(), s]),
+                Value::Integer(i) => args.extend([long.clone(), i.to_string()]),
+                Value::Boolean(b) => args.extend([long.clone(), b.to_string()]),
+                Value::Array(_) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!(
+                            "Error in config file {:?}: {:?} can't contain nested arrays",
+                            slashed(path),
+                            key
+                        ),
+                    ))
+                }
+            }
+        }
+    }
+    Ok(args)
+}
+
+/// Applies the settings in a `.si-config` file to `options`. Only the options that affect how
+/// directives are read can be set per directory; other keys are warned about and ignored, and a
+/// value of the wrong type is an error.
+pub fn apply_dir_config(path: &Path, options: &mut IncludeOptions) -> io::Result<()> {
+    let content = fs::read_to_string(path)?;
+    let config: DirConfig = toml::from_str(&content).map_err(|e| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "Error in directory config {:?}: {}",
+                slashed(path),
+                e.to_string().trim_end()
+            ),
+        )
+    })?;
+    for key in config.other.keys() {
+        warn!(
+            "{:?} can't be set in directory config {:?}, ignoring it",
+            key,
+            slashed(path)
+        );
+    }
+    if let Some(prefixes) = config.include {
+        options.include_strings = prefixes.into();
+    }
+    if let Some(suffix) = config.include_suffix {
+        options.include_suffix = suffix;
+    }
+    if let Some(marker) = config.comment_marker {
+        options.comment_marker = marker;
+    }
+    if let Some(prefix) = config.root_prefix {
+        options.root_prefix = prefix;
+    }
+    if let Some(prefixes) = config.strip_lines {
+        options.strip_lines = prefixes.into();
+    }
+    if let Some(flags) = config.feature {
+        options.features.extend(Vec::from(flags));
+    }
+    Ok(())
+}
+
+/// Writes the settings in `matches` in the config file format, with every option that has a
+/// value, including defaults, so the result can be read back as a config file
+pub fn write_settings(
+    command: &Command,
+    matches: &ArgMatches,
+    out: &mut impl Write,
+) -> io::Result<()> {
+    for arg in command.get_arguments() {
+        let id = arg.get_id().as_str();
+        if arg.get_long().is_none() || matches!(id, "config" | "print_config") {
+            continue;
+        }
+        let value = match arg.get_action() {
+            ArgAction::SetTrue | ArgAction::SetFalse => matches.get_flag(id).to_string(),
+            ArgAction::Count => matches.get_count(id).to_string(),
+            ArgAction::Set | ArgAction::Append => {
+                let Some(raw) = matches.get_raw(id) else {
+                    continue;
+                };
+                let values: Vec<String> = raw
+                    .map(|value| toml_value(&value.to_string_lossy()))
+                    .collect();
+                if matches!(arg.get_action(), ArgAction::Append) {
+                    format!("[{}]", values.join(", "))
+                } else {
+                    values.join(", ")
+                }
+            }
+            _ => continue,
+        };
+        writeln!(out, "{} = {}", id, value)?;
+    }
+    Ok(())
+}
+
+/// A value as written in the config file: numbers as they are and anything else as a string
+fn toml_value(value: &str) -> String {
+    match value.parse::<i64>() {
+        Ok(number) if number.to_string() == value => value.to_string(),
+        _ => quote(value),
+    }
+}
@@ -0,0 +1,44 @@
+//! Asking before overwriting target files with `--interactive`, so hand-written files in a
+//! populated target directory aren't clobbered by mistake.
+use std::io::{self, BufRead, Write};
+use std::path::Path;
+
+use simple_include::slashed;
+
+/// Asks whether to overwrite each existing target file, remembering an answer of all or none for
+/// the rest of the run
+#[derive(Default)]
+pub struct Confirm {
+    /// The answer for every remaining file, once all or none was given
+    all: Option<bool>,
+}
+
+impl Confirm {
+    /// Asks on stderr whether to overwrite `target`, reading the answer from stdin. Once stdin
+    /// runs out, e.g. when nothing is piped to it, files are overwritten without asking.
+    pub fn overwrite(&mut self, target: &Path) -> bool {
+        let stdin = io::stdin();
+        while self.all.is_none() {
+            eprint!(
+                "Overwrite {:?}, which this run didn't generate? (y)es, (n)o, (a)ll, n(o)ne: ",
+                slashed(target)
+            );
+            let _ = io::stderr().flush();
+            let mut answer = String::new();
+            match stdin.lock().read_line(&mut answer) {
+                Ok(0) | Err(_) => {
+                    eprintln!();
+                    self.all = Some(true);
+                }
+                Ok(_) => match answer.trim().to_ascii_lowercase().as_str() {
+                    "y" | "yes" => return true,
+                    "n" | "no" => return false,
+                    "a" | "all" => self.all = Some(true),
+                    "o" | "none" => self.all = Some(false),
+                    _ => {}
+                },
+            }
+        }
+        self.all.unwrap_or(true)
+    }
+}
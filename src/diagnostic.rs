@@ -0,0 +1,127 @@
+//! Warnings and errors about include directives. They are collected while a file is expanded and
+//! reported once it is done, as text or, for editor tooling, as one JSON object per line.
+use std::fmt;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use log::warn;
+
+use crate::{quote, slashed};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    /// The directive was left as it is, or only part of it was used
+    Warning,
+    /// The file failed because of the directive, in strict mode
+    Error,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Severity::Warning => write!(f, "warning"),
+            Severity::Error => write!(f, "error"),
+        }
+    }
+}
+
+/// A problem with an include directive
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    /// The file containing the directive
+    pub file: PathBuf,
+    /// The 1-based line of the directive
+    pub line: usize,
+    pub message: String,
+    /// The include path as written in the directive, or the matching file for a glob
+    pub include: Option<String>,
+}
+
+impl Diagnostic {
+    /// The diagnostic as a single line JSON object
+    pub fn to_json(&self) -> String {
+        format!(
+            "{{\"severity\": {}, \"file\": {}, \"line\": {}, \"message\": {}, \"include\": {}}}",
+            quote(&self.severity.to_string()),
+            quote(&slashed(&self.file).to_string()),
+            self.line,
+            quote(&self.message),
+            self.include
+                .as_deref()
+                .map_or_else(|| "null".to_string(), quote)
+        )
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}: {}", slashed(&self.file), self.line, self.message)
+    }
+}
+
+/// The number of warnings written as JSON, which don't go through the logger
+static JSON_WARNINGS: AtomicUsize = AtomicUsize::new(0);
+
+/// The number of warnings reported as JSON so far. Warnings in the text format are logged, so
+/// the logger can count them.
+pub fn json_warnings() -> usize {
+    JSON_WARNINGS.load(Ordering::Relaxed)
+}
+
+/// How diagnostics are reported
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DiagnosticFormat {
+    /// Warnings as `file:line: message` log messages. Errors are returned to the caller, which
+    /// reports them.
+    #[default]
+    Text,
+    /// Every diagnostic, errors included, as a JSON object on its own line on stderr
+    Json,
+}
+
+impl DiagnosticFormat {
+    /// Reports the diagnostics for one file
+    pub(crate) fn report(self, diagnostics: &[Diagnostic]) {
+        for diagnostic in diagnostics {
+            match self {
+                DiagnosticFormat::Text => {
+                    if diagnostic.severity == Severity::Warning {
+                        warn!("{}", diagnostic);
+                    }
+                }
+                DiagnosticFormat::Json => {
+                    if diagnostic.severity == Severity::Warning {
+                        JSON_WARNINGS.fetch_add(1, Ordering::Relaxed);
+                    }
+                    eprintln!("{}", diagnostic.to_json());
+                }
+            }
+        }
+    }
+}
+
+impl FromStr for DiagnosticFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "text" => Ok(DiagnosticFormat::Text),
+            "json" => Ok(DiagnosticFormat::Json),
+            _ => Err(format!(
+                "unsupported format {:?}, expected one of text, json",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for DiagnosticFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DiagnosticFormat::Text => write!(f, "text"),
+            DiagnosticFormat::Json => write!(f, "json"),
+        }
+    }
+}
@@ -0,0 +1,23 @@
+//! Unified diffs between an existing output and what would be written in its place, for `--diff`
+use similar::TextDiff;
+
+/// Lines of unchanged text shown around each change
+const CONTEXT: usize = 3;
+
+/// A unified diff from `old` to `new`, headed with the two labels, or `None` if they are the same
+pub(crate) fn unified_diff(
+    old: &str,
+    new: &str,
+    old_label: &str,
+    new_label: &str,
+) -> Option<String> {
+    if old == new {
+        return None;
+    }
+    let diff = TextDiff::from_lines(old, new)
+        .unified_diff()
+        .context_radius(CONTEXT)
+        .header(old_label, new_label)
+        .to_string();
+    Some(diff)
+}
@@ -0,0 +1,695 @@
+//! Parsing of include directives and the selections that pick part of an included file
+use std::env;
+use std::fmt;
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+
+use log::info;
+
+use crate::{slashed, IncludeOptions};
+
+/// The part of an included file to include, written after a colon (or `#` for regions and `@`
+/// for byte ranges) following the path
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Selection<'a> {
+    /// A region between named markers, e.g. `utils.rs#setup`
+    Region(&'a str),
+    /// A man page section, e.g. `other.1:SYNOPSIS`
+    ManSection(&'a str),
+    /// A 1-based inclusive line range, e.g. `sample.rs:10-25`, `sample.rs:10-` or `sample.rs:10`
+    Lines(&'a str),
+    /// A range of bytes given as an offset and a length, e.g. `data.bin@1024+256`
+    Bytes(&'a str),
+}
+
+impl fmt::Display for Selection<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Selection::Region(name) => write!(f, "region={}", name),
+            Selection::ManSection(section) => write!(f, "section={}", section),
+            Selection::Lines(range) => write!(f, "lines={}", range),
+            Selection::Bytes(range) => write!(f, "bytes={}", range),
+        }
+    }
+}
+
+/// A parsed include directive
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Directive<'a> {
+    /// The include path, without the selection
+    pub(crate) path: &'a str,
+    /// The part of the file to include, if not all of it
+    pub(crate) selection: Option<Selection<'a>>,
+    /// Whether to wrap the included content in a fenced code block, from `--fence[=lang]`
+    pub(crate) fence: Option<Fence<'a>>,
+    /// The flag that must be defined for the include to be expanded, from `--include-if FLAG`
+    pub(crate) condition: Option<&'a str>,
+    /// The filters to transform the included content with, as written after the first `|`, e.g.
+    /// `base64 |indent=4`
+    pub(crate) filters: Option<&'a str>,
+}
+
+impl Directive<'_> {
+    /// The names of the filters to apply, in order, e.g. `base64` and `indent=4`
+    pub(crate) fn filters(&self) -> impl Iterator<Item = &str> {
+        self.filters
+            .into_iter()
+            .flat_map(|filters| filters.split('|'))
+            .map(str::trim)
+            .filter(|filter| !filter.is_empty())
+    }
+
+    /// The selection, fence, condition and filters, each after a space, e.g. ` lines=1-10 |base64`
+    pub(crate) fn modifiers(&self) -> String {
+        let mut modifiers = String::new();
+        if let Some(selection) = self.selection {
+            modifiers.push_str(&format!(" {}", selection));
+        }
+        if let Some(fence) = self.fence {
+            modifiers.push_str(&format!(" {}", fence));
+        }
+        if let Some(condition) = self.condition {
+            modifiers.push_str(&format!(" if={}", condition));
+        }
+        for filter in self.filters() {
+            modifiers.push_str(&format!(" |{}", filter));
+        }
+        modifiers
+    }
+}
+
+/// A fenced code block to wrap included content in, written as `--fence` or `--fence=lang`
+/// before the path
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Fence<'a> {
+    /// The language is inferred from the included file's extension
+    Inferred,
+    Language(&'a str),
+}
+
+impl fmt::Display for Fence<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Fence::Inferred => write!(f, "fence"),
+            Fence::Language(language) => write!(f, "fence={}", language),
+        }
+    }
+}
+
+impl Fence<'_> {
+    /// Wraps content in a fenced code block, tagged with the language or, if none was given, the
+    /// language for the extension of `path`
+    pub(crate) fn wrap(&self, content: &str, path: &str) -> String {
+        let language = match self {
+            Fence::Language(language) => language.to_string(),
+            Fence::Inferred => language_for_path(path),
+        };
+        let mut fenced = format!("```{}\n{}", language, content);
+        if !content.is_empty() && !content.ends_with('\n') {
+            fenced.push('\n');
+        }
+        fenced.push_str("```");
+        fenced
+    }
+}
+
+/// The Markdown language tag for a file, from its extension. Extensions without a better known
+/// tag are used as they are, and files without one get no tag.
+fn language_for_path(path: &str) -> String {
+    let path = path.split(['?', '#']).next().unwrap_or(path);
+    let Some(extension) = Path::new(path).extension().and_then(|e| e.to_str()) else {
+        return String::new();
+    };
+    let language = match extension.to_ascii_lowercase().as_str() {
+        "rs" => "rust",
+        "py" => "python",
+        "js" | "mjs" | "cjs" => "javascript",
+        "ts" => "typescript",
+        "rb" => "ruby",
+        "sh" => "bash",
+        "yml" => "yaml",
+        "md" => "markdown",
+        "h" => "c",
+        "hpp" | "cc" | "cxx" => "cpp",
+        "kt" => "kotlin",
+        "cs" => "csharp",
+        _ => return extension.to_string(),
+    };
+    language.to_string()
+}
+
+/// Parses an include directive, returning the include path, the part of the file to include,
+/// any fence, condition and filters, or `None` if the line is not a directive. The directive may be
+/// indented, may end with `include_suffix` (if not empty), e.g. `-->` for
+/// `<!--include header.html-->`, and may have a trailing comment starting with `comment_marker`
+/// (if not empty), e.g. `--include header.txt # shared legal header`. A path with spaces can be
+/// quoted, e.g. `--include "my docs/header file.txt":1-5`. The directive can also be written with
+/// its arguments in parentheses, e.g. `--include(path="a.txt", lines="1-10", fence="rust")`, in
+/// which case a malformed one is not a directive; see `malformed_arguments`.
+pub(crate) fn parse_directive<'a>(
+    line: &'a str,
+    include_strings: &[String],
+    include_suffix: &str,
+    comment_marker: &str,
+) -> Option<Directive<'a>> {
+    let line = line.trim_start();
+    let include_string = directive_prefix(line, include_strings)?;
+    let arguments = &line[include_string.len()..];
+    if arguments.starts_with('(') {
+        return parse_arguments(arguments, include_suffix).ok();
+    }
+    let (condition, include_path) = split_condition(line.trim_start_matches(include_string));
+    let mut include_path = include_path.trim();
+    if !include_suffix.is_empty() {
+        if let Some(without_suffix) = include_path.strip_suffix(include_suffix) {
+            include_path = without_suffix.trim_end();
+        }
+    }
+    let (fence, include_path) = split_fence(include_path);
+    let include_path = strip_comment(include_path, comment_marker);
+    // A command include keeps any `#`, `:` or `|` in its command
+    let (include_path, filters) = if include_path.starts_with('!') {
+        (include_path, None)
+    } else {
+        split_filters(include_path)
+    };
+    let (path, selection) = if include_path.starts_with('!') {
+        (include_path, None)
+    } else if let Some(quoted) = split_quoted(include_path) {
+        quoted
+    } else {
+        split_selection(include_path)
+    };
+    Some(Directive {
+        path,
+        selection,
+        fence,
+        condition,
+        filters,
+    })
+}
+
+/// Why a directive written with its arguments in parentheses can't be parsed, or `None` if the
+/// line isn't one or it is well formed
+pub(crate) fn malformed_arguments(
+    line: &str,
+    include_strings: &[String],
+    include_suffix: &str,
+) -> Option<String> {
+    let line = line.trim_start();
+    let arguments = &line[directive_prefix(line, include_strings)?.len()..];
+    if !arguments.starts_with('(') {
+        return None;
+    }
+    parse_arguments(arguments, include_suffix).err()
+}
+
+/// Parses the arguments of a directive like `(path="a.txt", lines="1-10", fence="rust")`, which
+/// may be given in any order. Each value is quoted with `"` or `'`. The path is required, and the
+/// other arguments are the modifiers of the plain form: `lines`, `region`, `section` or `bytes` to
+/// select part of the file, `fence`, `if` and `filters`.
+fn parse_arguments<'a>(arguments: &'a str, include_suffix: &str) -> Result<Directive<'a>, String> {
+    let mut arguments = arguments.trim_end();
+    if !include_suffix.is_empty() {
+        if let Some(without_suffix) = arguments.strip_suffix(include_suffix) {
+            arguments = without_suffix.trim_end();
+        }
+    }
+    let mut rest = arguments
+        .strip_prefix('(')
+        .and_then(|arguments| arguments.strip_suffix(')'))
+        .ok_or("missing closing parenthesis")?;
+    let mut directive = Directive {
+        path: "",
+        selection: None,
+        fence: None,
+        condition: None,
+        filters: None,
+    };
+    let mut given = Vec::new();
+    while !rest.trim().is_empty() {
+        let (key, after) = rest
+            .split_once('=')
+            .map(|(key, after)| (key.trim(), after.trim_start()))
+            .filter(|(key, _)| !key.is_empty() && !key.contains(char::is_whitespace))
+            .ok_or_else(|| format!("expected key=\"value\" at {:?}", rest.trim()))?;
+        let (value, after) =
+            quoted(after).ok_or_else(|| format!("the value of {} must be quoted", key))?;
+        if given.contains(&key) {
+            return Err(format!("{} is given more than once", key));
+        }
+        given.push(key);
+        set_argument(&mut directive, key, value)?;
+        let after = after.trim_start();
+        rest = match after.strip_prefix(',') {
+            Some(after) => after,
+            None if after.is_empty() => after,
+            None => return Err(format!("expected a comma before {:?}", after)),
+        };
+    }
+    if directive.path.is_empty() {
+        return Err("no path given".to_string());
+    }
+    Ok(directive)
+}
+
+/// Sets the part of a directive that a `key="value"` argument gives
+fn set_argument<'a>(
+    directive: &mut Directive<'a>,
+    key: &str,
+    value: &'a str,
+) -> Result<(), String> {
+    match key {
+        "path" => directive.path = value,
+        "lines" | "region" | "section" | "bytes" => {
+            if directive.selection.is_some() {
+                return Err("only one of lines, region, section and bytes can be given".to_string());
+            }
+            let selection = match key {
+                "lines" => is_line_range(value).then_some(Selection::Lines(value)),
+                "region" => is_region_name(value).then_some(Selection::Region(value)),
+                "section" => is_man_section_name(value).then_some(Selection::ManSection(value)),
+                _ => byte_range(value).map(|_| Selection::Bytes(value)),
+            };
+            directive.selection =
+                Some(selection.ok_or_else(|| format!("invalid {} {:?}", key, value))?);
+        }
+        "fence" if value.is_empty() => directive.fence = Some(Fence::Inferred),
+        "fence" => directive.fence = Some(Fence::Language(value)),
+        "if" => directive.condition = Some(value),
+        "filters" => directive.filters = Some(value),
+        _ => return Err(format!("unknown argument {:?}", key)),
+    }
+    Ok(())
+}
+
+/// Splits the filters off an include path, e.g. `key.pem |base64` into `key.pem` and `base64`.
+/// Like a comment, the first `|` only starts the filters after whitespace, and not inside a quoted
+/// path.
+fn split_filters(include_path: &str) -> (&str, Option<&str>) {
+    let quoted_len = quoted(include_path).map_or(0, |(path, _)| path.len() + 2);
+    include_path
+        .match_indices('|')
+        .find(|(start, _)| {
+            *start >= quoted_len && include_path[..*start].ends_with(char::is_whitespace)
+        })
+        .map_or((include_path, None), |(start, _)| {
+            (
+                include_path[..start].trim_end(),
+                Some(&include_path[start + 1..]),
+            )
+        })
+}
+
+/// Removes a trailing comment from an include path. The marker only starts a comment after
+/// whitespace, so a path like `C#/intro.txt` or a region like `utils.rs#setup` is left alone, and
+/// not inside a quoted path.
+fn strip_comment<'a>(include_path: &'a str, comment_marker: &str) -> &'a str {
+    let marker = comment_marker.trim_start();
+    if marker.is_empty() {
+        return include_path;
+    }
+    let quoted_len = quoted(include_path).map_or(0, |(path, _)| path.len() + 2);
+    include_path
+        .match_indices(marker)
+        .find(|(start, _)| {
+            *start >= quoted_len && include_path[..*start].ends_with(char::is_whitespace)
+        })
+        .map_or(include_path, |(start, _)| include_path[..start].trim_end())
+}
+
+/// Splits an include path that starts with a `"` or `'` into the text up to the matching quote
+/// and what follows it. Returns `None` if the path isn't quoted or the quote isn't closed.
+fn quoted(include_path: &str) -> Option<(&str, &str)> {
+    let quote = include_path
+        .chars()
+        .next()
+        .filter(|c| matches!(c, '"' | '\''))?;
+    include_path[1..].split_once(quote)
+}
+
+/// Splits a quoted include path from the selection after it, so `"my docs/sample.rs":10-25`
+/// becomes (`my docs/sample.rs`, a line range). Returns `None` if the path isn't quoted, the quote
+/// isn't closed or it is followed by something other than a selection, in which case the path is
+/// used as it is written, quotes and all.
+fn split_quoted(include_path: &str) -> Option<(&str, Option<Selection<'_>>)> {
+    let (path, rest) = quoted(include_path)?;
+    if path.is_empty() {
+        return None;
+    }
+    let mut rest = rest.trim_start().chars();
+    let selection = match rest.next() {
+        None => None,
+        Some(separator) => Some(selection(separator, rest.as_str())?),
+    };
+    Some((path, selection))
+}
+
+/// Splits the flag off a conditional include, which has `-if FLAG` straight after the prefix,
+/// e.g. `--include-if FEATURE_X extra.txt`
+fn split_condition(rest: &str) -> (Option<&str>, &str) {
+    let conditional = rest
+        .strip_prefix("-if")
+        .filter(|after| after.starts_with(char::is_whitespace))
+        .and_then(|after| after.trim_start().split_once(char::is_whitespace));
+    match conditional {
+        Some((condition, include_path)) => (Some(condition), include_path),
+        None => (None, rest),
+    }
+}
+
+/// Splits a leading `--fence` or `--fence=lang` modifier off an include path
+fn split_fence(include_path: &str) -> (Option<Fence<'_>>, &str) {
+    let Some(rest) = include_path.strip_prefix("--fence") else {
+        return (None, include_path);
+    };
+    if let Some(rest) = rest.strip_prefix('=') {
+        if let Some((language, include_path)) = rest.split_once(char::is_whitespace) {
+            if !language.is_empty() {
+                return (Some(Fence::Language(language)), include_path.trim_start());
+            }
+        }
+    } else if rest.starts_with(char::is_whitespace) {
+        return (Some(Fence::Inferred), rest.trim_start());
+    }
+    (None, include_path)
+}
+
+/// Resolves an include path against the directory of the file it is in (or the include base, if
+/// one is set), or against `root` if it starts with the root prefix, after expanding environment
+/// variables (`$VAR` or `${VAR}`) and a leading `~`. Absolute paths are used as they are. With
+/// `case_insensitive`, a path that doesn't exist is matched against the files on disk ignoring
+/// case. Returns the name of the variable if one is not defined.
+pub(crate) fn resolve_include_path(
+    parent_dir: &Path,
+    root: &Path,
+    include_path: &str,
+    options: &IncludeOptions,
+) -> Result<PathBuf, String> {
+    let root_relative = include_path
+        .strip_prefix(options.root_prefix.as_str())
+        .filter(|_| !options.root_prefix.is_empty());
+    let (base_dir, include_path) = match root_relative {
+        Some(include_path) => (root, include_path),
+        None => (
+            options.include_base.as_deref().unwrap_or(parent_dir),
+            include_path,
+        ),
+    };
+    let expanded = PathBuf::from(expand_env_vars(include_path)?);
+    if expanded.is_absolute() {
+        return Ok(find_path(expanded, options).unwrap_or_else(|resolved| resolved));
+    }
+    let resolved = match find_path(base_dir.join(&expanded), options) {
+        Ok(found) => return Ok(found),
+        Err(resolved) => resolved,
+    };
+    // Root-relative paths have a base of their own, so only plain relative paths are searched for
+    if root_relative.is_none() {
+        for search_dir in &options.include_paths {
+            if let Ok(found) = find_path(search_dir.join(&expanded), options) {
+                info!(
+                    "{:?} not found next to the including file, using {:?} from the include path",
+                    include_path,
+                    slashed(&found)
+                );
+                return Ok(found);
+            }
+        }
+    }
+    Ok(resolved)
+}
+
+/// The existing file or directory at `path`, matched ignoring case if `case_insensitive` is set,
+/// or `path` back as the error if there isn't one
+fn find_path(path: PathBuf, options: &IncludeOptions) -> Result<PathBuf, PathBuf> {
+    if path.exists() {
+        return Ok(path);
+    }
+    if options.case_insensitive {
+        if let Some(found) = find_ignoring_case(&path) {
+            info!(
+                "{:?} matched {:?} ignoring case",
+                slashed(&path),
+                slashed(&found)
+            );
+            return Ok(found);
+        }
+    }
+    Err(path)
+}
+
+/// Finds the file a path refers to when the case of its names is ignored, going down one
+/// directory at a time and picking the entry whose name matches. An exact match is preferred, and
+/// otherwise the first match in sorted order, so the result doesn't depend on the directory order.
+fn find_ignoring_case(path: &Path) -> Option<PathBuf> {
+    let mut found = PathBuf::new();
+    for component in path.components() {
+        let Component::Normal(name) = component else {
+            found.push(component);
+            continue;
+        };
+        let exact = found.join(name);
+        if exact.exists() {
+            found = exact;
+            continue;
+        }
+        let name = name.to_str()?;
+        let dir = if found.as_os_str().is_empty() {
+            Path::new(".")
+        } else {
+            found.as_path()
+        };
+        let mut matches: Vec<_> = fs::read_dir(dir)
+            .ok()?
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.file_name())
+            .filter(|entry| {
+                entry
+                    .to_str()
+                    .is_some_and(|entry| entry.to_lowercase() == name.to_lowercase())
+            })
+            .collect();
+        matches.sort();
+        found.push(matches.first()?);
+    }
+    Some(found)
+}
+
+/// Expands `$VAR`, `${VAR}` and a leading `~` (for the home directory). A `$` that isn't followed
+/// by a variable name is kept as it is.
+fn expand_env_vars(path: &str) -> Result<String, String> {
+    let lookup = |name: &str| env::var(name).map_err(|_| name.to_string());
+    let mut expanded = String::with_capacity(path.len());
+    let mut rest = path;
+    if let Some(after) = rest.strip_prefix('~') {
+        if after.is_empty() || after.starts_with('/') {
+            expanded.push_str(&lookup("HOME")?);
+            rest = after;
+        }
+    }
+    while let Some(index) = rest.find('$') {
+        expanded.push_str(&rest[..index]);
+        let after = &rest[index + 1..];
+        let braced = after
+            .strip_prefix('{')
+            .and_then(|after| after.split_once('}'));
+        if let Some((name, remainder)) = braced {
+            expanded.push_str(&lookup(name)?);
+            rest = remainder;
+            continue;
+        }
+        let name_len = after
+            .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+            .unwrap_or(after.len());
+        if name_len == 0 {
+            expanded.push('$');
+        } else {
+            expanded.push_str(&lookup(&after[..name_len])?);
+        }
+        rest = &after[name_len..];
+    }
+    expanded.push_str(rest);
+    Ok(expanded)
+}
+
+pub(crate) fn indentation(line: &str) -> &str {
+    &line[..line.len() - line.trim_start().len()]
+}
+
+/// The first of `include_strings` that the (already trimmed) line starts with
+fn directive_prefix<'a>(line: &str, include_strings: &'a [String]) -> Option<&'a str> {
+    include_strings
+        .iter()
+        .map(String::as_str)
+        .find(|include_string| line.starts_with(include_string))
+}
+
+/// Whether a line is an include-once marker, an include prefix followed by `-once` on a line of its
+/// own, e.g. `--include-once`. On a file's first line it means the file is included at most once
+/// into each output, like `#pragma once`.
+pub(crate) fn is_once_marker(line: &str, include_strings: &[String]) -> bool {
+    let line = line.trim();
+    include_strings
+        .iter()
+        .any(|include_string| line.strip_prefix(include_string.as_str()) == Some("-once"))
+}
+
+/// Returns the line without the backslash if it is an escaped directive, e.g.
+/// `\--include foo.txt`, so it can be written out literally. Indentation is kept.
+pub(crate) fn unescape_directive(line: &str, include_strings: &[String]) -> Option<String> {
+    let indent = indentation(line);
+    line[indent.len()..]
+        .strip_prefix('\\')
+        .filter(|rest| directive_prefix(rest, include_strings).is_some())
+        .map(|rest| format!("{}{}", indent, rest))
+}
+
+/// Splits a selection off an include path, so `utils.rs#setup` becomes (`utils.rs`, a region),
+/// `other.1:SYNOPSIS` becomes (`other.1`, a man page section) and `sample.rs:10-25` becomes
+/// (`sample.rs`, a line range), and `data.bin@1024+256` becomes (`data.bin`, a byte range). Only
+/// an all-caps name or digits and dashes after the last colon, and an offset and length after the
+/// last `@`, count as a selection.
+fn split_selection(include_path: &str) -> (&str, Option<Selection<'_>>) {
+    for separator in ['#', ':', '@'] {
+        if let Some((file, name)) = include_path.rsplit_once(separator) {
+            if let Some(selection) = selection(separator, name).filter(|_| !file.is_empty()) {
+                return (file, Some(selection));
+            }
+        }
+    }
+    (include_path, None)
+}
+
+/// The selection written as `name` after `separator`, if it is one
+fn selection(separator: char, name: &str) -> Option<Selection<'_>> {
+    match separator {
+        '#' if is_region_name(name) => Some(Selection::Region(name)),
+        ':' if is_man_section_name(name) => Some(Selection::ManSection(name)),
+        ':' if is_line_range(name) => Some(Selection::Lines(name)),
+        '@' if byte_range(name).is_some() => Some(Selection::Bytes(name)),
+        _ => None,
+    }
+}
+
+fn is_region_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_alphanumeric() || c == '_' || c == '-' || c == '.')
+}
+
+/// Returns the name following `marker` if the line contains it, e.g. `setup` for
+/// `// region: setup`. The marker must not be preceded by a letter, so `region:` doesn't match
+/// `endregion:`.
+fn region_marker_name<'a>(line: &'a str, marker: &str) -> Option<&'a str> {
+    let mut search_from = 0;
+    while let Some(index) = line[search_from..].find(marker) {
+        let start = search_from + index;
+        let preceded_by_word = line[..start]
+            .chars()
+            .next_back()
+            .is_some_and(|c| c.is_alphanumeric() || c == '_');
+        if !preceded_by_word {
+            return line[start + marker.len()..].split_whitespace().next();
+        }
+        search_from = start + marker.len();
+    }
+    None
+}
+
+/// Extracts the lines between the start and end markers of the named region. Marker lines of
+/// any other regions inside it are left out, so regions can nest and overlap.
+pub(crate) fn extract_region(
+    content: &str,
+    name: &str,
+    options: &IncludeOptions,
+) -> Option<String> {
+    let is_marker = |line: &str| {
+        region_marker_name(line, &options.region_start).is_some()
+            || region_marker_name(line, &options.region_end).is_some()
+    };
+    let mut lines = content.lines();
+    lines.find(|line| region_marker_name(line, &options.region_start) == Some(name))?;
+    let mut region = Vec::new();
+    for line in lines {
+        if region_marker_name(line, &options.region_end) == Some(name) {
+            return Some(region.join("\n"));
+        }
+        if !is_marker(line) {
+            region.push(line);
+        }
+    }
+    None
+}
+
+fn is_line_range(range: &str) -> bool {
+    range.chars().any(|c| c.is_ascii_digit())
+        && range.chars().all(|c| c.is_ascii_digit() || c == '-')
+}
+
+/// The offset and length of a byte range written as `offset+length`, e.g. `1024+256`
+pub(crate) fn byte_range(range: &str) -> Option<(u64, u64)> {
+    let (offset, length) = range.split_once('+')?;
+    let number = |digits: &str| {
+        (!digits.is_empty() && digits.chars().all(|c| c.is_ascii_digit()))
+            .then(|| digits.parse::<u64>().ok())
+            .flatten()
+    };
+    let (offset, length) = (number(offset)?, number(length)?);
+    // The end must fit too, so it can be compared with the file size
+    offset.checked_add(length)?;
+    Some((offset, length))
+}
+
+/// Extracts lines `start-end` (1-based, inclusive), `start-` (to the end of the file) or a single
+/// line. Returns `None` if the range is malformed or out of bounds.
+pub(crate) fn extract_lines(content: &str, range: &str) -> Option<String> {
+    let (start, end) = range.split_once('-').unwrap_or((range, range));
+    let start: usize = start.parse().ok()?;
+    let lines: Vec<&str> = content.lines().collect();
+    let end: usize = if end.is_empty() {
+        lines.len()
+    } else {
+        end.parse().ok()?
+    };
+    if start == 0 || start > end || end > lines.len() {
+        return None;
+    }
+    Some(lines[start - 1..end].join("\n"))
+}
+
+fn is_man_section_name(name: &str) -> bool {
+    name.chars().any(|c| c.is_ascii_uppercase())
+        && name
+            .chars()
+            .all(|c| c.is_ascii_uppercase() || c.is_ascii_digit() || c == ' ' || c == '-')
+}
+
+/// Returns the section name if the line is a man-page section header, either a roff `.SH NAME`
+/// request or an all-caps line starting in the first column as in formatted man pages.
+fn man_section_header(line: &str) -> Option<&str> {
+    let name = match line.strip_prefix(".SH") {
+        Some(rest) if rest.starts_with(char::is_whitespace) => rest.trim().trim_matches('"'),
+        Some(_) => return None,
+        None if line.starts_with(char::is_whitespace) => return None,
+        None => line.trim_end(),
+    };
+    if is_man_section_name(name) {
+        Some(name)
+    } else {
+        None
+    }
+}
+
+/// Extracts the named section, from its header up to (but not including) the next section header
+pub(crate) fn extract_man_section(content: &str, section: &str) -> Option<String> {
+    let mut lines = content.lines();
+    let header = lines.find(|line| man_section_header(line) == Some(section))?;
+    let mut result = String::from(header);
+    for line in lines.take_while(|line| man_section_header(line).is_none()) {
+        result.push('\n');
+        result.push_str(line);
+    }
+    Some(result)
+}
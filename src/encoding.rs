@@ -0,0 +1,91 @@
+//! Text encodings other than UTF-8 that files can be decoded from and written back to, by their
+//! WHATWG label: legacy encodings such as Windows-1252 or Shift_JIS, and UTF-16 as exported by
+//! some Windows tools
+use std::fmt;
+use std::str::FromStr;
+
+use encoding_rs::{EncoderResult, UTF_16BE, UTF_16LE, UTF_8};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Encoding(&'static encoding_rs::Encoding);
+
+impl Encoding {
+    /// The UTF-16 encoding whose byte order mark `bytes` starts with, if any
+    pub fn from_bom(bytes: &[u8]) -> Option<Encoding> {
+        encoding_rs::Encoding::for_bom(bytes)
+            .filter(|(encoding, _)| *encoding != UTF_8)
+            .map(|(encoding, _)| Encoding(encoding))
+    }
+
+    /// The byte order mark written at the start of a file in this encoding, which is empty for
+    /// everything but UTF-16
+    pub fn bom(&self) -> &'static [u8] {
+        if self.0 == UTF_16LE {
+            &[0xFF, 0xFE]
+        } else if self.0 == UTF_16BE {
+            &[0xFE, 0xFF]
+        } else {
+            &[]
+        }
+    }
+
+    /// Decodes the whole buffer, without its byte order mark, returning `None` if it contains
+    /// bytes that are malformed in this encoding or NUL characters, which are a sure sign of
+    /// binary data
+    pub fn decode(&self, bytes: &[u8]) -> Option<String> {
+        let bytes = bytes.strip_prefix(self.bom()).unwrap_or(bytes);
+        let text = self
+            .0
+            .decode_without_bom_handling_and_without_replacement(bytes)?;
+        (!text.contains('\0')).then(|| text.into_owned())
+    }
+
+    /// Encodes the text, replacing any characters that can't be represented with `?`
+    pub fn encode(&self, text: &str) -> Vec<u8> {
+        // encoding_rs only decodes UTF-16, so it is written here
+        if self.0 == UTF_16LE {
+            return text.encode_utf16().flat_map(u16::to_le_bytes).collect();
+        }
+        if self.0 == UTF_16BE {
+            return text.encode_utf16().flat_map(u16::to_be_bytes).collect();
+        }
+        let mut encoder = self.0.new_encoder();
+        let mut bytes = Vec::with_capacity(text.len());
+        let mut rest = text;
+        loop {
+            let (result, read) =
+                encoder.encode_from_utf8_to_vec_without_replacement(rest, &mut bytes, true);
+            rest = &rest[read..];
+            match result {
+                EncoderResult::InputEmpty => return bytes,
+                EncoderResult::OutputFull => bytes.reserve(
+                    encoder
+                        .max_buffer_length_from_utf8_without_replacement(rest.len())
+                        .unwrap_or(rest.len()),
+                ),
+                EncoderResult::Unmappable(_) => bytes.push(b'?'),
+            }
+        }
+    }
+}
+
+impl FromStr for Encoding {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        encoding_rs::Encoding::for_label_no_replacement(s.as_bytes())
+            .map(Encoding)
+            .ok_or_else(|| {
+                format!(
+                    "unsupported encoding {:?}, expected a WHATWG encoding label such as latin1, windows-1252, shift_jis or utf-16le",
+                    s
+                )
+            })
+    }
+}
+
+impl fmt::Display for Encoding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0.name().to_ascii_lowercase())
+    }
+}
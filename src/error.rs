@@ -0,0 +1,160 @@
+//! Errors returned while processing files
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::slashed;
+
+#[derive(Debug)]
+pub enum IncludeError {
+    /// The file being processed does not exist
+    SourceNotFound(PathBuf),
+    /// An include directive points at a file that does not exist
+    IncludeNotFound {
+        include: PathBuf,
+        included_from: PathBuf,
+    },
+    /// The file is not valid text (UTF-8, or the fallback encoding if one is set)
+    BinaryData(PathBuf),
+    /// The file has a line longer than `max_line_length` bytes, so it isn't read line by line
+    LineTooLong { path: PathBuf, limit: usize },
+    /// An include directive points at a file that is not valid text. Only returned in strict mode,
+    /// otherwise the directive is left as it is.
+    BinaryInclude {
+        include: PathBuf,
+        included_from: PathBuf,
+    },
+    /// Following the include would expand a file that is already being expanded. Holds the chain
+    /// of files, starting and ending with the same file. Only returned in strict mode, otherwise
+    /// the directive is left as it is.
+    CycleDetected(Vec<PathBuf>),
+    /// An `--if` line without a matching `--endif`, or an `--endif` without an `--if`. Holds the
+    /// file, the 1-based line of the marker and the marker line itself.
+    UnmatchedConditional {
+        file: PathBuf,
+        line: usize,
+        marker: String,
+    },
+    /// A file or directory can't be read or written because of its permissions. `write` is set
+    /// when it was being written.
+    PermissionDenied { path: PathBuf, write: bool },
+    /// Any other error reading or writing files
+    Io(io::Error),
+    /// An include that failed in strict mode, with the file and 1-based line of its directive.
+    /// Only wraps `IncludeNotFound`, `BinaryInclude` and `CycleDetected`.
+    AtLine {
+        file: PathBuf,
+        line: usize,
+        error: Box<IncludeError>,
+    },
+}
+
+impl IncludeError {
+    /// Classifies an error from reading `path`
+    pub fn from_io(e: io::Error, path: &Path) -> IncludeError {
+        match e.kind() {
+            io::ErrorKind::NotFound => IncludeError::SourceNotFound(path.to_path_buf()),
+            io::ErrorKind::InvalidData => IncludeError::BinaryData(path.to_path_buf()),
+            io::ErrorKind::PermissionDenied => IncludeError::PermissionDenied {
+                path: path.to_path_buf(),
+                write: false,
+            },
+            _ => IncludeError::Io(e),
+        }
+    }
+
+    /// The error without the location of the directive that caused it
+    pub fn unlocated(&self) -> &IncludeError {
+        match self {
+            IncludeError::AtLine { error, .. } => error.unlocated(),
+            e => e,
+        }
+    }
+
+    /// Classifies an error from writing `path`
+    pub fn from_write(e: io::Error, path: &Path) -> IncludeError {
+        match e.kind() {
+            io::ErrorKind::PermissionDenied => IncludeError::PermissionDenied {
+                path: path.to_path_buf(),
+                write: true,
+            },
+            _ => IncludeError::Io(e),
+        }
+    }
+}
+
+impl fmt::Display for IncludeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            IncludeError::SourceNotFound(path) => write!(f, "File not found: {:?}", slashed(path)),
+            IncludeError::IncludeNotFound {
+                include,
+                included_from,
+            } => write!(
+                f,
+                "Include file not found: {:?} (included in file {:?})",
+                slashed(include),
+                slashed(included_from)
+            ),
+            IncludeError::BinaryData(path) => write!(f, "Binary data in file: {:?}", slashed(path)),
+            IncludeError::LineTooLong { path, limit } => write!(
+                f,
+                "Line longer than {} bytes in file: {:?}",
+                limit,
+                slashed(path)
+            ),
+            IncludeError::BinaryInclude {
+                include,
+                included_from,
+            } => write!(
+                f,
+                "Binary data in include file: {:?} (included in file {:?})",
+                slashed(include),
+                slashed(included_from)
+            ),
+            IncludeError::CycleDetected(chain) => {
+                let chain: Vec<String> = chain.iter().map(|p| slashed(p).to_string()).collect();
+                write!(f, "Cycle detected: {}", chain.join(" -> "))
+            }
+            IncludeError::UnmatchedConditional { file, line, marker } => write!(
+                f,
+                "Unmatched {:?} on line {} of file {:?}",
+                marker,
+                line,
+                slashed(file)
+            ),
+            IncludeError::PermissionDenied { path, write: false } => write!(
+                f,
+                "Permission denied reading {:?}. Make sure the current user can read it, e.g. with `chmod u+r {}`",
+                slashed(path),
+                slashed(path)
+            ),
+            IncludeError::PermissionDenied { path, write: true } => write!(
+                f,
+                "Permission denied writing {:?}. Make sure the current user can write to it and to the directory it is in",
+                slashed(path)
+            ),
+            IncludeError::Io(e) => write!(f, "{}", e),
+            IncludeError::AtLine { file, line, error } => {
+                write!(f, "{}:{}: {}", slashed(file), line, error)
+            }
+        }
+    }
+}
+
+impl Error for IncludeError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            IncludeError::Io(e) => Some(e),
+            IncludeError::AtLine { error, .. } => Some(error.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for IncludeError {
+    fn from(e: io::Error) -> Self {
+        IncludeError::Io(e)
+    }
+}
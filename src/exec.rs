@@ -0,0 +1,94 @@
+//! Running the shell commands of command includes, e.g. `--include !git rev-parse HEAD`.
+//!
+//! The command is run with `sh -c` (`cmd /C` on Windows) in the directory of the including file,
+//! and its standard output is inlined. It must be UTF-8 text.
+use std::io::{self, Read};
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// The command of a command include, which is the include path after a `!`
+pub(crate) fn command(include_path: &str) -> Option<&str> {
+    include_path
+        .strip_prefix('!')
+        .map(str::trim)
+        .filter(|command| !command.is_empty())
+}
+
+/// Runs a command in `dir` and returns its standard output, failing if it can't be started,
+/// doesn't finish within `timeout` or exits with an error
+pub(crate) fn run(command: &str, dir: &Path, timeout: Duration) -> io::Result<String> {
+    let mut child = shell(command)
+        .current_dir(dir)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    // Read on other threads, so a command with a lot of output doesn't block on a full pipe
+    let stdout = read_on_thread(child.stdout.take());
+    let stderr = read_on_thread(child.stderr.take());
+    let deadline = Instant::now() + timeout;
+    let timed_out = || {
+        io::Error::new(
+            io::ErrorKind::TimedOut,
+            format!("timed out after {} seconds", timeout.as_secs_f64()),
+        )
+    };
+    let status = loop {
+        if let Some(status) = child.try_wait()? {
+            break status;
+        }
+        if Instant::now() >= deadline {
+            let _ = child.kill();
+            let _ = child.wait();
+            return Err(timed_out());
+        }
+        thread::sleep(Duration::from_millis(10));
+    };
+    // A process the command left running in the background can keep the pipes open after it
+    // exits, so the output is only waited for until the deadline
+    let read = |output: mpsc::Receiver<Vec<u8>>| {
+        output
+            .recv_timeout(deadline.saturating_duration_since(Instant::now()))
+            .map_err(|_| timed_out())
+    };
+    let stdout = read(stdout)?;
+    if !status.success() {
+        let stderr = read(stderr)?;
+        let stderr = String::from_utf8_lossy(&stderr);
+        return Err(io::Error::other(match stderr.trim() {
+            "" => format!("exited with {}", status),
+            message => format!("exited with {}: {}", status, message),
+        }));
+    }
+    String::from_utf8(stdout).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+#[cfg(windows)]
+fn shell(command: &str) -> Command {
+    let mut shell = Command::new("cmd");
+    shell.arg("/C").arg(command);
+    shell
+}
+
+#[cfg(not(windows))]
+fn shell(command: &str) -> Command {
+    let mut shell = Command::new("sh");
+    shell.arg("-c").arg(command);
+    shell
+}
+
+/// Reads everything from a pipe on a new thread, sending it once the pipe is closed
+fn read_on_thread(pipe: Option<impl Read + Send + 'static>) -> mpsc::Receiver<Vec<u8>> {
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || {
+        let mut content = Vec::new();
+        if let Some(mut pipe) = pipe {
+            let _ = pipe.read_to_end(&mut content);
+        }
+        let _ = tx.send(content);
+    });
+    rx
+}
@@ -0,0 +1,110 @@
+//! The exit codes of a run, one for each reason it can fail, so scripts can tell them apart.
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+use simple_include::IncludeError;
+
+/// The exit codes, as listed at the end of `--help`
+pub const HELP: &str = "Exit codes:
+  0  Success
+  1  Any other error, e.g. a file that can't be read or written
+  2  An include is missing or binary, with --strict or --check
+  3  An include would create a cycle, with --strict
+  4  The options or the config file are invalid
+  5  Warnings were reported, with --fail-on-warning
+  6  The generated files differ from --expect-manifest";
+
+/// Why a run failed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Failure {
+    Error,
+    UnresolvedInclude,
+    Cycle,
+    Config,
+    Warnings,
+    ManifestChanged,
+}
+
+impl Failure {
+    pub fn code(self) -> u8 {
+        match self {
+            Failure::Error => 1,
+            Failure::UnresolvedInclude => 2,
+            Failure::Cycle => 3,
+            Failure::Config => 4,
+            Failure::Warnings => 5,
+            Failure::ManifestChanged => 6,
+        }
+    }
+
+    /// The failure an error processing a file stands for
+    pub fn of_include_error(e: &IncludeError) -> Failure {
+        match e.unlocated() {
+            IncludeError::IncludeNotFound { .. } | IncludeError::BinaryInclude { .. } => {
+                Failure::UnresolvedInclude
+            }
+            IncludeError::CycleDetected(_) => Failure::Cycle,
+            _ => Failure::Error,
+        }
+    }
+}
+
+/// An error that ends the run with the exit code for `failure`. Shown as its message alone.
+struct RunError {
+    failure: Failure,
+    message: String,
+}
+
+impl fmt::Display for RunError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl fmt::Debug for RunError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", self.message)
+    }
+}
+
+impl Error for RunError {}
+
+/// An error ending the run for the reason `failure`
+pub fn error(failure: Failure, message: impl fmt::Display) -> io::Error {
+    io::Error::other(RunError {
+        failure,
+        message: message.to_string(),
+    })
+}
+
+/// The exit code for an error that ended the run. An error with an invalid combination of options
+/// is a config error, and an error not made with `error` is any other error.
+pub fn of(e: &notify::Error) -> u8 {
+    let notify::ErrorKind::Io(e) = &e.kind else {
+        return Failure::Error.code();
+    };
+    if e.kind() == io::ErrorKind::InvalidInput {
+        return Failure::Config.code();
+    }
+    e.get_ref()
+        .and_then(|e| e.downcast_ref::<RunError>())
+        .map_or(Failure::Error, |e| e.failure)
+        .code()
+}
+
+/// The message for an error that ended the run, without the wrapping `notify` and `io` errors add
+/// around it
+pub fn message(e: &notify::Error) -> String {
+    let notify::ErrorKind::Io(io_error) = &e.kind else {
+        return e.to_string();
+    };
+    match io_error
+        .get_ref()
+        .and_then(|inner| inner.downcast_ref::<RunError>())
+    {
+        Some(run_error) => run_error.to_string(),
+        None if e.paths.is_empty() => io_error.to_string(),
+        None => e.to_string(),
+    }
+}
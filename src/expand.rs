@@ -0,0 +1,1283 @@
+//! Expansion of include directives, including nested includes
+use std::borrow::Cow;
+use std::collections::HashSet;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use log::info;
+use walkdir::WalkDir;
+
+#[cfg(feature = "archive-includes")]
+use crate::archive;
+use crate::conditional::Conditionals;
+#[cfg(feature = "archive-includes")]
+use crate::decode_text;
+use crate::diagnostic::{Diagnostic, Severity};
+#[cfg(any(feature = "remote-includes", feature = "exec-includes"))]
+use crate::directive::Fence;
+use crate::directive::{
+    byte_range, extract_lines, extract_man_section, extract_region, indentation, is_once_marker,
+    malformed_arguments, parse_directive, resolve_include_path, unescape_directive, Directive,
+    Selection,
+};
+#[cfg(feature = "exec-includes")]
+use crate::exec;
+use crate::filter;
+use crate::gitignore::IncludeIgnore;
+use crate::glob::{expand_glob, is_glob};
+#[cfg(feature = "remote-includes")]
+use crate::remote::{fetch, is_url};
+use crate::variables::substitute_variables;
+use crate::{
+    normalize_path, raw_lines, read_to_string_with_fallback, slashed, timed, without_bom,
+    BrokenInclude, IncludeError, IncludeOptions, Passes, TracedInclude,
+};
+
+/// Includes larger than this many bytes are streamed to the output a line at a time, rather than
+/// read into memory, when nothing needs the whole of their content
+const STREAM_INCLUDE_SIZE: u64 = 4 * 1024 * 1024;
+
+/// Where streamed content is written
+pub(crate) type Sink<'w> = dyn FnMut(&[u8]) -> io::Result<()> + 'w;
+
+/// Expands include directives, following any includes inside the included files
+pub(crate) struct Expander<'a> {
+    pub(crate) options: &'a IncludeOptions,
+    /// The directory that root-relative includes are resolved against
+    pub(crate) root: PathBuf,
+    /// Every include path encountered, including those in nested includes
+    pub(crate) paths: Vec<PathBuf>,
+    /// How many includes were expanded
+    pub(crate) expanded: usize,
+    /// How many includes were left as they are because they couldn't be read
+    pub(crate) skipped: usize,
+    /// The includes that were skipped because they were missing or binary
+    pub(crate) broken: Vec<BrokenInclude>,
+    /// The files currently being expanded, outermost first, used to detect cycles
+    pub(crate) stack: Vec<PathBuf>,
+    /// Every include expanded so far, with the part of it that was selected, so repeats can be
+    /// left out with `dedupe_includes`
+    pub(crate) included: HashSet<(PathBuf, Option<String>)>,
+    /// The files marked with an include-once marker that were already expanded, which are left
+    /// out wherever else they are included
+    pub(crate) included_once: HashSet<PathBuf>,
+    /// The first error that fails the whole file: an include that could not be read, in strict
+    /// mode only, or an unmatched conditional marker
+    pub(crate) error: Option<IncludeError>,
+    /// Problems with include directives, reported when the expander is dropped so they are
+    /// reported however the expansion ends
+    pub(crate) diagnostics: Vec<Diagnostic>,
+    /// The files that must not be included, from a `.includeignore` file in the root
+    pub(crate) include_ignore: Option<IncludeIgnore>,
+    /// The time spent reading included files, with `timings`
+    pub(crate) read_time: Duration,
+    /// Whether bytes that aren't text can be written to the output as they are, which they can't
+    /// when it is returned as a `String`
+    pub(crate) raw_output: bool,
+    /// Each include directive met so far and what became of it, with `trace`
+    pub(crate) trace: Vec<TracedInclude>,
+}
+
+impl Drop for Expander<'_> {
+    fn drop(&mut self) {
+        self.options.diagnostic_format.report(&self.diagnostics);
+    }
+}
+
+/// Where an include directive is: the file and the 1-based line number, shown as `file:line` at
+/// the start of warnings about the include
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Location<'a> {
+    pub(crate) file: &'a Path,
+    pub(crate) line: usize,
+}
+
+impl fmt::Display for Location<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", slashed(self.file), self.line)
+    }
+}
+
+impl Expander<'_> {
+    /// Expands a single line of `file`, substituting any `{{NAME}}` placeholders before or after
+    /// that as `passes` says. Lines that are not include directives, or whose include can't be read
+    /// or would create a cycle, are returned unchanged. Escaped directives are returned without the
+    /// escape. Returns `None` if the line is dropped, which is the case for a conditional include
+    /// whose flag isn't defined, for a repeated include with `dedupe_includes` and for a line
+    /// starting with one of the `strip_lines` prefixes.
+    pub(crate) fn expand_line(
+        &mut self,
+        line: &str,
+        file: &Path,
+        line_number: usize,
+    ) -> Option<String> {
+        let line = self.substitute_before(line, file);
+        self.expand_substituted_line(&line, file, line_number)
+    }
+
+    /// Expands a line the way `expand_line` does, once its placeholders have been substituted
+    /// if substitution is the first pass
+    fn expand_substituted_line(
+        &mut self,
+        line: &str,
+        file: &Path,
+        line_number: usize,
+    ) -> Option<String> {
+        let include_strings = self.options.include_strings_for(file);
+        if let Some(literal) = unescape_directive(line, include_strings) {
+            return Some(self.substitute_after(literal, file));
+        }
+        if line_number == 1 && is_once_marker(line, include_strings) {
+            return None;
+        }
+        let Some(directive) = parse_directive(
+            line,
+            include_strings,
+            &self.options.include_suffix,
+            &self.options.comment_marker,
+        ) else {
+            if let Some(message) =
+                malformed_arguments(line, include_strings, &self.options.include_suffix)
+            {
+                let at = Location {
+                    file,
+                    line: line_number,
+                };
+                let message = format!(
+                    "malformed include directive, leaving it as it is: {}",
+                    message
+                );
+                self.warn(at, None, message);
+                return Some(self.substitute_after(line.to_string(), file));
+            }
+            let stripped = self
+                .options
+                .strip_lines
+                .iter()
+                .any(|prefix| line.trim_start().starts_with(prefix.as_str()));
+            return (!stripped).then(|| self.substitute_after(line.to_string(), file));
+        };
+        let at = Location {
+            file,
+            line: line_number,
+        };
+        let traced = self.options.trace.then(|| self.start_trace(directive, at));
+        if let Some(condition) = directive.condition {
+            if !self.options.is_defined(condition) {
+                info!(
+                    "{}: {:?} is not defined, dropping the include of {:?}",
+                    at, condition, directive.path
+                );
+                if let Some(index) = traced {
+                    self.trace[index].status = "dropped".to_string();
+                }
+                return None;
+            }
+        }
+        let (diagnostics, broken) = (self.diagnostics.len(), self.broken.len());
+        let expanded = self.expand_directive(line, directive, at);
+        if let Some(index) = traced {
+            self.trace[index].status = self.trace_status(&expanded, line, at, diagnostics, broken);
+        }
+        // Included content had its own lines substituted as they were expanded, so only a
+        // directive left as it is is substituted here
+        let expanded = expanded?;
+        if expanded == line {
+            return Some(self.substitute_after(expanded, file));
+        }
+        Some(expanded)
+    }
+
+    /// Records a directive in the trace, before it is expanded so any directives in what it
+    /// includes come after it, and returns its index to record what became of it
+    fn start_trace(&mut self, directive: Directive, at: Location) -> usize {
+        #[cfg(feature = "remote-includes")]
+        let is_path = !is_url(directive.path);
+        #[cfg(not(feature = "remote-includes"))]
+        let is_path = true;
+        #[cfg(feature = "exec-includes")]
+        let is_path =
+            is_path && !(self.options.allow_exec && exec::command(directive.path).is_some());
+        let parent_dir = at.file.parent().unwrap_or_else(|| Path::new(""));
+        let resolved = is_path
+            .then(|| resolve_include_path(parent_dir, &self.root, directive.path, self.options))
+            .and_then(Result::ok)
+            .map(|resolved| normalize_path(&resolved));
+        self.trace.push(TracedInclude {
+            depth: self.stack.len() - 1,
+            file: at.file.to_path_buf(),
+            line: at.line,
+            path: directive.path.to_string(),
+            modifiers: directive.modifiers(),
+            resolved,
+            status: String::new(),
+        });
+        self.trace.len() - 1
+    }
+
+    /// What became of the directive at `at`, from what expanding it returned and the broken
+    /// includes and diagnostics recorded since there were `broken` and `diagnostics` of them
+    fn trace_status(
+        &self,
+        expanded: &Option<String>,
+        line: &str,
+        at: Location,
+        diagnostics: usize,
+        broken: usize,
+    ) -> String {
+        let message = self.diagnostics[diagnostics..]
+            .iter()
+            .rfind(|diagnostic| diagnostic.file == at.file && diagnostic.line == at.line)
+            .map(|diagnostic| diagnostic.message.as_str());
+        match (expanded.as_deref(), message) {
+            (None, _) => "repeated".to_string(),
+            (Some(expanded), message) if expanded == line => match self.broken.get(broken) {
+                Some(include) if include.binary => "binary".to_string(),
+                Some(_) => "missing".to_string(),
+                None => format!("skipped: {}", message.unwrap_or("left as it is")),
+            },
+            (Some(_), Some(message)) => format!("expanded: {}", message),
+            (Some(_), None) => "expanded".to_string(),
+        }
+    }
+
+    /// Substitutes the placeholders in a line before its include directive is expanded, if
+    /// substitution is the first pass
+    fn substitute_before<'l>(&self, line: &'l str, file: &Path) -> Cow<'l, str> {
+        match self.options.passes {
+            Passes::SubstituteInclude => substitute_variables(line, file, self.options),
+            Passes::IncludeSubstitute => Cow::Borrowed(line),
+        }
+    }
+
+    /// Substitutes the placeholders in a line that include expansion left as it is, if
+    /// substitution is the second pass
+    fn substitute_after(&self, line: String, file: &Path) -> String {
+        match self.options.passes {
+            Passes::SubstituteInclude => line,
+            Passes::IncludeSubstitute => {
+                substitute_variables(&line, file, self.options).into_owned()
+            }
+        }
+    }
+
+    /// Expands a line the way `expand_line` does and writes it to `out`, followed by `ending`
+    /// unless it already ends a line. An include of a single file over `STREAM_INCLUDE_SIZE` bytes
+    /// as it is, with no selection, fence, filters, indentation, annotation or encoding, is
+    /// written straight to `out` a line at a time rather than read into memory, and so is a byte
+    /// range that isn't UTF-8.
+    pub(crate) fn stream_line(
+        &mut self,
+        line: &str,
+        file: &Path,
+        line_number: usize,
+        ending: &str,
+        out: &mut Sink,
+    ) -> io::Result<()> {
+        // Substituted once however the line is expanded, so each placeholder is reported once
+        let line = self.substitute_before(line, file);
+        if self.stream_directive(&line, file, line_number, ending, out)? {
+            return Ok(());
+        }
+        if let Some(expanded) = self.expand_substituted_line(&line, file, line_number) {
+            out(expanded.as_bytes())?;
+            if !ends_with_line_ending(&expanded) {
+                out(ending.as_bytes())?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes what the directive on `line`, which has had its placeholders substituted, includes
+    /// straight to `out` if `stream_line` can stream it. Returns `false` without writing anything
+    /// for any other line.
+    fn stream_directive(
+        &mut self,
+        line: &str,
+        file: &Path,
+        line_number: usize,
+        ending: &str,
+        out: &mut Sink,
+    ) -> io::Result<bool> {
+        let options = self.options;
+        let include_strings = options.include_strings_for(file);
+        if unescape_directive(line, include_strings).is_some()
+            || (line_number == 1 && is_once_marker(line, include_strings))
+        {
+            return Ok(false);
+        }
+        let Some(directive) = parse_directive(
+            line,
+            include_strings,
+            &options.include_suffix,
+            &options.comment_marker,
+        ) else {
+            return Ok(false);
+        };
+        let at = Location {
+            file,
+            line: line_number,
+        };
+        if let Some(Selection::Bytes(range)) = directive.selection {
+            return self.stream_byte_range(line, directive, range, at, ending, out);
+        }
+        // Traced directives are expanded the usual way, which records what became of them
+        if options.trace
+            || options.annotate.is_some()
+            || options.encoding.is_some()
+            || options.fallback_encoding.is_some()
+            || directive.selection.is_some()
+            || directive.fence.is_some()
+            || directive.filters.is_some()
+            || directive.condition.is_some_and(|c| !options.is_defined(c))
+            || is_glob(directive.path)
+            || !options
+                .indent
+                .apply(indentation(line), options.tab_width)
+                .is_empty()
+        {
+            return Ok(false);
+        }
+        #[cfg(feature = "exec-includes")]
+        if options.allow_exec && exec::command(directive.path).is_some() {
+            return Ok(false);
+        }
+        let parent_dir = file.parent().unwrap_or_else(|| Path::new(""));
+        let Ok(include_path) =
+            resolve_include_path(parent_dir, &self.root, directive.path, options)
+        else {
+            return Ok(false);
+        };
+        // Anything that isn't a large text file, including URLs and archive members, which don't
+        // resolve to a file, is read the usual way, which also reports any problem with it. Only
+        // the start of the file is checked to be text here, so it is only read once.
+        let large = fs::metadata(&include_path)
+            .is_ok_and(|metadata| metadata.is_file() && metadata.len() > STREAM_INCLUDE_SIZE);
+        if !large {
+            return Ok(false);
+        }
+        let Ok(mut reader) = File::open(&include_path).map(BufReader::new) else {
+            return Ok(false);
+        };
+        if !reader.fill_buf().is_ok_and(starts_as_utf8) {
+            return Ok(false);
+        }
+        self.note_unexpected_target(&include_path, directive.path, at);
+        if self.is_repeated(&include_path, directive, at) {
+            return Ok(true);
+        }
+        let Some(shown_path) = self.admit_include(&include_path, directive, at, false) else {
+            out(self.substitute_after(line.to_string(), file).as_bytes())?;
+            out(ending.as_bytes())?;
+            return Ok(true);
+        };
+        self.expanded += 1;
+        let normalized_include_path = normalize_path(&include_path);
+        if options.dedupe_includes {
+            self.included
+                .insert((normalized_include_path.clone(), None));
+        }
+        let mut ends_line = false;
+        let mut write = |bytes: &[u8]| {
+            if !bytes.is_empty() {
+                ends_line = bytes.ends_with(b"\n");
+            }
+            out(bytes)
+        };
+        self.stack.push(normalized_include_path);
+        let streamed = self.stream_include(&include_path, reader, at, &shown_path, &mut write);
+        self.stack.pop();
+        streamed?;
+        if !ends_line {
+            out(ending.as_bytes())?;
+        }
+        Ok(true)
+    }
+
+    /// Writes the bytes a byte range selects straight to `out`, as they are, if they aren't
+    /// UTF-8 and the output isn't text. Returns `false` for a range that is UTF-8, which is
+    /// included as text, or that is needed as text for a fence, filters, indentation or an
+    /// annotation, which is binary; both are left to `expand_line`, and so is any problem with it.
+    fn stream_byte_range(
+        &mut self,
+        line: &str,
+        directive: Directive,
+        range: &str,
+        at: Location,
+        ending: &str,
+        out: &mut Sink,
+    ) -> io::Result<bool> {
+        let options = self.options;
+        if !self.raw_output
+            || options.annotate.is_some()
+            || directive.fence.is_some()
+            || directive.filters.is_some()
+            || directive.condition.is_some_and(|c| !options.is_defined(c))
+            || !options
+                .indent
+                .apply(indentation(line), options.tab_width)
+                .is_empty()
+        {
+            return Ok(false);
+        }
+        let parent_dir = at.file.parent().unwrap_or_else(|| Path::new(""));
+        let Ok(include_path) =
+            resolve_include_path(parent_dir, &self.root, directive.path, options)
+        else {
+            return Ok(false);
+        };
+        let Ok(Some(bytes)) = read_byte_range(&include_path, at.file, range) else {
+            return Ok(false);
+        };
+        if std::str::from_utf8(&bytes).is_ok() {
+            return Ok(false);
+        }
+        let traced = options.trace.then(|| self.start_trace(directive, at));
+        let (diagnostics, broken) = (self.diagnostics.len(), self.broken.len());
+        self.note_unexpected_target(&include_path, directive.path, at);
+        // What `expand_line` would have returned, which says what became of the directive
+        let expanded = if self.is_repeated(&include_path, directive, at) {
+            None
+        } else if self
+            .admit_include(&include_path, directive, at, false)
+            .is_none()
+        {
+            out(self.substitute_after(line.to_string(), at.file).as_bytes())?;
+            out(ending.as_bytes())?;
+            Some(line.to_string())
+        } else {
+            self.expanded += 1;
+            if options.dedupe_includes {
+                self.included.insert((
+                    normalize_path(&include_path),
+                    directive.selection.map(|s| s.to_string()),
+                ));
+            }
+            out(&bytes)?;
+            if !bytes.ends_with(b"\n") {
+                out(ending.as_bytes())?;
+            }
+            Some(String::new())
+        };
+        if let Some(index) = traced {
+            self.trace[index].status = self.trace_status(&expanded, line, at, diagnostics, broken);
+        }
+        Ok(true)
+    }
+
+    /// Expands each line of the included `file`, read from `reader`, in turn, writing it to
+    /// `out`, like `expand_content` does for content that was read into memory. Binary data after
+    /// the start of the file, which is all that was checked, fails the file with the directive at
+    /// `at`, as part of the file has been written by then.
+    fn stream_include(
+        &mut self,
+        file: &Path,
+        reader: BufReader<File>,
+        at: Location,
+        shown_path: &str,
+        out: &mut Sink,
+    ) -> io::Result<()> {
+        let mut conditionals = Conditionals::default();
+        for (line_index, line) in without_bom(raw_lines(reader, None), self.options.bom).enumerate()
+        {
+            let line = match line {
+                Ok(line) => line,
+                Err(e) if e.kind() == io::ErrorKind::InvalidData => {
+                    let message = format!(
+                        "binary data in include after line {}: {}",
+                        line_index, shown_path
+                    );
+                    let e = IncludeError::BinaryInclude {
+                        include: file.to_path_buf(),
+                        included_from: at.file.to_path_buf(),
+                    };
+                    self.fail(at, Some(shown_path), message, e);
+                    return Ok(());
+                }
+                Err(e) => return Err(e),
+            };
+            let (text, ending) = split_line_ending(&line);
+            let line_number = line_index + 1;
+            if line_number == 1 && is_once_marker(text, self.options.include_strings_for(file)) {
+                self.included_once.insert(normalize_path(file));
+            }
+            match conditionals.drop_line(text, line_number, file, self.options) {
+                Ok(true) => continue,
+                Ok(false) => {}
+                Err(e) => {
+                    self.error.get_or_insert(e);
+                    return Ok(());
+                }
+            }
+            self.stream_line(text, file, line_number, ending, out)?;
+        }
+        if let Err(e) = conditionals.finish(file) {
+            self.error.get_or_insert(e);
+        }
+        Ok(())
+    }
+
+    /// Expands an include directive, returning the line unchanged if the include can't be read or
+    /// would create a cycle, or `None` if everything it includes was already included
+    fn expand_directive(
+        &mut self,
+        line: &str,
+        directive: Directive,
+        at: Location,
+    ) -> Option<String> {
+        let directive_path = directive.path;
+        let indent = self
+            .options
+            .indent
+            .apply(indentation(line), self.options.tab_width);
+        let indent = indent.as_str();
+        #[cfg(feature = "remote-includes")]
+        if is_url(directive_path) {
+            return Some(self.expand_remote(line, directive_path, directive.fence, at, indent));
+        }
+        #[cfg(feature = "exec-includes")]
+        if self.options.allow_exec {
+            if let Some(command) = exec::command(directive_path) {
+                return Some(self.expand_command(line, command, directive.fence, at, indent));
+            }
+        }
+        let parent_dir = at.file.parent().unwrap_or_else(|| Path::new(""));
+        #[cfg(feature = "archive-includes")]
+        if let Some((archive, member)) = archive::split(directive_path) {
+            if let Ok(archive_path) =
+                resolve_include_path(parent_dir, &self.root, archive, self.options)
+            {
+                return Some(self.expand_archive(
+                    line,
+                    &archive_path,
+                    member,
+                    directive,
+                    at,
+                    indent,
+                ));
+            }
+        }
+        let include_path =
+            match resolve_include_path(parent_dir, &self.root, directive_path, self.options) {
+                Ok(include_path) => include_path,
+                Err(variable) => {
+                    self.warn(
+                        at,
+                        Some(directive_path),
+                        format!(
+                            "environment variable {:?} is not defined, skipping",
+                            variable
+                        ),
+                    );
+                    return Some(line.to_string());
+                }
+            };
+        if let Some((dir, recursive)) = included_directory(directive_path, &include_path) {
+            if !self.options.directory_includes {
+                self.skipped += 1;
+                self.warn(
+                    at,
+                    Some(directive_path),
+                    format!(
+                        "include target {} is a directory, not a file, skipping",
+                        directive_path
+                    ),
+                );
+                return Some(line.to_string());
+            }
+            let files = directory_files(&dir, recursive);
+            if files.is_empty() {
+                self.warn(
+                    at,
+                    Some(directive_path),
+                    format!("no files in {}, skipping", directive_path),
+                );
+                return Some(line.to_string());
+            }
+            return self.expand_include_files(line, files, directive, at, indent, true);
+        }
+        if is_glob(directive_path) {
+            let matches = expand_glob(&normalize_path(&include_path));
+            if matches.is_empty() {
+                self.warn(
+                    at,
+                    Some(directive_path),
+                    format!("no files match {}, skipping", directive_path),
+                );
+                return Some(line.to_string());
+            }
+            return self.expand_include_files(line, matches, directive, at, indent, false);
+        }
+        self.note_unexpected_target(&include_path, directive_path, at);
+        if self.is_repeated(&include_path, directive, at) {
+            return None;
+        }
+        Some(
+            self.expand_include_file(&include_path, directive, at, indent, false)
+                .unwrap_or_else(|| line.to_string()),
+        )
+    }
+
+    /// Expands each of the files matched by a glob or found in an included directory, one after
+    /// the other. Returns the line unchanged if none of them could be read, or `None` if they had
+    /// all been included already.
+    fn expand_include_files(
+        &mut self,
+        line: &str,
+        files: Vec<PathBuf>,
+        directive: Directive,
+        at: Location,
+        indent: &str,
+        in_directory: bool,
+    ) -> Option<String> {
+        let mut result = String::new();
+        let mut repeated = false;
+        for include_path in files {
+            if self.is_repeated(&include_path, directive, at) {
+                repeated = true;
+                continue;
+            }
+            if let Some(expanded) =
+                self.expand_include_file(&include_path, directive, at, indent, in_directory)
+            {
+                if !result.is_empty() && !ends_with_line_ending(&result) {
+                    result.push('\n');
+                }
+                result.push_str(&expanded);
+            }
+        }
+        if result.is_empty() {
+            return (!repeated).then(|| line.to_string());
+        }
+        Some(result)
+    }
+
+    /// Mentions in verbose mode an include that resolves to the file it is in, or to a file
+    /// somewhere else that has the same name as a file next to the including one, as both are
+    /// easy to get by mistake
+    fn note_unexpected_target(&self, include_path: &Path, directive_path: &str, at: Location) {
+        let Ok(target) = fs::canonicalize(include_path) else {
+            return;
+        };
+        if fs::canonicalize(at.file).is_ok_and(|file| file == target) {
+            info!("{}: {} includes itself", at, slashed(at.file));
+            return;
+        }
+        let Some(name) = target.file_name() else {
+            return;
+        };
+        let neighbour = at.file.parent().unwrap_or_else(|| Path::new("")).join(name);
+        if fs::canonicalize(&neighbour).is_ok_and(|neighbour| neighbour != target) {
+            info!(
+                "{}: {} resolves to {}, not to {} next to {}",
+                at,
+                directive_path,
+                slashed(&target),
+                slashed(&neighbour),
+                slashed(at.file)
+            );
+        }
+    }
+
+    /// Whether the include was already expanded into the file being processed and should be left
+    /// out, because it is marked to be included once or, with the same selection, because
+    /// `dedupe_includes` is set
+    fn is_repeated(&self, include_path: &Path, directive: Directive, at: Location) -> bool {
+        if self.included_once.contains(&normalize_path(include_path)) {
+            info!(
+                "{}: {} is marked to be included once and was already included, leaving it out",
+                at,
+                slashed(include_path)
+            );
+            return true;
+        }
+        if !self.options.dedupe_includes {
+            return false;
+        }
+        let key = (
+            normalize_path(include_path),
+            directive.selection.map(|s| s.to_string()),
+        );
+        let repeated = self.included.contains(&key);
+        if repeated {
+            info!(
+                "{}: {} was already included, leaving out the repeated include",
+                at,
+                slashed(include_path)
+            );
+        }
+        repeated
+    }
+
+    /// Checks that an include may be expanded: that it is inside the source directory in sandbox
+    /// mode, isn't blocked, wouldn't create a cycle and is within the maximum depth and size.
+    /// Records the include and returns the path to show in messages about it, or `None` with a
+    /// warning if it is to be left as it is.
+    fn admit_include(
+        &mut self,
+        include_path: &Path,
+        directive: Directive,
+        at: Location,
+        in_directory: bool,
+    ) -> Option<String> {
+        // The path as written is easier to find in the file, unless it was a glob or directory
+        let shown_path = if is_glob(directive.path) || in_directory {
+            slashed(include_path).to_string()
+        } else {
+            directive.path.to_string()
+        };
+        let normalized_include_path = normalize_path(include_path);
+        if self.options.sandbox && !normalized_include_path.starts_with(normalize_path(&self.root))
+        {
+            let message = format!(
+                "{} is outside the source directory {}, skipping",
+                shown_path,
+                slashed(&self.root)
+            );
+            self.warn(at, Some(&shown_path), message);
+            return None;
+        }
+        if self.is_blocked(&normalized_include_path) {
+            self.skipped += 1;
+            let message = format!("include blocked by .includeignore: {}", shown_path);
+            self.warn(at, Some(&shown_path), message);
+            return None;
+        }
+        self.paths.push(normalized_include_path.clone());
+
+        if let Some(start) = self
+            .stack
+            .iter()
+            .position(|p| *p == normalized_include_path)
+        {
+            let mut cycle = self.stack[start..].to_vec();
+            cycle.push(normalized_include_path);
+            let shown_cycle: Vec<String> = cycle.iter().map(|p| slashed(p).to_string()).collect();
+            let message = format!("include cycle, skipping: {}", shown_cycle.join(" -> "));
+            if self.options.strict {
+                self.fail(
+                    at,
+                    Some(&shown_path),
+                    message,
+                    IncludeError::CycleDetected(cycle),
+                );
+            } else {
+                self.warn(at, Some(&shown_path), message);
+            }
+            return None;
+        }
+
+        // The stack holds the top level file as well as each include being expanded
+        let depth = self.stack.len();
+        if depth > self.options.max_depth {
+            let message = format!(
+                "maximum include depth ({}) reached, not including {}",
+                self.options.max_depth, shown_path
+            );
+            self.warn(at, Some(&shown_path), message);
+            return None;
+        }
+
+        if let Some(max_size) = self.options.max_include_size {
+            // Checked before reading, so a huge file is never loaded. Errors are left to the read.
+            let size = fs::metadata(include_path).map_or(0, |metadata| metadata.len());
+            if size > max_size {
+                let message = format!(
+                    "{} is {} bytes, over the maximum include size of {} bytes, skipping",
+                    shown_path, size, max_size
+                );
+                self.warn(at, Some(&shown_path), message);
+                return None;
+            }
+        }
+        Some(shown_path)
+    }
+
+    /// Expands a single included file, or the selected part of it, fenced if the directive asks
+    /// for it. Returns `None` if it can't be read, would create a cycle or is outside the source
+    /// directory in sandbox mode, so the directive is left as it is. A binary file `in_directory`,
+    /// one found in an included directory, is left out with a warning.
+    fn expand_include_file(
+        &mut self,
+        include_path: &Path,
+        directive: Directive,
+        at: Location,
+        indent: &str,
+        in_directory: bool,
+    ) -> Option<String> {
+        let file = at.file;
+        let shown_path = self.admit_include(include_path, directive, at, in_directory)?;
+        let normalized_include_path = normalize_path(include_path);
+        let options = self.options;
+        let read = timed(options.timings, &mut self.read_time, || {
+            match directive.selection {
+                // Included as text, so a range that isn't UTF-8 is binary here
+                Some(Selection::Bytes(range)) => read_byte_range(include_path, file, range)
+                    .and_then(|bytes| match bytes.map(String::from_utf8).transpose() {
+                        Ok(text) => Ok(text),
+                        Err(_) => Err(IncludeError::BinaryData(include_path.to_path_buf())),
+                    }),
+                _ => read_include(include_path, file, options).map(Some),
+            }
+        });
+        if let (Ok(None), Some(Selection::Bytes(range))) = (&read, directive.selection) {
+            let message = format!("byte range {} is outside {}, skipping", range, shown_path);
+            self.warn(at, Some(&shown_path), message);
+            return None;
+        }
+        let read = read.map(Option::unwrap_or_default);
+        let once = read.as_ref().is_ok_and(|include_content| {
+            let first_line = include_content.lines().next().unwrap_or_default();
+            is_once_marker(first_line, options.include_strings_for(include_path))
+        });
+        let selected = match read {
+            Ok(include_content) => match directive.selection {
+                None | Some(Selection::Bytes(_)) => Some(include_content),
+                Some(Selection::ManSection(section)) => {
+                    let section_content = extract_man_section(&include_content, section);
+                    if section_content.is_none() {
+                        let message = format!(
+                            "section {:?} not found in {}, skipping",
+                            section, shown_path
+                        );
+                        self.warn(at, Some(&shown_path), message);
+                    }
+                    section_content
+                }
+                Some(Selection::Region(name)) => {
+                    let region = extract_region(&include_content, name, self.options);
+                    if region.is_none() {
+                        let message =
+                            format!("region {:?} not found in {}, skipping", name, shown_path);
+                        self.warn(at, Some(&shown_path), message);
+                    }
+                    region
+                }
+                Some(Selection::Lines(range)) => match extract_lines(&include_content, range) {
+                    Some(lines) => Some(lines),
+                    None => {
+                        let message = format!(
+                            "invalid line range {:?} for {}, including the whole file",
+                            range, shown_path
+                        );
+                        self.warn(at, Some(&shown_path), message);
+                        Some(include_content)
+                    }
+                },
+            },
+            Err(IncludeError::BinaryData(_)) if in_directory => {
+                let message = format!(
+                    "{} in {} is a binary file, leaving it out",
+                    shown_path, directive.path
+                );
+                self.warn(at, Some(&shown_path), message);
+                return None;
+            }
+            Err(e) => {
+                self.skipped += 1;
+                if let IncludeError::IncludeNotFound { .. } | IncludeError::BinaryData(_) = e {
+                    self.broken.push(BrokenInclude {
+                        included_from: normalize_path(file),
+                        include: normalize_path(include_path),
+                        binary: matches!(e, IncludeError::BinaryData(_)),
+                    });
+                }
+                let message = match e {
+                    IncludeError::BinaryData(_) => {
+                        format!("binary data in include: {}", shown_path)
+                    }
+                    IncludeError::IncludeNotFound { .. } => {
+                        format!("include not found: {}", shown_path)
+                    }
+                    IncludeError::PermissionDenied { .. } => e.to_string(),
+                    _ => format!("error reading include {}: {}", shown_path, e),
+                };
+                if !self.options.strict {
+                    self.warn(at, Some(&shown_path), message);
+                    return None;
+                }
+                let e = match e {
+                    IncludeError::BinaryData(include) => IncludeError::BinaryInclude {
+                        include,
+                        included_from: file.to_path_buf(),
+                    },
+                    e => e,
+                };
+                self.fail(at, Some(&shown_path), message, e);
+                None
+            }
+        };
+        let content = selected?;
+        self.expanded += 1;
+        if once {
+            self.included_once.insert(normalized_include_path.clone());
+        }
+        if self.options.dedupe_includes {
+            self.included.insert((
+                normalized_include_path,
+                directive.selection.map(|s| s.to_string()),
+            ));
+        }
+        Some(self.expand_include(&content, include_path, &shown_path, directive, at, indent))
+    }
+
+    /// Whether a `.includeignore` file blocks including `path`
+    fn is_blocked(&self, path: &Path) -> bool {
+        self.include_ignore
+            .as_ref()
+            .is_some_and(|include_ignore| include_ignore.is_blocked(path))
+    }
+
+    /// Records an error about the directive at `at`, in strict mode, which fails the whole file.
+    /// An unresolved include or a cycle is returned with `at`, so its message says where it is.
+    fn fail(&mut self, at: Location, include: Option<&str>, message: String, e: IncludeError) {
+        let e = match e {
+            e @ (IncludeError::IncludeNotFound { .. }
+            | IncludeError::BinaryInclude { .. }
+            | IncludeError::CycleDetected(_)) => IncludeError::AtLine {
+                file: at.file.to_path_buf(),
+                line: at.line,
+                error: Box::new(e),
+            },
+            e => e,
+        };
+        self.diagnostics.push(Diagnostic {
+            severity: Severity::Error,
+            file: at.file.to_path_buf(),
+            line: at.line,
+            message,
+            include: include.map(str::to_string),
+        });
+        self.error.get_or_insert(e);
+    }
+
+    /// Records a warning about the directive at `at`
+    fn warn(&mut self, at: Location, include: Option<&str>, message: String) {
+        self.diagnostics.push(Diagnostic {
+            severity: Severity::Warning,
+            file: at.file.to_path_buf(),
+            line: at.line,
+            message,
+            include: include.map(str::to_string),
+        });
+    }
+
+    /// Expands the content of an included file while it is on the include stack, then applies the
+    /// directive's filters, fences it if asked to, annotates it with `shown_path` if annotations
+    /// are on, and indents each non-empty line by `indent`
+    fn expand_include(
+        &mut self,
+        content: &str,
+        file: &Path,
+        shown_path: &str,
+        directive: Directive,
+        at: Location,
+        indent: &str,
+    ) -> String {
+        self.stack.push(normalize_path(file));
+        let result = self.expand_content(content, file);
+        self.stack.pop();
+        let mut result = self.apply_filters(result, directive, at, shown_path);
+        if let Some(fence) = directive.fence {
+            result = fence.wrap(&result, &file.to_string_lossy());
+        }
+        indent_lines(self.annotate(result, shown_path), indent)
+    }
+
+    /// Applies each of the directive's filters in turn. An unknown filter is skipped with a
+    /// warning, passing the content through unchanged.
+    fn apply_filters(
+        &mut self,
+        mut content: String,
+        directive: Directive,
+        at: Location,
+        shown_path: &str,
+    ) -> String {
+        for name in directive.filters() {
+            match filter::apply(&content, name) {
+                Some(filtered) => content = filtered,
+                None => {
+                    let message = format!("unknown filter {:?}, leaving it out", name);
+                    self.warn(at, Some(shown_path), message);
+                }
+            }
+        }
+        content
+    }
+
+    /// Wraps included content in comments naming the include, if annotations are on
+    fn annotate(&self, content: String, shown_path: &str) -> String {
+        match &self.options.annotate {
+            // The comments are written in the syntax of the generated file, which is the first
+            // file on the stack
+            Some(annotation) => annotation.wrap(&content, shown_path, &self.stack[0]),
+            None => content,
+        }
+    }
+
+    /// Inlines the body of a remote include. Directives in it are not expanded, as there is no
+    /// directory to resolve them against.
+    #[cfg(feature = "remote-includes")]
+    fn expand_remote(
+        &mut self,
+        line: &str,
+        url: &str,
+        fence: Option<Fence>,
+        at: Location,
+        indent: &str,
+    ) -> String {
+        match fetch(url, self.options.fetch_timeout) {
+            Ok(content) => {
+                let content = match fence {
+                    Some(fence) => fence.wrap(&content, url),
+                    None => content,
+                };
+                indent_lines(self.annotate(content, url), indent)
+            }
+            Err(e) => {
+                self.warn(
+                    at,
+                    Some(url),
+                    format!("could not fetch {}: {}, skipping", url, e),
+                );
+                line.to_string()
+            }
+        }
+    }
+
+    /// Inlines the output of a command include. Directives in it are not expanded.
+    #[cfg(feature = "exec-includes")]
+    fn expand_command(
+        &mut self,
+        line: &str,
+        command: &str,
+        fence: Option<Fence>,
+        at: Location,
+        indent: &str,
+    ) -> String {
+        let dir = at.file.parent().unwrap_or_else(|| Path::new(""));
+        match exec::run(command, dir, self.options.exec_timeout) {
+            Ok(content) => {
+                let content = match fence {
+                    Some(fence) => fence.wrap(&content, ""),
+                    None => content,
+                };
+                indent_lines(self.annotate(content, command), indent)
+            }
+            Err(e) => {
+                self.warn(
+                    at,
+                    Some(command),
+                    format!("command {:?} failed: {}, skipping", command, e),
+                );
+                line.to_string()
+            }
+        }
+    }
+
+    /// Inlines a member of a zip or tar archive, from an include like `snippets.zip!header.txt`.
+    /// Directives in it are not expanded, as there is no directory to resolve them against.
+    #[cfg(feature = "archive-includes")]
+    fn expand_archive(
+        &mut self,
+        line: &str,
+        archive_path: &Path,
+        member: &str,
+        directive: Directive,
+        at: Location,
+        indent: &str,
+    ) -> String {
+        let shown_path = directive.path;
+        let normalized_archive_path = normalize_path(archive_path);
+        if self.options.sandbox && !normalized_archive_path.starts_with(normalize_path(&self.root))
+        {
+            let message = format!(
+                "{} is outside the source directory {}, skipping",
+                shown_path,
+                slashed(&self.root)
+            );
+            self.warn(at, Some(shown_path), message);
+            return line.to_string();
+        }
+        if self.is_blocked(&normalized_archive_path) {
+            self.skipped += 1;
+            let message = format!("include blocked by .includeignore: {}", shown_path);
+            self.warn(at, Some(shown_path), message);
+            return line.to_string();
+        }
+        // Recorded so a changed archive regenerates the files that include from it
+        self.paths.push(normalized_archive_path);
+        let options = self.options;
+        let read = timed(options.timings, &mut self.read_time, || {
+            archive::read_member(archive_path, member, options.max_include_size)
+                .and_then(|bytes| decode_text(bytes, options))
+        });
+        match read {
+            Ok((content, _)) => {
+                self.expanded += 1;
+                let content = self.apply_filters(content, directive, at, shown_path);
+                let content = match directive.fence {
+                    Some(fence) => fence.wrap(&content, member),
+                    None => content,
+                };
+                indent_lines(self.annotate(content, shown_path), indent)
+            }
+            Err(e) => {
+                let message = format!(
+                    "could not read {} from {}: {}, skipping",
+                    member,
+                    slashed(archive_path),
+                    e
+                );
+                self.warn(at, Some(shown_path), message);
+                line.to_string()
+            }
+        }
+    }
+
+    /// Expands every line of content read from `file`, keeping the original line endings. An
+    /// unmatched conditional marker stops the expansion and is kept as the error.
+    pub(crate) fn expand_content(&mut self, content: &str, file: &Path) -> String {
+        let mut result = String::with_capacity(content.len());
+        let mut conditionals = Conditionals::default();
+        for (line_index, line) in content.split_inclusive('\n').enumerate() {
+            let (text, ending) = split_line_ending(line);
+            match conditionals.drop_line(text, line_index + 1, file, self.options) {
+                Ok(true) => continue,
+                Ok(false) => {}
+                Err(e) => {
+                    self.error.get_or_insert(e);
+                    return result;
+                }
+            }
+            let Some(expanded) = self.expand_line(text, file, line_index + 1) else {
+                continue;
+            };
+            result.push_str(&expanded);
+            if !ends_with_line_ending(&expanded) {
+                result.push_str(ending);
+            }
+        }
+        if let Err(e) = conditionals.finish(file) {
+            self.error.get_or_insert(e);
+        }
+        result
+    }
+}
+
+/// Whether the start of a file is valid UTF-8, but for a character cut off at the end of it
+fn starts_as_utf8(start: &[u8]) -> bool {
+    match std::str::from_utf8(start) {
+        Ok(_) => true,
+        Err(e) => e.error_len().is_none(),
+    }
+}
+
+/// Adds `indent` to the start of every non-empty line
+pub(crate) fn indent_lines(text: String, indent: &str) -> String {
+    if indent.is_empty() {
+        return text;
+    }
+    let mut indented = String::with_capacity(text.len());
+    for line in text.split_inclusive('\n') {
+        if !line.trim_end_matches(['\r', '\n']).is_empty() {
+            indented.push_str(indent);
+        }
+        indented.push_str(line);
+    }
+    indented
+}
+
+/// Whether expanded text already ends a line, which is the case for included content that ends
+/// with a line ending. The directive's own line ending isn't added then, to avoid a blank line.
+fn ends_with_line_ending(expanded: &str) -> bool {
+    expanded.ends_with('\n')
+}
+
+/// Splits a line into its text and its line ending, which is empty for a last line without one
+pub(crate) fn split_line_ending(line: &str) -> (&str, &str) {
+    match line.strip_suffix("\r\n") {
+        Some(text) => (text, "\r\n"),
+        None => match line.strip_suffix('\n') {
+            Some(text) => (text, "\n"),
+            None => (line, ""),
+        },
+    }
+}
+
+/// The directory an include refers to and whether to include the files in its subdirectories too:
+/// `chapters/**` includes everything under `chapters`, and `chapters/` (or `chapters`, if it is a
+/// directory) only the files directly in it
+pub(crate) fn included_directory(
+    directive_path: &str,
+    include_path: &Path,
+) -> Option<(PathBuf, bool)> {
+    if directive_path.ends_with("/**") {
+        let dir = include_path.parent()?;
+        return dir.is_dir().then(|| (dir.to_path_buf(), true));
+    }
+    (!is_glob(directive_path) && include_path.is_dir()).then(|| (include_path.to_path_buf(), false))
+}
+
+/// The files in a directory, or with `recursive` in it and its subdirectories, sorted by path
+pub(crate) fn directory_files(dir: &Path, recursive: bool) -> Vec<PathBuf> {
+    let max_depth = if recursive { usize::MAX } else { 1 };
+    let mut files: Vec<PathBuf> = WalkDir::new(dir)
+        .min_depth(1)
+        .max_depth(max_depth)
+        .into_iter()
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.file_type().is_file())
+        .map(|entry| entry.into_path())
+        .collect();
+    files.sort();
+    files
+}
+
+/// Reads an included file, reporting a missing file as an `IncludeNotFound` from `included_from`
+pub(crate) fn read_include(
+    include_path: &Path,
+    included_from: &Path,
+    options: &IncludeOptions,
+) -> Result<String, IncludeError> {
+    read_to_string_with_fallback(include_path, options)
+        .map_err(|e| include_error(e, include_path, included_from))
+}
+
+/// Reads the bytes a range such as `1024+256` selects from an included file, seeking to the
+/// offset rather than reading the whole file. The bytes are returned as they are, with no byte
+/// order mark or encoding detection. Returns `None` if the range goes past the end of the file.
+fn read_byte_range(
+    include_path: &Path,
+    included_from: &Path,
+    range: &str,
+) -> Result<Option<Vec<u8>>, IncludeError> {
+    let Some((offset, length)) = byte_range(range) else {
+        return Ok(None);
+    };
+    let read = || -> io::Result<Option<Vec<u8>>> {
+        let mut file = File::open(include_path)?;
+        if offset + length > file.metadata()?.len() {
+            return Ok(None);
+        }
+        file.seek(SeekFrom::Start(offset))?;
+        let mut bytes = vec![0; length as usize];
+        file.read_exact(&mut bytes)?;
+        Ok(Some(bytes))
+    };
+    read().map_err(|e| include_error(e, include_path, included_from))
+}
+
+/// The error for an include that can't be read, which is `IncludeNotFound` if it doesn't exist
+fn include_error(e: io::Error, include_path: &Path, included_from: &Path) -> IncludeError {
+    match IncludeError::from_io(e, include_path) {
+        IncludeError::SourceNotFound(include) => IncludeError::IncludeNotFound {
+            include,
+            included_from: included_from.to_path_buf(),
+        },
+        e => e,
+    }
+}
@@ -0,0 +1,44 @@
+//! Filters that transform included content, written after the path with a `|`, e.g.
+//! `--include key.pem |base64` or `--include snippet.txt |trim |indent=4`
+use crate::expand::indent_lines;
+
+/// Applies a single filter to content, returning `None` if the filter isn't known or its argument
+/// isn't valid
+pub(crate) fn apply(content: &str, filter: &str) -> Option<String> {
+    let (name, argument) = match filter.split_once('=') {
+        Some((name, argument)) => (name, Some(argument)),
+        None => (filter, None),
+    };
+    match (name, argument) {
+        ("base64", None) => Some(base64(content.as_bytes())),
+        ("upper", None) => Some(content.to_uppercase()),
+        ("lower", None) => Some(content.to_lowercase()),
+        ("trim", None) => Some(content.trim().to_string()),
+        ("indent", Some(width)) => {
+            let width: usize = width.parse().ok()?;
+            Some(indent_lines(content.to_string(), &" ".repeat(width)))
+        }
+        _ => None,
+    }
+}
+
+/// Encodes bytes as standard base64, with padding and without line breaks
+fn base64(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut encoded = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let group = chunk.iter().enumerate().fold(0u32, |group, (i, &byte)| {
+            group | (u32::from(byte) << (16 - 8 * i))
+        });
+        for i in 0..4 {
+            if i <= chunk.len() {
+                encoded.push(char::from(
+                    ALPHABET[((group >> (18 - 6 * i)) & 0x3f) as usize],
+                ));
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
@@ -0,0 +1,85 @@
+//! The `.includeignore` file in the source root, which uses `.gitignore` patterns to block files
+//! from being included at all, whatever directive refers to them. The patterns are matched by
+//! the `ignore` crate, which also skips git's ignored files with `--respect-gitignore`.
+use std::fs;
+use std::path::Path;
+
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+
+/// The rules from a `.includeignore` file, which name files that must never be included, such as
+/// secrets
+pub(crate) struct IncludeIgnore {
+    rules: Gitignore,
+    /// The rules without a `/` other than a trailing one, for files outside the root
+    name_rules: Gitignore,
+}
+
+impl IncludeIgnore {
+    /// Loads the `.includeignore` file in `root`, if there is one
+    pub(crate) fn load(root: &Path) -> Option<IncludeIgnore> {
+        let file = root.join(".includeignore");
+        let content = fs::read_to_string(&file).ok()?;
+        let mut rules = GitignoreBuilder::new(root);
+        let mut name_rules = GitignoreBuilder::new(root);
+        for line in content.lines() {
+            // Invalid patterns are left out, as git does
+            let _ = rules.add_line(Some(file.clone()), line);
+            if !line.trim_end().trim_end_matches('/').contains('/') {
+                let _ = name_rules.add_line(Some(file.clone()), line);
+            }
+        }
+        Some(IncludeIgnore {
+            rules: rules.build().ok()?,
+            name_rules: name_rules.build().ok()?,
+        })
+    }
+
+    /// Whether including `path` is blocked, either by a pattern matching it or, as in git, one
+    /// matching a directory it is in. A file outside the root can only be blocked by a pattern
+    /// without a slash, which is matched against its name.
+    pub(crate) fn is_blocked(&self, path: &Path) -> bool {
+        if path.starts_with(self.rules.path()) {
+            return self
+                .rules
+                .matched_path_or_any_parents(path, false)
+                .is_ignore();
+        }
+        path.file_name()
+            .is_some_and(|name| self.name_rules.matched(name, false).is_ignore())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn blocks_like_git() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        fs::write(
+            root.join(".includeignore"),
+            "secrets/\n*.pem\n/top.txt\ndocs/*.md\n!docs/keep.md\n",
+        )
+        .unwrap();
+        let include_ignore = IncludeIgnore::load(root).unwrap();
+        let blocked = |path: &str| include_ignore.is_blocked(&root.join(path));
+
+        assert!(blocked("secrets/token.txt"));
+        assert!(blocked("a/secrets/deeper/token.txt"));
+        assert!(blocked("key.pem"));
+        assert!(blocked("certs/key.pem"));
+        assert!(blocked("top.txt"));
+        assert!(!blocked("sub/top.txt"));
+        assert!(blocked("docs/a.md"));
+        assert!(!blocked("docs/keep.md"));
+        assert!(!blocked("docs/sub/a.md"));
+        assert!(!blocked("snippet.txt"));
+
+        // Outside the root only the patterns without a slash apply, to the name
+        let outside = dir.path().parent().unwrap();
+        assert!(include_ignore.is_blocked(&outside.join("shared/key.pem")));
+        assert!(!include_ignore.is_blocked(&outside.join("top.txt")));
+        assert!(!include_ignore.is_blocked(&outside.join("shared/docs/a.md")));
+    }
+}
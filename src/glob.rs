@@ -0,0 +1,86 @@
+//! Glob patterns, used for `--only` style options and for includes that match several files
+use std::path::{Component, Path, PathBuf};
+use std::str::FromStr;
+
+use globset::{GlobBuilder, GlobMatcher};
+use walkdir::WalkDir;
+
+/// A glob like `guide/**` or `*.md`, matched against relative paths with `/` between their
+/// components on every platform, so `*` and `?` don't match across directories but `**` does
+#[derive(Clone, Debug)]
+pub struct Glob {
+    matcher: GlobMatcher,
+}
+
+impl Glob {
+    /// Whether a relative path matches the glob
+    pub fn matches(&self, path: &Path) -> bool {
+        self.matcher.is_match(slash_joined(path))
+    }
+}
+
+impl FromStr for Glob {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        GlobBuilder::new(s)
+            .literal_separator(true)
+            .backslash_escape(true)
+            .build()
+            .map(|glob| Glob {
+                matcher: glob.compile_matcher(),
+            })
+            .map_err(|e| format!("invalid glob {:?}: {}", s, e.kind()))
+    }
+}
+
+/// Whether an include path contains glob metacharacters
+pub(crate) fn is_glob(path: &str) -> bool {
+    path.contains(['*', '?', '['])
+}
+
+/// Finds the files matching a glob, sorted by path. The walk starts at the deepest directory in
+/// the glob without metacharacters and only goes as deep as the glob does, unless it has a `**`.
+/// A glob that isn't valid, such as one with an unclosed `[`, matches nothing.
+pub(crate) fn expand_glob(pattern: &Path) -> Vec<PathBuf> {
+    let mut base_dir = PathBuf::new();
+    for component in pattern.components() {
+        if is_glob(&component.as_os_str().to_string_lossy()) {
+            break;
+        }
+        base_dir.push(component);
+    }
+    let components: Vec<_> = pattern
+        .strip_prefix(&base_dir)
+        .unwrap_or(pattern)
+        .components()
+        .filter_map(|c| match c {
+            Component::Normal(c) => Some(c.to_string_lossy()),
+            _ => None,
+        })
+        .collect();
+    let Ok(glob) = components.join("/").parse::<Glob>() else {
+        return Vec::new();
+    };
+    let mut walk = WalkDir::new(&base_dir).min_depth(1);
+    if !components.iter().any(|c| c.contains("**")) {
+        walk = walk.max_depth(components.len());
+    }
+    let mut matches: Vec<PathBuf> = walk
+        .into_iter()
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.file_type().is_file())
+        .filter(|entry| glob.matches(entry.path().strip_prefix(&base_dir).unwrap()))
+        .map(|entry| entry.into_path())
+        .collect();
+    matches.sort();
+    matches
+}
+
+/// A path's components with `/` between them
+fn slash_joined(path: &Path) -> String {
+    path.components()
+        .map(|c| c.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/")
+}
@@ -0,0 +1,54 @@
+//! Export of the include graph, as Graphviz DOT or (for a `.json` path) JSON.
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use simple_include::quote;
+
+/// Writes one edge per includer -> included pair to `out_path`, naming each file with `name`
+pub fn write_graph(
+    out_path: &Path,
+    included_files: &HashMap<PathBuf, HashSet<PathBuf>>,
+    name: impl Fn(&Path) -> String,
+) -> io::Result<()> {
+    let mut edges: Vec<(String, String)> = included_files
+        .iter()
+        .flat_map(|(included, includers)| {
+            includers
+                .iter()
+                .map(|includer| (name(includer), name(included)))
+        })
+        .collect();
+    edges.sort();
+
+    let is_json = out_path
+        .extension()
+        .is_some_and(|extension| extension.eq_ignore_ascii_case("json"));
+    let content = if is_json {
+        to_json(&edges)
+    } else {
+        to_dot(&edges)
+    };
+    fs::write(out_path, content)
+}
+
+fn to_dot(edges: &[(String, String)]) -> String {
+    let mut dot = String::from("digraph includes {\n");
+    for (from, to) in edges {
+        dot.push_str(&format!("    {} -> {};\n", quote(from), quote(to)));
+    }
+    dot.push_str("}\n");
+    dot
+}
+
+fn to_json(edges: &[(String, String)]) -> String {
+    let edges: Vec<String> = edges
+        .iter()
+        .map(|(from, to)| format!("  {{\"from\": {}, \"to\": {}}}", quote(from), quote(to)))
+        .collect();
+    if edges.is_empty() {
+        return "[]\n".to_string();
+    }
+    format!("[\n{}\n]\n", edges.join(",\n"))
+}
@@ -0,0 +1,2023 @@
+//! A simple include preprocessor.
+//!
+//! Looks for lines starting with an include prefix (`--include` by default) and replaces them
+//! with the contents of the file they point to. Included files are expanded too.
+//!
+//! ```
+//! use simple_include::{expand_string, IncludeOptions};
+//!
+//! let dir = tempfile::tempdir().unwrap();
+//! std::fs::write(dir.path().join("header.txt"), "Hello from the header\n").unwrap();
+//!
+//! let (expanded, includes) =
+//!     expand_string("--include header.txt\nBody", dir.path(), &IncludeOptions::default());
+//! assert_eq!(expanded, "Hello from the header\nBody");
+//! assert_eq!(includes, vec![dir.path().join("header.txt")]);
+//! ```
+use std::cell::Cell;
+use std::collections::{HashMap, HashSet};
+use std::ffi::OsString;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::mem;
+use std::path::{Component, Path, PathBuf};
+use std::str::FromStr;
+use std::thread;
+use std::time::{Duration, Instant};
+use walkdir::WalkDir;
+
+pub mod annotation;
+#[cfg(feature = "archive-includes")]
+mod archive;
+mod conditional;
+pub mod diagnostic;
+mod diff;
+mod directive;
+pub mod encoding;
+pub mod error;
+#[cfg(feature = "exec-includes")]
+mod exec;
+mod expand;
+mod filter;
+mod gitignore;
+mod glob;
+mod output;
+#[cfg(feature = "remote-includes")]
+mod remote;
+mod variables;
+
+use annotation::Annotation;
+use conditional::Conditionals;
+use diagnostic::DiagnosticFormat;
+use directive::{
+    byte_range, extract_lines, extract_man_section, parse_directive, resolve_include_path,
+    Selection,
+};
+use encoding::Encoding;
+pub use error::IncludeError;
+use expand::{directory_files, included_directory, split_line_ending, Expander};
+use filetime::FileTime;
+use gitignore::IncludeIgnore;
+pub use glob::Glob;
+use glob::{expand_glob, is_glob};
+use ignore::WalkBuilder;
+use log::{debug, error, info, warn};
+use output::Output;
+
+/// Settings that control how files are processed
+#[derive(Debug, Clone)]
+pub struct IncludeOptions {
+    /// The prefixes that mark a line as an include directive. The first one a line starts with is
+    /// used, so a longer prefix should come before a shorter one it starts with
+    pub include_strings: Vec<String>,
+    /// Prefixes to use instead of `include_strings` in files with these extensions (without the
+    /// dot), e.g. `//include` for `rs`
+    pub include_strings_by_extension: HashMap<String, String>,
+    /// Text that ends an include directive, which is removed from the include path, e.g. `-->`
+    /// for `<!--include header.html-->`. Empty if directives have no end marker.
+    pub include_suffix: String,
+    /// Starts a trailing comment in an include directive, e.g. ` #` for
+    /// `--include header.txt # shared legal header`. Only recognised after whitespace. Empty if
+    /// directives can't have comments.
+    pub comment_marker: String,
+    /// Lines that start with any of these, after their indentation, are left out of the output,
+    /// e.g. `//!build` for notes that shouldn't be published. Include directives are kept.
+    pub strip_lines: Vec<String>,
+    /// Print the input and output file paths and anything that was skipped
+    pub verbose: bool,
+    /// Encoding to try when a file is not valid UTF-8, before treating it as binary
+    pub fallback_encoding: Option<Encoding>,
+    /// Encoding of every text file, instead of UTF-8. Files that can't be decoded with it are
+    /// treated as binary.
+    pub encoding: Option<Encoding>,
+    /// Warn when a file includes the same file more than once
+    pub warn_duplicate_includes: bool,
+    /// Leave out an include if the same file (and section of it) was already included in the file
+    /// being processed, directly or through another include
+    pub dedupe_includes: bool,
+    /// Expand an include that points at a directory into the files in it. When off, such an
+    /// include is left as it is with a warning.
+    pub directory_includes: bool,
+    /// Maximum depth of nested includes
+    pub max_depth: usize,
+    /// What to do with source files that aren't valid text
+    pub binary_files: BinaryFiles,
+    /// How binary files that are copied get to the target
+    pub link: Link,
+    /// What to do with a UTF-8 byte order mark at the start of a file or include
+    pub bom: Bom,
+    /// Whether placeholders are substituted before or after include directives are expanded
+    pub passes: Passes,
+    /// Includes larger than this many bytes are left as they are, with a warning
+    pub max_include_size: Option<u64>,
+    /// Files with a line longer than this many bytes (not counting its line ending) are copied as
+    /// they are, like binary files, instead of being read a line at a time
+    pub max_line_length: Option<usize>,
+    /// How many more times to read a file that changed while it was being read, e.g. because an
+    /// editor was still saving it, waiting a little longer each time
+    pub read_retries: u32,
+    /// Fail with an error when an include is missing or binary, or would create a cycle, instead of
+    /// leaving the directive as it is
+    pub strict: bool,
+    /// Marker for the start of a named region
+    pub region_start: String,
+    /// Marker for the end of a named region
+    pub region_end: String,
+    /// Text added around the contents of every generated text file
+    pub wrappers: Wrappers,
+    /// The line endings written to generated text files
+    pub line_endings: LineEndings,
+    /// How the indentation of an indented directive is applied to the included lines
+    pub indent: Indent,
+    /// The width of a tab when converting indentation, and the number of spaces per tab for
+    /// `Indent::Spaces`
+    pub tab_width: usize,
+    /// Remove the line ending at the end of generated text files. Otherwise they end with one only
+    /// if their source does.
+    pub no_trailing_newline: bool,
+    /// Prefix for include paths that are relative to the source root rather than the including
+    /// file, e.g. `@/` for `--include @/shared/header.txt`. Empty to disable.
+    pub root_prefix: String,
+    /// The directory that root-relative includes are resolved against. Defaults to the directory
+    /// of the file being processed.
+    pub source_root: Option<PathBuf>,
+    /// The directory that relative include paths are resolved against, instead of the directory
+    /// of the file containing the directive. Absolute and root-relative paths are unaffected.
+    pub include_base: Option<PathBuf>,
+    /// Directories searched in order for a relative include that isn't found where it would
+    /// otherwise resolve, like a compiler's `-I` paths
+    pub include_paths: Vec<PathBuf>,
+    /// Leave include directives as they are if the file they point at is outside the source root,
+    /// for processing untrusted input
+    pub sandbox: bool,
+    /// Find include paths that don't exist as written by ignoring the case of their names, for
+    /// sources written on a case-insensitive file system
+    pub case_insensitive: bool,
+    /// How warnings and errors about include directives are reported
+    pub diagnostic_format: DiagnosticFormat,
+    /// Wrap included content in comments naming the include, in this comment syntax
+    pub annotate: Option<Annotation>,
+    /// How long to wait for a remote include, with the `remote-includes` feature
+    pub fetch_timeout: Duration,
+    /// Run the shell command of includes like `--include !git rev-parse HEAD` and inline its
+    /// output, with the `exec-includes` feature. Off by default, as sources can run anything.
+    pub allow_exec: bool,
+    /// How long a command include may run before it is stopped and left as it is
+    pub exec_timeout: Duration,
+    /// Print the files that would be written instead of writing them
+    pub dry_run: bool,
+    /// With `dry_run`, don't print the files that would be written either, for callers that only
+    /// want the includes that were found
+    pub quiet_dry_run: bool,
+    /// With `dry_run`, print a unified diff of each output against the file already there instead
+    /// of its path, and nothing for outputs that wouldn't change
+    pub diff: bool,
+    /// Give each output the modification time of its source, or of the newest file it includes
+    /// if that is newer, instead of the time it was written
+    pub preserve_mtime: bool,
+    /// Measure the time spent reading and writing each file, for `ProcessedFile::read_time` and
+    /// `ProcessedFile::write_time`
+    pub timings: bool,
+    /// Record each include directive met while expanding, in `ProcessedFile::trace`
+    pub trace: bool,
+    /// Values for `{{NAME}}` placeholders, which are replaced in every file and included file
+    pub variables: HashMap<String, String>,
+    /// Warn about `{{NAME}}` placeholders whose variable isn't defined
+    pub warn_undefined_variables: bool,
+    /// Flags for conditional includes, like `--include-if FEATURE_X extra.txt`, as well as any
+    /// variable
+    pub features: HashSet<String>,
+    /// Marker for the start of a region that is only kept if a flag is defined, e.g. `--if` for
+    /// `--if FEATURE_X`
+    pub if_marker: String,
+    /// Marker for the end of a conditional region
+    pub endif_marker: String,
+}
+
+impl IncludeOptions {
+    pub fn new(include_string: &str, verbose: bool) -> IncludeOptions {
+        IncludeOptions {
+            include_strings: vec![include_string.to_string()],
+            verbose,
+            ..IncludeOptions::default()
+        }
+    }
+
+    /// The prefixes that mark include directives in the file at `path`, which depend on its
+    /// extension
+    pub fn include_strings_for(&self, path: &Path) -> &[String] {
+        path.extension()
+            .and_then(|extension| extension.to_str())
+            .and_then(|extension| self.include_strings_by_extension.get(extension))
+            .map_or(&self.include_strings, std::slice::from_ref)
+    }
+
+    /// Whether a flag for conditional includes is set, either as a feature or a variable
+    pub fn is_defined(&self, name: &str) -> bool {
+        self.features.contains(name) || self.variables.contains_key(name)
+    }
+}
+
+impl Default for IncludeOptions {
+    fn default() -> Self {
+        IncludeOptions {
+            include_strings: vec!["--include".to_string()],
+            include_strings_by_extension: HashMap::new(),
+            include_suffix: String::new(),
+            comment_marker: " #".to_string(),
+            strip_lines: Vec::new(),
+            verbose: false,
+            fallback_encoding: None,
+            encoding: None,
+            warn_duplicate_includes: false,
+            dedupe_includes: false,
+            directory_includes: true,
+            max_depth: 64,
+            strict: false,
+            region_start: "region:".to_string(),
+            region_end: "endregion:".to_string(),
+            wrappers: Wrappers::default(),
+            line_endings: LineEndings::Preserve,
+            indent: Indent::Preserve,
+            binary_files: BinaryFiles::Copy,
+            link: Link::Copy,
+            bom: Bom::Strip,
+            passes: Passes::SubstituteInclude,
+            max_include_size: None,
+            max_line_length: None,
+            read_retries: 0,
+            tab_width: 4,
+            no_trailing_newline: false,
+            root_prefix: "@/".to_string(),
+            source_root: None,
+            include_base: None,
+            include_paths: Vec::new(),
+            sandbox: false,
+            case_insensitive: false,
+            diagnostic_format: DiagnosticFormat::Text,
+            annotate: None,
+            fetch_timeout: Duration::from_secs(10),
+            allow_exec: false,
+            exec_timeout: Duration::from_secs(10),
+            dry_run: false,
+            quiet_dry_run: false,
+            diff: false,
+            preserve_mtime: false,
+            timings: false,
+            trace: false,
+            variables: HashMap::new(),
+            warn_undefined_variables: false,
+            features: HashSet::new(),
+            if_marker: "--if".to_string(),
+            endif_marker: "--endif".to_string(),
+        }
+    }
+}
+
+/// Text added around the contents of every generated text file
+#[derive(Debug, Clone, Default)]
+pub struct Wrappers {
+    pub prepend: String,
+    pub append: String,
+}
+
+impl Wrappers {
+    pub fn load(prepend: Option<&str>, append: Option<&str>) -> io::Result<Wrappers> {
+        Ok(Wrappers {
+            prepend: prepend
+                .map(fs::read_to_string)
+                .transpose()?
+                .unwrap_or_default(),
+            append: append
+                .map(fs::read_to_string)
+                .transpose()?
+                .unwrap_or_default(),
+        })
+    }
+}
+
+/// The line endings written to generated text files
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LineEndings {
+    /// Every line ends in `\n`
+    Lf,
+    /// Every line ends in `\r\n`
+    Crlf,
+    /// Each line keeps the ending it had in its source or included file
+    Preserve,
+}
+
+impl LineEndings {
+    /// Converts the line endings in `content`
+    pub fn apply(self, content: String) -> String {
+        match self {
+            LineEndings::Preserve => content,
+            LineEndings::Lf => content.replace("\r\n", "\n"),
+            LineEndings::Crlf => content.replace("\r\n", "\n").replace('\n', "\r\n"),
+        }
+    }
+}
+
+impl FromStr for LineEndings {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "lf" => Ok(LineEndings::Lf),
+            "crlf" => Ok(LineEndings::Crlf),
+            "preserve" => Ok(LineEndings::Preserve),
+            _ => Err(format!(
+                "unsupported line endings {:?}, expected one of lf, crlf, preserve",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for LineEndings {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LineEndings::Lf => write!(f, "lf"),
+            LineEndings::Crlf => write!(f, "crlf"),
+            LineEndings::Preserve => write!(f, "preserve"),
+        }
+    }
+}
+
+/// What to do with source files that aren't valid text
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BinaryFiles {
+    /// Copy them to the target as they are
+    Copy,
+    /// Leave them out of the target
+    Skip,
+    /// Report them as errors
+    Error,
+}
+
+impl FromStr for BinaryFiles {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "copy" => Ok(BinaryFiles::Copy),
+            "skip" => Ok(BinaryFiles::Skip),
+            "error" => Ok(BinaryFiles::Error),
+            _ => Err(format!(
+                "unsupported binary file handling {:?}, expected one of copy, skip, error",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for BinaryFiles {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BinaryFiles::Copy => write!(f, "copy"),
+            BinaryFiles::Skip => write!(f, "skip"),
+            BinaryFiles::Error => write!(f, "error"),
+        }
+    }
+}
+
+/// How binary files are put in the target
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Link {
+    /// Copy their bytes
+    Copy,
+    /// Hard link them to the source, copying them if that fails, e.g. across file systems
+    Hardlink,
+    /// Clone them copy-on-write where the file system supports it, copying them otherwise
+    Reflink,
+}
+
+impl FromStr for Link {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "copy" => Ok(Link::Copy),
+            "hardlink" => Ok(Link::Hardlink),
+            "reflink" => Ok(Link::Reflink),
+            _ => Err(format!(
+                "unsupported link mode {:?}, expected one of copy, hardlink, reflink",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Link {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Link::Copy => write!(f, "copy"),
+            Link::Hardlink => write!(f, "hardlink"),
+            Link::Reflink => write!(f, "reflink"),
+        }
+    }
+}
+
+/// The order in which each line has its `{{NAME}}` placeholders substituted and its include
+/// directive expanded
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Passes {
+    /// Substitute placeholders first, so a placeholder can produce the path of an include
+    SubstituteInclude,
+    /// Expand includes first, then substitute placeholders in the lines that aren't expanded.
+    /// Placeholders in a directive are then part of the include path as written.
+    IncludeSubstitute,
+}
+
+impl FromStr for Passes {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let passes: Vec<String> = s
+            .split(',')
+            .map(|pass| pass.trim().to_ascii_lowercase())
+            .collect();
+        match passes.as_slice() {
+            [first, second] if first == "substitute" && second == "include" => {
+                Ok(Passes::SubstituteInclude)
+            }
+            [first, second] if first == "include" && second == "substitute" => {
+                Ok(Passes::IncludeSubstitute)
+            }
+            _ => Err(format!(
+                "unsupported pass order {:?}, expected substitute,include or include,substitute",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Passes {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Passes::SubstituteInclude => write!(f, "substitute,include"),
+            Passes::IncludeSubstitute => write!(f, "include,substitute"),
+        }
+    }
+}
+
+/// What to do with a UTF-8 byte order mark at the start of a file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Bom {
+    /// Remove it, so an included file doesn't put one in the middle of the output
+    Strip,
+    /// Keep it as part of the text
+    Keep,
+}
+
+impl FromStr for Bom {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "strip" => Ok(Bom::Strip),
+            "keep" => Ok(Bom::Keep),
+            _ => Err(format!(
+                "unsupported byte order mark handling {:?}, expected one of strip, keep",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Bom {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Bom::Strip => write!(f, "strip"),
+            Bom::Keep => write!(f, "keep"),
+        }
+    }
+}
+
+impl Bom {
+    /// Removes the byte order mark from the start of `text`, the first line or whole content of a
+    /// file, if it is to be stripped
+    fn apply(self, mut text: String) -> String {
+        if self == Bom::Strip && text.starts_with('\u{feff}') {
+            text.drain(..'\u{feff}'.len_utf8());
+        }
+        text
+    }
+}
+
+/// How the indentation of an indented directive is applied to the lines it includes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Indent {
+    /// Exactly the whitespace before the directive
+    Preserve,
+    /// As many tabs as fit in the indentation's width, then spaces for the rest
+    Tabs,
+    /// The indentation's width in spaces
+    Spaces,
+}
+
+impl Indent {
+    /// Converts the whitespace before a directive, counting a tab as reaching the next multiple
+    /// of `tab_width` columns
+    pub fn apply(self, indent: &str, tab_width: usize) -> String {
+        let tab_width = tab_width.max(1);
+        let width = || {
+            indent.chars().fold(0, |column, c| match c {
+                '\t' => (column / tab_width + 1) * tab_width,
+                _ => column + 1,
+            })
+        };
+        match self {
+            Indent::Preserve => indent.to_string(),
+            Indent::Spaces => " ".repeat(width()),
+            Indent::Tabs => {
+                let width = width();
+                format!(
+                    "{}{}",
+                    "\t".repeat(width / tab_width),
+                    " ".repeat(width % tab_width)
+                )
+            }
+        }
+    }
+}
+
+impl FromStr for Indent {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "preserve" => Ok(Indent::Preserve),
+            "tabs" => Ok(Indent::Tabs),
+            "spaces" => Ok(Indent::Spaces),
+            _ => Err(format!(
+                "unsupported indent {:?}, expected one of tabs, spaces, preserve",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Indent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Indent::Preserve => write!(f, "preserve"),
+            Indent::Tabs => write!(f, "tabs"),
+            Indent::Spaces => write!(f, "spaces"),
+        }
+    }
+}
+
+pub fn are_paths_equal(path1: &Path, path2: &Path) -> bool {
+    let norm_path1 = normalize_path(path1);
+    let norm_path2 = normalize_path(path2);
+
+    norm_path1 == norm_path2
+}
+
+/// Lists every file under `dir`, except those in `target`, sorted by path so every run processes
+/// them in the same order. Symlinks are skipped unless `follow_links` is set, in which case they
+/// are listed under the path of the link. With `max_depth`, only files that many levels down or
+/// less are listed, where the files directly in `dir` are at depth 1.
+pub fn list_of_paths(
+    dir: &Path,
+    target: &Path,
+    follow_links: bool,
+    max_depth: Option<usize>,
+) -> io::Result<Vec<PathBuf>> {
+    list_of_paths_filtered(dir, target, follow_links, max_depth, false)
+}
+
+/// Like `list_of_paths`, but skips anything ignored by `.gitignore` or `.ignore` files in the
+/// tree, the repository's `.git/info/exclude` or the global git excludes file
+pub fn list_of_paths_respecting_gitignore(
+    dir: &Path,
+    target: &Path,
+    follow_links: bool,
+    max_depth: Option<usize>,
+) -> io::Result<Vec<PathBuf>> {
+    list_of_unignored_paths(dir, target, follow_links, max_depth, false)
+}
+
+/// Lists every directory under `dir`, not counting `dir` itself, except `target` and the
+/// directories in it. Symlinks to directories are skipped unless `follow_links` is set. With
+/// `max_depth`, only directories that many levels down or less are listed.
+pub fn list_of_dirs(
+    dir: &Path,
+    target: &Path,
+    follow_links: bool,
+    max_depth: Option<usize>,
+) -> io::Result<Vec<PathBuf>> {
+    list_of_paths_filtered(dir, target, follow_links, max_depth, true)
+}
+
+/// Like `list_of_dirs`, but skips directories ignored the same way as
+/// `list_of_paths_respecting_gitignore`
+pub fn list_of_dirs_respecting_gitignore(
+    dir: &Path,
+    target: &Path,
+    follow_links: bool,
+    max_depth: Option<usize>,
+) -> io::Result<Vec<PathBuf>> {
+    list_of_unignored_paths(dir, target, follow_links, max_depth, true)
+}
+
+/// Lists the files under `dir`, or with `dirs` the directories, down to `max_depth` levels if
+/// given. `target` is skipped, along with everything in it.
+fn list_of_paths_filtered(
+    dir: &Path,
+    target: &Path,
+    follow_links: bool,
+    max_depth: Option<usize>,
+    dirs: bool,
+) -> io::Result<Vec<PathBuf>> {
+    let mut paths = Vec::new();
+    // Sorting each directory's entries by name lists the paths in sorted order, as paths compare
+    // a component at a time
+    for entry in WalkDir::new(dir)
+        .follow_links(follow_links)
+        .max_depth(max_depth.unwrap_or(usize::MAX))
+        .sort_by_file_name()
+        .into_iter()
+        .filter_entry(|e| !are_paths_equal(e.path(), target))
+    {
+        let entry = entry.map_err(|e| match (e.loop_ancestor(), e.path()) {
+            (Some(ancestor), Some(path)) => io::Error::other(format!(
+                "Symlink loop: {:?} points to {:?}, which contains it",
+                slashed(path),
+                slashed(ancestor)
+            )),
+            _ => e.into(),
+        })?;
+        let wanted = if dirs {
+            entry.file_type().is_dir() && entry.depth() > 0
+        } else {
+            entry.file_type().is_file()
+        };
+        if wanted {
+            paths.push(entry.into_path());
+        }
+    }
+    Ok(paths)
+}
+
+/// Like `list_of_paths_filtered`, but walks with the `ignore` crate, which reads the ignore files
+/// as git does, so git's ignored files are skipped. Hidden files are listed as they are by
+/// `list_of_paths`, but `.git` directories aren't.
+fn list_of_unignored_paths(
+    dir: &Path,
+    target: &Path,
+    follow_links: bool,
+    max_depth: Option<usize>,
+    dirs: bool,
+) -> io::Result<Vec<PathBuf>> {
+    let target = target.to_path_buf();
+    let walk = WalkBuilder::new(dir)
+        .standard_filters(false)
+        .ignore(true)
+        .git_ignore(true)
+        .git_global(true)
+        .git_exclude(true)
+        .parents(true)
+        .require_git(false)
+        .follow_links(follow_links)
+        .max_depth(max_depth)
+        .sort_by_file_name(|a, b| a.cmp(b))
+        .filter_entry(move |e| e.file_name() != ".git" && !are_paths_equal(e.path(), &target))
+        .build();
+    let mut paths = Vec::new();
+    for entry in walk {
+        let entry = entry.map_err(|e| {
+            let kind = e.io_error().map_or(io::ErrorKind::Other, io::Error::kind);
+            io::Error::new(kind, e)
+        })?;
+        let file_type = entry.file_type();
+        let wanted = if dirs {
+            file_type.is_some_and(|t| t.is_dir()) && entry.depth() > 0
+        } else {
+            file_type.is_some_and(|t| t.is_file())
+        };
+        if wanted {
+            paths.push(entry.into_path());
+        }
+    }
+    Ok(paths)
+}
+
+/// Quotes a string for DOT or JSON, which escape the characters a path can contain the same way
+pub fn quote(s: &str) -> String {
+    let mut quoted = String::with_capacity(s.len() + 2);
+    quoted.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            c if c.is_control() => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// A path as shown in messages and reports, with `/` between its components on every platform so
+/// the output reads the same everywhere. `{}` shows it as it is and `{:?}` quotes it.
+#[derive(Clone, Copy)]
+pub struct Slashed<'a>(&'a Path);
+
+/// Shows a path with `/` separators, e.g. `warn!("{:?} is missing", slashed(path))`
+pub fn slashed(path: &Path) -> Slashed<'_> {
+    Slashed(path)
+}
+
+impl fmt::Display for Slashed<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let path = self.0.to_string_lossy();
+        if std::path::MAIN_SEPARATOR == '/' {
+            f.write_str(&path)
+        } else {
+            f.write_str(&path.replace(std::path::MAIN_SEPARATOR, "/"))
+        }
+    }
+}
+
+impl fmt::Debug for Slashed<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&self.to_string(), f)
+    }
+}
+
+/// Removes `.` components and resolves `..` against the component before it, without touching
+/// the file system. A Windows prefix such as `C:`, `\\?\C:` or `\\server\share` and the root are
+/// kept as they are, and a `..` at the root stays there. A relative path keeps the `..`
+/// components that go above its start, e.g. `../shared/x.txt`.
+pub fn normalize_path(path: &Path) -> PathBuf {
+    let mut result = PathBuf::new();
+
+    for component in path.components() {
+        match component {
+            Component::ParentDir => match result.components().next_back() {
+                Some(Component::Normal(_)) => {
+                    result.pop();
+                }
+                // There is nothing above the root
+                Some(Component::Prefix(_) | Component::RootDir) => {}
+                Some(Component::ParentDir) | None => result.push(".."),
+                Some(Component::CurDir) => unreachable!("`.` components are never kept"),
+            },
+            Component::CurDir => {}
+            _ => {
+                result.push(component.as_os_str());
+            }
+        }
+    }
+
+    result
+}
+
+/// Expands the include directives in the file at `path` and writes the result to `out_path`.
+/// Binary files are copied as they are, left out or returned as `BinaryData` errors, depending on
+/// `options.binary_files`. Returns the paths of every file that was included; use
+/// `process_file_with_stats` to also get where the output is.
+///
+/// In strict mode a missing or binary include is returned as an error and nothing is written.
+/// In a dry run the output path is printed instead of written.
+pub fn process_file(
+    path: &Path,
+    out_path: &Path,
+    options: &IncludeOptions,
+) -> Result<Vec<PathBuf>, IncludeError> {
+    process_file_with_stats(path, out_path, options).map(|processed| processed.includes)
+}
+
+/// What processing a single file did
+#[derive(Debug, Default, Clone)]
+pub struct ProcessedFile {
+    /// The paths of every file that was included
+    pub includes: Vec<PathBuf>,
+    /// How many includes were expanded, counting nested ones
+    pub expanded: usize,
+    /// How many includes were left as they are because they were missing or binary
+    pub skipped: usize,
+    /// How many bytes were written to the output
+    pub bytes_written: u64,
+    /// The includes that were left as they are because they were missing or binary, counting
+    /// nested ones
+    pub broken: Vec<BrokenInclude>,
+    /// Whether the file has no output, because it is a binary file and binary files are skipped
+    pub not_written: bool,
+    /// Where the output is, whether it was expanded, copied as a binary file or already up to
+    /// date. `None` in a dry run or if the file has no output.
+    pub output: Option<PathBuf>,
+    /// The time spent reading the file and its includes, with `timings`
+    pub read_time: Duration,
+    /// The time spent writing the output, with `timings`
+    pub write_time: Duration,
+    /// Each include directive met while expanding the file, with `trace`
+    pub trace: Vec<TracedInclude>,
+}
+
+/// An include directive met while expanding a file, and what became of it. Directives in
+/// included files follow the directive that included them.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TracedInclude {
+    /// How deeply the directive is nested: 0 in the file itself, 1 in the files it includes and
+    /// so on
+    pub depth: usize,
+    /// The file that contains the directive, and the 1-based line it is on
+    pub file: PathBuf,
+    pub line: usize,
+    /// The include path as written
+    pub path: String,
+    /// The selection, fence, condition and filters, each after a space, e.g. ` lines=1-10 |base64`
+    pub modifiers: String,
+    /// The file the path resolves to, if it is a path rather than a URL or a command
+    pub resolved: Option<PathBuf>,
+    /// What became of it: `expanded`, `missing`, `binary`, `dropped` or `repeated`, or `skipped`
+    /// followed by the reason
+    pub status: String,
+}
+
+/// An include directive whose file is missing or is not valid text
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct BrokenInclude {
+    /// The file that contains the directive
+    pub included_from: PathBuf,
+    /// The file the directive points at
+    pub include: PathBuf,
+    /// Whether the file exists but is binary, rather than missing
+    pub binary: bool,
+}
+
+/// Like `process_file`, but also reports how many includes were expanded or skipped and how much
+/// was written
+pub fn process_file_with_stats(
+    path: &Path,
+    out_path: &Path,
+    options: &IncludeOptions,
+) -> Result<ProcessedFile, IncludeError> {
+    // The output that would be written, kept to compare with the existing one
+    let mut diff_output = Vec::new();
+    let result = if options.dry_run && options.diff {
+        expand_file_to(path, options, &mut diff_output)
+    } else if options.dry_run {
+        // Only the size is needed
+        expand_file_to(path, options, io::sink())
+    } else {
+        if let Some(parent) = out_path.parent() {
+            fs::create_dir_all(parent).map_err(|e| IncludeError::from_write(e, parent))?;
+        }
+        // The output is written to the temporary file as it is expanded, so a failed expansion
+        // leaves any previous output as it was
+        let mut processed = ProcessedFile::default();
+        write_atomically(out_path, |temp_path| {
+            processed = read_settled(path, options.read_retries, || {
+                let temp_file =
+                    File::create(temp_path).map_err(|e| IncludeError::from_write(e, out_path))?;
+                expand_file_to(path, options, BufWriter::new(temp_file))
+            })?;
+            copy_permissions(path, temp_path)?;
+            if options.preserve_mtime {
+                copy_mtime(path, &processed.includes, temp_path)?;
+            }
+            Ok(())
+        })
+        .map(|()| processed)
+    };
+    let mut processed = match result {
+        Ok(processed) => processed,
+        Err(IncludeError::BinaryData(binary_path)) if binary_path == path => {
+            return match options.binary_files {
+                BinaryFiles::Copy => copy_binary_file(path, out_path, options),
+                BinaryFiles::Skip => {
+                    debug!("Binary data in file: {:?}, skipping", slashed(path));
+                    Ok(ProcessedFile {
+                        not_written: true,
+                        ..ProcessedFile::default()
+                    })
+                }
+                BinaryFiles::Error => Err(IncludeError::BinaryData(binary_path)),
+            };
+        }
+        Err(IncludeError::LineTooLong {
+            path: long_path,
+            limit,
+        }) if long_path == path => {
+            warn!(
+                "{:?} has a line longer than {} bytes, copying it as it is",
+                slashed(path),
+                limit
+            );
+            return copy_binary_file(path, out_path, options);
+        }
+        Err(e) => return Err(e),
+    };
+    if options.dry_run {
+        let size = mem::take(&mut processed.bytes_written);
+        if options.quiet_dry_run {
+            return Ok(processed);
+        }
+        if options.diff {
+            print_diff(out_path, diff_output)?;
+            return Ok(processed);
+        }
+        if options.verbose {
+            println!("Would write {:?} ({} bytes)", slashed(out_path), size);
+        } else {
+            println!("Would write {:?}", slashed(out_path));
+        }
+        return Ok(processed);
+    }
+    if !processed.includes.is_empty() {
+        info!("Input {:?}, Output {:?}", slashed(path), slashed(out_path));
+    }
+    processed.output = Some(out_path.to_path_buf());
+    Ok(processed)
+}
+
+/// Prints a unified diff of `output` against what `out_path` has now, treating a missing file as
+/// empty. Output that isn't UTF-8, e.g. because it is written in another encoding, is only
+/// reported as differing. Each diff is printed in one go, so diffs of files processed in parallel
+/// don't interleave.
+fn print_diff(out_path: &Path, output: Vec<u8>) -> io::Result<()> {
+    let existing = match fs::read(out_path) {
+        Ok(existing) => Some(existing),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+        Err(e) => return Err(e),
+    };
+    let label = slashed(out_path).to_string();
+    let old_label = if existing.is_some() {
+        label.as_str()
+    } else {
+        "/dev/null"
+    };
+    let existing = existing.unwrap_or_default();
+    let diff = match (std::str::from_utf8(&existing), String::from_utf8(output)) {
+        (Ok(old), Ok(new)) => diff::unified_diff(old, &new, old_label, &label),
+        (_, new) => (new.map_or_else(|e| e.into_bytes(), String::into_bytes) != existing)
+            .then(|| format!("{}: binary differs\n", label)),
+    };
+    match diff {
+        Some(diff) => io::stdout().lock().write_all(diff.as_bytes()),
+        None => Ok(()),
+    }
+}
+
+/// Copies a binary file to the target as it is, unless the target already has the same content
+fn copy_binary_file(
+    path: &Path,
+    out_path: &Path,
+    options: &IncludeOptions,
+) -> Result<ProcessedFile, IncludeError> {
+    if options.dry_run {
+        if options.diff {
+            if !same_content(path, out_path).map_err(|e| copy_error(e, path, out_path))? {
+                println!("{}: binary differs", slashed(out_path));
+            }
+        } else if !options.quiet_dry_run {
+            println!("Would copy {:?} to {:?}", slashed(path), slashed(out_path));
+        }
+        return Ok(ProcessedFile::default());
+    }
+    if same_content(path, out_path).map_err(|e| copy_error(e, path, out_path))? {
+        debug!(
+            "Binary file {:?} is unchanged in {:?}, not copying",
+            slashed(path),
+            slashed(out_path)
+        );
+        return Ok(ProcessedFile {
+            output: Some(out_path.to_path_buf()),
+            ..ProcessedFile::default()
+        });
+    }
+    info!(
+        "Binary data in file: {:?}, copying to {:?}",
+        slashed(path),
+        slashed(out_path)
+    );
+    if let Some(parent) = out_path.parent() {
+        fs::create_dir_all(parent).map_err(|e| IncludeError::from_write(e, parent))?;
+    }
+    let mut bytes_written = 0;
+    write_atomically(out_path, |temp_path| -> io::Result<()> {
+        if options.link == Link::Hardlink {
+            // The link shares the source's permissions and modification time
+            match fs::hard_link(path, temp_path) {
+                Ok(()) => {
+                    bytes_written = fs::metadata(path)?.len();
+                    return Ok(());
+                }
+                Err(e) => info!("Could not hard link {:?}, copying it: {}", slashed(path), e),
+            }
+        }
+        bytes_written = match options.link {
+            Link::Reflink => reflink(path, temp_path).or_else(|e| {
+                debug!("Could not clone {:?}, copying it: {}", slashed(path), e);
+                fs::copy(path, temp_path)
+            })?,
+            Link::Copy | Link::Hardlink => fs::copy(path, temp_path)?,
+        };
+        copy_permissions(path, temp_path)?;
+        if options.preserve_mtime {
+            copy_mtime(path, &[], temp_path)?;
+        }
+        Ok(())
+    })
+    .map_err(|e| copy_error(e, path, out_path))?;
+    Ok(ProcessedFile {
+        bytes_written,
+        output: Some(out_path.to_path_buf()),
+        ..ProcessedFile::default()
+    })
+}
+
+/// Writes an output by calling `write` with a temporary path next to it, then renaming the
+/// temporary file into place, so the output is never seen partly written. If anything fails the
+/// temporary file is removed and any previous output is left as it was.
+fn write_atomically<E: From<io::Error>>(
+    out_path: &Path,
+    write: impl FnOnce(&Path) -> Result<(), E>,
+) -> Result<(), E> {
+    let mut temp_name = OsString::from(".");
+    temp_name.push(out_path.file_name().unwrap_or_default());
+    temp_name.push(format!(".{}.tmp", std::process::id()));
+    let temp_path = out_path.with_file_name(temp_name);
+    let result = write(&temp_path).and_then(|()| Ok(fs::rename(&temp_path, out_path)?));
+    if result.is_err() {
+        let _ = fs::remove_file(&temp_path);
+    }
+    result
+}
+
+/// The error for a binary file that couldn't be copied, naming the file that couldn't be read or
+/// written, or both if it isn't clear which
+fn copy_error(e: io::Error, path: &Path, out_path: &Path) -> IncludeError {
+    if e.kind() == io::ErrorKind::PermissionDenied {
+        return if File::open(path).is_err() {
+            IncludeError::from_io(e, path)
+        } else {
+            IncludeError::from_write(e, out_path)
+        };
+    }
+    IncludeError::Io(io::Error::new(
+        e.kind(),
+        format!(
+            "Could not copy {:?} to {:?}: {}",
+            slashed(path),
+            slashed(out_path),
+            e
+        ),
+    ))
+}
+
+/// Whether two files have the same content, comparing their sizes first. A missing `other` is
+/// never the same.
+fn same_content(path: &Path, other: &Path) -> io::Result<bool> {
+    let other_metadata = match fs::metadata(other) {
+        Ok(metadata) => metadata,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(false),
+        Err(e) => return Err(e),
+    };
+    if !other_metadata.is_file() || fs::metadata(path)?.len() != other_metadata.len() {
+        return Ok(false);
+    }
+    let mut file = BufReader::new(File::open(path)?);
+    let mut other = BufReader::new(File::open(other)?);
+    loop {
+        let buffer = file.fill_buf()?;
+        if buffer.is_empty() {
+            // The sizes are the same, so both files end here unless one changed meanwhile
+            return Ok(other.fill_buf()?.is_empty());
+        }
+        let other_buffer = other.fill_buf()?;
+        let len = buffer.len().min(other_buffer.len());
+        if len == 0 || buffer[..len] != other_buffer[..len] {
+            return Ok(false);
+        }
+        file.consume(len);
+        other.consume(len);
+    }
+}
+
+/// Sets the modification time of the output to the newest of its source and the files it
+/// includes. Includes that no longer exist are ignored.
+fn copy_mtime(path: &Path, includes: &[PathBuf], out_path: &Path) -> io::Result<()> {
+    let mut newest = fs::metadata(path)?.modified()?;
+    for include in includes {
+        if let Ok(modified) = fs::metadata(include).and_then(|m| m.modified()) {
+            newest = newest.max(modified);
+        }
+    }
+    filetime::set_file_mtime(out_path, FileTime::from_system_time(newest))
+}
+
+/// Gives the output the same permissions as its source, so e.g. scripts stay executable. The
+/// output is kept writable by its owner so it can be regenerated.
+#[cfg(unix)]
+fn copy_permissions(path: &Path, out_path: &Path) -> io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let mode = fs::metadata(path)?.permissions().mode();
+    fs::set_permissions(out_path, fs::Permissions::from_mode(mode | 0o200))
+}
+
+#[cfg(not(unix))]
+fn copy_permissions(_path: &Path, _out_path: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+/// Clones a file copy-on-write, sharing its blocks with the original until either is changed,
+/// and returns its size. Fails on file systems that can't, such as ext4.
+#[cfg(target_os = "linux")]
+fn reflink(path: &Path, out_path: &Path) -> io::Result<u64> {
+    use std::os::fd::AsRawFd;
+
+    let source = File::open(path)?;
+    let clone = File::create(out_path)?;
+    // SAFETY: both file descriptors stay open for the duration of the call
+    if unsafe { libc::ioctl(clone.as_raw_fd(), libc::FICLONE as _, source.as_raw_fd()) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(source.metadata()?.len())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn reflink(_path: &Path, _out_path: &Path) -> io::Result<u64> {
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+/// Expands the include directives in the file at `path`, returning the expanded content (in the
+/// file's own encoding) and the paths of every file that was included. Binary files are an error.
+pub fn expand_file(
+    path: &Path,
+    options: &IncludeOptions,
+) -> Result<(Vec<u8>, Vec<PathBuf>), IncludeError> {
+    let mut content = Vec::new();
+    let processed = expand_file_to(path, options, &mut content)?;
+    Ok((content, processed.includes))
+}
+
+/// Like `expand_file`, but writes the output to `out` as it is expanded, and also counts the
+/// includes that were expanded and skipped and the bytes written
+fn expand_file_to(
+    path: &Path,
+    options: &IncludeOptions,
+    out: impl Write,
+) -> Result<ProcessedFile, IncludeError> {
+    let file = File::open(path);
+    if file.is_err() {
+        let e = file.err().unwrap();
+        if e.kind() == io::ErrorKind::NotFound {
+            debug!("File not found: {:?}, skipping. If this looks like a temp file, it was probably deleted before we could parse and copy it.", slashed(path));
+        } else if e.kind() != io::ErrorKind::PermissionDenied {
+            // Permission errors are reported by the caller, with how to fix them
+            error!(
+                "Error opening file for processing: {:?}, {:?}. ",
+                slashed(path),
+                e
+            );
+        }
+        return Err(IncludeError::from_io(e, path));
+    }
+
+    let mut file = io::BufReader::new(file?);
+    let mut output_encoding = None;
+    let mut read_time = Duration::ZERO;
+    let utf16 = options.encoding.is_none() && Encoding::from_bom(file.fill_buf()?).is_some();
+    let lines: Box<dyn Iterator<Item = io::Result<String>>> =
+        if options.encoding.is_none() && options.fallback_encoding.is_none() && !utf16 {
+            Box::new(without_bom(
+                raw_lines(file, options.max_line_length),
+                options.bom,
+            ))
+        } else {
+            // The whole file is needed up front to decode it, or to retry decoding it with the
+            // fallback encoding
+            let mut bytes = Vec::new();
+            timed(options.timings, &mut read_time, || {
+                file.read_to_end(&mut bytes)
+            })?;
+            let content = decode_text(bytes, options).map(|(content, encoding)| {
+                if let (None, false, Some(fallback_encoding)) = (options.encoding, utf16, encoding)
+                {
+                    info!(
+                        "File {:?} is not valid UTF-8, decoded as {}",
+                        slashed(path),
+                        fallback_encoding
+                    );
+                }
+                output_encoding = encoding;
+                content
+            });
+            let content = content.and_then(|c| within_line_length(c, options.max_line_length));
+            match content {
+                Ok(content) => Box::new(
+                    content
+                        .split_inclusive('\n')
+                        .map(|l| Ok(l.to_string()))
+                        .collect::<Vec<_>>()
+                        .into_iter(),
+                ),
+                Err(e) => Box::new(std::iter::once(Err(e))),
+            }
+        };
+
+    let read_time = Cell::new(read_time);
+    let lines = timed_lines(lines, options.timings, &read_time);
+    let mut output = Output::new(out, options, output_encoding);
+    let mut processed = expand_lines(lines, path, options, &mut output, true)?;
+    let mut write_time = output.write_time;
+    let (_, bytes_written) = timed(options.timings, &mut write_time, || output.finish())?;
+    processed.bytes_written = bytes_written;
+    processed.read_time += read_time.get();
+    processed.write_time = write_time;
+    Ok(processed)
+}
+
+/// Runs `f`, adding the time it takes to `total` if `timings` is set
+pub(crate) fn timed<T>(timings: bool, total: &mut Duration, f: impl FnOnce() -> T) -> T {
+    if !timings {
+        return f();
+    }
+    let start = Instant::now();
+    let result = f();
+    *total += start.elapsed();
+    result
+}
+
+/// Adds the time spent reading each line to `total`, if `timings` is set
+fn timed_lines<'a>(
+    mut lines: impl Iterator<Item = io::Result<String>> + 'a,
+    timings: bool,
+    total: &'a Cell<Duration>,
+) -> impl Iterator<Item = io::Result<String>> + 'a {
+    std::iter::from_fn(move || {
+        let mut time = total.get();
+        let line = timed(timings, &mut time, || lines.next());
+        total.set(time);
+        line
+    })
+}
+
+/// Expands the include directives in text read from `reader`, such as stdin, resolving relative
+/// include paths against `base_dir`. Returns the expanded text and the paths of every file that
+/// was included.
+pub fn expand_reader<R: BufRead>(
+    reader: R,
+    base_dir: &Path,
+    options: &IncludeOptions,
+) -> Result<(String, Vec<PathBuf>), IncludeError> {
+    // Includes are resolved against the parent of the file being expanded, so stand in a file
+    // name inside the base directory
+    let mut output = Output::new(Vec::new(), options, None);
+    let lines = without_bom(raw_lines(reader, None), options.bom);
+    let processed = expand_lines(lines, &base_dir.join("-"), options, &mut output, false)?;
+    let (content, _) = output.finish()?;
+    // Nothing is encoded, so the output is the UTF-8 it was written as
+    let content = String::from_utf8(content).expect("the output is UTF-8");
+    Ok((content, processed.includes))
+}
+
+/// Reads lines like `BufRead::lines`, but keeps each line's ending. A line longer than
+/// `max_line_length` bytes is an error of kind `FileTooLarge`, found without reading the rest of it.
+fn raw_lines<R: BufRead>(
+    mut reader: R,
+    max_line_length: Option<usize>,
+) -> impl Iterator<Item = io::Result<String>> {
+    std::iter::from_fn(move || {
+        let mut line = Vec::new();
+        // One byte more than the limit, for the line ending or to see that the line is too long
+        let limit = max_line_length.map_or(u64::MAX, |max| max as u64 + 1);
+        match reader.by_ref().take(limit).read_until(b'\n', &mut line) {
+            Ok(0) => None,
+            Ok(read) if read as u64 == limit && !line.ends_with(b"\n") => {
+                Some(Err(line_too_long()))
+            }
+            Ok(_) => Some(
+                String::from_utf8(line).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e)),
+            ),
+            Err(e) => Some(Err(e)),
+        }
+    })
+}
+
+/// Checks decoded content for lines longer than `max_line_length`, like `raw_lines` does as it reads
+fn within_line_length(content: String, max_line_length: Option<usize>) -> io::Result<String> {
+    match max_line_length {
+        Some(max) if content.lines().any(|line| line.len() > max) => Err(line_too_long()),
+        _ => Ok(content),
+    }
+}
+
+fn line_too_long() -> io::Error {
+    io::Error::new(io::ErrorKind::FileTooLarge, "line too long")
+}
+
+/// Removes a byte order mark from the start of the first line, if it is to be stripped
+fn without_bom(
+    lines: impl Iterator<Item = io::Result<String>>,
+    bom: Bom,
+) -> impl Iterator<Item = io::Result<String>> {
+    lines.enumerate().map(move |(index, line)| match index {
+        0 => line.map(|line| bom.apply(line)),
+        _ => line,
+    })
+}
+
+/// Expands each line read from the file at `path` and writes it to `output` as it goes, adding
+/// the wrappers and warning about duplicate includes. With `raw_output`, byte ranges that aren't
+/// UTF-8 are written as they are; otherwise the output is text, and they are binary.
+fn expand_lines<W: Write>(
+    lines: impl Iterator<Item = io::Result<String>>,
+    path: &Path,
+    options: &IncludeOptions,
+    output: &mut Output<W>,
+    raw_output: bool,
+) -> Result<ProcessedFile, IncludeError> {
+    let root = source_root(path, options);
+    let mut expander = Expander {
+        options,
+        include_ignore: IncludeIgnore::load(&normalize_path(&root)),
+        root,
+        paths: Vec::new(),
+        expanded: 0,
+        skipped: 0,
+        broken: Vec::new(),
+        stack: vec![normalize_path(path)],
+        included: HashSet::new(),
+        included_once: HashSet::new(),
+        error: None,
+        diagnostics: Vec::new(),
+        trace: Vec::new(),
+        read_time: Duration::ZERO,
+        raw_output,
+    };
+    output.write_str(&options.wrappers.prepend)?;
+    let parent_dir = path.parent().unwrap_or_else(|| Path::new(""));
+    let root = expander.root.clone();
+    let mut include_lines: HashMap<(PathBuf, Option<String>), Vec<usize>> = HashMap::new();
+    let mut conditionals = Conditionals::default();
+    for (line_index, line) in lines.enumerate() {
+        match line {
+            Ok(line) => {
+                let (line, ending) = split_line_ending(&line);
+                if conditionals.drop_line(line, line_index + 1, path, options)? {
+                    continue;
+                }
+                if options.warn_duplicate_includes {
+                    if let Some((Ok(include_path), selection)) = parse_directive(
+                        line,
+                        options.include_strings_for(path),
+                        &options.include_suffix,
+                        &options.comment_marker,
+                    )
+                    .map(|directive| {
+                        (
+                            resolve_include_path(parent_dir, &root, directive.path, options),
+                            directive.selection,
+                        )
+                    }) {
+                        include_lines
+                            .entry((
+                                normalize_path(&include_path),
+                                selection.map(|s| s.to_string()),
+                            ))
+                            .or_default()
+                            .push(line_index + 1);
+                    }
+                }
+                let mut write = |bytes: &[u8]| output.write_bytes(bytes);
+                expander.stream_line(line, path, line_index + 1, ending, &mut write)?;
+            }
+            Err(e) if e.kind() == io::ErrorKind::FileTooLarge => {
+                // Reported by the caller, which copies the file instead
+                return Err(IncludeError::LineTooLong {
+                    path: path.to_path_buf(),
+                    limit: options.max_line_length.unwrap_or_default(),
+                });
+            }
+            Err(e) => {
+                match e.kind() {
+                    // Binary files are reported by the caller, which decides what to do, and
+                    // so are permission errors
+                    io::ErrorKind::InvalidData | io::ErrorKind::PermissionDenied => {}
+                    io::ErrorKind::NotFound => {
+                        debug!("File not found: {:?}, skipping", slashed(path));
+                    }
+                    _ => {
+                        error!(
+                            "Error reading file: \"{:?}\". Error: \"{:?}\", skipping",
+                            slashed(path),
+                            e
+                        );
+                    }
+                }
+                return Err(IncludeError::from_io(e, path));
+            }
+        }
+    }
+    conditionals.finish(path)?;
+    if let Some(e) = expander.error.take() {
+        return Err(e);
+    }
+    output.write_str(&options.wrappers.append)?;
+    let processed = ProcessedFile {
+        includes: mem::take(&mut expander.paths),
+        expanded: expander.expanded,
+        skipped: expander.skipped,
+        bytes_written: 0,
+        broken: mem::take(&mut expander.broken),
+        not_written: false,
+        output: None,
+        read_time: expander.read_time,
+        write_time: Duration::ZERO,
+        trace: mem::take(&mut expander.trace),
+    };
+    let mut duplicates: Vec<_> = include_lines
+        .into_iter()
+        .filter(|(_, line_numbers)| line_numbers.len() > 1)
+        .collect();
+    duplicates.sort();
+    for ((include_path, _), line_numbers) in duplicates {
+        let line_numbers: Vec<String> = line_numbers.iter().map(|n| n.to_string()).collect();
+        warn!(
+            "{:?} includes {:?} more than once (lines {})",
+            slashed(path),
+            include_path,
+            line_numbers.join(", ")
+        );
+    }
+    Ok(processed)
+}
+
+/// Expands the include directives in `content`, resolving relative include paths against
+/// `base_dir`. Returns the expanded text and the paths of every file that was included.
+pub fn expand_string(
+    content: &str,
+    base_dir: &Path,
+    options: &IncludeOptions,
+) -> (String, Vec<PathBuf>) {
+    // Includes are resolved against the parent of the file being expanded, so stand in a file
+    // name inside the base directory
+    let file = base_dir.join("-");
+    let root = source_root(&file, options);
+    let mut expander = Expander {
+        options,
+        include_ignore: IncludeIgnore::load(&normalize_path(&root)),
+        root,
+        paths: Vec::new(),
+        expanded: 0,
+        skipped: 0,
+        broken: Vec::new(),
+        stack: vec![normalize_path(&file)],
+        included: HashSet::new(),
+        included_once: HashSet::new(),
+        error: None,
+        diagnostics: Vec::new(),
+        trace: Vec::new(),
+        read_time: Duration::ZERO,
+        raw_output: false,
+    };
+    let expanded = expander.expand_content(content, &file);
+    (expanded, mem::take(&mut expander.paths))
+}
+
+/// The directory that root-relative includes in `path` are resolved against
+fn source_root(path: &Path, options: &IncludeOptions) -> PathBuf {
+    match &options.source_root {
+        Some(root) => root.clone(),
+        None => path.parent().unwrap_or_else(|| Path::new("")).to_path_buf(),
+    }
+}
+
+/// Reads a text file, decoding it with the encoding if one is set, otherwise as UTF-8 or the
+/// fallback encoding (if any)
+pub(crate) fn read_to_string_with_fallback(
+    path: &Path,
+    options: &IncludeOptions,
+) -> io::Result<String> {
+    let bytes = read_settled(path, options.read_retries, || fs::read(path))?;
+    decode_text(bytes, options).map(|(content, _)| content)
+}
+
+/// Calls `read` until `path` has the same size and modification time after it as before, so a
+/// file caught while it is being written is read again once the writer is done. Gives up after
+/// `retries` more attempts, waiting twice as long each time, and returns the last result. A file
+/// that doesn't exist is never retried.
+fn read_settled<T, E>(
+    path: &Path,
+    retries: u32,
+    mut read: impl FnMut() -> Result<T, E>,
+) -> Result<T, E> {
+    let file_state = || {
+        fs::metadata(path)
+            .ok()
+            .map(|metadata| (metadata.len(), metadata.modified().ok()))
+    };
+    let mut wait = Duration::from_millis(10);
+    for _ in 0..retries {
+        let before = file_state();
+        let result = read();
+        if before.is_none() || file_state() == before {
+            return result;
+        }
+        debug!(
+            "{:?} changed while it was read, reading it again",
+            slashed(path)
+        );
+        thread::sleep(wait);
+        wait *= 2;
+    }
+    read()
+}
+
+/// Decodes the contents of a text file, returning the text and the encoding it was decoded from if
+/// it wasn't UTF-8. Bytes that can't be decoded are an `InvalidData` error, as for binary files.
+pub(crate) fn decode_text(
+    bytes: Vec<u8>,
+    options: &IncludeOptions,
+) -> io::Result<(String, Option<Encoding>)> {
+    // A UTF-16 byte order mark says how the file is encoded, unless the encoding is given
+    if let Some(encoding) = options.encoding.or_else(|| Encoding::from_bom(&bytes)) {
+        return match encoding.decode(&bytes) {
+            Some(content) => Ok((content, Some(encoding))),
+            None => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("stream did not contain valid {}", encoding),
+            )),
+        };
+    }
+    match String::from_utf8(bytes) {
+        Ok(content) => Ok((options.bom.apply(content), None)),
+        Err(e) => match options.fallback_encoding {
+            Some(fallback_encoding) => match fallback_encoding.decode(e.as_bytes()) {
+                Some(content) => Ok((content, Some(fallback_encoding))),
+                None => Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "stream did not contain valid UTF-8 or {}",
+                        fallback_encoding
+                    ),
+                )),
+            },
+            None => Err(io::Error::new(io::ErrorKind::InvalidData, e.utf8_error())),
+        },
+    }
+}
+
+/// Finds every file included by the file at `path`, following nested includes, without expanding
+/// anything. Includes that can't be read are listed but not followed.
+pub fn find_includes(path: &Path, options: &IncludeOptions) -> Result<Vec<PathBuf>, IncludeError> {
+    let content =
+        read_to_string_with_fallback(path, options).map_err(|e| IncludeError::from_io(e, path))?;
+    let mut includes = Vec::new();
+    let mut seen = HashSet::from([normalize_path(path)]);
+    let root = source_root(path, options);
+    let mut pending = vec![(normalize_path(path), content)];
+    while let Some((file, content)) = pending.pop() {
+        let parent_dir = file.parent().unwrap_or_else(|| Path::new(""));
+        for line in content.lines() {
+            let Some(directive) = parse_directive(
+                line,
+                options.include_strings_for(&file),
+                &options.include_suffix,
+                &options.comment_marker,
+            ) else {
+                continue;
+            };
+            let include_path = directive.path;
+            // A member of an archive depends on the archive itself
+            #[cfg(feature = "archive-includes")]
+            let include_path =
+                archive::split(include_path).map_or(include_path, |(archive, _)| archive);
+            let Ok(include) = resolve_include_path(parent_dir, &root, include_path, options) else {
+                continue;
+            };
+            let include = normalize_path(&include);
+            let directory =
+                included_directory(include_path, &include).filter(|_| options.directory_includes);
+            let matches = if let Some((dir, recursive)) = directory {
+                directory_files(&dir, recursive)
+            } else if is_glob(include_path) {
+                expand_glob(&include)
+            } else {
+                vec![include]
+            };
+            for include in matches {
+                if !seen.insert(include.clone()) {
+                    continue;
+                }
+                includes.push(include.clone());
+                if let Ok(content) = read_to_string_with_fallback(&include, options) {
+                    pending.push((include, content));
+                }
+            }
+        }
+    }
+    Ok(includes)
+}
+
+/// Expands the file at `path` without writing it anywhere, returning each include directive met
+/// on the way, nested ones included, and what became of it. Missing includes are traced rather
+/// than failing the expansion, even with `strict`.
+pub fn trace_file(
+    path: &Path,
+    options: &IncludeOptions,
+) -> Result<Vec<TracedInclude>, IncludeError> {
+    let options = IncludeOptions {
+        trace: true,
+        strict: false,
+        ..options.clone()
+    };
+    expand_file_to(path, &options, io::sink()).map(|processed| processed.trace)
+}
+
+/// Prints each directive in the file with its line number, path, modifiers and whether it resolves
+pub fn dump_directives(path: &Path, options: &IncludeOptions) -> io::Result<()> {
+    let content = read_to_string_with_fallback(path, options)?;
+    let parent_dir = path.parent().unwrap_or_else(|| Path::new(""));
+    let root = source_root(path, options);
+    for (line_index, line) in content.lines().enumerate() {
+        let Some(directive) = parse_directive(
+            line,
+            options.include_strings_for(path),
+            &options.include_suffix,
+            &options.comment_marker,
+        ) else {
+            continue;
+        };
+        let (include_path, selection) = (directive.path, directive.selection);
+        let modifiers = directive.modifiers();
+        #[cfg(feature = "archive-includes")]
+        if let Some((archive, member)) = archive::split(include_path) {
+            if let Ok(resolved) = resolve_include_path(parent_dir, &root, archive, options) {
+                let resolution =
+                    match archive::read_member(&resolved, member, options.max_include_size) {
+                        Ok(_) => "found".to_string(),
+                        Err(e) if e.kind() == io::ErrorKind::NotFound => "not-found".to_string(),
+                        Err(e) => format!("error: {}", e),
+                    };
+                println!(
+                    "line {}: {}{} -> {} ({})",
+                    line_index + 1,
+                    include_path,
+                    modifiers,
+                    slashed(&normalize_path(&resolved)),
+                    resolution
+                );
+                continue;
+            }
+        }
+        let resolved = match resolve_include_path(parent_dir, &root, include_path, options) {
+            Ok(resolved) => normalize_path(&resolved),
+            Err(variable) => {
+                println!(
+                    "line {}: {}{} -> ? (undefined variable {})",
+                    line_index + 1,
+                    include_path,
+                    modifiers,
+                    variable
+                );
+                continue;
+            }
+        };
+        let directory =
+            included_directory(include_path, &resolved).filter(|_| options.directory_includes);
+        let resolution = if let Some((dir, recursive)) = directory {
+            match directory_files(&dir, recursive).len() {
+                0 => "empty directory".to_string(),
+                1 => "directory, 1 file".to_string(),
+                count => format!("directory, {} files", count),
+            }
+        } else if resolved.is_dir() {
+            "directory, not a file".to_string()
+        } else if is_glob(include_path) {
+            match expand_glob(&resolved).len() {
+                0 => "no-matches".to_string(),
+                1 => "1 file".to_string(),
+                count => format!("{} files", count),
+            }
+        } else if let Some(Selection::Bytes(range)) = selection {
+            // Only the range is read, so the rest of the file may well be binary
+            let fits =
+                |size| byte_range(range).is_some_and(|(offset, length)| offset + length <= size);
+            match fs::metadata(&resolved) {
+                Ok(metadata) if fits(metadata.len()) => "found".to_string(),
+                Ok(_) => "found, byte range out of bounds".to_string(),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => "not-found".to_string(),
+                Err(e) => format!("error: {}", e),
+            }
+        } else {
+            match read_to_string_with_fallback(&resolved, options) {
+                Ok(include_content) => match selection {
+                    Some(Selection::ManSection(section))
+                        if extract_man_section(&include_content, section).is_none() =>
+                    {
+                        "section-not-found".to_string()
+                    }
+                    Some(Selection::Lines(range))
+                        if extract_lines(&include_content, range).is_none() =>
+                    {
+                        "found, invalid line range".to_string()
+                    }
+                    _ => "found".to_string(),
+                },
+                Err(e) if e.kind() == io::ErrorKind::NotFound => "not-found".to_string(),
+                Err(e) if e.kind() == io::ErrorKind::InvalidData => "binary".to_string(),
+                Err(e) => format!("error: {}", e),
+            }
+        };
+        println!(
+            "line {}: {}{} -> {} ({})",
+            line_index + 1,
+            include_path,
+            modifiers,
+            slashed(&resolved),
+            resolution
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::expand::read_include;
+    use tempfile::tempdir;
+
+    #[test]
+    fn paths_are_listed_in_sorted_order() {
+        let dir = tempdir().unwrap();
+        for file in [
+            "b.txt",
+            "a.txt",
+            "a/z.txt",
+            "a/b/c.txt",
+            "a-b.txt",
+            "C.txt",
+            "c/a.txt",
+        ] {
+            let path = dir.path().join(file);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, "").unwrap();
+        }
+        let paths = list_of_paths(dir.path(), &dir.path().join("target"), false, None).unwrap();
+        assert_eq!(paths.len(), 7);
+        assert!(
+            paths.windows(2).all(|pair| pair[0] < pair[1]),
+            "{:?}",
+            paths
+        );
+    }
+
+    #[test]
+    fn streamed_output_matches_buffered_output() {
+        // Mixed line endings, lone carriage returns and non-ASCII text, several megabytes long
+        let mut text = String::new();
+        for i in 0..100_000 {
+            text.push_str(match i % 5 {
+                0 => "plain line\n",
+                1 => "windows line\r\n",
+                2 => "lone \r carriage return\n",
+                3 => "caf\u{e9} \r\r\n",
+                _ => "",
+            });
+        }
+        text.push_str("last line\r\n");
+        for line_endings in [LineEndings::Preserve, LineEndings::Lf, LineEndings::Crlf] {
+            for no_trailing_newline in [false, true] {
+                for encoding in [None, Some("latin1".parse::<Encoding>().unwrap())] {
+                    let options = IncludeOptions {
+                        line_endings,
+                        no_trailing_newline,
+                        ..IncludeOptions::default()
+                    };
+                    // The whole output at once, as it was before it was streamed
+                    let mut buffered = text.clone();
+                    if no_trailing_newline {
+                        let (without_ending, _) = split_line_ending(&buffered);
+                        buffered.truncate(without_ending.len());
+                    }
+                    let buffered = line_endings.apply(buffered);
+                    let buffered = match encoding {
+                        Some(encoding) => encoding.encode(&buffered),
+                        None => buffered.into_bytes(),
+                    };
+
+                    // Uneven chunks, some of which split a \r\n
+                    let mut output = Output::new(Vec::new(), &options, encoding);
+                    let mut rest = text.as_str();
+                    let mut chunk_len = 1;
+                    while !rest.is_empty() {
+                        let mut end = chunk_len.min(rest.len());
+                        while !rest.is_char_boundary(end) {
+                            end += 1;
+                        }
+                        output.write_str(&rest[..end]).unwrap();
+                        rest = &rest[end..];
+                        chunk_len = chunk_len % 97 + 13;
+                    }
+                    let (streamed, bytes_written) = output.finish().unwrap();
+
+                    assert!(
+                        streamed == buffered,
+                        "{} {} {:?}",
+                        line_endings,
+                        no_trailing_newline,
+                        encoding
+                    );
+                    assert_eq!(bytes_written, buffered.len() as u64);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn missing_include_is_include_not_found() {
+        let temp_dir = tempdir().unwrap();
+        let main_file = temp_dir.path().join("main.txt");
+        let missing = temp_dir.path().join("missing.txt");
+
+        let result = read_include(&missing, &main_file, &IncludeOptions::default());
+        match result {
+            Err(IncludeError::IncludeNotFound {
+                include,
+                included_from,
+            }) => {
+                assert_eq!(include, missing);
+                assert_eq!(included_from, main_file);
+            }
+            other => panic!("Expected IncludeNotFound, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn binary_include_is_binary_data() {
+        let temp_dir = tempdir().unwrap();
+        let main_file = temp_dir.path().join("main.txt");
+        let binary = temp_dir.path().join("binary.bin");
+        fs::write(&binary, [0, 159, 146, 150]).unwrap();
+
+        let result = read_include(&binary, &main_file, &IncludeOptions::default());
+        assert!(matches!(result, Err(IncludeError::BinaryData(path)) if path == binary));
+    }
+
+    #[test]
+    fn binary_source_is_binary_data() {
+        let temp_dir = tempdir().unwrap();
+        let binary = temp_dir.path().join("binary.bin");
+        fs::write(&binary, [0, 159, 146, 150]).unwrap();
+
+        let options = IncludeOptions {
+            binary_files: BinaryFiles::Error,
+            ..IncludeOptions::default()
+        };
+        let result = process_file(&binary, &temp_dir.path().join("out.bin"), &options);
+        assert!(matches!(result, Err(IncludeError::BinaryData(path)) if path == binary));
+    }
+
+    #[test]
+    fn processed_file_gives_its_output() {
+        let temp_dir = tempdir().unwrap();
+        let source = temp_dir.path().join("main.txt");
+        let binary = temp_dir.path().join("image.bin");
+        fs::write(temp_dir.path().join("part.txt"), "Part.\n").unwrap();
+        fs::write(&source, "--include part.txt\n").unwrap();
+        fs::write(&binary, [0, 159, 146, 150]).unwrap();
+
+        let out = temp_dir.path().join("out/main.html");
+        let processed = process_file_with_stats(&source, &out, &IncludeOptions::default()).unwrap();
+        assert_eq!(processed.output.as_deref(), Some(out.as_path()));
+        assert_eq!(fs::read_to_string(&out).unwrap(), "Part.\n");
+
+        // Copied as it is, and still given when it is already up to date
+        let out = temp_dir.path().join("out/image.bin");
+        for _ in 0..2 {
+            let processed =
+                process_file_with_stats(&binary, &out, &IncludeOptions::default()).unwrap();
+            assert_eq!(processed.output.as_deref(), Some(out.as_path()));
+            assert_eq!(fs::read(&out).unwrap(), [0, 159, 146, 150]);
+        }
+
+        let options = IncludeOptions {
+            binary_files: BinaryFiles::Skip,
+            ..IncludeOptions::default()
+        };
+        let out = temp_dir.path().join("skipped/image.bin");
+        let processed = process_file_with_stats(&binary, &out, &options).unwrap();
+        assert_eq!(processed.output, None);
+        assert!(!out.exists());
+    }
+
+    #[test]
+    fn missing_source_is_source_not_found() {
+        let temp_dir = tempdir().unwrap();
+        let missing = temp_dir.path().join("missing.txt");
+
+        let result = process_file(
+            &missing,
+            &temp_dir.path().join("out.txt"),
+            &IncludeOptions::default(),
+        );
+        assert!(matches!(result, Err(IncludeError::SourceNotFound(path)) if path == missing));
+    }
+
+    #[test]
+    fn failed_write_keeps_previous_output() {
+        let temp_dir = tempdir().unwrap();
+        let out_path = temp_dir.path().join("out.txt");
+        fs::write(&out_path, "Previous\n").unwrap();
+
+        let result = write_atomically(&out_path, |temp_path| {
+            fs::write(temp_path, "Part")?;
+            Err(io::Error::other("interrupted"))
+        });
+        assert!(result.is_err());
+        assert_eq!(fs::read_to_string(&out_path).unwrap(), "Previous\n");
+        assert_eq!(fs::read_dir(temp_dir.path()).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn paths_are_shown_with_forward_slashes() {
+        // Joined with the native separator, which is `\` on Windows
+        let path = Path::new("docs").join("guide").join("intro.md");
+        assert_eq!(slashed(&path).to_string(), "docs/guide/intro.md");
+        assert_eq!(format!("{:?}", slashed(&path)), "\"docs/guide/intro.md\"");
+
+        let error = IncludeError::IncludeNotFound {
+            include: Path::new("parts").join("missing.txt"),
+            included_from: Path::new("docs").join("main.txt"),
+        };
+        assert_eq!(
+            error.to_string(),
+            "Include file not found: \"parts/missing.txt\" (included in file \"docs/main.txt\")"
+        );
+    }
+
+    #[test]
+    fn normalize_path_stops_at_the_root() {
+        assert_eq!(normalize_path(Path::new("/a/./b/../c")), Path::new("/a/c"));
+        assert_eq!(normalize_path(Path::new("/a/../../b")), Path::new("/b"));
+        assert_eq!(normalize_path(Path::new("/..")), Path::new("/"));
+        assert_eq!(
+            normalize_path(Path::new("../shared/./x/../y.txt")),
+            Path::new("../shared/y.txt")
+        );
+        assert_eq!(normalize_path(Path::new("a/../../b")), Path::new("../b"));
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn normalize_path_keeps_windows_prefixes() {
+        assert_eq!(
+            normalize_path(Path::new(r"C:\a\..\..\b")),
+            Path::new(r"C:\b")
+        );
+        assert_eq!(
+            normalize_path(Path::new(r"\\?\C:\a\..\b")),
+            Path::new(r"\\?\C:\b")
+        );
+        assert_eq!(
+            normalize_path(Path::new(r"\\server\share\a\..\..\b")),
+            Path::new(r"\\server\share\b")
+        );
+        let normalized = normalize_path(Path::new(r"\\?\UNC\server\share\a\..\b"));
+        assert_eq!(normalized, Path::new(r"\\?\UNC\server\share\b"));
+        assert!(matches!(
+            normalized.components().next(),
+            Some(Component::Prefix(prefix)) if prefix.kind().is_verbatim()
+        ));
+    }
+
+    #[test]
+    fn file_changed_while_read_is_read_again() {
+        let temp_dir = tempdir().unwrap();
+        let path = temp_dir.path().join("saving.txt");
+        fs::write(&path, "First half").unwrap();
+
+        // The rest is written while the first read is going on
+        let mut reads = 0;
+        let content = read_settled(&path, 3, || {
+            reads += 1;
+            let content = fs::read_to_string(&path);
+            if reads == 1 {
+                fs::write(&path, "First half, second half").unwrap();
+            }
+            content
+        })
+        .unwrap();
+        assert_eq!(content, "First half, second half");
+        assert_eq!(reads, 2);
+
+        let missing = temp_dir.path().join("missing.txt");
+        let mut reads = 0;
+        let result = read_settled(&missing, 3, || {
+            reads += 1;
+            fs::read_to_string(&missing)
+        });
+        assert!(result.is_err());
+        assert_eq!(reads, 1);
+    }
+}
@@ -0,0 +1,125 @@
+//! A tiny live-reload endpoint for watch mode.
+//!
+//! Browsers subscribe with an `EventSource` on `http://localhost:<port>/livereload` (or just add
+//! `<script src="http://localhost:<port>/livereload.js"></script>` to the page) and are sent a
+//! `reload` event after each batch of regenerated files.
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+/// How long a browser has to send its request, and to take each event, before it is dropped
+const TIMEOUT: Duration = Duration::from_secs(1);
+
+const RELOAD_SCRIPT: &str = "(function(){var src=document.currentScript.src;\
+new EventSource(src.replace(/\\.js$/,'')).addEventListener('reload',function(){location.reload();});})();\n";
+
+pub struct LiveReload {
+    addr: SocketAddr,
+    clients: Arc<Mutex<Vec<TcpStream>>>,
+    shutdown: Arc<AtomicBool>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl LiveReload {
+    /// Starts listening on the given port on localhost
+    pub fn start(port: u16) -> io::Result<LiveReload> {
+        let listener = TcpListener::bind(("127.0.0.1", port))?;
+        let addr = listener.local_addr()?;
+        let clients = Arc::new(Mutex::new(Vec::new()));
+        let shutdown = Arc::new(AtomicBool::new(false));
+
+        let handle = {
+            let clients = clients.clone();
+            let shutdown = shutdown.clone();
+            thread::spawn(move || {
+                for stream in listener.incoming() {
+                    if shutdown.load(Ordering::SeqCst) {
+                        break;
+                    }
+                    // Each request is read on its own thread, so a slow or idle connection doesn't
+                    // hold up the others
+                    if let Ok(stream) = stream {
+                        let clients = clients.clone();
+                        thread::spawn(move || {
+                            if let Some(stream) = handle_connection(stream) {
+                                clients.lock().unwrap().push(stream);
+                            }
+                        });
+                    }
+                }
+            })
+        };
+
+        Ok(LiveReload {
+            addr,
+            clients,
+            shutdown,
+            handle: Some(handle),
+        })
+    }
+
+    /// Tells every connected browser to reload, forgetting any that have gone away or are too slow
+    /// to take the event
+    pub fn notify(&self) {
+        let mut clients = self.clients.lock().unwrap();
+        clients.retain_mut(|client| {
+            client
+                .write_all(b"event: reload\ndata: reload\n\n")
+                .and_then(|_| client.flush())
+                .is_ok()
+        });
+    }
+}
+
+impl Drop for LiveReload {
+    fn drop(&mut self) {
+        self.shutdown.store(true, Ordering::SeqCst);
+        // Wake the accept loop so it can see the shutdown flag
+        let _ = TcpStream::connect_timeout(&self.addr, Duration::from_millis(500));
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+        for client in self.clients.lock().unwrap().drain(..) {
+            let _ = client.shutdown(std::net::Shutdown::Both);
+        }
+    }
+}
+
+/// Reads the request and answers it. Returns the stream if it has subscribed to reload events.
+fn handle_connection(mut stream: TcpStream) -> Option<TcpStream> {
+    stream.set_read_timeout(Some(TIMEOUT)).ok()?;
+    // A stalled browser, e.g. a backgrounded tab, would otherwise block `notify` once its socket
+    // buffer fills
+    stream.set_write_timeout(Some(TIMEOUT)).ok()?;
+    let mut reader = BufReader::new(stream.try_clone().ok()?);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line).ok()?;
+    // Skip the headers, we don't need any of them
+    loop {
+        let mut header = String::new();
+        if reader.read_line(&mut header).ok()? == 0 || header.trim().is_empty() {
+            break;
+        }
+    }
+    let path = request_line.split_whitespace().nth(1).unwrap_or("/");
+
+    if path.ends_with(".js") {
+        let response = format!(
+            "HTTP/1.1 200 OK\r\nContent-Type: application/javascript\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            RELOAD_SCRIPT.len(),
+            RELOAD_SCRIPT
+        );
+        let _ = stream.write_all(response.as_bytes());
+        return None;
+    }
+    stream
+        .write_all(
+            b"HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\nCache-Control: no-cache\r\nAccess-Control-Allow-Origin: *\r\nConnection: keep-alive\r\n\r\n",
+        )
+        .ok()?;
+    stream.flush().ok()?;
+    Some(stream)
+}
@@ -0,0 +1,116 @@
+//! Prints log messages as they are, errors and warnings to stderr and everything else to stdout.
+//! Only messages from this crate are shown. Each level has its own color, which is left out when
+//! the output isn't a terminal, `NO_COLOR` is set or `--color never` is given. A progress bar on
+//! screen is cleared before each message. Warnings are counted for `--fail-on-warning`. With
+//! `--timestamps`, messages in watch mode start with the local time they were logged. With
+//! `--watch-events-json`, stdout is kept for the JSON events, so every message goes to stderr.
+use std::io::{self, Write};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use anstyle::{AnsiColor, Style};
+use chrono::{Local, SecondsFormat};
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use simple_include::quote;
+
+use crate::progress;
+
+struct Logger;
+
+static LOGGER: Logger = Logger;
+static WARNINGS: AtomicUsize = AtomicUsize::new(0);
+static TIMESTAMPS: AtomicBool = AtomicBool::new(false);
+static JSON_STREAM: AtomicBool = AtomicBool::new(false);
+static JSON_ERRORS: AtomicBool = AtomicBool::new(false);
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= log::max_level() && metadata.target().starts_with("simple_include")
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        if record.level() == Level::Warn {
+            WARNINGS.fetch_add(1, Ordering::Relaxed);
+        }
+        let style = style(record.level());
+        let timestamp = if TIMESTAMPS.load(Ordering::Relaxed) {
+            format!("{} ", timestamp())
+        } else {
+            String::new()
+        };
+        if record.level() == Level::Error && JSON_ERRORS.load(Ordering::Relaxed) {
+            eprintln!(
+                "{{\"severity\": \"error\", \"message\": {}}}",
+                quote(&record.args().to_string())
+            );
+            return;
+        }
+        let to_stderr = JSON_STREAM.load(Ordering::Relaxed);
+        progress::suspend(|| match record.level() {
+            Level::Error | Level::Warn => {
+                anstream::eprintln!("{timestamp}{style}{}{style:#}", record.args())
+            }
+            _ if to_stderr => anstream::eprintln!("{timestamp}{style}{}{style:#}", record.args()),
+            _ => anstream::println!("{timestamp}{style}{}{style:#}", record.args()),
+        });
+    }
+
+    fn flush(&self) {
+        let _ = io::stdout().flush();
+    }
+}
+
+/// Errors in red, warnings in yellow, files written in green and changes seen in watch mode in cyan
+fn style(level: Level) -> Style {
+    match level {
+        Level::Error => AnsiColor::Red.on_default().bold(),
+        Level::Warn => AnsiColor::Yellow.on_default(),
+        Level::Info => AnsiColor::Green.on_default(),
+        Level::Debug => AnsiColor::Cyan.on_default(),
+        Level::Trace => Style::new().dimmed(),
+    }
+}
+
+pub fn init(level: LevelFilter) {
+    log::set_logger(&LOGGER).expect("the logger is only set once");
+    log::set_max_level(level);
+}
+
+/// The level for the number of `-v` flags: warnings by default, then info, debug and trace.
+/// `--quiet` shows only errors.
+pub fn level(verbose: u8, quiet: bool) -> LevelFilter {
+    if quiet {
+        return LevelFilter::Error;
+    }
+    match verbose {
+        0 => LevelFilter::Warn,
+        1 => LevelFilter::Info,
+        2 => LevelFilter::Debug,
+        _ => LevelFilter::Trace,
+    }
+}
+
+/// The number of warnings logged so far
+pub fn warnings() -> usize {
+    WARNINGS.load(Ordering::Relaxed)
+}
+
+/// Starts every message with the time it was logged, from now on
+pub fn enable_timestamps() {
+    TIMESTAMPS.store(true, Ordering::Relaxed);
+}
+
+/// Sends every message to stderr from now on, leaving stdout to JSON events, and with
+/// `json_errors` writes errors as JSON objects like the `--format json` diagnostics
+pub fn enable_json_stream(json_errors: bool) {
+    JSON_STREAM.store(true, Ordering::Relaxed);
+    JSON_ERRORS.store(json_errors, Ordering::Relaxed);
+}
+
+/// An RFC 3339 timestamp in the local time zone to the millisecond, e.g.
+/// `2024-05-01T11:30:15.042+02:00`, or ending in `Z` where local time is UTC
+fn timestamp() -> String {
+    Local::now().to_rfc3339_opts(SecondsFormat::Millis, true)
+}
@@ -1,364 +1,2755 @@
-use notify::{Event, RecursiveMode, Result, Watcher};
-use std::collections::{HashMap, HashSet};
+use notify::event::ModifyKind;
+use notify::{Config, Event, EventKind, PollWatcher, RecursiveMode, Result, Watcher};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 use std::env::current_dir;
-use std::fs::{self, canonicalize, File};
-use std::io::{self, BufRead, Write};
-use std::path::{Component, Path, PathBuf};
+use std::fmt;
+use std::fs::{self, canonicalize};
+use std::io::{self, Write};
+use std::num::NonZeroUsize;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::sync::mpsc;
+use std::time::{Duration, Instant, SystemTime};
+
+use anstream::ColorChoice;
+use clap::{ArgAction, ArgMatches, CommandFactory, FromArgMatches, Parser, ValueEnum};
+use log::{debug, error, info, warn, LevelFilter};
+use rayon::prelude::*;
+use simple_include::annotation::Annotation;
+use simple_include::diagnostic::{json_warnings, DiagnosticFormat};
+use simple_include::encoding::Encoding;
+use simple_include::{
+    dump_directives, expand_file, expand_reader, find_includes, list_of_dirs,
+    list_of_dirs_respecting_gitignore, list_of_paths, list_of_paths_respecting_gitignore,
+    normalize_path, process_file, process_file_with_stats, quote, slashed, trace_file, BinaryFiles,
+    Bom, BrokenInclude, Glob, IncludeError, IncludeOptions, Indent, LineEndings, Link, Passes,
+    ProcessedFile, Slashed, Wrappers,
+};
 use walkdir::WalkDir;
 
-use clap::Parser;
+use exit_code::Failure;
+
+mod cache;
+mod config;
+mod confirm;
+mod exit_code;
+mod graph;
+#[cfg(feature = "livereload")]
+mod livereload;
+mod logger;
+mod manifest;
+mod progress;
+mod response_file;
+mod selftest;
+mod signal;
+mod since;
+mod stats;
+
+/// How many messages can wait for the watch loop before the watcher has to wait too
+const WATCH_QUEUE_SIZE: usize = 1024;
+
+/// How many debounce windows a batch of watch events can grow for before it is handled anyway, so
+/// a steady stream of writes can't hold off regenerating forever
+const MAX_BATCH_DEBOUNCES: u32 = 10;
+
+/// What the watch loop receives: file system events, or a request to stop
+enum WatchMessage {
+    Event(Result<Event>),
+    Interrupt,
+}
+
+/// When to color the output
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum ColorWhen {
+    Auto,
+    Always,
+    Never,
+}
+
+/// What to do when a file can't be processed
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum OnError {
+    /// Report the error, process the other files, then exit with an error
+    Continue,
+    /// Stop at the first error, treating missing and binary includes as errors too
+    Abort,
+}
+
+/// A `--rename` of target file names ending in `from` to end in `to` instead
+#[derive(Clone, Debug)]
+struct Rename {
+    from: String,
+    to: String,
+}
+
+impl FromStr for Rename {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.split_once(':') {
+            Some((from, to)) if !from.is_empty() => Ok(Rename {
+                from: from.to_string(),
+                to: to.to_string(),
+            }),
+            _ => Err(format!(
+                "expected <from>:<to>, e.g. .md.in:.md, got {:?}",
+                s
+            )),
+        }
+    }
+}
+
+/// An `--include-for` prefix to use instead of `--include` in files with an extension
+#[derive(Clone, Debug)]
+struct IncludeFor {
+    extension: String,
+    prefix: String,
+}
+
+impl FromStr for IncludeFor {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.split_once('=') {
+            Some((extension, prefix)) if !extension.is_empty() && !prefix.is_empty() => {
+                Ok(IncludeFor {
+                    extension: extension.trim_start_matches('.').to_string(),
+                    prefix: prefix.to_string(),
+                })
+            }
+            _ => Err(format!(
+                "expected <ext>=<prefix>, e.g. rs=//include, got {:?}",
+                s
+            )),
+        }
+    }
+}
+
+/// A `--define` of a variable for `{{NAME}}` placeholders
+#[derive(Clone, Debug)]
+struct Define {
+    name: String,
+    value: String,
+}
+
+impl FromStr for Define {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.split_once('=') {
+            Some((name, value)) if !name.is_empty() => Ok(Define {
+                name: name.to_string(),
+                value: value.to_string(),
+            }),
+            _ => Err(format!(
+                "expected <name>=<value>, e.g. PRODUCT=Acme, got {:?}",
+                s
+            )),
+        }
+    }
+}
 
 /// A simple include preprocessor
 #[derive(Parser, Debug)]
-#[command(version, about, long_about = None)]
+#[command(version, about, long_about = None, after_help = exit_code::HELP)]
 struct Args {
     ///  Watch for changes in the source directory
     #[arg(short, long, default_value_t = false)]
     watch: bool,
 
-    /// Source directory
+    /// Source directory. Can be given more than once to merge several source trees into the target
     #[arg(short, long, default_value = ".")]
-    src: String,
+    src: Vec<String>,
 
     /// Target directory
     #[arg(short, long, default_value = "target")]
     target: String,
 
-    /// Include Prefix
+    /// Include Prefix, which can be repeated to accept several; the first that matches a line is used
     #[arg(short, long, default_value = "--include")]
-    include: String,
+    include: Vec<String>,
 
-    ///Verbose output - prints the input and output file paths
-    #[arg(short, long, default_value_t = false)]
-    verbose: bool,
+    /// Text that ends an include directive and is removed from the include path, e.g. `-->` for `<!--include header.html-->`
+    #[arg(long, value_name = "SUFFIX", allow_hyphen_values = true)]
+    include_suffix: Option<String>,
+
+    /// Text that starts a trailing comment in an include directive, e.g. `--include header.txt # shared legal header`. Only recognised after whitespace, so paths containing it are unaffected. Set to an empty string to disable
+    #[arg(
+        long,
+        value_name = "MARKER",
+        default_value = " #",
+        allow_hyphen_values = true
+    )]
+    comment_marker: String,
+
+    /// Leave lines starting with PREFIX (after any indentation) out of the output, e.g. `//!build` for authoring notes. Include directives are never left out. Can be given more than once
+    #[arg(long, value_name = "PREFIX", allow_hyphen_values = true)]
+    strip_lines: Vec<String>,
+
+    /// Include prefix for files with the extension EXT, e.g. `rs=//include`. Can be given more than once; other files use --include
+    #[arg(long, value_name = "EXT=PREFIX")]
+    include_for: Vec<IncludeFor>,
+
+    /// Verbose output - prints the input and output file paths. Repeat for more detail: -vv also prints
+    /// each change in watch mode, -vvv everything
+    #[arg(short, long, action = ArgAction::Count)]
+    verbose: u8,
+
+    /// Only print errors
+    #[arg(short, long, default_value_t = false, conflicts_with = "verbose")]
+    quiet: bool,
+
+    /// When to color the output. `auto` colors it when printing to a terminal, unless the NO_COLOR
+    /// environment variable is set
+    #[arg(long, value_name = "WHEN", default_value = "auto")]
+    color: ColorWhen,
+
+    /// Encoding to try when a file is not valid UTF-8, before treating it as binary, by its WHATWG label (e.g. windows-1252)
+    #[arg(long, value_name = "CHARSET")]
+    fallback_encoding: Option<Encoding>,
+
+    /// Encoding of the source files, if they aren't UTF-8, by its WHATWG label (e.g. latin1, shift_jis or utf-16le). Generated files are written in it too. Files starting with a UTF-16 byte order mark are read as UTF-16 without it
+    #[arg(long, value_name = "CHARSET", conflicts_with = "fallback_encoding")]
+    encoding: Option<Encoding>,
+
+    /// Maximum depth of nested includes. Deeper include directives are left as they are
+    #[arg(long, default_value_t = 64)]
+    max_depth: usize,
+
+    /// Leave includes of files larger than BYTES as they are, with a warning, instead of inlining them
+    #[arg(long, value_name = "BYTES")]
+    max_include_size: Option<u64>,
+
+    /// Copy source files with a line longer than BYTES as they are, with a warning, instead of reading them a line at a time
+    #[arg(long, value_name = "BYTES")]
+    max_line_length: Option<usize>,
+
+    /// Stop with an error once more than BYTES have been written across all generated files, as a guard against runaway expansions
+    #[arg(long, value_name = "BYTES")]
+    max_total_output: Option<u64>,
+
+    /// Marker for the start of a named region, used by includes like `--include utils.rs#setup`
+    #[arg(long, default_value = "region:")]
+    region_start: String,
+
+    /// Marker for the end of a named region
+    #[arg(long, default_value = "endregion:")]
+    region_end: String,
+
+    /// Prefix for include paths that are resolved against the source directory instead of the
+    /// including file, e.g. `--include @/shared/header.txt`. Set to an empty string to disable
+    #[arg(long, default_value = "@/")]
+    root_prefix: String,
+
+    /// Resolve root-relative includes against the nearest directory at or above the source directory that contains NAME, e.g. `.si-root`, instead of the source directory itself. NAME defaults to `.git`
+    #[arg(long, value_name = "NAME", num_args = 0..=1, default_missing_value = ".git")]
+    root_marker: Option<String>,
+
+    /// Resolve relative include paths against DIR instead of the directory of the file containing the directive. Absolute paths and paths with the root prefix are unaffected
+    #[arg(long, value_name = "DIR")]
+    include_base: Option<String>,
+
+    /// Search DIR for relative includes that aren't found next to the file containing the directive, like a compiler's `-I`. Can be given more than once; the directories are searched in order
+    #[arg(long, value_name = "DIR")]
+    include_path: Vec<String>,
+
+    /// Don't expand includes that point outside the source directory, e.g. `--include ../../etc/passwd`, when processing untrusted input
+    #[arg(long, default_value_t = false)]
+    sandbox: bool,
+
+    /// When an include isn't found as written, look for it ignoring the case of its file and directory names, e.g. for sources written on macOS or Windows
+    #[arg(long, default_value_t = false)]
+    case_insensitive: bool,
+
+    /// Wrap each included file's content in comments naming the include, e.g. `<!-- begin include: header.html -->` and `<!-- end include: header.html -->`, to see where generated content came from
+    #[arg(long, default_value_t = false)]
+    annotate: bool,
+
+    /// Comment syntax for --annotate: auto to pick it from the generated file's extension (falling back to `#`), a line comment prefix such as `//`, or a template such as `/* {} */`
+    #[arg(
+        long,
+        value_name = "STYLE",
+        default_value = "auto",
+        allow_hyphen_values = true
+    )]
+    annotate_style: Annotation,
+
+    /// How to report problems with include directives: text, or json for one JSON object per line on stderr with the severity, file, line, message and include
+    #[arg(long, value_name = "FORMAT", default_value = "text")]
+    format: DiagnosticFormat,
+
+    /// What to do with source files that aren't valid text: copy them to the target as they are, skip them, or report them as errors
+    #[arg(long, value_name = "ACTION", default_value = "copy")]
+    binary: BinaryFiles,
+
+    /// How binary files get to the target: copy them, hard link them to the source, or reflink them as copy-on-write clones where the file system supports it. Either link falls back to copying
+    #[arg(long, value_name = "MODE", default_value = "copy")]
+    link: Link,
+
+    /// What to do with a UTF-8 byte order mark at the start of a file or include: strip it, or keep it as part of the text
+    #[arg(long, value_name = "ACTION", default_value = "strip")]
+    bom: Bom,
+
+    /// Leave binary files out of the target, the same as --binary skip
+    #[arg(long, default_value_t = false, conflicts_with = "binary")]
+    no_copy_binaries: bool,
+
+    /// Exit with an error if any include is missing, contains binary data or would create a cycle
+    #[arg(long, default_value_t = false)]
+    strict: bool,
+
+    /// Process everything, then exit with an error if any warnings were reported, e.g. for a missing or binary include
+    #[arg(long, default_value_t = false, conflicts_with_all = ["watch", "quiet"])]
+    fail_on_warning: bool,
+
+    /// What to do when a file can't be read or written, or has a missing or binary include: continue with the other files and exit with an error at the end, or abort at the first error
+    #[arg(
+        long,
+        value_name = "POLICY",
+        default_value = "continue",
+        conflicts_with = "watch"
+    )]
+    on_error: OnError,
+
+    /// Stop starting new files as soon as one fails, then exit with an error, instead of finishing the run and reporting every failure. Unlike --on-error abort, what counts as a failure doesn't change, e.g. a missing include is only one with --strict
+    #[arg(long, default_value_t = false, conflicts_with = "watch")]
+    fail_fast: bool,
+
+    /// Number of files to process at once [default: number of cores]
+    #[arg(short, long, value_name = "N")]
+    jobs: Option<NonZeroUsize>,
+
+    /// Only process files whose output is older than the file, its includes or the wrapper files
+    #[arg(long, default_value_t = false)]
+    incremental: bool,
+
+    /// Like --incremental, but compare a hash of the content of each file and its includes with the last run instead of modification times, which a fresh checkout resets
+    #[arg(long, default_value_t = false, conflicts_with_all = ["since", "check"])]
+    hash_cache: bool,
+
+    /// Only process files modified after TIME, an RFC 3339 timestamp like 2024-05-01T09:30:15Z or a duration before now like 15m or 2h, and the files that include them according to the --incremental cache
+    #[arg(long, value_name = "TIME", value_parser = since::parse, conflicts_with_all = ["incremental", "watch", "mirror", "list_includes", "check"])]
+    since: Option<SystemTime>,
+
+    /// After processing, delete files in the target directory whose source file no longer exists
+    #[arg(long, default_value_t = false)]
+    clean: bool,
+
+    /// After processing, delete every file in the target directory that this run didn't write,
+    /// such as the outputs of deleted sources, skipped binaries and files no longer matched by
+    /// --only or --rename, then any directories left empty
+    #[arg(long, default_value_t = false, conflicts_with_all = ["clean", "watch"])]
+    mirror: bool,
+
+    /// Write the include graph to FILE after processing, as DOT (or JSON if FILE ends in .json)
+    #[arg(long, value_name = "FILE")]
+    graph: Option<String>,
+
+    /// Write a JSON list of the generated files to FILE after processing, with their sources, includes and sizes
+    #[arg(long, value_name = "FILE")]
+    manifest: Option<String>,
+
+    /// Compare the generated files with the manifest in FILE, as written by --manifest, and exit with an error listing the outputs added, removed or changed since
+    #[arg(long, value_name = "FILE", conflicts_with = "watch")]
+    expect_manifest: Option<String>,
+
+    /// With --expect-manifest, write this run's manifest to its FILE instead of comparing, to accept the changes
+    #[arg(long, default_value_t = false, requires = "expect_manifest")]
+    update_manifest: bool,
+
+    /// Write a JSON performance report to FILE after processing: the files and includes processed, the time spent reading and writing, the largest generated file and how long each phase took
+    #[arg(long, value_name = "FILE")]
+    stats_json: Option<String>,
+
+    /// Skip files ignored by .gitignore and .ignore files in the source directory, by the repository's .git/info/exclude and by the global git excludes file (core.excludesFile)
+    #[arg(long, default_value_t = false)]
+    respect_gitignore: bool,
+
+    /// Only process source files matching PATTERN, a glob relative to the source directory like `guide/**`. Can be given more than once
+    #[arg(long, value_name = "PATTERN")]
+    only: Vec<Glob>,
+
+    /// Create every source directory in the target, including empty ones, which otherwise aren't mirrored
+    #[arg(long, default_value_t = false)]
+    preserve_empty_dirs: bool,
+
+    /// Follow symlinks to files and directories in the source directories, which are skipped otherwise
+    #[arg(long, default_value_t = false)]
+    follow_symlinks: bool,
+
+    /// Only process files at most N levels below the source directory, without walking any deeper, e.g. 1 for just the files directly in it
+    #[arg(long, value_name = "N")]
+    max_walk_depth: Option<usize>,
+
+    /// Only process the files directly in the source directory, not those in its subdirectories. The same as --max-walk-depth 1
+    #[arg(long, default_value_t = false, conflicts_with = "max_walk_depth")]
+    no_recursive: bool,
+
+    /// Check for changes every MS milliseconds instead of relying on file system events. Use this
+    /// when changes are missed, e.g. on NFS, SMB or some Docker bind mounts. Polling finds changes
+    /// reliably but reads every file in the source tree on each interval, so costs more CPU on large trees.
+    #[arg(long, value_name = "MS", requires = "watch")]
+    poll: Option<u64>,
+
+    /// How long to wait for more changes before regenerating files in watch mode, in milliseconds. Changes that keep arriving delay regenerating by at most ten times this
+    #[arg(long, value_name = "MS", default_value_t = 100)]
+    debounce: u64,
+
+    /// In watch mode, handle a burst of more than --batch-threshold files changing within MS milliseconds, e.g. from switching git branches, with one full pass over the source directories instead of file by file
+    #[arg(long, value_name = "MS", default_value_t = 1000, requires = "watch")]
+    batch_window: u64,
+
+    /// In watch mode, how many files changing within --batch-window make a burst that is handled with one full pass
+    #[arg(long, value_name = "N", default_value_t = 100, requires = "watch")]
+    batch_threshold: usize,
+
+    /// Start each message in watch mode with the time it was logged, as an RFC 3339 timestamp in the local time zone
+    #[arg(long, default_value_t = false, requires = "watch")]
+    timestamps: bool,
+
+    /// In watch mode, run COMMAND with the shell after each file is regenerated, with the file's path relative to its source directory in the SI_CHANGED_FILE environment variable
+    #[arg(long, value_name = "COMMAND", requires = "watch")]
+    on_change: Option<String>,
+
+    /// In watch mode, regenerate every file when an included file changes, rather than only the files known to include it
+    #[arg(long, requires = "watch")]
+    rebuild_all_on_include_change: bool,
+
+    /// In watch mode, how many more times to read a file that changed while it was being read, e.g. one an editor was still saving
+    #[arg(long, value_name = "N", default_value_t = 3, requires = "watch")]
+    read_retries: u32,
+
+    /// In watch mode, exit after handling N batches of changes, e.g. for scripted runs and smoke tests
+    #[arg(long, value_name = "N", requires = "watch")]
+    watch_events: Option<u64>,
+
+    /// In watch mode, exit once no changes have arrived for SECONDS seconds
+    #[arg(long, value_name = "SECONDS", requires = "watch")]
+    watch_timeout: Option<u64>,
+
+    /// In watch mode, ignore changes to files matching PATTERN, a glob relative to the source directory like `**/*.swp`, e.g. for editor swap files and lock files that come and go. Can be given more than once
+    #[arg(long, value_name = "PATTERN", requires = "watch")]
+    watch_ignore: Vec<Glob>,
+
+    /// In watch mode, print each change as a JSON object on its own line on stdout, with the event, the source and target files and the files rebuilt. Other messages go to stderr, and errors are JSON too with --format json
+    #[arg(long, default_value_t = false, requires = "watch")]
+    watch_events_json: bool,
+
+    /// Line endings for generated files: lf, crlf, or preserve to keep each line's own ending
+    #[arg(long, value_name = "STYLE", default_value = "preserve")]
+    line_endings: LineEndings,
+
+    /// Indentation for the lines of an indented include: preserve to repeat the whitespace before the directive exactly, or tabs or spaces to convert it
+    #[arg(long, value_name = "STYLE", default_value = "preserve")]
+    indent: Indent,
+
+    /// Columns per tab when converting indentation with --indent
+    #[arg(long, value_name = "N", default_value_t = 4)]
+    tab_width: usize,
+
+    /// Don't end generated files with a newline, even if their source does
+    #[arg(long, default_value_t = false)]
+    no_trailing_newline: bool,
+
+    /// Give each generated file the modification time of its source, or of the newest file it includes
+    #[arg(long, default_value_t = false)]
+    preserve_mtime: bool,
+
+    /// Change the ending of generated file names from FROM to TO, e.g. `.md.in:.md`. Can be given more than once; the first match is used
+    #[arg(long, value_name = "FROM:TO")]
+    rename: Vec<Rename>,
+
+    /// Add PREFIX to the start of each generated file's name, e.g. `gen-` to write `intro.md` as `gen-intro.md`
+    #[arg(long, value_name = "PREFIX", default_value = "")]
+    target_prefix: String,
+
+    /// Add SUFFIX to each generated file's name before its extension, e.g. `.generated` to write `intro.md` as `intro.generated.md`
+    #[arg(long, value_name = "SUFFIX", default_value = "")]
+    target_suffix: String,
+
+    /// Write every file directly into the target, with the directories in its path joined to its name by SEP, e.g. `docs/guide/intro.md` to `docs__guide__intro.md` with `--flatten __`
+    #[arg(long, value_name = "SEP", conflicts_with = "preserve_empty_dirs")]
+    flatten: Option<String>,
+
+    /// Warn when a file includes the same file more than once
+    #[arg(long, default_value_t = false)]
+    warn_duplicate_includes: bool,
+
+    /// Leave out an include if the same file was already included in the file being processed, directly or through another include
+    #[arg(long, default_value_t = false)]
+    dedupe_includes: bool,
+
+    /// Don't expand an include that points at a directory into the files in it; leave it as it is with a warning instead
+    #[arg(long, default_value_t = false)]
+    no_directory_includes: bool,
+
+    /// Replace `{{NAME}}` placeholders in every file and included file with VALUE. Can be given more than once
+    #[arg(long, value_name = "NAME=VALUE")]
+    define: Vec<Define>,
+
+    /// Warn about `{{NAME}}` placeholders whose variable isn't defined with --define
+    #[arg(long, default_value_t = false)]
+    warn_undefined_variables: bool,
+
+    /// The order in which lines have their `{{NAME}}` placeholders substituted and their include directive expanded: substitute,include lets a placeholder produce an include path, include,substitute substitutes only the lines that aren't expanded
+    #[arg(long, value_name = "ORDER", default_value = "substitute,include")]
+    passes: Passes,
+
+    /// Set a flag for conditional includes, e.g. `--feature FEATURE_X` for `--include-if FEATURE_X extra.txt`. Variables set with --define count too
+    #[arg(long, value_name = "FLAG")]
+    feature: Vec<String>,
+
+    /// Marker for the start of a region that is only kept if a flag is defined, e.g. `--if FEATURE_X`
+    #[arg(long, default_value = "--if", allow_hyphen_values = true)]
+    if_marker: String,
+
+    /// Marker for the end of a conditional region
+    #[arg(long, default_value = "--endif", allow_hyphen_values = true)]
+    endif_marker: String,
+
+    /// File whose contents are added to the start of every generated text file
+    #[arg(long, value_name = "FILE")]
+    prepend: Option<String>,
+
+    /// File whose contents are added to the end of every generated text file
+    #[arg(long, value_name = "FILE")]
+    append: Option<String>,
+
+    /// Read a single file from stdin and write the expanded result to stdout
+    #[arg(long, default_value_t = false, conflicts_with_all = ["watch", "stdout"])]
+    stdin: bool,
+
+    /// Directory that includes are resolved against when reading from stdin [default: the current directory]
+    #[arg(long, value_name = "DIR", requires = "stdin")]
+    base: Option<String>,
+
+    /// Expand the single file given with --src and write the result to stdout instead of the target directory
+    #[arg(long, default_value_t = false, conflicts_with = "watch")]
+    stdout: bool,
+
+    /// Expand only FILE, following its includes, and write the result to the file given with --out, like a bundler, instead of processing the source directories
+    #[arg(long, value_name = "FILE", requires = "out", conflicts_with_all = ["watch", "stdin", "stdout"])]
+    entry: Option<String>,
+
+    /// With --entry, the file to write the expanded entry point to
+    #[arg(long, value_name = "FILE", requires = "entry")]
+    out: Option<String>,
+
+    /// Print the include directives found in FILE and how they resolve, without processing anything
+    #[arg(long, value_name = "FILE")]
+    dump_directives: Option<String>,
+
+    /// Print the tree of include directives met while expanding FILE, a path relative to a source directory, with the line of each, its modifiers, where it resolves and whether it was expanded, skipped or missing, then exit without writing anything
+    #[arg(long, value_name = "FILE", conflicts_with_all = ["watch", "list_includes", "check"])]
+    trace_include: Option<String>,
+
+    /// Expand a small sample tree in a temporary directory and report the result and the optional features this build has, to check an install. Exits with an error if the expansion fails
+    #[arg(long, default_value_t = false)]
+    selftest: bool,
+
+    /// Serve a live-reload endpoint on this port that tells connected browsers to reload after each change (watch mode only)
+    #[cfg(feature = "livereload")]
+    #[arg(long, value_name = "PORT", requires = "watch")]
+    livereload: Option<u16>,
+
+    /// How long to wait for a remote (http://) include before leaving the directive as it is, in seconds
+    #[cfg(feature = "remote-includes")]
+    #[arg(long, value_name = "SECONDS", default_value_t = 10)]
+    fetch_timeout: u64,
+
+    /// Run the shell command of includes like `--include !git rev-parse HEAD` and inline its output. Only use this with trusted sources
+    #[cfg(feature = "exec-includes")]
+    #[arg(long, default_value_t = false)]
+    allow_exec: bool,
+
+    /// How long a command include may run before it is stopped and the directive left as it is, in seconds
+    #[cfg(feature = "exec-includes")]
+    #[arg(
+        long,
+        value_name = "SECONDS",
+        default_value_t = 10,
+        requires = "allow_exec"
+    )]
+    exec_timeout: u64,
+
+    /// Print how many files were processed, includes expanded and skipped, and bytes written
+    #[arg(long, default_value_t = false)]
+    summary: bool,
+
+    /// Print the files that would be written or removed without changing anything on disk
+    #[arg(long, default_value_t = false)]
+    dry_run: bool,
+
+    /// Ask before overwriting each target file that already exists, answering yes, no, all or none. Answers are read from stdin; once it runs out, files are overwritten without asking
+    #[arg(long, default_value_t = false, conflicts_with_all = ["watch", "stdin", "stdout", "entry"])]
+    interactive: bool,
+
+    /// Expand includes in the source files themselves, writing each file back over its source instead of into a target directory. Needs a single --src
+    #[arg(long, default_value_t = false, conflicts_with_all = ["target", "watch", "interactive", "stdin", "stdout", "entry", "rename", "target_prefix", "target_suffix", "flatten", "clean", "mirror"])]
+    in_place: bool,
+
+    /// With --in-place, copy each source file to the same path with `.bak` added before overwriting it. Files ending in `.bak` are not processed in place, so backups aren't expanded in turn
+    #[arg(long, default_value_t = false, requires = "in_place")]
+    backup: bool,
+
+    /// Print a unified diff of each output against the file already in the target, without writing anything. Outputs that wouldn't change print nothing
+    #[arg(long, default_value_t = false, conflicts_with_all = ["list_includes", "check", "stdout"])]
+    diff: bool,
+
+    /// Print each file and the files it includes, then exit without writing anything
+    #[arg(long, default_value_t = false, conflicts_with_all = ["watch", "stdin", "stdout"])]
+    list_includes: bool,
+
+    /// With --list-includes, print each included file and the files that include it instead
+    #[arg(long, default_value_t = false, requires = "list_includes")]
+    reverse: bool,
+
+    /// Print the source files that no other file includes, then exit without writing anything
+    #[arg(long, default_value_t = false, conflicts_with_all = ["watch", "stdin", "stdout", "list_includes", "check"])]
+    report_unused: bool,
+
+    /// With --report-unused, only report files matching PATTERN, a glob relative to the source directory like `snippets/**`. Can be given more than once
+    #[arg(long, value_name = "PATTERN", requires = "report_unused")]
+    unused_glob: Vec<Glob>,
+
+    /// Report every missing or binary include, grouped by the file it is in, without writing anything. Exits with an error if any are found
+    #[arg(long, default_value_t = false, conflicts_with_all = ["watch", "stdin", "stdout", "list_includes", "strict", "incremental"])]
+    check: bool,
+
+    /// Read default options from FILE [default: simple-include.toml in the source directory or the current directory]
+    #[arg(long, value_name = "FILE")]
+    config: Option<String>,
+
+    /// Print the settings in effect, from the command line, the config file and the defaults, in the config file format, then exit without processing anything
+    #[arg(long, default_value_t = false)]
+    print_config: bool,
 }
 
-fn main() -> Result<()> {
-    let args = Args::parse();
+fn main() -> ExitCode {
+    match run() {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("Error: {}", exit_code::message(&e));
+            ExitCode::from(exit_code::of(&e))
+        }
+    }
+}
 
-    let src = if args.src == "." {
-        &current_dir().unwrap()
-    } else {
-        Path::new(&args.src)
+fn run() -> Result<()> {
+    let started = Instant::now();
+    // Warnings about the config file are printed before the log level is known
+    logger::init(LevelFilter::Warn);
+    let (mut args, matches) = parse_args().map_err(|e| exit_code::error(Failure::Config, e))?;
+    if args.print_config {
+        config::write_settings(&Args::command(), &matches, &mut io::stdout().lock())?;
+        return Ok(());
+    }
+    // Listing includes, checking them, reporting unused files, diffing and tracing are dry runs,
+    // so nothing is written
+    args.dry_run |= args.list_includes
+        || args.check
+        || args.report_unused
+        || args.diff
+        || args.trace_include.is_some();
+    args.incremental |= args.hash_cache;
+    log::set_max_level(logger::level(args.verbose, args.quiet));
+    if args.watch_events_json {
+        logger::enable_json_stream(args.format == DiagnosticFormat::Json);
+    }
+    match args.color {
+        ColorWhen::Auto => ColorChoice::Auto,
+        ColorWhen::Always => ColorChoice::Always,
+        ColorWhen::Never => ColorChoice::Never,
+    }
+    .write_global();
+    if let Some(jobs) = args.jobs {
+        rayon::ThreadPoolBuilder::new()
+            .num_threads(jobs.get())
+            .build_global()
+            .map_err(io::Error::other)?;
+    }
+
+    if args.selftest {
+        selftest::run()?;
+        return Ok(());
+    }
+
+    let mut options = IncludeOptions {
+        include_strings: args.include.clone(),
+        include_strings_by_extension: args
+            .include_for
+            .iter()
+            .map(|include_for| (include_for.extension.clone(), include_for.prefix.clone()))
+            .collect(),
+        include_suffix: args.include_suffix.clone().unwrap_or_default(),
+        comment_marker: args.comment_marker.clone(),
+        strip_lines: args.strip_lines.clone(),
+        verbose: args.verbose > 0,
+        fallback_encoding: args.fallback_encoding,
+        encoding: args.encoding,
+        warn_duplicate_includes: args.warn_duplicate_includes,
+        dedupe_includes: args.dedupe_includes,
+        directory_includes: !args.no_directory_includes,
+        max_depth: args.max_depth,
+        max_include_size: args.max_include_size,
+        max_line_length: args.max_line_length,
+        read_retries: if args.watch { args.read_retries } else { 0 },
+        strict: args.strict || args.on_error == OnError::Abort,
+        region_start: args.region_start.clone(),
+        region_end: args.region_end.clone(),
+        wrappers: Wrappers::default(),
+        line_endings: args.line_endings,
+        bom: args.bom,
+        passes: args.passes,
+        indent: args.indent,
+        binary_files: if args.no_copy_binaries {
+            BinaryFiles::Skip
+        } else {
+            args.binary
+        },
+        link: args.link,
+        tab_width: args.tab_width,
+        no_trailing_newline: args.no_trailing_newline,
+        root_prefix: args.root_prefix.clone(),
+        sandbox: args.sandbox,
+        case_insensitive: args.case_insensitive,
+        diagnostic_format: args.format,
+        annotate: args.annotate.then(|| args.annotate_style.clone()),
+        include_base: args
+            .include_base
+            .as_ref()
+            .map(|dir| {
+                canonicalize(dir).map_err(|e| {
+                    io::Error::new(
+                        e.kind(),
+                        format!("Include base {:?}: {}", slashed(Path::new(dir)), e),
+                    )
+                })
+            })
+            .transpose()?,
+        include_paths: args
+            .include_path
+            .iter()
+            .map(|dir| {
+                canonicalize(dir).map_err(|e| {
+                    io::Error::new(
+                        e.kind(),
+                        format!("Include path {:?}: {}", slashed(Path::new(dir)), e),
+                    )
+                })
+            })
+            .collect::<io::Result<_>>()?,
+        source_root: None,
+        dry_run: args.dry_run,
+        quiet_dry_run: args.list_includes || args.check || args.report_unused,
+        diff: args.diff,
+        preserve_mtime: args.preserve_mtime,
+        timings: args.stats_json.is_some(),
+        variables: args
+            .define
+            .iter()
+            .map(|define| (define.name.clone(), define.value.clone()))
+            .collect(),
+        warn_undefined_variables: args.warn_undefined_variables,
+        features: args.feature.iter().cloned().collect(),
+        if_marker: args.if_marker.clone(),
+        endif_marker: args.endif_marker.clone(),
+        ..IncludeOptions::default()
     };
-    let target = if args.target == "." {
+    #[cfg(feature = "remote-includes")]
+    {
+        options.fetch_timeout = Duration::from_secs(args.fetch_timeout);
+    }
+    #[cfg(feature = "exec-includes")]
+    {
+        options.allow_exec = args.allow_exec;
+        options.exec_timeout = Duration::from_secs(args.exec_timeout);
+    }
+
+    if let Some(file) = &args.dump_directives {
+        dump_directives(Path::new(file), &options)?;
+        return Ok(());
+    }
+
+    if args.stdin {
+        let base = match &args.base {
+            Some(base) => PathBuf::from(base),
+            None => current_dir()?,
+        };
+        options.wrappers = Wrappers::load(args.prepend.as_deref(), args.append.as_deref())?;
+        if let Some(marker) = &args.root_marker {
+            options.source_root = find_marked_root(&base, marker);
+        }
+        let (content, _includes) =
+            expand_reader(io::stdin().lock(), &base, &options).map_err(include_failure)?;
+        io::stdout().write_all(content.as_bytes())?;
+        return Ok(());
+    }
+
+    if args.stdout {
+        let file = match args.src.as_slice() {
+            [file] if Path::new(file).is_file() => Path::new(file),
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "--stdout needs a single file passed with --src",
+                )
+                .into())
+            }
+        };
+        options.wrappers = Wrappers::load(args.prepend.as_deref(), args.append.as_deref())?;
+        if let Some(marker) = &args.root_marker {
+            let dir = file
+                .parent()
+                .filter(|dir| !dir.as_os_str().is_empty())
+                .unwrap_or_else(|| Path::new("."));
+            options.source_root = find_marked_root(dir, marker);
+        }
+        let (content, _includes) = expand_file(file, &options).map_err(include_failure)?;
+        io::stdout().write_all(&content)?;
+        return Ok(());
+    }
+
+    if let (Some(entry), Some(out)) = (&args.entry, &args.out) {
+        let entry = Path::new(entry);
+        options.wrappers = Wrappers::load(args.prepend.as_deref(), args.append.as_deref())?;
+        if let Some(marker) = &args.root_marker {
+            let dir = entry
+                .parent()
+                .filter(|dir| !dir.as_os_str().is_empty())
+                .unwrap_or_else(|| Path::new("."));
+            options.source_root = find_marked_root(dir, marker);
+        }
+        process_file(entry, Path::new(out), &options).map_err(include_failure)?;
+        return Ok(());
+    }
+
+    let srcs: Vec<PathBuf> = args
+        .src
+        .iter()
+        .map(|src| {
+            if src == "." {
+                current_dir().unwrap()
+            } else {
+                PathBuf::from(src)
+            }
+        })
+        .collect();
+    if args.in_place && srcs.len() != 1 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "--in-place needs a single source directory",
+        )
+        .into());
+    }
+    let target = if args.in_place {
+        &srcs[0]
+    } else if args.target == "." {
         &current_dir().unwrap()
     } else {
         Path::new(&args.target)
     };
 
-    if !target.exists() {
+    if !target.exists() && !args.dry_run {
         let res = fs::create_dir_all(target);
-        if res.is_err() {
-            eprintln!(
+        if let Err(e) = res {
+            if e.kind() == io::ErrorKind::PermissionDenied {
+                let e = IncludeError::from_write(e, target);
+                error!("{}", e);
+                return Err(io::Error::new(io::ErrorKind::PermissionDenied, e).into());
+            }
+            error!(
                 "The target directory {:?} does not exist and could not be created.",
-                target
+                slashed(target)
             );
-            return Err(res.err().unwrap().into());
+            return Err(e.into());
         }
     }
 
-    let abs_src = fs::canonicalize(src)?;
-    let abs_target = fs::canonicalize(target)?;
+    let abs_srcs = srcs
+        .iter()
+        .map(fs::canonicalize)
+        .collect::<io::Result<Vec<_>>>()?;
+    let roots = abs_srcs
+        .iter()
+        .map(|src| {
+            args.root_marker
+                .as_ref()
+                .and_then(|marker| find_marked_root(src, marker))
+                .unwrap_or_else(|| src.clone())
+        })
+        .collect();
+    let dirs = Dirs {
+        srcs: abs_srcs,
+        roots,
+        target: target.to_path_buf(),
+        // A dry run doesn't create the target directory, so it may not exist
+        abs_target: if target.exists() {
+            fs::canonicalize(target)?
+        } else {
+            normalize_path(&current_dir()?.join(target))
+        },
+        renames: args.rename.clone(),
+        flatten: args.flatten.clone(),
+        target_prefix: args.target_prefix.clone(),
+        target_suffix: args.target_suffix.clone(),
+        only: args.only.clone(),
+        in_place: args.in_place,
+        max_depth: if args.no_recursive {
+            Some(1)
+        } else {
+            args.max_walk_depth
+        },
+    };
+    dirs.check_overlap()?;
+    if let Some(file) = &args.trace_include {
+        print_trace(file, &dirs, &options)?;
+        return Ok(());
+    }
 
-    let mut included_files: HashMap<PathBuf, HashSet<PathBuf>> = HashMap::new();
+    let wrapper_paths = [&args.prepend, &args.append]
+        .into_iter()
+        .flatten()
+        .map(canonicalize)
+        .collect::<io::Result<Vec<_>>>()?;
+    options.wrappers = Wrappers::load(args.prepend.as_deref(), args.append.as_deref())?;
 
-    for file in list_of_paths(&abs_src, &abs_target)? {
-        match process_file(
-            &file,
-            &target.join(file.clone().strip_prefix(&abs_src).unwrap()),
-            &args.include,
-            args.verbose,
-        ) {
-            Ok(includes) => {
-                for included in includes.iter() {
-                    let relative_included_file = &included
-                        .strip_prefix(&abs_src)
-                        .unwrap_or(&included)
-                        .to_path_buf();
-                    included_files
-                        .entry(relative_included_file.clone())
-                        .or_insert_with(HashSet::new)
-                        .insert(file.strip_prefix(&abs_src).unwrap_or(&file).to_path_buf());
-                    if args.verbose {
-                        let watch_str = if args.watch {
-                            " and will be regenerated after any changes"
-                        } else {
-                            ""
-                        };
-                        println!(
-                            "The file {:?} includes {:?} {:?}",
-                            file, relative_included_file, watch_str
-                        );
-                    }
+    let cache_path = dirs.abs_target.join(cache::CACHE_FILE_NAME);
+    let known_includes = if args.incremental || args.since.is_some() {
+        match cache::load(&cache_path) {
+            Ok(cached) => {
+                if !cached.is_empty() {
+                    info!("Loaded the include cache from {:?}", slashed(&cache_path));
                 }
+                cache::includes_by_file(&cached)
+            }
+            Err(e) => {
+                warn!("Ignoring the include cache: {}", e);
+                HashMap::new()
             }
-            Err(_e) => {}
+        }
+    } else {
+        HashMap::new()
+    };
+    let hashes_path = dirs.abs_target.join(cache::HASHES_FILE_NAME);
+    let hashes = if args.hash_cache {
+        match cache::Hashes::load(&hashes_path) {
+            Ok(hashes) => Some(hashes),
+            Err(e) => {
+                warn!("Ignoring the hash cache: {}", e);
+                Some(cache::Hashes::default())
+            }
+        }
+    } else {
+        None
+    };
+    // The paths a dry run prints would be mixed up with the bar
+    let show_progress = !args.quiet && (!args.dry_run || options.quiet_dry_run);
+    let process_started = Instant::now();
+    let (mut included_files, summary, manifest_entries) = process_all(
+        &args,
+        &options,
+        &dirs,
+        &known_includes,
+        hashes.as_ref(),
+        show_progress,
+    )?;
+    let process_time = process_started.elapsed();
+    if args.list_includes {
+        print_includes(&included_files, &dirs, args.reverse);
+        return Ok(());
+    }
+    if args.report_unused {
+        print_unused_files(&args, &dirs, &included_files)?;
+        return Ok(());
+    }
+    if args.check {
+        print_broken_includes(&summary.broken, &dirs);
+        if !summary.broken.is_empty() {
+            return Err(exit_code::error(
+                Failure::UnresolvedInclude,
+                format!("{} broken include(s) found", summary.broken.len()),
+            )
+            .into());
+        }
+        return Ok(());
+    }
+    if args.summary {
+        // Stdout is kept for the JSON events
+        if args.watch_events_json {
+            eprintln!("{}", summary);
+        } else {
+            println!("{}", summary);
         }
     }
+    if args.incremental && !args.dry_run {
+        cache::save(&cache_path, &included_files)?;
+        if let Some(hashes) = &hashes {
+            hashes.save(&hashes_path)?;
+        }
+    }
+    if args.preserve_empty_dirs {
+        create_source_dirs(&args, &options, &dirs)?;
+    }
+    if args.clean {
+        clean_target(&args, &dirs)?;
+    }
+    if args.mirror {
+        mirror_target(&args, &dirs, &summary.outputs)?;
+    }
+    if let Some(manifest_path) = &args.manifest {
+        manifest::write_manifest(Path::new(manifest_path), &manifest_entries)?;
+    }
+    if let Some(expected_path) = &args.expect_manifest {
+        check_manifest(
+            Path::new(expected_path),
+            &manifest_entries,
+            args.update_manifest,
+        )?;
+    }
+    if let Some(graph_path) = &args.graph {
+        graph::write_graph(Path::new(graph_path), &included_files, |path| {
+            slashed(dirs.relative_path(path).unwrap_or(path)).to_string()
+        })?;
+    }
+    if let Some(stats_path) = &args.stats_json {
+        let largest_file = summary.largest_file.as_ref().map(|(path, bytes)| {
+            let relative = path.strip_prefix(&dirs.target).unwrap_or(path);
+            (slashed(relative).to_string(), *bytes)
+        });
+        let wall_time = started.elapsed();
+        let setup_time = process_started.duration_since(started);
+        let report = stats::Report {
+            files: summary.files,
+            includes: summary.expanded,
+            skipped: summary.skipped,
+            bytes_written: summary.bytes_written,
+            largest_file,
+            read_time: summary.read_time,
+            write_time: summary.write_time,
+            setup_time,
+            process_time,
+            finish_time: wall_time.saturating_sub(setup_time + process_time),
+            wall_time,
+        };
+        stats::write_stats(Path::new(stats_path), &report)?;
+    }
     if !args.watch {
+        if summary.failed > 0 {
+            let message = format!("{} file(s) could not be processed", summary.failed);
+            return Err(exit_code::error(Failure::Error, message).into());
+        }
+        let warnings = logger::warnings() + json_warnings();
+        if args.fail_on_warning && warnings > 0 {
+            let message = format!("{} warning(s) reported", warnings);
+            return Err(exit_code::error(Failure::Warnings, message).into());
+        }
         return Ok(());
     }
-    if args.verbose {
-        println!("Watching for changes in {:?}, writing to {:?}", src, target);
+    if args.timestamps {
+        logger::enable_timestamps();
+    }
+    info!(
+        "Watching for changes in {:?}, writing to {:?}",
+        slashed_all(&srcs),
+        slashed(target)
+    );
+    // Bounded, so a burst of changes such as a `git checkout` makes the watcher wait for the loop
+    // to catch up instead of queueing up every event in memory
+    let (tx, rx) = mpsc::sync_channel::<WatchMessage>(WATCH_QUEUE_SIZE);
+    let interrupt_tx = tx.clone();
+    signal::on_interrupt(move || {
+        let _ = interrupt_tx.send(WatchMessage::Interrupt);
+    })?;
+    let event_handler = move |res| {
+        let _ = tx.send(WatchMessage::Event(res));
+    };
+    let mut watcher: Box<dyn Watcher> = match args.poll {
+        Some(interval) => Box::new(PollWatcher::new(
+            event_handler,
+            // Modification times are only compared to the second, which would miss quick edits
+            Config::default()
+                .with_poll_interval(Duration::from_millis(interval))
+                .with_compare_contents(true),
+        )?),
+        None => Box::new(notify::recommended_watcher(event_handler)?),
+    };
+
+    for abs_src in &dirs.srcs {
+        watcher.watch(abs_src, RecursiveMode::Recursive)?;
+    }
+    // Wrapper files aren't part of the source tree, so watch their directories (editors often
+    // replace files rather than writing them in place, which would drop a watch on the file itself)
+    for wrapper_dir in wrapper_paths.iter().filter_map(|p| p.parent()) {
+        if dirs.relative_path(wrapper_dir).is_none() {
+            watcher.watch(wrapper_dir, RecursiveMode::NonRecursive)?;
+        }
+    }
+    // Likewise for included files outside the source directories. They are watched as they are
+    // found, including any that are only included after a change.
+    let mut external_include_dirs = HashSet::new();
+    watch_external_includes(
+        watcher.as_mut(),
+        &dirs,
+        &included_files,
+        &mut external_include_dirs,
+    );
+    // Changes made between the first pass and the watches being set up aren't reported, so go
+    // over the source directories once more. With --incremental only files whose output is now
+    // out of date are processed again.
+    if !args.dry_run {
+        debug!("Rescanning {:?} now that it is watched", slashed_all(&srcs));
+        let known_includes = cache::includes_by_file(&included_files);
+        match process_all(&args, &options, &dirs, &known_includes, None, false) {
+            Ok((rescanned_included_files, _, _)) => included_files = rescanned_included_files,
+            Err(e) => error!("Error rescanning files. Error details: {:?}", e),
+        }
+        watch_external_includes(
+            watcher.as_mut(),
+            &dirs,
+            &included_files,
+            &mut external_include_dirs,
+        );
+        if args.clean {
+            clean_target(&args, &dirs)?;
+        }
     }
-    let (tx, rx) = mpsc::channel::<Result<Event>>();
-    let mut watcher = notify::recommended_watcher(tx)?;
 
-    watcher.watch(Path::new(&abs_src), RecursiveMode::Recursive)?;
+    #[cfg(feature = "livereload")]
+    let livereload = match args.livereload {
+        Some(port) => {
+            let server = livereload::LiveReload::start(port)?;
+            info!(
+                "Live reload available on http://localhost:{}/livereload",
+                port
+            );
+            Some(server)
+        }
+        None => None,
+    };
 
-    // Block forever, handling events as they come in
-    for res in rx {
-        match res {
-            Ok(event) => {
-                if event.kind.is_access() {
-                    continue;
+    let debounce = Duration::from_millis(args.debounce);
+    let max_batch_age = debounce * MAX_BATCH_DEBOUNCES;
+    let batch_window = Duration::from_millis(args.batch_window);
+    let idle_timeout = args.watch_timeout.map(Duration::from_secs);
+    let mut handled_events = 0;
+    // The outputs written for earlier batches and their size and modification time once written,
+    // so events from writing them are ignored even if the target check below misses them, while
+    // a later change to one, e.g. with --in-place, is still handled
+    let mut written: HashMap<PathBuf, FileState> = HashMap::new();
+    let cwd = current_dir()?;
+    // Block until interrupted, or --watch-events or --watch-timeout is reached, handling events
+    // as they come in. Editors often write a file several times
+    // when saving it, so events are gathered until none arrive for the debounce window, or the
+    // batch has been growing for MAX_BATCH_DEBOUNCES of them, and each path is then handled once,
+    // based on whether it still exists.
+    // An interrupt lets the changes already seen be handled first, so no output is left half
+    // written
+    let mut interrupted = false;
+    while !interrupted {
+        let first = match idle_timeout {
+            Some(idle_timeout) => match rx.recv_timeout(idle_timeout) {
+                Ok(first) => first,
+                Err(mpsc::RecvTimeoutError::Timeout) => {
+                    info!("No changes for {} seconds, exiting", idle_timeout.as_secs());
+                    break;
                 }
-                if event.kind.is_remove() {
-                    event.paths.iter().for_each(|path| {
-                        let path = normalize_path(path);
-
-                        let target_file = target.join(path.strip_prefix(&abs_src).unwrap());
-                        if target_file.exists()
-                            && target_file.is_file()
-                            && target_file.starts_with(&target)
-                        {
-                            std::fs::remove_file(target_file.clone()).expect(
-                                format!(
-                                    "Failed to remove file {:?} when {:?} was removed",
-                                    target_file, path
-                                )
-                                .as_str(),
-                            );
-                        }
-                        if args.verbose {
-                            println!(
-                                "File removed: {:?}, removing target file: {:?}",
-                                path, target_file
-                            );
-                        }
-                    });
-                    continue;
-                } else {
-                    event.paths.iter().for_each(|path| {
-                    let path = normalize_path(path);
-                    if args.verbose {
-                        println!("File changed: {:?}, src: {:?}, change kind:{:?}", path, abs_src, event.kind);
-                    }
-                    if !path.starts_with(&abs_target) {
-                        let file = path.clone();
-                        let canon_file = canonicalize(file.clone()).unwrap_or(file.clone());
-                        let relative_file = canon_file.strip_prefix(abs_src.clone());
-                        if relative_file.is_err() {
-                            if args.verbose {
-                                eprintln!("{:?}{:?}{:?}", abs_src.clone(), file, relative_file.err());
-                            }
-                        } else {
-                            let relative_file = relative_file.unwrap();
-                            let target_file = target.join(relative_file);
-
-                            match process_file(&file.clone(), &target_file, &args.include, args.verbose) {
-                                Ok(includes) => {
-                                    for included in includes.iter() {
-                                        let relative_include = included.strip_prefix(abs_src.clone());
-                                        if relative_include.is_err() {
-                                            if args.verbose {
-                                                eprintln!("{:?}{:?}{:?}", src, included, relative_include.err());
-                                            }
-                                        } else {
-                                            included_files
-                                                .entry(relative_include.unwrap().to_path_buf())
-                                                .or_insert_with(HashSet::new)
-                                                .insert(relative_file.to_path_buf());
-                                        }
-                                    }
-                                }
-                                Err(e) => {
-                                    if args.verbose {
-                                        println!("Error processing file {:?}: {:?}", file, e);
-                                    }
-                                }
-                            };
+                Err(mpsc::RecvTimeoutError::Disconnected) => break,
+            },
+            None => match rx.recv() {
+                Ok(first) => first,
+                Err(_) => break,
+            },
+        };
+        let mut wrappers_changed = false;
+        // Set when the watcher lost track of events, e.g. because the kernel's queue overflowed
+        let mut rescan = false;
+        // Each path once, in the order first seen, however many events mention it. Events are
+        // folded in as they arrive, so a burst of them takes memory for each path, not each event.
+        let mut paths: Vec<PathBuf> = Vec::new();
+        let mut seen: HashSet<PathBuf> = HashSet::new();
+        // What first happened to each path, for --watch-events-json
+        let mut kinds: HashMap<PathBuf, &str> = HashMap::new();
+        // How many paths changed within the batch window, to spot a burst such as a checkout
+        let batch_started = Instant::now();
+        let mut burst = 0;
+        let mut message = Some(first);
+        while let Some(current) = message.take() {
+            match current {
+                WatchMessage::Interrupt => interrupted = true,
+                WatchMessage::Event(Ok(event)) if event.need_rescan() => rescan = true,
+                WatchMessage::Event(Ok(event)) if !event.kind.is_access() => {
+                    let kind = event_name(&event.kind);
+                    for path in event.paths.iter().map(|path| normalize_path(path)) {
+                        if is_watch_ignored(&args.watch_ignore, &dirs, &path) {
+                            continue;
                         }
-                        let changed_file = &file.strip_prefix(&abs_src).unwrap_or(&file).to_path_buf();
-                        if let Some(included) = included_files.get(changed_file) {
-                            for included_file in included.iter() {
-                                match process_file(
-                                    &src.join(included_file),
-                                    &target.join(included_file),
-                                    &args.include,
-                                    args.verbose,
-                                ) {
-                                    Ok(_includes) => {
-                                        //the file we processed here has not changed so the includes have not changed
-                                    },
-                                    Err(e) => {
-                                        match e.kind() {
-                                            io::ErrorKind::NotFound => {
-                                                if args.verbose {
-                                                    println!("The file {:?} was included in {:?}, but was not found", included_file, file);
-                                                }
-                                            },
-                                            io::ErrorKind::InvalidData => {
-                                                if args.verbose {
-                                                    println!("The file {:?} was included in {:?}, but contains binary data", included_file, file);
-                                                }
-                                            }
-                                            _ => {
-                                                println!("Error processing file {:?}. Error details: {:?}", included_file, e);
-                                            }
-                                        }
-                                    }
-                                }
+                        if wrapper_paths.contains(&path) {
+                            wrappers_changed = true;
+                        } else if !dirs.is_in_target(&path)
+                            && !is_own_write(&written, &path)
+                            && (dirs.relative_path(&path).is_some()
+                                || included_files.contains_key(&include_key(&path)))
+                            && seen.insert(path.clone())
+                        {
+                            // Other files next to an external include don't matter
+                            if batch_started.elapsed() <= batch_window {
+                                burst += 1;
                             }
+                            kinds.insert(path.clone(), kind);
+                            paths.push(path);
                         }
                     }
-                });
+                }
+                WatchMessage::Event(Ok(_)) => {}
+                WatchMessage::Event(Err(e)) => {
+                    error!("Error watching for changes. Error details: {:?}", e)
                 }
             }
-            Err(e) => println!("Error watching for changes. Error details: {:?}", e),
+            let remaining = max_batch_age.saturating_sub(batch_started.elapsed());
+            if !remaining.is_zero() {
+                message = rx.recv_timeout(debounce.min(remaining)).ok();
+            }
         }
-    }
-
-    Ok(())
-}
-pub fn are_paths_equal(path1: &Path, path2: &Path) -> bool {
-    let norm_path1 = normalize_path(path1);
-    let norm_path2 = normalize_path(path2);
-
-    norm_path1 == norm_path2
-}
-
-pub fn list_of_paths(dir: &Path, target: &Path) -> io::Result<Vec<PathBuf>> {
-    let mut paths = Vec::new();
-    for entry in WalkDir::new(dir)
-        .into_iter()
-        .filter_entry(|e| !are_paths_equal(e.path(), target))
-    {
-        let entry = entry?;
-        if entry.file_type().is_file() {
-            let path = entry.into_path();
-            paths.push(path);
+        if !wrappers_changed && !rescan && paths.is_empty() {
+            continue;
         }
-    }
-    Ok(paths)
-}
-
-fn normalize_path(path: &Path) -> PathBuf {
-    let mut components = path.components().peekable();
-    let mut result = PathBuf::new();
 
-    while let Some(component) = components.next() {
-        match component {
-            Component::ParentDir => {
-                result.pop();
+        let mut regenerate_all = rescan;
+        if wrappers_changed {
+            // Every output contains the wrappers, so everything needs regenerating
+            info!("Wrapper file changed, regenerating all files");
+            match Wrappers::load(args.prepend.as_deref(), args.append.as_deref()) {
+                Ok(new_wrappers) => {
+                    options.wrappers = new_wrappers;
+                    regenerate_all = true;
+                }
+                Err(e) => {
+                    warn!("Could not read wrapper files, keeping the previous contents. Error details: {:?}", e);
+                }
             }
-            Component::CurDir => {}
-            _ => {
-                result.push(component.as_os_str());
+        } else if rescan {
+            warn!("Some changes may have been missed, regenerating all files");
+        } else if paths.iter().any(|path| is_dir_config(path)) {
+            // A directory config can change how any file below it is read
+            info!("Directory config changed, regenerating all files");
+            regenerate_all = true;
+        } else if burst > args.batch_threshold {
+            // One pass over everything beats following the includes of each file in turn
+            info!(
+                "{} files changed within {} ms, regenerating all files",
+                burst, args.batch_window
+            );
+            regenerate_all = true;
+        } else if args.rebuild_all_on_include_change {
+            // The include map may be missing files that include it, e.g. from a stale cache
+            if let Some(include) = paths
+                .iter()
+                .find(|path| included_files.contains_key(&include_key(path)))
+            {
+                info!(
+                    "Included file {:?} changed, regenerating all files",
+                    slashed(include)
+                );
+                regenerate_all = true;
+            }
+        }
+        let mut regenerated = Vec::new();
+        if regenerate_all {
+            match process_all(&args, &options, &dirs, &HashMap::new(), None, false) {
+                Ok((new_included_files, summary, _)) => {
+                    included_files = new_included_files;
+                    regenerated = summary.outputs.into_iter().collect();
+                    regenerated.sort();
+                }
+                Err(e) => {
+                    error!("Error regenerating files. Error details: {:?}", e)
+                }
+            }
+        }
+        let mut handled = HashSet::new();
+        let mut outputs = Vec::new();
+        for path in paths {
+            let kind = kinds.get(&path).copied().unwrap_or("modified");
+            // A rename shows up as its old path, which no longer exists, and its new one
+            if !path.exists() {
+                let Some(target_file) = dirs.target_file(&path) else {
+                    continue;
+                };
+                if args.watch_events_json {
+                    let kind = if kind == "renamed" { kind } else { "removed" };
+                    print_watch_event(kind, &path, Some(&target_file), &[]);
+                }
+                if args.dry_run {
+                    if target_file.exists() {
+                        println!("Would remove {:?}", slashed(&target_file));
+                    }
+                    continue;
+                }
+                if target_file.exists() && target_file.is_file() && target_file.starts_with(target)
+                {
+                    if let Err(e) = std::fs::remove_file(&target_file) {
+                        error!(
+                            "Error removing file {:?} when {:?} was removed. Error details: {:?}",
+                            slashed(&target_file),
+                            slashed(&path),
+                            e
+                        );
+                    }
+                } else if target_file.is_dir()
+                    && target_file.starts_with(target)
+                    && target_file != target
+                {
+                    // A removed or renamed directory takes everything generated from it along
+                    if let Err(e) = std::fs::remove_dir_all(&target_file) {
+                        error!(
+                            "Error removing directory {:?} when {:?} was removed. Error details: {:?}",
+                            slashed(&target_file),
+                            slashed(&path),
+                            e
+                        );
+                    }
+                }
+                debug!(
+                    "File removed: {:?}, removing target file: {:?}",
+                    slashed(&path),
+                    slashed(&target_file)
+                );
+                continue;
             }
+            // A file removed and written again, as some editors save, was modified
+            let kind = if kind == "removed" { "modified" } else { kind };
+            if regenerate_all || wrappers_changed {
+                // Already regenerated with everything else
+                if args.watch_events_json {
+                    print_watch_event(
+                        kind,
+                        &path,
+                        dirs.target_file(&path).as_deref(),
+                        &regenerated,
+                    );
+                }
+                continue;
+            }
+            let mut rebuilt = Vec::new();
+            if path.is_dir() {
+                // A new or moved in directory may only get an event for itself, not its files
+                // Files too deep below the source directory are skipped when they are processed
+                match list_of_paths(&path, &dirs.abs_target, args.follow_symlinks, None) {
+                    Ok(files) => {
+                        for file in files {
+                            rebuilt.extend(process_changed_file(
+                                &options,
+                                &dirs,
+                                &mut included_files,
+                                &mut handled,
+                                args.on_change.as_deref(),
+                                &file,
+                            ));
+                        }
+                    }
+                    Err(e) => error!(
+                        "Error listing files in {:?}. Error details: {:?}",
+                        slashed(&path),
+                        e
+                    ),
+                }
+            } else {
+                // Created files are handled like modified ones, as is a new file that existing
+                // files already tried to include
+                rebuilt = process_changed_file(
+                    &options,
+                    &dirs,
+                    &mut included_files,
+                    &mut handled,
+                    args.on_change.as_deref(),
+                    &path,
+                );
+            }
+            if args.watch_events_json {
+                print_watch_event(kind, &path, dirs.target_file(&path).as_deref(), &rebuilt);
+            }
+            outputs.extend(rebuilt);
+        }
+        // Outputs changed since they were written are forgotten, as their events are handled
+        written.retain(|path, state| file_state(path).as_ref() == Some(state));
+        for output in outputs.into_iter().chain(regenerated) {
+            let output = normalize_path(&cwd.join(output));
+            if let Some(state) = file_state(&output) {
+                written.insert(output, state);
+            }
+        }
+        watch_external_includes(
+            watcher.as_mut(),
+            &dirs,
+            &included_files,
+            &mut external_include_dirs,
+        );
+        if args.incremental && !args.dry_run {
+            if let Err(e) = cache::save(&cache_path, &included_files) {
+                error!("Error saving the include cache. Error details: {:?}", e);
+            }
+        }
+        #[cfg(feature = "livereload")]
+        if let Some(livereload) = &livereload {
+            livereload.notify();
+        }
+        handled_events += 1;
+        if args
+            .watch_events
+            .is_some_and(|events| handled_events >= events)
+        {
+            info!("Handled {} batches of changes, exiting", handled_events);
+            break;
+        }
+    }
+    if interrupted {
+        info!("Shutting down");
+    }
+
+    Ok(())
+}
+
+/// Whether a changed file in a source directory matches one of the `--watch-ignore` patterns
+fn is_watch_ignored(patterns: &[Glob], dirs: &Dirs, path: &Path) -> bool {
+    dirs.relative_path(path)
+        .is_some_and(|relative| patterns.iter().any(|pattern| pattern.matches(relative)))
+}
+
+/// The size and modification time of a file
+type FileState = (u64, SystemTime);
+
+fn file_state(path: &Path) -> Option<FileState> {
+    let metadata = fs::metadata(path).ok()?;
+    Some((metadata.len(), metadata.modified().ok()?))
+}
+
+/// Whether a path is an output this run wrote that is still as it was written, so an event for it
+/// came from writing it, however late the watcher reports it
+fn is_own_write(written: &HashMap<PathBuf, FileState>, path: &Path) -> bool {
+    written
+        .get(path)
+        .is_some_and(|state| file_state(path).as_ref() == Some(state))
+}
+
+/// Watches the directory of each included file outside the source directories, so a change to it
+/// regenerates the files that include it. `watched` holds the directories already watched.
+fn watch_external_includes(
+    watcher: &mut dyn Watcher,
+    dirs: &Dirs,
+    included_files: &IncludedFiles,
+    watched: &mut HashSet<PathBuf>,
+) {
+    let external_dirs = included_files
+        .keys()
+        .filter(|included| dirs.relative_path(included).is_none())
+        .filter_map(|included| included.parent());
+    for dir in external_dirs {
+        // A directory that can't be watched is only reported once
+        if !watched.insert(dir.to_path_buf()) {
+            continue;
+        }
+        match watcher.watch(dir, RecursiveMode::NonRecursive) {
+            Ok(()) => debug!("Watching {:?} for changes to included files", slashed(dir)),
+            Err(e) => warn!(
+                "Could not watch {:?} for changes to included files: {}",
+                slashed(dir),
+                e
+            ),
         }
     }
+}
 
-    result
+/// Parses the command line, taking options it doesn't give from the config file if there is one.
+/// Returns the matches the options were parsed from too.
+fn parse_args() -> io::Result<(Args, ArgMatches)> {
+    let all_args = response_file::expand(std::env::args_os())?;
+    let command = Args::command();
+    let matches = command
+        .clone()
+        .try_get_matches_from(&all_args)
+        .unwrap_or_else(|e| exit_on_usage_error(e));
+    let args = Args::from_arg_matches(&matches).unwrap_or_else(|e| exit_on_usage_error(e));
+    let config_file = match &args.config {
+        Some(file) => Some(PathBuf::from(file)),
+        None => config::find(&args.src),
+    };
+    let Some(config_file) = config_file else {
+        return Ok((args, matches));
+    };
+    let config_args = config::to_args(&config_file, &command, &matches)?;
+    let mut all_args = all_args.into_iter();
+    let program = all_args.next();
+    let matches = command
+        .try_get_matches_from(
+            program
+                .into_iter()
+                .chain(config_args.into_iter().map(Into::into))
+                .chain(all_args),
+        )
+        .unwrap_or_else(|e| exit_on_usage_error(e));
+    let args = Args::from_arg_matches(&matches).unwrap_or_else(|e| exit_on_usage_error(e));
+    Ok((args, matches))
 }
 
-pub fn process_file(
+/// Exits for an error parsing the arguments, with the exit code for a config error rather than
+/// clap's usual one, or successfully for `--help` and `--version`
+fn exit_on_usage_error(e: clap::Error) -> ! {
+    if !e.use_stderr() {
+        e.exit();
+    }
+    let _ = e.print();
+    std::process::exit(Failure::Config.code().into());
+}
+
+/// Converts an error from processing a single file to one that ends the run with its exit code
+fn include_failure(e: IncludeError) -> io::Error {
+    exit_code::error(Failure::of_include_error(&e), e)
+}
+
+/// Processes a new or changed source file and then every file that includes it, directly or
+/// through other includes. `handled` holds the files already processed for the current batch of
+/// changes, which are skipped, so a file is written once however many of its includes changed.
+/// `on_change` is run after each file that is regenerated. Returns the target files written.
+fn process_changed_file(
+    options: &IncludeOptions,
+    dirs: &Dirs,
+    included_files: &mut HashMap<PathBuf, HashSet<PathBuf>>,
+    handled: &mut HashSet<PathBuf>,
+    on_change: Option<&str>,
     path: &Path,
-    out_path: &Path,
-    include_string: &str,
-    verbose: bool,
-) -> io::Result<Vec<PathBuf>> {
-    let file = File::open(path);
-    if file.is_err() {
-        let e = file.err().unwrap();
-        if e.kind() == io::ErrorKind::NotFound {
-            if verbose {
-                eprintln!("File not found: {:?}, skipping. If this looks like a temp file, it was probably deleted before we could parse and copy it.", path);
+) -> Vec<PathBuf> {
+    let mut rebuilt = Vec::new();
+    if is_dir_config(path) {
+        return rebuilt;
+    }
+    debug!(
+        "File changed: {:?}, src: {:?}",
+        slashed(path),
+        slashed_all(&dirs.srcs)
+    );
+    let file = path.to_path_buf();
+    let canon_file = canonicalize(file.clone()).unwrap_or(file.clone());
+    if handled.contains(&canon_file) {
+        debug!(
+            "{:?} was already regenerated for these changes",
+            slashed(&file)
+        );
+    } else if let Some(target_file) = dirs.target_file(&canon_file) {
+        handled.insert(canon_file.clone());
+        match process_file(&file, &target_file, &dirs.options_for(&canon_file, options)) {
+            Ok(includes) => {
+                for included in includes {
+                    included_files
+                        .entry(include_key(&included))
+                        .or_default()
+                        .insert(canon_file.clone());
+                }
+                run_on_change(on_change, dirs, &canon_file);
+                rebuilt.push(target_file);
+            }
+            Err(e @ IncludeError::PermissionDenied { .. }) => error!("{}", e),
+            Err(e) => {
+                debug!("Error processing file {:?}: {}", slashed(&file), e);
+            }
+        };
+    } else {
+        debug!(
+            "{:?} is not in any of the source directories {:?}, or not selected by --only",
+            slashed(&file),
+            slashed_all(&dirs.srcs)
+        );
+    }
+    for included_file in dependents(included_files, &canon_file) {
+        if !handled.insert(included_file.clone()) {
+            continue;
+        }
+        let Some(target_file) = dirs.target_file(&included_file) else {
+            continue;
+        };
+        let options = dirs.options_for(&included_file, options);
+        match process_file(&included_file, &target_file, &options) {
+            Ok(includes) => {
+                // The file itself hasn't changed, but what it includes through the changed file
+                // may have
+                for included in includes {
+                    included_files
+                        .entry(include_key(&included))
+                        .or_default()
+                        .insert(included_file.clone());
+                }
+                run_on_change(on_change, dirs, &included_file);
+                rebuilt.push(target_file);
             }
+            Err(e) => match e {
+                IncludeError::SourceNotFound(_) => {
+                    debug!(
+                        "The file {:?} was included in {:?}, but was not found",
+                        slashed(&included_file),
+                        slashed(&file)
+                    );
+                }
+                IncludeError::BinaryData(_) => {
+                    debug!(
+                        "The file {:?} was included in {:?}, but contains binary data",
+                        slashed(&included_file),
+                        slashed(&file)
+                    );
+                }
+                _ => {
+                    error!(
+                        "Error processing file {:?}. Error details: {}",
+                        slashed(&included_file),
+                        e
+                    );
+                }
+            },
+        }
+    }
+    rebuilt
+}
+
+/// What happened to a path in a file system event, as reported by `--watch-events-json`
+fn event_name(kind: &EventKind) -> &'static str {
+    match kind {
+        EventKind::Create(_) => "created",
+        EventKind::Remove(_) => "removed",
+        EventKind::Modify(ModifyKind::Name(_)) => "renamed",
+        _ => "modified",
+    }
+}
+
+/// Prints a change handled in watch mode as a JSON object on its own line, for `--watch-events-json`
+fn print_watch_event(event: &str, source: &Path, target: Option<&Path>, rebuilt: &[PathBuf]) {
+    let rebuilt: Vec<String> = rebuilt
+        .iter()
+        .map(|path| quote(&slashed(path).to_string()))
+        .collect();
+    println!(
+        "{{\"event\": {}, \"source\": {}, \"target\": {}, \"rebuilt\": [{}]}}",
+        quote(event),
+        quote(&slashed(source).to_string()),
+        target.map_or_else(
+            || "null".to_string(),
+            |target| quote(&slashed(target).to_string())
+        ),
+        rebuilt.join(", ")
+    );
+}
+
+/// Runs the `--on-change` command, if there is one, after `file` was regenerated. A command that
+/// can't be run or fails is reported and watching carries on.
+fn run_on_change(command: Option<&str>, dirs: &Dirs, file: &Path) {
+    let Some(command) = command else {
+        return;
+    };
+    let relative = dirs.relative_path(file).unwrap_or(file);
+    let mut shell = if cfg!(windows) {
+        let mut shell = std::process::Command::new("cmd");
+        shell.arg("/C");
+        shell
+    } else {
+        let mut shell = std::process::Command::new("sh");
+        shell.arg("-c");
+        shell
+    };
+    match shell.arg(command).env("SI_CHANGED_FILE", relative).status() {
+        Ok(status) if status.success() => {}
+        Ok(status) => warn!(
+            "The --on-change command for {:?} exited with {}",
+            slashed(relative),
+            status
+        ),
+        Err(e) => warn!("Could not run the --on-change command {:?}: {}", command, e),
+    }
+}
+
+/// The path an included file is tracked under: its canonical path, so every way of referring to
+/// it, e.g. `../shared/x.txt` or through a symlinked directory, shares one set of dependents. A
+/// file that doesn't exist keeps its normalized path.
+fn include_key(path: &Path) -> PathBuf {
+    canonicalize(path).unwrap_or_else(|_| normalize_path(path))
+}
+
+/// Every file that includes `file`, directly or through the files that include it, found by
+/// following the reverse-include map until no new files turn up. Each file is visited once, so
+/// include cycles don't loop forever.
+fn dependents(included_files: &IncludedFiles, file: &Path) -> Vec<PathBuf> {
+    let mut visited: HashSet<&Path> = HashSet::from([file]);
+    let mut pending = vec![file];
+    let mut dependents = Vec::new();
+    while let Some(current) = pending.pop() {
+        for includer in included_files.get(current).into_iter().flatten() {
+            if visited.insert(includer) {
+                dependents.push(includer.clone());
+                pending.push(includer);
+            }
+        }
+    }
+    dependents.sort();
+    dependents
+}
+
+/// Whether a file is a `.si-config`, which configures the files around it and isn't processed
+/// itself
+fn is_dir_config(path: &Path) -> bool {
+    path.file_name()
+        .is_some_and(|name| name == config::DIR_CONFIG_FILE_NAME)
+}
+
+/// Whether a file is a backup made by `--backup`, which isn't processed in place itself
+fn is_backup(args: &Args, path: &Path) -> bool {
+    args.in_place && path.extension().is_some_and(|extension| extension == "bak")
+}
+
+/// Copies a source file to the same path with `.bak` added, before `--in-place` overwrites it
+fn back_up(file: &Path) -> std::result::Result<(), IncludeError> {
+    let mut backup = file.as_os_str().to_owned();
+    backup.push(".bak");
+    let backup = PathBuf::from(backup);
+    debug!("Backing up {:?} to {:?}", slashed(file), slashed(&backup));
+    fs::copy(file, &backup).map_err(|e| IncludeError::from_write(e, &backup))?;
+    Ok(())
+}
+
+/// Shows a list of paths with `/` separators, for messages
+fn slashed_all(paths: &[PathBuf]) -> Vec<Slashed<'_>> {
+    paths.iter().map(|path| slashed(path)).collect()
+}
+
+/// Each included file and the files that include it
+type IncludedFiles = HashMap<PathBuf, HashSet<PathBuf>>;
+
+/// The canonical source directories and the target directory they are written to
+struct Dirs {
+    srcs: Vec<PathBuf>,
+    /// The directory each source directory's root-relative includes are resolved against, in the
+    /// same order as `srcs`. It is the source directory itself unless `--root-marker` found another.
+    roots: Vec<PathBuf>,
+    target: PathBuf,
+    abs_target: PathBuf,
+    renames: Vec<Rename>,
+    /// The separator that replaces directory separators in output paths, with `--flatten`
+    flatten: Option<String>,
+    /// Added to the start of each output's file name, with `--target-prefix`
+    target_prefix: String,
+    /// Added to each output's file name before its extension, with `--target-suffix`
+    target_suffix: String,
+    /// Globs that source files must match one of to be processed, with `--only`
+    only: Vec<Glob>,
+    /// Whether the target is the source directory itself, with `--in-place`
+    in_place: bool,
+    /// How many levels below a source directory files are processed, with `--max-walk-depth` or
+    /// `--no-recursive`
+    max_depth: Option<usize>,
+}
+
+impl Dirs {
+    /// The source directory containing a file. If source directories are nested, the innermost
+    /// one is used.
+    fn source_dir(&self, file: &Path) -> Option<&PathBuf> {
+        self.srcs
+            .iter()
+            .filter(|src| file.starts_with(src))
+            .max_by_key(|src| src.components().count())
+    }
+
+    /// The path of a file relative to the source directory containing it
+    fn relative_path<'a>(&self, file: &'a Path) -> Option<&'a Path> {
+        self.source_dir(file)
+            .map(|src| file.strip_prefix(src).unwrap())
+    }
+
+    /// The options for processing a file, which resolve root-relative includes against its source
+    /// directory, or the root `--root-marker` found for it
+    fn options_for(&self, file: &Path, options: &IncludeOptions) -> IncludeOptions {
+        let source_dir = self.source_dir(file);
+        let root = source_dir.and_then(|src| {
+            let index = self.srcs.iter().position(|s| s == src)?;
+            self.roots.get(index)
+        });
+        let mut options = IncludeOptions {
+            source_root: root.or(source_dir).cloned(),
+            ..options.clone()
+        };
+        for dir_config in self.dir_configs(file) {
+            if let Err(e) = config::apply_dir_config(&dir_config, &mut options) {
+                warn!("{}, ignoring it", e);
+            }
+        }
+        options
+    }
+
+    /// The `.si-config` files that apply to a source file, from the one in its source directory
+    /// down to the one next to it, so the nearest one is applied last
+    fn dir_configs(&self, file: &Path) -> Vec<PathBuf> {
+        let Some(source_dir) = self.source_dir(file) else {
+            return Vec::new();
+        };
+        let mut dir_configs: Vec<PathBuf> = file
+            .ancestors()
+            .skip(1)
+            .take_while(|dir| dir.starts_with(source_dir))
+            .map(|dir| dir.join(config::DIR_CONFIG_FILE_NAME))
+            .filter(|dir_config| dir_config.is_file())
+            .collect();
+        dir_configs.reverse();
+        dir_configs
+    }
+
+    /// Checks that no source directory is the target or inside it, where the output would
+    /// overwrite the sources or be read back as sources. A target inside a source directory is
+    /// fine, as the target and everything in it are left out when listing the source files.
+    fn check_overlap(&self) -> io::Result<()> {
+        if self.in_place {
+            return Ok(());
+        }
+        for src in &self.srcs {
+            if *src == self.abs_target {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!(
+                        "The source and target directories are both {:?}",
+                        slashed(src)
+                    ),
+                ));
+            }
+            if src.starts_with(&self.abs_target) {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!(
+                        "The source directory {:?} is inside the target directory {:?}",
+                        slashed(src),
+                        slashed(&self.abs_target)
+                    ),
+                ));
+            }
+            if self.abs_target.starts_with(src) {
+                info!(
+                    "The target directory {:?} is inside the source directory {:?}, so it is left out of the source files",
+                    slashed(&self.abs_target),
+                    slashed(src)
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether a path is in the target directory, so a change to it is one of this run's own
+    /// writes. Paths are also compared after resolving symlinks in their directory, as the watcher
+    /// may report them by another path than the canonical target.
+    fn is_in_target(&self, path: &Path) -> bool {
+        if path.starts_with(&self.abs_target) {
+            return true;
+        }
+        // The file itself may be gone, e.g. a temporary file already renamed over an output
+        let (Some(parent), Some(name)) = (path.parent(), path.file_name()) else {
+            return false;
+        };
+        canonicalize(parent).is_ok_and(|parent| parent.join(name).starts_with(&self.abs_target))
+    }
+
+    /// The directory left out when listing the source files: the target, unless it is the source
+    /// directory itself with `--in-place`
+    fn excluded_dir(&self) -> &Path {
+        if self.in_place {
+            Path::new("")
         } else {
-            eprintln!("Error opening file for processing: {:?}, {:?}. ", path, e);
-        }
-        return Err(e);
-    }
-
-    let reader = io::BufReader::new(file?);
-
-    let mut new_content = String::new();
-    let parent_dir = path.parent().unwrap_or_else(|| Path::new(""));
-    let mut paths = Vec::new();
-    for line in reader.lines() {
-        match line {
-            Ok(line) => {
-                if line.starts_with(include_string) {
-                    let include_path = line.trim_start_matches(include_string).trim();
-                    let include_path = parent_dir.join(include_path);
-                    let include_content = fs::read_to_string(include_path.clone());
-                    match include_content {
-                        Ok(include_content) => {
-                            new_content.push_str(&include_content);
-                        }
-                        Err(e) => {
-                            if verbose {
-                                match e.kind() {
-                                    io::ErrorKind::InvalidData => {
-                                        println!(
-                                            "Binary data in include file: {:?}, skipping",
-                                            include_path
-                                        );
-                                    }
-                                    io::ErrorKind::NotFound => {
-                                        println!("Include file not found: {:?} (included in file {:?}), skipping", include_path, path);
-                                    }
-                                    _ => {
-                                        println!(
-                                            "Error reading include file: \"{:?}\" (included in file {:?}). Error: \"{:?}\", skipping",
-                                            include_path,path, e
-                                        );
-                                    }
-                                }
-                            }
-                            new_content.push_str(&line);
-                        }
-                    }
+            &self.abs_target
+        }
+    }
 
-                    paths.push(normalize_path(&include_path));
-                } else {
-                    new_content.push_str(&line);
-                };
+    /// Whether a file, given relative to its source directory, is processed. With `--only`, it
+    /// must match one of the patterns, and with a maximum depth it must not be deeper.
+    fn is_selected(&self, relative: &Path) -> bool {
+        if self
+            .max_depth
+            .is_some_and(|max_depth| relative.components().count() > max_depth)
+        {
+            return false;
+        }
+        self.only.is_empty() || self.only.iter().any(|pattern| pattern.matches(relative))
+    }
+
+    /// Where the output for a source file is written, or `None` if it isn't in a source
+    /// directory or isn't selected by `--only`
+    fn target_file(&self, file: &Path) -> Option<PathBuf> {
+        self.relative_path(file)
+            .filter(|relative| self.is_selected(relative))
+            .map(|relative| self.target_path(relative))
+    }
+
+    /// Where a file is written, given its path relative to its source directory, after applying
+    /// the first `--rename` that matches its name and flattening it with `--flatten`
+    fn target_path(&self, relative: &Path) -> PathBuf {
+        let name = relative.file_name().map(|name| name.to_string_lossy());
+        let renamed = name.and_then(|name| {
+            self.renames.iter().find_map(|rename| {
+                name.strip_suffix(rename.from.as_str())
+                    .map(|stem| relative.with_file_name(format!("{}{}", stem, rename.to)))
+            })
+        });
+        let mut relative = renamed.unwrap_or_else(|| relative.to_path_buf());
+        if let Some(name) = relative.file_name().map(|name| name.to_string_lossy()) {
+            let decorated = self.decorate(&name);
+            relative.set_file_name(decorated);
+        }
+        match &self.flatten {
+            Some(separator) => {
+                let components: Vec<_> = relative
+                    .components()
+                    .map(|component| component.as_os_str().to_string_lossy())
+                    .collect();
+                self.target.join(components.join(separator))
             }
-            Err(e) => {
-                if verbose {
-                    match e.kind() {
-                        io::ErrorKind::InvalidData => {
-                            println!("Binary data in file: {:?}, copying to {:?}", path, out_path);
-                            std::fs::copy(path, out_path)?;
-                            return Ok(Vec::new());
-                        }
-                        io::ErrorKind::NotFound => {
-                            println!("File not found: {:?}, skipping", path);
-                        }
-                        _ => {
-                            println!(
-                                "Error reading file: \"{:?}\". Error: \"{:?}\", skipping",
-                                path, e
-                            );
-                        }
-                    }
+            None => self.target.join(relative),
+        }
+    }
+
+    /// A file name with `--target-prefix` added to its start and `--target-suffix` before its
+    /// extension
+    fn decorate(&self, name: &str) -> String {
+        let (stem, extension) = split_extension(name);
+        format!(
+            "{}{}{}{}",
+            self.target_prefix, stem, self.target_suffix, extension
+        )
+    }
+
+    /// The file name `decorate` turned into `name`, or `None` if it doesn't have the prefix and
+    /// suffix
+    fn undecorate(&self, name: &str) -> Option<String> {
+        let (stem, extension) = split_extension(name.strip_prefix(self.target_prefix.as_str())?);
+        let stem = stem.strip_suffix(self.target_suffix.as_str())?;
+        Some(format!("{}{}", stem, extension))
+    }
+
+    /// Whether a file in the target directory, given relative to it, was generated from a file
+    /// that still exists in one of the source directories
+    fn has_source(&self, relative: &Path) -> bool {
+        // A flattened name is split back into its directories. Names that contain the separator
+        // themselves don't match, so their outputs are treated as having no source.
+        let unflattened = match &self.flatten {
+            Some(separator) if !separator.is_empty() => relative
+                .to_string_lossy()
+                .split(separator.as_str())
+                .collect(),
+            _ => relative.to_path_buf(),
+        };
+        let Some(name) = unflattened.file_name().map(|name| name.to_string_lossy()) else {
+            return false;
+        };
+        let Some(undecorated) = self.undecorate(&name) else {
+            return false;
+        };
+        let unflattened = unflattened.with_file_name(undecorated);
+        let mut candidates = vec![unflattened.clone()];
+        if let Some(name) = unflattened.file_name().map(|name| name.to_string_lossy()) {
+            for rename in &self.renames {
+                if let Some(stem) = name.strip_suffix(rename.to.as_str()) {
+                    candidates.push(unflattened.with_file_name(format!("{}{}", stem, rename.from)));
+                }
+            }
+        }
+        self.srcs.iter().any(|src| {
+            candidates.iter().any(|candidate| {
+                let source = src.join(candidate);
+                source.exists() && self.target_path(candidate) == self.target.join(relative)
+            })
+        })
+    }
+}
+
+/// The nearest directory at or above `start` that contains a file or directory called `marker`,
+/// for `--root-marker`. Returns `None` if there isn't one, leaving the usual root.
+fn find_marked_root(start: &Path, marker: &str) -> Option<PathBuf> {
+    let start = canonicalize(start).ok()?;
+    let root = start
+        .ancestors()
+        .find(|dir| dir.join(marker).exists())
+        .map(Path::to_path_buf);
+    match &root {
+        Some(root) => debug!(
+            "Found {:?} in {:?}, using it as the root",
+            marker,
+            slashed(root)
+        ),
+        None => debug!(
+            "No {:?} found above {:?}, using the usual root",
+            marker,
+            slashed(&start)
+        ),
+    }
+    root
+}
+
+/// Splits a file name into its stem and its extension, with the dot. A name with no dot after its
+/// first character, like `README` or `.gitignore`, has no extension.
+fn split_extension(name: &str) -> (&str, &str) {
+    match name.rfind('.') {
+        Some(dot) if dot > 0 => name.split_at(dot),
+        _ => (name, ""),
+    }
+}
+
+/// Processes every file in the source directories in parallel, returning a map of each
+/// included file to the files that include it, the totals for the run and, with `--manifest`, an
+/// entry for each generated file. `known_includes` holds what each file included last time, and
+/// `hashes` the content hashes from the last run with `--hash-cache`, for incremental builds. With
+/// `show_progress`, a progress bar is shown on a terminal.
+fn process_all(
+    args: &Args,
+    options: &IncludeOptions,
+    dirs: &Dirs,
+    known_includes: &HashMap<PathBuf, Vec<PathBuf>>,
+    hashes: Option<&cache::Hashes>,
+    show_progress: bool,
+) -> io::Result<(IncludedFiles, Summary, Vec<manifest::Entry>)> {
+    // Each source file and where it is written. When sources overlap, only the file from the
+    // later source is processed.
+    let mut jobs: Vec<(PathBuf, PathBuf)> = Vec::new();
+    let mut written: HashMap<PathBuf, usize> = HashMap::new();
+    for abs_src in &dirs.srcs {
+        let files = if args.respect_gitignore {
+            list_of_paths_respecting_gitignore(
+                abs_src,
+                dirs.excluded_dir(),
+                args.follow_symlinks,
+                dirs.max_depth,
+            )?
+        } else {
+            list_of_paths(
+                abs_src,
+                dirs.excluded_dir(),
+                args.follow_symlinks,
+                dirs.max_depth,
+            )?
+        };
+        for file in files {
+            let relative = file.strip_prefix(abs_src).unwrap();
+            if !dirs.is_selected(relative) || is_dir_config(&file) || is_backup(args, &file) {
+                continue;
+            }
+            let target_file = dirs.target_path(relative);
+            match written.get(&target_file) {
+                // Overlapping sources are merged on purpose, but flattened names clashing is
+                // likely a mistake
+                Some(&index) if args.flatten.is_some() => {
+                    warn!(
+                        "The files {:?} and {:?} both flatten to {:?}, using {:?}",
+                        slashed(&jobs[index].0),
+                        slashed(&file),
+                        slashed(&target_file),
+                        slashed(&file)
+                    );
+                    jobs[index].0 = file;
+                }
+                Some(&index) => {
+                    info!(
+                        "The files {:?} and {:?} both write to {:?}, using {:?}",
+                        slashed(&jobs[index].0),
+                        slashed(&file),
+                        slashed(&target_file),
+                        slashed(&file)
+                    );
+                    jobs[index].0 = file;
+                }
+                None => {
+                    written.insert(target_file.clone(), jobs.len());
+                    jobs.push((file, target_file));
+                }
+            }
+        }
+    }
+
+    if let Some(since) = args.since {
+        jobs = modified_since(jobs, since, known_includes);
+    }
+    // Asked up front, before the files are processed in parallel. Files the user keeps are still
+    // outputs, so --mirror leaves them alone.
+    let mut kept = Vec::new();
+    if args.interactive && !args.dry_run {
+        let mut confirm = confirm::Confirm::default();
+        jobs.retain(|(_, target_file)| {
+            if !target_file.is_file() || confirm.overwrite(target_file) {
+                return true;
+            }
+            info!("Leaving {:?} as it is", slashed(target_file));
+            kept.push(target_file.clone());
+            false
+        });
+    }
+
+    let src_options: HashMap<&PathBuf, IncludeOptions> = dirs
+        .srcs
+        .iter()
+        .map(|src| (src, dirs.options_for(src, options)))
+        .collect();
+    // The options for each directory with a `.si-config` in it or above it, read once per run
+    let mut dir_options: HashMap<&Path, IncludeOptions> = HashMap::new();
+    for (file, _) in &jobs {
+        let Some(dir) = file.parent() else {
+            continue;
+        };
+        if !dir_options.contains_key(dir) && !dirs.dir_configs(file).is_empty() {
+            dir_options.insert(dir, dirs.options_for(file, options));
+        }
+    }
+    // Set by the first failure with --fail-fast or --on-error abort, so no more files are started
+    let aborted = AtomicBool::new(false);
+    // The bytes written so far, and whether that went over --max-total-output, which also aborts
+    let total_written = AtomicU64::new(0);
+    let over_limit = AtomicBool::new(false);
+    let fails = |result: &std::result::Result<ProcessedFile, IncludeError>| match result {
+        // Deleted since it was listed
+        Ok(_) | Err(IncludeError::SourceNotFound(_)) => false,
+        Err(IncludeError::BinaryData(_)) => options.binary_files == BinaryFiles::Error,
+        Err(_) => true,
+    };
+    let progress_bar = show_progress
+        .then(|| progress::Progress::start(jobs.len()))
+        .flatten();
+    // Collected in the order of `jobs`, so reports don't depend on which thread processed which file
+    let results: Vec<(usize, std::result::Result<ProcessedFile, IncludeError>)> = jobs
+        .par_iter()
+        .enumerate()
+        .filter_map(|(index, (file, target_file))| {
+            if aborted.load(Ordering::Relaxed) {
+                return None;
+            }
+            let options = file
+                .parent()
+                .and_then(|dir| dir_options.get(dir))
+                .unwrap_or_else(|| &src_options[dirs.source_dir(file).unwrap()]);
+            let backed_up = if args.backup && !args.dry_run {
+                back_up(file)
+            } else {
+                Ok(())
+            };
+            let result = if let Err(e) = backed_up {
+                Err(e)
+            } else if args.incremental {
+                process_file_if_changed(
+                    args,
+                    file,
+                    target_file,
+                    options,
+                    known_includes.get(file),
+                    hashes,
+                )
+            } else {
+                process_file_with_stats(file, target_file, options)
+            };
+            if let Some(progress_bar) = &progress_bar {
+                progress_bar.advance(dirs.relative_path(file).unwrap_or(file));
+            }
+            // In strict mode a file that can't be read or written stops the run
+            let denied =
+                options.strict && matches!(result, Err(IncludeError::PermissionDenied { .. }));
+            let stop_on_failure = args.fail_fast || args.on_error == OnError::Abort;
+            if (stop_on_failure && fails(&result)) || denied {
+                aborted.store(true, Ordering::Relaxed);
+            }
+            if let (Some(limit), Ok(processed)) = (args.max_total_output, &result) {
+                let written = total_written.fetch_add(processed.bytes_written, Ordering::Relaxed)
+                    + processed.bytes_written;
+                if written > limit {
+                    over_limit.store(true, Ordering::Relaxed);
+                    aborted.store(true, Ordering::Relaxed);
+                }
+            }
+            Some((index, result))
+        })
+        .collect();
+    drop(progress_bar);
+    let not_processed = jobs.len() - results.len();
+
+    let mut included_files: HashMap<PathBuf, HashSet<PathBuf>> = HashMap::new();
+    // Files whose includes could not be resolved, which only happens in strict mode
+    let mut unresolved = 0;
+    let mut cycles = 0;
+    let mut summary = Summary::default();
+    summary.outputs.extend(kept);
+    let mut manifest_entries = Vec::new();
+    for (index, result) in results {
+        let (file, target_file) = &jobs[index];
+        if let (Some(hashes), Err(_)) = (hashes, &result) {
+            hashes.forget(file);
+        }
+        match result {
+            Ok(processed) => {
+                summary.add(&processed, target_file);
+                if !processed.not_written {
+                    summary.outputs.insert(target_file.clone());
+                }
+                if args.manifest.is_some() || args.expect_manifest.is_some() {
+                    manifest_entries.push(manifest_entry(dirs, file, target_file, &processed));
+                }
+                for included in processed.includes.iter() {
+                    included_files
+                        .entry(include_key(included))
+                        .or_default()
+                        .insert(file.clone());
+                    let watch_str = if args.watch {
+                        " and will be regenerated after any changes"
+                    } else {
+                        ""
+                    };
+                    info!(
+                        "The file {:?} includes {:?} {:?}",
+                        slashed(file),
+                        slashed(dirs.relative_path(included).unwrap_or(included)),
+                        watch_str
+                    );
                 }
-                return Err(e);
             }
+            Err(
+                e @ (IncludeError::IncludeNotFound { .. }
+                | IncludeError::BinaryInclude { .. }
+                | IncludeError::CycleDetected(_)
+                | IncludeError::AtLine { .. }),
+            ) => {
+                // Already reported as a JSON diagnostic
+                if options.diagnostic_format == DiagnosticFormat::Text {
+                    error!("{}", e);
+                }
+                match e.unlocated() {
+                    IncludeError::CycleDetected(_) => cycles += 1,
+                    _ => unresolved += 1,
+                }
+            }
+            Err(IncludeError::Io(e)) => {
+                error!("Error processing file {:?}: {}", slashed(file), e);
+                summary.failed += 1;
+                summary.outputs.insert(target_file.clone());
+            }
+            Err(
+                e @ (IncludeError::UnmatchedConditional { .. }
+                | IncludeError::PermissionDenied { .. }),
+            ) => {
+                error!("{}", e);
+                summary.failed += 1;
+                summary.outputs.insert(target_file.clone());
+            }
+            Err(IncludeError::BinaryData(path)) if options.binary_files == BinaryFiles::Error => {
+                error!("Binary data in file: {:?}", slashed(&path));
+                summary.failed += 1;
+                summary.outputs.insert(target_file.clone());
+            }
+            // Skipped binary files and files deleted since they were listed don't need an output
+            Err(_e) => {}
         }
-        new_content.push('\n');
     }
-    if let Some(parent) = out_path.parent() {
-        fs::create_dir_all(parent)?;
+    if over_limit.into_inner() {
+        return Err(io::Error::other(format!(
+            "Stopped after writing {} bytes, over the --max-total-output limit of {} bytes. {} file(s) were not processed",
+            total_written.into_inner(),
+            args.max_total_output.unwrap_or_default(),
+            not_processed
+        )));
+    }
+    // An abort is reported with the exit code for the error that caused it
+    let failure = match (unresolved, cycles) {
+        (0, 0) => Failure::Error,
+        (0, _) => Failure::Cycle,
+        _ => Failure::UnresolvedInclude,
+    };
+    if aborted.into_inner() {
+        return Err(exit_code::error(
+            failure,
+            format!(
+                "Stopped at the first error, {} file(s) were not processed",
+                not_processed
+            ),
+        ));
+    }
+    if unresolved > 0 {
+        return Err(exit_code::error(
+            failure,
+            format!(
+                "{} file(s) have includes that could not be resolved",
+                unresolved
+            ),
+        ));
     }
-    let mut file = File::create(out_path)?;
-    file.write_all(new_content.as_bytes())?;
-    if verbose && !paths.is_empty() {
-        println!("Input {:?}, Output {:?}", path, out_path);
+    if cycles > 0 {
+        return Err(exit_code::error(
+            failure,
+            format!("{} file(s) have include cycles", cycles),
+        ));
+    }
+    Ok((included_files, summary, manifest_entries))
+}
+
+/// The jobs for files modified after `since`, and for the files that include them according to
+/// `known_includes`, what each file included last time
+fn modified_since(
+    jobs: Vec<(PathBuf, PathBuf)>,
+    since: SystemTime,
+    known_includes: &HashMap<PathBuf, Vec<PathBuf>>,
+) -> Vec<(PathBuf, PathBuf)> {
+    let mut included_files: IncludedFiles = HashMap::new();
+    for (file, includes) in known_includes {
+        for included in includes {
+            included_files
+                .entry(included.clone())
+                .or_default()
+                .insert(file.clone());
+        }
+    }
+    let modified: Vec<&PathBuf> = jobs
+        .iter()
+        .map(|(file, _)| file)
+        .filter(|file| {
+            fs::metadata(file)
+                .and_then(|metadata| metadata.modified())
+                .is_ok_and(|modified| modified > since)
+        })
+        .collect();
+    let mut selected: HashSet<PathBuf> = modified.iter().map(|file| (*file).clone()).collect();
+    for file in modified {
+        selected.extend(dependents(&included_files, file));
+    }
+    let total = jobs.len();
+    let jobs: Vec<_> = jobs
+        .into_iter()
+        .filter(|(file, _)| selected.contains(file))
+        .collect();
+    info!(
+        "{} of {} files were modified since the cutoff or include one that was",
+        jobs.len(),
+        total
+    );
+    jobs
+}
+
+/// Compares the generated files with the manifest at `expected_path`, printing each output added,
+/// removed or changed and failing if there are any. With `update`, the manifest is rewritten
+/// instead.
+fn check_manifest(
+    expected_path: &Path,
+    entries: &[manifest::Entry],
+    update: bool,
+) -> io::Result<()> {
+    if update {
+        manifest::write_manifest(expected_path, entries)?;
+        info!("Updated the manifest {:?}", slashed(expected_path));
+        return Ok(());
+    }
+    let expected = manifest::read_manifest(expected_path)?;
+    let differences = manifest::compare(&expected, entries);
+    if differences.is_empty() {
+        info!("The generated files match {:?}", slashed(expected_path));
+        return Ok(());
+    }
+    for (change, outputs) in [
+        ("added", &differences.added),
+        ("removed", &differences.removed),
+        ("changed", &differences.changed),
+    ] {
+        for output in outputs {
+            error!("Output {}: {}", change, output);
+        }
+    }
+    Err(exit_code::error(
+        Failure::ManifestChanged,
+        format!(
+            "The generated files don't match {:?}: {} added, {} removed, {} changed. Run with --update-manifest to accept them",
+            slashed(expected_path),
+            differences.added.len(),
+            differences.removed.len(),
+            differences.changed.len()
+        ),
+    ))
+}
+
+/// Describes a processed file for the manifest. Its size is read from the output, as files that
+/// were up to date or unchanged weren't written this time.
+fn manifest_entry(
+    dirs: &Dirs,
+    file: &Path,
+    target_file: &Path,
+    processed: &ProcessedFile,
+) -> manifest::Entry {
+    let name = |path: &Path| slashed(dirs.relative_path(path).unwrap_or(path)).to_string();
+    manifest::Entry {
+        source: name(file),
+        output: slashed(
+            target_file
+                .strip_prefix(&dirs.target)
+                .unwrap_or(target_file),
+        )
+        .to_string(),
+        includes: processed.includes.iter().map(|i| name(i)).collect(),
+        bytes: fs::metadata(target_file)
+            .map(|metadata| metadata.len())
+            .unwrap_or(processed.bytes_written),
+    }
+}
+
+/// Totals over every file processed in a run
+#[derive(Debug, Default)]
+struct Summary {
+    files: usize,
+    expanded: usize,
+    skipped: usize,
+    bytes_written: u64,
+    /// Files that could not be read or written
+    failed: usize,
+    /// Includes that were missing or binary, each listed once, in order
+    broken: BTreeSet<BrokenInclude>,
+    /// Where each file processed is written, including files that failed, whose previous output
+    /// is kept
+    outputs: HashSet<PathBuf>,
+    /// The time spent reading and writing, with `--stats-json`
+    read_time: Duration,
+    write_time: Duration,
+    /// The largest output and its size
+    largest_file: Option<(PathBuf, u64)>,
+}
+
+impl Summary {
+    fn add(&mut self, processed: &ProcessedFile, target_file: &Path) {
+        self.files += 1;
+        self.expanded += processed.expanded;
+        self.skipped += processed.skipped;
+        self.bytes_written += processed.bytes_written;
+        self.broken.extend(processed.broken.iter().cloned());
+        self.read_time += processed.read_time;
+        self.write_time += processed.write_time;
+        if self
+            .largest_file
+            .as_ref()
+            .is_none_or(|(_, bytes)| processed.bytes_written > *bytes)
+        {
+            self.largest_file = Some((target_file.to_path_buf(), processed.bytes_written));
+        }
+    }
+}
+
+impl fmt::Display for Summary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Processed {} files: {} includes expanded, {} skipped (missing or binary), {} bytes written",
+            self.files, self.expanded, self.skipped, self.bytes_written
+        )?;
+        if self.failed > 0 {
+            write!(f, ", {} failed", self.failed)?;
+        }
+        Ok(())
+    }
+}
+
+/// Creates every source directory in the target, so empty ones are mirrored too. Directories
+/// skipped when listing files are skipped here as well.
+fn create_source_dirs(args: &Args, options: &IncludeOptions, dirs: &Dirs) -> io::Result<()> {
+    // Only the directories that files within the maximum depth can be in
+    let dir_depth = dirs.max_depth.map(|max_depth| max_depth.saturating_sub(1));
+    for abs_src in &dirs.srcs {
+        let source_dirs = if args.respect_gitignore {
+            list_of_dirs_respecting_gitignore(
+                abs_src,
+                dirs.excluded_dir(),
+                args.follow_symlinks,
+                dir_depth,
+            )?
+        } else {
+            list_of_dirs(
+                abs_src,
+                dirs.excluded_dir(),
+                args.follow_symlinks,
+                dir_depth,
+            )?
+        };
+        for source_dir in source_dirs {
+            let target_dir = dirs.target.join(source_dir.strip_prefix(abs_src).unwrap());
+            if target_dir.is_dir() {
+                continue;
+            }
+            if args.dry_run {
+                if !options.quiet_dry_run {
+                    println!("Would create {:?}", slashed(&target_dir));
+                }
+            } else {
+                fs::create_dir_all(&target_dir)?;
+                debug!("Created directory {:?}", slashed(&target_dir));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Deletes files in the target directory that don't have a source file in any of the source
+/// directories, then any directories left empty
+fn clean_target(args: &Args, dirs: &Dirs) -> io::Result<()> {
+    for entry in WalkDir::new(&dirs.abs_target)
+        .min_depth(1)
+        .contents_first(true)
+    {
+        let entry = entry?;
+        let path = entry.path();
+        // Never touch anything outside the target
+        if !path.starts_with(&dirs.abs_target) {
+            continue;
+        }
+        if [cache::CACHE_FILE_NAME, cache::HASHES_FILE_NAME]
+            .iter()
+            .any(|name| path == dirs.abs_target.join(name))
+        {
+            continue;
+        }
+        let relative = path.strip_prefix(&dirs.abs_target).unwrap();
+        if dirs.has_source(relative) {
+            continue;
+        }
+        if args.dry_run {
+            if !entry.file_type().is_dir() {
+                println!(
+                    "Would remove {:?}, its source file no longer exists",
+                    slashed(path)
+                );
+            }
+        } else if entry.file_type().is_dir() {
+            if fs::read_dir(path)?.next().is_none() {
+                fs::remove_dir(path)?;
+            }
+        } else {
+            fs::remove_file(path)?;
+            info!(
+                "Removed {:?}, its source file no longer exists",
+                slashed(path)
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Deletes every file in the target directory that isn't one of `outputs`, the files written by
+/// this run, then any directories left empty. With `--preserve-empty-dirs`, directories that
+/// mirror a source directory are kept.
+fn mirror_target(args: &Args, dirs: &Dirs, outputs: &HashSet<PathBuf>) -> io::Result<()> {
+    for entry in WalkDir::new(&dirs.abs_target)
+        .min_depth(1)
+        .contents_first(true)
+    {
+        let entry = entry?;
+        let path = entry.path();
+        // Never touch anything outside the target
+        if !path.starts_with(&dirs.abs_target) {
+            continue;
+        }
+        if [cache::CACHE_FILE_NAME, cache::HASHES_FILE_NAME]
+            .iter()
+            .any(|name| path == dirs.abs_target.join(name))
+        {
+            continue;
+        }
+        let relative = path.strip_prefix(&dirs.abs_target).unwrap();
+        if entry.file_type().is_dir() {
+            let keep = args.preserve_empty_dirs && dirs.has_source(relative);
+            if !keep && !args.dry_run && fs::read_dir(path)?.next().is_none() {
+                fs::remove_dir(path)?;
+            }
+            continue;
+        }
+        if outputs.contains(&dirs.target.join(relative)) {
+            continue;
+        }
+        if args.dry_run {
+            println!(
+                "Would remove {:?}, it wasn't written by this run",
+                slashed(path)
+            );
+        } else {
+            fs::remove_file(path)?;
+            info!("Removed {:?}, it wasn't written by this run", slashed(path));
+        }
+    }
+    Ok(())
+}
+
+/// Prints the include directives met while expanding `file`, found in the first source directory
+/// that has it, indented by how deeply they are nested
+fn print_trace(file: &str, dirs: &Dirs, options: &IncludeOptions) -> io::Result<()> {
+    let Some(path) = dirs
+        .srcs
+        .iter()
+        .map(|src| normalize_path(&src.join(file)))
+        .find(|p| p.is_file())
+    else {
+        return Err(exit_code::error(
+            Failure::Config,
+            format!("{:?} is not a file in any source directory", file),
+        ));
+    };
+    if dirs.relative_path(&path).is_none() {
+        return Err(exit_code::error(
+            Failure::Config,
+            format!("{:?} is outside the source directories", file),
+        ));
+    }
+    let trace = trace_file(&path, &dirs.options_for(&path, options)).map_err(include_failure)?;
+    let name = |path: &Path| slashed(dirs.relative_path(path).unwrap_or(path)).to_string();
+    println!("{}", name(&path));
+    for include in trace {
+        let resolved = include
+            .resolved
+            .as_deref()
+            .map(|resolved| format!(" -> {}", name(resolved)))
+            .unwrap_or_default();
+        println!(
+            "{}{}:{}: {}{}{} ({})",
+            "  ".repeat(include.depth + 1),
+            name(&include.file),
+            include.line,
+            include.path,
+            include.modifiers,
+            resolved,
+            include.status
+        );
+    }
+    Ok(())
+}
+
+/// Prints each file and the files it includes, or with `reverse` each included file and the files
+/// that include it, sorted so the output can be compared between runs
+fn print_includes(included_files: &IncludedFiles, dirs: &Dirs, reverse: bool) {
+    let name = |path: &Path| slashed(dirs.relative_path(path).unwrap_or(path)).to_string();
+    let mut listing: BTreeMap<String, BTreeSet<String>> = BTreeMap::new();
+    for (included, includers) in included_files {
+        for includer in includers {
+            let (file, related) = if reverse {
+                (included, includer)
+            } else {
+                (includer, included)
+            };
+            listing.entry(name(file)).or_default().insert(name(related));
+        }
+    }
+    for (file, related) in listing {
+        println!("{}", file);
+        for related in related {
+            println!("    {}", related);
+        }
+    }
+}
+
+/// Prints each source file that nothing includes, limited to those matching `--unused-glob` if
+/// it is given
+fn print_unused_files(args: &Args, dirs: &Dirs, included_files: &IncludedFiles) -> io::Result<()> {
+    let mut unused = BTreeSet::new();
+    for abs_src in &dirs.srcs {
+        let files = if args.respect_gitignore {
+            list_of_paths_respecting_gitignore(
+                abs_src,
+                dirs.excluded_dir(),
+                args.follow_symlinks,
+                dirs.max_depth,
+            )?
+        } else {
+            list_of_paths(
+                abs_src,
+                dirs.excluded_dir(),
+                args.follow_symlinks,
+                dirs.max_depth,
+            )?
+        };
+        for file in files {
+            let relative = file.strip_prefix(abs_src).unwrap();
+            let candidate = args.unused_glob.is_empty()
+                || args
+                    .unused_glob
+                    .iter()
+                    .any(|pattern| pattern.matches(relative));
+            if candidate
+                && !is_dir_config(&file)
+                && !included_files.contains_key(&include_key(&file))
+            {
+                unused.insert(slashed(relative).to_string());
+            }
+        }
+    }
+    for file in unused {
+        println!("{}", file);
+    }
+    Ok(())
+}
+
+/// Prints each file with a missing or binary include, followed by those includes
+fn print_broken_includes(broken: &BTreeSet<BrokenInclude>, dirs: &Dirs) {
+    let name = |path: &Path| slashed(dirs.relative_path(path).unwrap_or(path)).to_string();
+    let mut by_file: BTreeMap<String, Vec<&BrokenInclude>> = BTreeMap::new();
+    for broken_include in broken {
+        by_file
+            .entry(name(&broken_include.included_from))
+            .or_default()
+            .push(broken_include);
+    }
+    for (file, broken) in by_file {
+        println!("{}", file);
+        for broken_include in broken {
+            let problem = if broken_include.binary {
+                "binary"
+            } else {
+                "missing"
+            };
+            println!("    {}: {}", problem, name(&broken_include.include));
+        }
+    }
+}
+
+/// Processes a file unless its output is newer than the file, everything it includes and the
+/// wrapper files, or with `hashes`, unless none of them have changed since the last run. Returns
+/// the files it includes either way. The includes are only looked for if they aren't already
+/// known.
+fn process_file_if_changed(
+    args: &Args,
+    file: &Path,
+    target_file: &Path,
+    options: &IncludeOptions,
+    known_includes: Option<&Vec<PathBuf>>,
+    hashes: Option<&cache::Hashes>,
+) -> std::result::Result<ProcessedFile, IncludeError> {
+    let includes = match known_includes {
+        Some(includes) => Ok(includes.clone()),
+        None => find_includes(file, options),
+    };
+    if let Ok(includes) = includes {
+        let output_modified = fs::metadata(target_file).and_then(|m| m.modified());
+        let wrappers = [&args.prepend, &args.append].into_iter().flatten();
+        let inputs: Vec<&Path> = std::iter::once(file)
+            .chain(includes.iter().map(PathBuf::as_path))
+            .chain(wrappers.map(Path::new))
+            .collect();
+        let up_to_date = match hashes {
+            // Every input is hashed, even without an output or after the first change, so the
+            // next run can compare against all of them
+            Some(hashes) => {
+                let changed = inputs
+                    .iter()
+                    .filter(|input| !hashes.unchanged(input))
+                    .count();
+                changed == 0 && output_modified.is_ok()
+            }
+            None => output_modified.is_ok_and(|output_modified| {
+                inputs.iter().all(|input| {
+                    // An include that can't be read might have appeared since, so rebuild
+                    fs::metadata(input)
+                        .and_then(|m| m.modified())
+                        .is_ok_and(|modified| modified <= output_modified)
+                })
+            }),
+        };
+        if up_to_date {
+            info!("{:?} is up to date, skipping", slashed(target_file));
+            return Ok(ProcessedFile {
+                includes,
+                ..ProcessedFile::default()
+            });
+        }
     }
-    Ok(paths)
+    process_file_with_stats(file, target_file, options)
 }
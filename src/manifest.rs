@@ -0,0 +1,255 @@
+//! Export of a manifest listing every generated file, as JSON, and comparison with a reference
+//! manifest for `--expect-manifest`.
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::iter::Peekable;
+use std::path::Path;
+use std::str::Chars;
+
+use simple_include::{quote, slashed};
+
+/// A generated file, with paths relative to the source and target directories
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Entry {
+    pub source: String,
+    pub output: String,
+    pub includes: Vec<String>,
+    pub bytes: u64,
+}
+
+/// Writes the entries to `out_path` as a JSON array of objects, sorted by output path
+pub fn write_manifest(out_path: &Path, entries: &[Entry]) -> io::Result<()> {
+    let mut entries = entries.to_vec();
+    entries.sort_by(|a, b| a.output.cmp(&b.output));
+    fs::write(out_path, to_json(&entries))
+}
+
+/// Reads a manifest written by `write_manifest`, or by hand in the same shape
+pub fn read_manifest(path: &Path) -> io::Result<Vec<Entry>> {
+    let invalid = |message: String| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Invalid manifest {:?}: {}", slashed(path), message),
+        )
+    };
+    let content = fs::read_to_string(path)?;
+    let mut chars = content.chars().peekable();
+    let value = parse_value(&mut chars).map_err(invalid)?;
+    skip_whitespace(&mut chars);
+    if let Some(c) = chars.next() {
+        return Err(invalid(format!("unexpected {:?} after the manifest", c)));
+    }
+    let Value::Array(values) = value else {
+        return Err(invalid("expected an array of entries".to_string()));
+    };
+    values
+        .into_iter()
+        .map(|value| entry(value).map_err(invalid))
+        .collect()
+}
+
+/// How the outputs of a run differ from a reference manifest, by output path
+#[derive(Debug, Default)]
+pub struct Differences {
+    pub added: Vec<String>,
+    pub removed: Vec<String>,
+    /// Outputs with a different source, includes or size
+    pub changed: Vec<String>,
+}
+
+impl Differences {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.changed.is_empty()
+    }
+}
+
+/// Compares the entries of a run with those of a reference manifest
+pub fn compare(expected: &[Entry], actual: &[Entry]) -> Differences {
+    let by_output = |entries: &[Entry]| -> BTreeMap<String, Entry> {
+        entries
+            .iter()
+            .map(|entry| (entry.output.clone(), entry.clone()))
+            .collect()
+    };
+    let (expected, actual) = (by_output(expected), by_output(actual));
+    let mut differences = Differences::default();
+    for (output, entry) in &actual {
+        match expected.get(output) {
+            None => differences.added.push(output.clone()),
+            Some(expected_entry) if expected_entry != entry => {
+                differences.changed.push(output.clone())
+            }
+            Some(_) => {}
+        }
+    }
+    differences.removed = expected
+        .keys()
+        .filter(|output| !actual.contains_key(*output))
+        .cloned()
+        .collect();
+    differences
+}
+
+fn to_json(entries: &[Entry]) -> String {
+    let entries: Vec<String> = entries
+        .iter()
+        .map(|entry| {
+            let includes: Vec<String> = entry.includes.iter().map(|i| quote(i)).collect();
+            format!(
+                "  {{\"source\": {}, \"output\": {}, \"includes\": [{}], \"bytes\": {}}}",
+                quote(&entry.source),
+                quote(&entry.output),
+                includes.join(", "),
+                entry.bytes
+            )
+        })
+        .collect();
+    if entries.is_empty() {
+        return "[]\n".to_string();
+    }
+    format!("[\n{}\n]\n", entries.join(",\n"))
+}
+
+/// A JSON value, as far as manifests need them
+enum Value {
+    String(String),
+    Number(u64),
+    Array(Vec<Value>),
+    Object(Vec<(String, Value)>),
+}
+
+fn entry(value: Value) -> Result<Entry, String> {
+    let Value::Object(fields) = value else {
+        return Err("expected an object for each entry".to_string());
+    };
+    let mut entry = Entry {
+        source: String::new(),
+        output: String::new(),
+        includes: Vec::new(),
+        bytes: 0,
+    };
+    for (key, value) in fields {
+        match (key.as_str(), value) {
+            ("source", Value::String(source)) => entry.source = source,
+            ("output", Value::String(output)) => entry.output = output,
+            ("bytes", Value::Number(bytes)) => entry.bytes = bytes,
+            ("includes", Value::Array(includes)) => {
+                entry.includes = includes
+                    .into_iter()
+                    .map(|include| match include {
+                        Value::String(include) => Ok(include),
+                        _ => Err("expected includes to be strings".to_string()),
+                    })
+                    .collect::<Result<_, _>>()?;
+            }
+            (key, _) => return Err(format!("unexpected {:?} in an entry", key)),
+        }
+    }
+    if entry.output.is_empty() {
+        return Err("an entry has no output".to_string());
+    }
+    Ok(entry)
+}
+
+fn skip_whitespace(chars: &mut Peekable<Chars>) {
+    while chars.next_if(|c| c.is_whitespace()).is_some() {}
+}
+
+fn parse_value(chars: &mut Peekable<Chars>) -> Result<Value, String> {
+    skip_whitespace(chars);
+    match chars.peek() {
+        Some('"') => parse_string(chars).map(Value::String),
+        Some('[') => {
+            chars.next();
+            let mut values = Vec::new();
+            parse_list(chars, ']', |chars| {
+                values.push(parse_value(chars)?);
+                Ok(())
+            })?;
+            Ok(Value::Array(values))
+        }
+        Some('{') => {
+            chars.next();
+            let mut fields = Vec::new();
+            parse_list(chars, '}', |chars| {
+                skip_whitespace(chars);
+                if chars.peek() != Some(&'"') {
+                    return Err("expected a key".to_string());
+                }
+                let key = parse_string(chars)?;
+                skip_whitespace(chars);
+                if chars.next() != Some(':') {
+                    return Err(format!("expected ':' after {:?}", key));
+                }
+                fields.push((key, parse_value(chars)?));
+                Ok(())
+            })?;
+            Ok(Value::Object(fields))
+        }
+        Some(c) if c.is_ascii_digit() => {
+            let mut digits = String::new();
+            while let Some(c) = chars.next_if(char::is_ascii_digit) {
+                digits.push(c);
+            }
+            digits
+                .parse()
+                .map(Value::Number)
+                .map_err(|_| format!("invalid number {}", digits))
+        }
+        Some(c) => Err(format!("unexpected {:?}", c)),
+        None => Err("expected a value".to_string()),
+    }
+}
+
+/// Parses the comma-separated items of an array or object, after its opening bracket, up to and
+/// including `close`
+fn parse_list(
+    chars: &mut Peekable<Chars>,
+    close: char,
+    mut item: impl FnMut(&mut Peekable<Chars>) -> Result<(), String>,
+) -> Result<(), String> {
+    skip_whitespace(chars);
+    if chars.next_if_eq(&close).is_some() {
+        return Ok(());
+    }
+    loop {
+        item(chars)?;
+        skip_whitespace(chars);
+        match chars.next() {
+            Some(',') => {}
+            Some(c) if c == close => return Ok(()),
+            _ => return Err(format!("expected ',' or {:?}", close)),
+        }
+    }
+}
+
+/// Parses a string with the escapes `quote` writes
+fn parse_string(chars: &mut Peekable<Chars>) -> Result<String, String> {
+    chars.next();
+    let mut s = String::new();
+    loop {
+        match chars.next() {
+            None => return Err("unterminated string".to_string()),
+            Some('"') => return Ok(s),
+            Some('\\') => match chars.next() {
+                Some('n') => s.push('\n'),
+                Some('t') => s.push('\t'),
+                Some('r') => s.push('\r'),
+                Some('"') => s.push('"'),
+                Some('\\') => s.push('\\'),
+                Some('/') => s.push('/'),
+                Some('u') => {
+                    let hex: String = chars.take(4).collect();
+                    let c = u32::from_str_radix(&hex, 16)
+                        .ok()
+                        .and_then(char::from_u32)
+                        .ok_or_else(|| format!("invalid escape \\u{}", hex))?;
+                    s.push(c);
+                }
+                c => return Err(format!("invalid escape {:?}", c)),
+            },
+            Some(c) => s.push(c),
+        }
+    }
+}
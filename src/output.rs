@@ -0,0 +1,105 @@
+//! Writing expanded text as it is produced, instead of building the whole output in memory first.
+//! Line endings, the output encoding and `no_trailing_newline` are applied on the way, with the
+//! same result as applying them to the whole output at once.
+use std::io::{self, Write};
+use std::time::Duration;
+
+use crate::encoding::Encoding;
+use crate::expand::split_line_ending;
+use crate::{timed, IncludeOptions, LineEndings};
+
+pub(crate) struct Output<W: Write> {
+    inner: W,
+    line_endings: LineEndings,
+    encoding: Option<Encoding>,
+    no_trailing_newline: bool,
+    /// The end of the text so far, held back until more text follows: a line ending that is
+    /// dropped if it ends the output, or a `\r` that may be the start of a `\r\n`
+    pending: String,
+    /// How many bytes have been written to `inner`
+    bytes_written: u64,
+    timings: bool,
+    /// The time spent writing to `inner` so far, with `timings`
+    pub(crate) write_time: Duration,
+}
+
+impl<W: Write> Output<W> {
+    /// Writes to `inner` with the line endings and trailing newline setting in `options`,
+    /// encoding the text with `encoding` if it isn't UTF-8
+    pub(crate) fn new(inner: W, options: &IncludeOptions, encoding: Option<Encoding>) -> Self {
+        Output {
+            inner,
+            line_endings: options.line_endings,
+            encoding,
+            no_trailing_newline: options.no_trailing_newline,
+            pending: String::new(),
+            bytes_written: 0,
+            timings: options.timings,
+            write_time: Duration::ZERO,
+        }
+    }
+
+    pub(crate) fn write_str(&mut self, text: &str) -> io::Result<()> {
+        if text.is_empty() {
+            return Ok(());
+        }
+        let mut text = std::mem::take(&mut self.pending) + text;
+        let (_, ending) = split_line_ending(&text);
+        let held = if self.no_trailing_newline && !ending.is_empty() {
+            ending.len()
+        } else if (self.no_trailing_newline || self.line_endings != LineEndings::Preserve)
+            && text.ends_with('\r')
+        {
+            1
+        } else {
+            0
+        };
+        self.pending = text.split_off(text.len() - held);
+        self.write_converted(text)
+    }
+
+    /// Writes text like `write_str`, or bytes that aren't UTF-8 as they are, with no line ending
+    /// conversion or encoding
+    pub(crate) fn write_bytes(&mut self, bytes: &[u8]) -> io::Result<()> {
+        if let Ok(text) = std::str::from_utf8(bytes) {
+            return self.write_str(text);
+        }
+        // Whatever was held back isn't the end of the output after all
+        let pending = std::mem::take(&mut self.pending);
+        self.write_converted(pending)?;
+        timed(self.timings, &mut self.write_time, || {
+            self.inner.write_all(bytes)
+        })?;
+        self.bytes_written += bytes.len() as u64;
+        Ok(())
+    }
+
+    /// Writes what was held back, except a final line ending if it is to be dropped, and returns
+    /// the writer and the number of bytes written
+    pub(crate) fn finish(mut self) -> io::Result<(W, u64)> {
+        let pending = std::mem::take(&mut self.pending);
+        let (_, ending) = split_line_ending(&pending);
+        if !self.no_trailing_newline || ending.is_empty() {
+            self.write_converted(pending)?;
+        }
+        self.inner.flush()?;
+        Ok((self.inner, self.bytes_written))
+    }
+
+    fn write_converted(&mut self, text: String) -> io::Result<()> {
+        let text = self.line_endings.apply(text);
+        let bytes = match self.encoding {
+            // A UTF-16 output starts with a byte order mark, as Windows tools expect
+            Some(encoding) if self.bytes_written == 0 && !text.is_empty() => {
+                [encoding.bom(), &encoding.encode(&text)].concat()
+            }
+            Some(encoding) => encoding.encode(&text),
+            None => text.into_bytes(),
+        };
+        timed(self.timings, &mut self.write_time, || {
+            self.inner.write_all(&bytes)
+        })?;
+        self.bytes_written += bytes.len() as u64;
+        Ok(())
+    }
+}
@@ -0,0 +1,67 @@
+//! A progress bar on stderr for the first pass over the source directories, which can take a
+//! while on large trees. It is only drawn when stderr is a terminal, so it never ends up in
+//! redirected output.
+use std::path::Path;
+use std::sync::Mutex;
+
+use indicatif::{ProgressBar, ProgressDrawTarget, ProgressStyle};
+use simple_include::slashed;
+
+/// The bar on screen, if any, so log messages can be printed above it
+static ACTIVE: Mutex<Option<ProgressBar>> = Mutex::new(None);
+
+const MAX_NAME_LEN: usize = 40;
+
+pub struct Progress {
+    bar: ProgressBar,
+}
+
+impl Progress {
+    /// Starts a bar for `total` files, unless stderr isn't a terminal or there is nothing to do
+    pub fn start(total: usize) -> Option<Progress> {
+        if total == 0 {
+            return None;
+        }
+        let bar = ProgressBar::with_draw_target(Some(total as u64), ProgressDrawTarget::stderr());
+        if bar.is_hidden() {
+            return None;
+        }
+        bar.set_style(
+            ProgressStyle::with_template("[{bar:30}] {pos}/{len} {msg}")
+                .unwrap()
+                .progress_chars("#-"),
+        );
+        *ACTIVE.lock().unwrap() = Some(bar.clone());
+        Some(Progress { bar })
+    }
+
+    /// Counts `file` as done and shows its name next to the bar
+    pub fn advance(&self, file: &Path) {
+        let name = slashed(file).to_string();
+        let name_len = name.chars().count();
+        let name = if name_len > MAX_NAME_LEN {
+            let end: String = name.chars().skip(name_len - MAX_NAME_LEN + 3).collect();
+            format!("...{}", end)
+        } else {
+            name
+        };
+        self.bar.set_message(name);
+        self.bar.inc(1);
+    }
+}
+
+impl Drop for Progress {
+    fn drop(&mut self) {
+        ACTIVE.lock().unwrap().take();
+        self.bar.finish_and_clear();
+    }
+}
+
+/// Runs `print` with the bar cleared from the screen, if one is shown, and draws it again after
+pub fn suspend(print: impl FnOnce()) {
+    let bar = ACTIVE.lock().unwrap().clone();
+    match bar {
+        Some(bar) => bar.suspend(print),
+        None => print(),
+    }
+}
@@ -0,0 +1,35 @@
+//! Fetching of remote includes over HTTP and HTTPS.
+//!
+//! HTTPS certificates are checked against the platform's trust store, so hosts with an internal
+//! CA work once it is installed there. Redirects are followed, and the body must be UTF-8 text.
+use std::io;
+use std::time::Duration;
+
+use ureq::tls::{RootCerts, TlsConfig};
+use ureq::Agent;
+
+/// Whether an include path is a URL rather than a file
+pub(crate) fn is_url(include_path: &str) -> bool {
+    include_path.starts_with("http://") || include_path.starts_with("https://")
+}
+
+/// Fetches the body of a URL, failing if it can't be fetched within `timeout` or the server
+/// responds with an error status
+pub(crate) fn fetch(url: &str, timeout: Duration) -> io::Result<String> {
+    let agent: Agent = Agent::config_builder()
+        .timeout_global(Some(timeout))
+        .user_agent("simple-include")
+        .tls_config(
+            TlsConfig::builder()
+                .root_certs(RootCerts::PlatformVerifier)
+                .build(),
+        )
+        .build()
+        .into();
+    let mut response = agent.get(url).call().map_err(ureq::Error::into_io)?;
+    let body = response
+        .body_mut()
+        .read_to_vec()
+        .map_err(ureq::Error::into_io)?;
+    String::from_utf8(body).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
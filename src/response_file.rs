@@ -0,0 +1,91 @@
+//! Response files: an argument `@file` is replaced by the arguments in the file, for invocations
+//! too long for a comfortable command line. Arguments are separated by whitespace and can be
+//! quoted with `"` or `'` to include spaces. Response files can name other response files.
+use std::ffi::OsString;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use simple_include::slashed;
+
+/// Expands every `@file` argument after the program name. An argument is only treated as a
+/// response file if the file exists, so values like `--root-prefix @/` are passed through.
+pub fn expand(args: impl IntoIterator<Item = OsString>) -> io::Result<Vec<OsString>> {
+    let mut args = args.into_iter();
+    let mut expanded: Vec<OsString> = args.next().into_iter().collect();
+    let mut stack = Vec::new();
+    for arg in args {
+        expand_arg(arg, &mut stack, &mut expanded)?;
+    }
+    Ok(expanded)
+}
+
+/// Adds `arg` to `expanded`, or the arguments in the file it names. `stack` holds the response
+/// files being read, to catch files that name themselves.
+fn expand_arg(
+    arg: OsString,
+    stack: &mut Vec<PathBuf>,
+    expanded: &mut Vec<OsString>,
+) -> io::Result<()> {
+    let Some(path) = response_file_path(&arg) else {
+        expanded.push(arg);
+        return Ok(());
+    };
+    let canonical = fs::canonicalize(path)?;
+    if stack.contains(&canonical) {
+        let chain: Vec<String> = stack
+            .iter()
+            .chain(std::iter::once(&canonical))
+            .map(|path| slashed(path).to_string())
+            .collect();
+        return Err(io::Error::other(format!(
+            "Response file loop: {}",
+            chain.join(" -> ")
+        )));
+    }
+    let content = fs::read_to_string(path).map_err(|e| {
+        io::Error::new(
+            e.kind(),
+            format!("Response file {:?}: {}", slashed(path), e),
+        )
+    })?;
+    let words = split(&content)
+        .map_err(|e| io::Error::other(format!("Response file {:?}: {}", slashed(path), e)))?;
+    stack.push(canonical);
+    for word in words {
+        expand_arg(word.into(), stack, expanded)?;
+    }
+    stack.pop();
+    Ok(())
+}
+
+/// The file named by an `@file` argument, if it is one and the file exists
+fn response_file_path(arg: &OsString) -> Option<&Path> {
+    let path = Path::new(arg.to_str()?.strip_prefix('@')?);
+    path.is_file().then_some(path)
+}
+
+/// Splits the content of a response file into arguments
+fn split(content: &str) -> Result<Vec<String>, String> {
+    let mut words = Vec::new();
+    let mut word: Option<String> = None;
+    let mut chars = content.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' | '\'' => {
+                let word = word.get_or_insert_with(String::new);
+                loop {
+                    match chars.next() {
+                        Some(q) if q == c => break,
+                        Some(other) => word.push(other),
+                        None => return Err(format!("unterminated {} quote", c)),
+                    }
+                }
+            }
+            c if c.is_whitespace() => words.extend(word.take()),
+            c => word.get_or_insert_with(String::new).push(c),
+        }
+    }
+    words.extend(word);
+    Ok(words)
+}
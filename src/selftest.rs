@@ -0,0 +1,85 @@
+//! A quick check that an installed binary works, for CI machines: expands a small tree in a
+//! temporary directory and reports the optional features it was built with.
+use std::env;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::process;
+
+use simple_include::{list_of_paths_respecting_gitignore, process_file, IncludeOptions};
+
+/// The optional features and whether this build has them
+const FEATURES: [(&str, bool); 4] = [
+    ("livereload", cfg!(feature = "livereload")),
+    ("remote-includes", cfg!(feature = "remote-includes")),
+    ("exec-includes", cfg!(feature = "exec-includes")),
+    ("archive-includes", cfg!(feature = "archive-includes")),
+];
+
+/// Runs the self-test, printing the version, the features and the result. Returns an error if the
+/// test fails.
+pub fn run() -> io::Result<()> {
+    println!("simple-include {}", env!("CARGO_PKG_VERSION"));
+    for (feature, enabled) in FEATURES {
+        println!(
+            "{}: {}",
+            feature,
+            if enabled { "enabled" } else { "disabled" }
+        );
+    }
+    let dir = env::temp_dir().join(format!("simple-include-selftest-{}", process::id()));
+    let result = expand_sample(&dir);
+    let _ = fs::remove_dir_all(&dir);
+    match result {
+        Ok(()) => {
+            println!("Self-test passed");
+            Ok(())
+        }
+        Err(e) => Err(io::Error::other(format!("Self-test failed: {}", e))),
+    }
+}
+
+/// Expands a nested include, a conditional include and a region in `dir`, and checks that
+/// gitignored files are left out of the file list
+fn expand_sample(dir: &Path) -> io::Result<()> {
+    let src = dir.join("src");
+    let target = dir.join("target");
+    fs::create_dir_all(src.join("parts"))?;
+    fs::write(src.join(".gitignore"), "*.log\n")?;
+    fs::write(src.join("build.log"), "Ignored.\n")?;
+    fs::write(
+        src.join("main.txt"),
+        "Start.\n--include parts/header.txt\n--include-if EXTRA parts/extra.txt\nEnd.\n",
+    )?;
+    fs::write(
+        src.join("parts/header.txt"),
+        "Header.\n--include code.rs#setup\n",
+    )?;
+    fs::write(
+        src.join("parts/code.rs"),
+        "fn main() {}\n// region: setup\nlet x = 1;\n// endregion: setup\n",
+    )?;
+    fs::write(src.join("parts/extra.txt"), "Extra.\n")?;
+
+    let files = list_of_paths_respecting_gitignore(&src, &target, false, None)?;
+    if files.iter().any(|file| file.ends_with("build.log")) {
+        return Err(io::Error::other("gitignored file was listed"));
+    }
+    let options = IncludeOptions {
+        features: ["EXTRA".to_string()].into(),
+        ..IncludeOptions::default()
+    };
+    let out = target.join("main.txt");
+    fs::create_dir_all(&target)?;
+    process_file(&src.join("main.txt"), &out, &options)
+        .map_err(|e| io::Error::other(e.to_string()))?;
+    let expected = "Start.\nHeader.\nlet x = 1;\nExtra.\nEnd.\n";
+    let actual = fs::read_to_string(&out)?;
+    if actual != expected {
+        return Err(io::Error::other(format!(
+            "expected {:?}, got {:?}",
+            expected, actual
+        )));
+    }
+    Ok(())
+}
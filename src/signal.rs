@@ -0,0 +1,20 @@
+//! Handling of Ctrl-C (SIGINT and SIGTERM on Unix, Ctrl-C, Ctrl-Break and closing the console on
+//! Windows) in watch mode, so the watch loop can finish what it is doing and exit cleanly instead
+//! of being killed partway through writing a file.
+use std::{io, process, thread};
+
+/// Calls `on_interrupt` on its own thread when the process is interrupted. A second interrupt
+/// ends the process straight away, with the exit code a shell reports for Ctrl-C, in case
+/// finishing up is stuck.
+pub fn on_interrupt(on_interrupt: impl FnOnce() + Send + 'static) -> io::Result<()> {
+    let mut on_interrupt = Some(on_interrupt);
+    ctrlc::set_handler(move || match on_interrupt.take() {
+        // On a thread of its own, so a callback that blocks can't keep the next interrupt from
+        // being seen
+        Some(on_interrupt) => {
+            thread::spawn(on_interrupt);
+        }
+        None => process::exit(130),
+    })
+    .map_err(io::Error::other)
+}
@@ -0,0 +1,123 @@
+//! The cutoff for `--since`: an RFC 3339 timestamp such as `2024-05-01T09:30:15Z` or
+//! `2024-05-01T11:30:15+02:00`, or a duration before now such as `90s`, `15m`, `2h`, `1d` or
+//! `1h30m`.
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Parses a `--since` value into the time it refers to
+pub fn parse(value: &str) -> Result<SystemTime, String> {
+    let value = value.trim();
+    if let Some(ago) = parse_duration(value) {
+        return SystemTime::now()
+            .checked_sub(ago)
+            .ok_or_else(|| format!("{:?} is too long ago", value));
+    }
+    parse_timestamp(value).ok_or_else(|| {
+        format!(
+            "expected an RFC 3339 timestamp like 2024-05-01T09:30:15Z or a duration like 15m, got {:?}",
+            value
+        )
+    })
+}
+
+/// A duration made of numbers with units `s`, `m`, `h`, `d` or `w`, e.g. `1h30m`
+fn parse_duration(value: &str) -> Option<Duration> {
+    let mut total = 0u64;
+    let mut rest = value;
+    while !rest.is_empty() {
+        let digits = rest.find(|c: char| !c.is_ascii_digit())?;
+        let number: u64 = rest[..digits].parse().ok()?;
+        let unit = match rest[digits..].chars().next()? {
+            's' => 1,
+            'm' => 60,
+            'h' => 3600,
+            'd' => 86_400,
+            'w' => 7 * 86_400,
+            _ => return None,
+        };
+        total = total.checked_add(number.checked_mul(unit)?)?;
+        rest = &rest[digits + 1..];
+    }
+    (!value.is_empty()).then(|| Duration::from_secs(total))
+}
+
+/// An RFC 3339 timestamp: a date, `T` (or a space), a time with optional fractional seconds, and
+/// `Z` or an offset from UTC
+fn parse_timestamp(value: &str) -> Option<SystemTime> {
+    let (date, time) = value.split_once(['T', 't', ' '])?;
+    let mut date = date.splitn(3, '-');
+    let year: i64 = parse_digits(date.next()?, 4)?;
+    let month: u32 = parse_digits(date.next()?, 2)?;
+    let day: u32 = parse_digits(date.next()?, 2)?;
+    if !(1..=12).contains(&month) || !(1..=days_in_month(year, month)).contains(&day) {
+        return None;
+    }
+
+    let (time, offset_seconds) = if let Some(time) = time.strip_suffix(['Z', 'z']) {
+        (time, 0)
+    } else {
+        let sign_at = time.rfind(['+', '-'])?;
+        let (time, offset) = time.split_at(sign_at);
+        let sign = if offset.starts_with('-') { -1 } else { 1 };
+        let (hours, minutes) = offset[1..].split_once(':')?;
+        let hours: i64 = parse_digits(hours, 2)?;
+        let minutes: i64 = parse_digits(minutes, 2)?;
+        if hours > 23 || minutes > 59 {
+            return None;
+        }
+        (time, sign * (hours * 3600 + minutes * 60))
+    };
+    let (time, fraction) = match time.split_once('.') {
+        Some((time, fraction)) => (time, Some(fraction)),
+        None => (time, None),
+    };
+    let mut time = time.splitn(3, ':');
+    let hour: i64 = parse_digits(time.next()?, 2)?;
+    let minute: i64 = parse_digits(time.next()?, 2)?;
+    // 60 allows for a leap second
+    let second: i64 = parse_digits(time.next()?, 2)?;
+    if hour > 23 || minute > 59 || second > 60 {
+        return None;
+    }
+    let nanos = match fraction {
+        Some(fraction) if !fraction.is_empty() && fraction.chars().all(|c| c.is_ascii_digit()) => {
+            // Only the first nine digits fit in nanoseconds
+            let digits = &fraction[..fraction.len().min(9)];
+            digits.parse::<u32>().ok()? * 10u32.pow(9 - digits.len() as u32)
+        }
+        Some(_) => return None,
+        None => 0,
+    };
+
+    let seconds = days_from_civil(year, month, day) * 86_400 + hour * 3600 + minute * 60 + second
+        - offset_seconds;
+    let since_epoch = Duration::new(u64::try_from(seconds).ok()?, nanos);
+    UNIX_EPOCH.checked_add(since_epoch)
+}
+
+/// Parses a number written with exactly `len` digits
+fn parse_digits<T: std::str::FromStr>(text: &str, len: usize) -> Option<T> {
+    (text.len() == len && text.chars().all(|c| c.is_ascii_digit()))
+        .then(|| text.parse().ok())
+        .flatten()
+}
+
+fn days_in_month(year: i64, month: u32) -> u32 {
+    match month {
+        2 if year % 4 == 0 && (year % 100 != 0 || year % 400 == 0) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+/// The number of days since 1970-01-01 for a date, using Howard Hinnant's `days_from_civil`
+/// algorithm
+fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = year - i64::from(month <= 2);
+    let era = year.div_euclid(400);
+    let year_of_era = year.rem_euclid(400);
+    let shifted_month = i64::from((month + 9) % 12);
+    let day_of_year = (153 * shifted_month + 2) / 5 + i64::from(day) - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
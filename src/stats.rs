@@ -0,0 +1,64 @@
+//! Export of a performance report for a run, as JSON, for `--stats-json`.
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::time::Duration;
+
+use simple_include::quote;
+
+/// The totals and timings for a run
+#[derive(Debug, Clone, Default)]
+pub struct Report {
+    pub files: usize,
+    /// Includes expanded, counting nested ones
+    pub includes: usize,
+    /// Includes left as they are because they were missing or binary
+    pub skipped: usize,
+    pub bytes_written: u64,
+    /// The largest generated file, relative to the target directory, and its size
+    pub largest_file: Option<(String, u64)>,
+    /// The time spent reading sources and includes, added up across threads
+    pub read_time: Duration,
+    /// The time spent writing outputs, added up across threads
+    pub write_time: Duration,
+    /// From the start of the run until files are processed: options, config and caches
+    pub setup_time: Duration,
+    pub process_time: Duration,
+    /// Cleaning up the target and writing the cache, manifest and graph
+    pub finish_time: Duration,
+    pub wall_time: Duration,
+}
+
+/// Writes the report to `out_path` as a JSON object, with times in seconds
+pub fn write_stats(out_path: &Path, report: &Report) -> io::Result<()> {
+    fs::write(out_path, to_json(report))
+}
+
+fn to_json(report: &Report) -> String {
+    let largest_file = match &report.largest_file {
+        Some((path, bytes)) => format!("{{\"path\": {}, \"bytes\": {}}}", quote(path), bytes),
+        None => "null".to_string(),
+    };
+    format!(
+        "{{\n  \"files\": {},\n  \"includes\": {},\n  \"skipped\": {},\n  \"bytes_written\": {},\n  \
+         \"largest_file\": {},\n  \"read_seconds\": {},\n  \"write_seconds\": {},\n  \
+         \"phases\": {{\"setup_seconds\": {}, \"process_seconds\": {}, \"finish_seconds\": {}}},\n  \
+         \"wall_seconds\": {}\n}}\n",
+        report.files,
+        report.includes,
+        report.skipped,
+        report.bytes_written,
+        largest_file,
+        seconds(report.read_time),
+        seconds(report.write_time),
+        seconds(report.setup_time),
+        seconds(report.process_time),
+        seconds(report.finish_time),
+        seconds(report.wall_time)
+    )
+}
+
+/// A duration in seconds, to the microsecond
+fn seconds(duration: Duration) -> String {
+    format!("{:.6}", duration.as_secs_f64())
+}
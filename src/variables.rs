@@ -0,0 +1,56 @@
+//! Substitution of `{{NAME}}` placeholders with variables defined on the command line.
+//!
+//! This is plain text replacement, with no expressions, filters or escaping.
+use std::borrow::Cow;
+use std::path::Path;
+
+use log::warn;
+
+use crate::{slashed, IncludeOptions};
+
+/// Replaces every `{{NAME}}` placeholder in the line whose name is a defined variable. Other
+/// placeholders are left as they are, with a warning if `warn_undefined_variables` is set.
+pub(crate) fn substitute_variables<'a>(
+    line: &'a str,
+    file: &Path,
+    options: &IncludeOptions,
+) -> Cow<'a, str> {
+    if !line.contains("{{") || (options.variables.is_empty() && !options.warn_undefined_variables) {
+        return Cow::Borrowed(line);
+    }
+    let mut result = String::with_capacity(line.len());
+    let mut rest = line;
+    while let Some(start) = rest.find("{{") {
+        result.push_str(&rest[..start]);
+        let after = &rest[start + 2..];
+        let Some(end) = after.find("}}") else {
+            rest = &rest[start..];
+            break;
+        };
+        let name = after[..end].trim();
+        let placeholder = &rest[start..start + 2 + end + 2];
+        match options.variables.get(name) {
+            Some(value) if is_variable_name(name) => result.push_str(value),
+            _ => {
+                if options.warn_undefined_variables && is_variable_name(name) {
+                    warn!(
+                        "Variable {:?} is not defined (used in file {:?}), leaving it as it is",
+                        name,
+                        slashed(file)
+                    );
+                }
+                result.push_str(placeholder);
+            }
+        }
+        rest = &after[end + 2..];
+    }
+    result.push_str(rest);
+    Cow::Owned(result)
+}
+
+fn is_variable_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-' || c == '.')
+}
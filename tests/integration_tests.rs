@@ -1,9 +1,10 @@
 use std::fs::{self, File};
 use std::io::Write;
 use std::io::{BufRead, BufReader};
+use std::path::Path;
 use std::process::{Command, Stdio};
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, SystemTime};
 use tempfile::tempdir;
 
 #[test]
@@ -148,7 +149,7 @@ fn test_watch_functionality() {
     let mut counter = 0;
     while counter < 20 && !output_main_file.exists() {
         thread::sleep(Duration::from_millis(100));
-        counter = counter + -1;
+        counter += -1;
     }
     //Wait for the file to be updated
     counter = 0;
@@ -158,7 +159,7 @@ fn test_watch_functionality() {
             .contains("This is the modified included file.")
     {
         thread::sleep(Duration::from_millis(100));
-        counter = counter + 1;
+        counter += 1;
     }
 
     println!("{:?}", output_main_file);
@@ -169,6 +170,7 @@ fn test_watch_functionality() {
 
     // Kill the child process
     child.kill().expect("Failed to kill process");
+    child.wait().expect("Failed to wait for process");
 }
 
 #[test]
@@ -217,3 +219,7765 @@ fn test_process_binary_file() {
     let output_binary_content = fs::read(target_dir.join("binary.bin")).unwrap();
     assert_eq!(binary_content, output_binary_content);
 }
+
+#[test]
+fn test_binary_copy_errors_name_the_files() {
+    let temp_dir = tempdir().unwrap();
+    let src_dir = temp_dir.path().join("src");
+    let target_dir = temp_dir.path().join("target");
+
+    fs::create_dir_all(&src_dir).unwrap();
+    fs::write(src_dir.join("image.bin"), [0u8, 159, 146, 150]).unwrap();
+    // A directory where the copy should go, which it can't replace
+    fs::create_dir_all(target_dir.join("image.bin").join("inside")).unwrap();
+
+    let output = Command::new("cargo")
+        .arg("run")
+        .arg("--")
+        .arg("--src")
+        .arg(src_dir.to_str().unwrap())
+        .arg("--target")
+        .arg(target_dir.to_str().unwrap())
+        .output()
+        .expect("Failed to execute process");
+
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains(&format!(
+            "Could not copy {:?} to {:?}",
+            src_dir.join("image.bin"),
+            target_dir.join("image.bin")
+        )),
+        "{}",
+        stderr
+    );
+}
+
+#[test]
+fn test_binary_file_handling() {
+    let temp_dir = tempdir().unwrap();
+    let src_dir = temp_dir.path().join("src");
+
+    fs::create_dir_all(&src_dir).unwrap();
+
+    fs::write(src_dir.join("logo.png"), [0u8, 159, 146, 150]).unwrap();
+    fs::write(src_dir.join("main.txt"), "Main.\n").unwrap();
+
+    let run = |target_dir: &Path, extra_args: &[&str]| {
+        Command::new("cargo")
+            .arg("run")
+            .arg("--")
+            .arg("--src")
+            .arg(src_dir.to_str().unwrap())
+            .arg("--target")
+            .arg(target_dir.to_str().unwrap())
+            .args(extra_args)
+            .output()
+            .expect("Failed to execute process")
+    };
+
+    // Copied by default, without -v
+    let target_dir = temp_dir.path().join("copy");
+    let output = run(&target_dir, &[]);
+    assert!(output.status.success());
+    assert_eq!(
+        fs::read(target_dir.join("logo.png")).unwrap(),
+        [0u8, 159, 146, 150]
+    );
+
+    let target_dir = temp_dir.path().join("skip");
+    let output = run(&target_dir, &["--binary", "skip"]);
+    assert!(output.status.success());
+    assert!(!target_dir.join("logo.png").exists());
+    assert!(target_dir.join("main.txt").exists());
+
+    let target_dir = temp_dir.path().join("no-copy");
+    let output = run(&target_dir, &["--no-copy-binaries"]);
+    assert!(output.status.success());
+    assert!(!target_dir.join("logo.png").exists());
+    assert!(target_dir.join("main.txt").exists());
+
+    let target_dir = temp_dir.path().join("error");
+    let output = run(&target_dir, &["--binary", "error"]);
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("logo.png"), "{}", stderr);
+    assert!(!target_dir.join("logo.png").exists());
+    assert!(target_dir.join("main.txt").exists());
+}
+
+#[test]
+fn test_unchanged_binary_file_is_not_copied() {
+    let temp_dir = tempdir().unwrap();
+    let src_dir = temp_dir.path().join("src");
+    let target_dir = temp_dir.path().join("target");
+
+    fs::create_dir_all(&src_dir).unwrap();
+    fs::create_dir_all(&target_dir).unwrap();
+
+    let binary_path = src_dir.join("binary.bin");
+    fs::write(&binary_path, [0u8, 159, 146, 150]).unwrap();
+
+    let run = || {
+        let output = Command::new("cargo")
+            .arg("run")
+            .arg("--")
+            .arg("-v")
+            .arg("--src")
+            .arg(src_dir.to_str().unwrap())
+            .arg("--target")
+            .arg(target_dir.to_str().unwrap())
+            .output()
+            .expect("Failed to execute process");
+        assert!(output.status.success());
+    };
+
+    run();
+    let target_binary = target_dir.join("binary.bin");
+    assert_eq!(fs::read(&target_binary).unwrap(), [0u8, 159, 146, 150]);
+
+    // Backdate the copy so a second copy would be visible
+    let old_mtime = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000);
+    File::options()
+        .write(true)
+        .open(&target_binary)
+        .unwrap()
+        .set_modified(old_mtime)
+        .unwrap();
+
+    run();
+    let mtime = fs::metadata(&target_binary).unwrap().modified().unwrap();
+    assert_eq!(mtime, old_mtime);
+
+    // Content of the same size that differs is still copied
+    fs::write(&binary_path, [0u8, 159, 146, 151]).unwrap();
+    run();
+    assert_eq!(fs::read(&target_binary).unwrap(), [0u8, 159, 146, 151]);
+}
+
+#[cfg(unix)]
+#[test]
+fn test_link_binary_files() {
+    use std::os::unix::fs::MetadataExt;
+
+    let temp_dir = tempdir().unwrap();
+    let src_dir = temp_dir.path().join("src");
+
+    fs::create_dir_all(&src_dir).unwrap();
+
+    let binary_path = src_dir.join("image.bin");
+    fs::write(&binary_path, [0u8, 159, 146, 150]).unwrap();
+    fs::write(src_dir.join("main.txt"), "Text.\n").unwrap();
+
+    let run = |link: &str| {
+        let target_dir = temp_dir.path().join(link);
+        let output = Command::new("cargo")
+            .arg("run")
+            .arg("--")
+            .arg("--src")
+            .arg(src_dir.to_str().unwrap())
+            .arg("--target")
+            .arg(target_dir.to_str().unwrap())
+            .arg("--link")
+            .arg(link)
+            .output()
+            .expect("Failed to execute process");
+        assert!(output.status.success());
+        target_dir
+    };
+
+    let target_dir = run("hardlink");
+    let target_binary = target_dir.join("image.bin");
+    assert_eq!(fs::read(&target_binary).unwrap(), [0u8, 159, 146, 150]);
+    assert_eq!(
+        fs::metadata(&target_binary).unwrap().ino(),
+        fs::metadata(&binary_path).unwrap().ino()
+    );
+    // Text files are still written
+    assert_ne!(
+        fs::metadata(target_dir.join("main.txt")).unwrap().ino(),
+        fs::metadata(src_dir.join("main.txt")).unwrap().ino()
+    );
+
+    // A clone, or a copy where the file system can't clone, is a file of its own
+    let target_binary = run("reflink").join("image.bin");
+    assert_eq!(fs::read(&target_binary).unwrap(), [0u8, 159, 146, 150]);
+    assert_ne!(
+        fs::metadata(&target_binary).unwrap().ino(),
+        fs::metadata(&binary_path).unwrap().ino()
+    );
+}
+
+#[cfg(feature = "livereload")]
+#[test]
+fn test_livereload_notifies_after_change() {
+    use std::io::Read;
+    use std::net::{TcpListener, TcpStream};
+
+    let temp_dir = tempdir().unwrap();
+    let src_dir = temp_dir.path().join("src");
+    let target_dir = temp_dir.path().join("target");
+
+    fs::create_dir_all(&src_dir).unwrap();
+    fs::create_dir_all(&target_dir).unwrap();
+
+    let main_file_path = src_dir.join("main.txt");
+    let mut main_file = File::create(&main_file_path).unwrap();
+    writeln!(main_file, "This is the main file.").unwrap();
+
+    // Find a free port for the live reload server
+    let port = TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+
+    let mut child = Command::new("cargo")
+        .arg("run")
+        .arg("--features")
+        .arg("livereload")
+        .arg("--")
+        .arg("--src")
+        .arg(src_dir.to_str().unwrap())
+        .arg("--target")
+        .arg(target_dir.to_str().unwrap())
+        .arg("--watch")
+        .arg("--livereload")
+        .arg(port.to_string())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .expect("Failed to start process");
+
+    // Wait for the server to come up
+    let mut stream = None;
+    for _ in 0..100 {
+        if let Ok(s) = TcpStream::connect(("127.0.0.1", port)) {
+            stream = Some(s);
+            break;
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+    let mut stream = stream.expect("Live reload server did not start");
+    stream
+        .write_all(b"GET /livereload HTTP/1.1\r\nHost: localhost\r\n\r\n")
+        .unwrap();
+    stream
+        .set_read_timeout(Some(Duration::from_millis(200)))
+        .unwrap();
+
+    let mut received = String::new();
+    let mut buf = [0u8; 1024];
+    for _ in 0..50 {
+        // Keep touching the file until the watcher picks it up and we get a reload message
+        let mut main_file = File::create(&main_file_path).unwrap();
+        writeln!(main_file, "This is the modified main file.").unwrap();
+        if let Ok(n) = stream.read(&mut buf) {
+            received.push_str(&String::from_utf8_lossy(&buf[..n]));
+        }
+        if received.contains("data: reload") {
+            break;
+        }
+    }
+
+    child.kill().expect("Failed to kill process");
+    child.wait().expect("Failed to wait for process");
+
+    assert!(received.contains("text/event-stream"));
+    assert!(received.contains("event: reload"));
+}
+
+#[test]
+fn test_include_man_page_section() {
+    let temp_dir = tempdir().unwrap();
+    let src_dir = temp_dir.path().join("src");
+    let target_dir = temp_dir.path().join("target");
+
+    fs::create_dir_all(&src_dir).unwrap();
+    fs::create_dir_all(&target_dir).unwrap();
+
+    let mut main_file = File::create(src_dir.join("main.txt")).unwrap();
+    writeln!(main_file, "--include other.1:SYNOPSIS").unwrap();
+    writeln!(main_file, "--include other.1:EXAMPLES").unwrap();
+    writeln!(main_file, "This is the main file.").unwrap();
+
+    let mut man_file = File::create(src_dir.join("other.1")).unwrap();
+    writeln!(man_file, ".TH OTHER 1").unwrap();
+    writeln!(man_file, ".SH NAME").unwrap();
+    writeln!(man_file, "other \\- does other things").unwrap();
+    writeln!(man_file, ".SH SYNOPSIS").unwrap();
+    writeln!(man_file, ".B other").unwrap();
+    writeln!(man_file, "[\\-v] file").unwrap();
+    writeln!(man_file, ".SH DESCRIPTION").unwrap();
+    writeln!(man_file, "Other does things.").unwrap();
+
+    let output = Command::new("cargo")
+        .arg("run")
+        .arg("--")
+        .arg("--src")
+        .arg(src_dir.to_str().unwrap())
+        .arg("--target")
+        .arg(target_dir.to_str().unwrap())
+        .output()
+        .expect("Failed to execute process");
+
+    assert!(output.status.success());
+
+    let output_content = fs::read_to_string(target_dir.join("main.txt")).unwrap();
+    assert_eq!(
+        output_content,
+        ".SH SYNOPSIS\n.B other\n[\\-v] file\n--include other.1:EXAMPLES\nThis is the main file.\n"
+    );
+}
+
+#[test]
+fn test_fallback_encoding_windows_1252() {
+    let temp_dir = tempdir().unwrap();
+    let src_dir = temp_dir.path().join("src");
+    let target_dir = temp_dir.path().join("target");
+
+    fs::create_dir_all(&src_dir).unwrap();
+    fs::create_dir_all(&target_dir).unwrap();
+
+    // "Café – €5" in Windows-1252, which is not valid UTF-8
+    let mut main_file = File::create(src_dir.join("main.txt")).unwrap();
+    main_file.write_all(b"--include include.txt\n").unwrap();
+    main_file.write_all(b"Caf\xe9 \x96 \x805\n").unwrap();
+
+    let mut include_file = File::create(src_dir.join("include.txt")).unwrap();
+    writeln!(include_file, "This is the included file.").unwrap();
+
+    let output = Command::new("cargo")
+        .arg("run")
+        .arg("--")
+        .arg("--src")
+        .arg(src_dir.to_str().unwrap())
+        .arg("--target")
+        .arg(target_dir.to_str().unwrap())
+        .arg("--fallback-encoding")
+        .arg("windows-1252")
+        .output()
+        .expect("Failed to execute process");
+
+    assert!(output.status.success());
+
+    let output_content = fs::read(target_dir.join("main.txt")).unwrap();
+    assert_eq!(
+        output_content,
+        b"This is the included file.\nCaf\xe9 \x96 \x805\n".to_vec()
+    );
+}
+
+#[test]
+fn test_encoding_latin1() {
+    let temp_dir = tempdir().unwrap();
+    let src_dir = temp_dir.path().join("src");
+    let target_dir = temp_dir.path().join("target");
+
+    fs::create_dir_all(&src_dir).unwrap();
+
+    // "Café" and "Año" in Latin-1, neither valid UTF-8
+    fs::write(src_dir.join("main.txt"), b"Caf\xe9\n--include a\xf1o.txt\n").unwrap();
+    fs::write(src_dir.join("a\u{f1}o.txt"), b"A\xf1o\n").unwrap();
+
+    let output = Command::new("cargo")
+        .arg("run")
+        .arg("--")
+        .arg("--src")
+        .arg(src_dir.to_str().unwrap())
+        .arg("--target")
+        .arg(target_dir.to_str().unwrap())
+        .arg("--encoding")
+        .arg("latin1")
+        .output()
+        .expect("Failed to execute process");
+
+    assert!(output.status.success());
+    assert_eq!(
+        fs::read(target_dir.join("main.txt")).unwrap(),
+        b"Caf\xe9\nA\xf1o\n".to_vec()
+    );
+}
+
+#[test]
+fn test_encoding_shift_jis() {
+    let temp_dir = tempdir().unwrap();
+    let src_dir = temp_dir.path().join("src");
+    let target_dir = temp_dir.path().join("target");
+
+    fs::create_dir_all(&src_dir).unwrap();
+
+    // "日本語" and "テスト" in Shift_JIS, neither valid UTF-8
+    fs::write(
+        src_dir.join("main.txt"),
+        b"\x93\xfa\x96\x7b\x8c\xea\n--include test.txt\n",
+    )
+    .unwrap();
+    fs::write(src_dir.join("test.txt"), b"\x83\x65\x83\x58\x83\x67\n").unwrap();
+
+    let output = Command::new("cargo")
+        .arg("run")
+        .arg("--")
+        .arg("--src")
+        .arg(src_dir.to_str().unwrap())
+        .arg("--target")
+        .arg(target_dir.to_str().unwrap())
+        .arg("--encoding")
+        .arg("shift_jis")
+        .output()
+        .expect("Failed to execute process");
+
+    assert!(output.status.success());
+    assert_eq!(
+        fs::read(target_dir.join("main.txt")).unwrap(),
+        b"\x93\xfa\x96\x7b\x8c\xea\n\x83\x65\x83\x58\x83\x67\n".to_vec()
+    );
+}
+
+#[test]
+fn test_utf16_files() {
+    let temp_dir = tempdir().unwrap();
+    let src_dir = temp_dir.path().join("src");
+    let target_dir = temp_dir.path().join("target");
+
+    fs::create_dir_all(&src_dir).unwrap();
+
+    let utf16le = |text: &str| -> Vec<u8> {
+        let mut bytes = vec![0xFF, 0xFE];
+        bytes.extend(text.encode_utf16().flat_map(u16::to_le_bytes));
+        bytes
+    };
+    // A UTF-16 file exported on Windows including a UTF-8 one, and the other way round
+    fs::write(
+        src_dir.join("main.txt"),
+        utf16le("Caf\u{e9} \u{1f600}\r\n--include note.txt\r\nEnd.\r\n"),
+    )
+    .unwrap();
+    fs::write(src_dir.join("note.txt"), "Note \u{2713}\n").unwrap();
+    fs::write(src_dir.join("readme.txt"), "Start.\n--include wide.txt\n").unwrap();
+    fs::write(src_dir.join("wide.txt"), utf16le("Wide \u{e9}.\r\n")).unwrap();
+
+    let output = Command::new("cargo")
+        .arg("run")
+        .arg("--")
+        .arg("--src")
+        .arg(src_dir.to_str().unwrap())
+        .arg("--target")
+        .arg(target_dir.to_str().unwrap())
+        .output()
+        .expect("Failed to execute process");
+
+    assert!(output.status.success());
+    assert_eq!(
+        fs::read(target_dir.join("main.txt")).unwrap(),
+        utf16le("Caf\u{e9} \u{1f600}\r\nNote \u{2713}\nEnd.\r\n")
+    );
+    assert_eq!(
+        fs::read_to_string(target_dir.join("readme.txt")).unwrap(),
+        "Start.\nWide \u{e9}.\r\n"
+    );
+    // Round-trips unchanged when there is nothing to include
+    assert_eq!(
+        fs::read(target_dir.join("wide.txt")).unwrap(),
+        fs::read(src_dir.join("wide.txt")).unwrap()
+    );
+}
+
+#[test]
+fn test_byte_order_marks() {
+    let temp_dir = tempdir().unwrap();
+    let src_dir = temp_dir.path().join("src");
+
+    fs::create_dir_all(&src_dir).unwrap();
+
+    // Both files start with a BOM, and the include has another one mid-line
+    fs::write(
+        src_dir.join("main.txt"),
+        "\u{feff}Start.\n--include header.txt\nEnd.\n",
+    )
+    .unwrap();
+    fs::write(src_dir.join("header.txt"), "\u{feff}Header \u{feff}here.\n").unwrap();
+
+    let run = |target_dir: &Path, extra_args: &[&str]| {
+        let output = Command::new("cargo")
+            .arg("run")
+            .arg("--")
+            .arg("--src")
+            .arg(src_dir.to_str().unwrap())
+            .arg("--target")
+            .arg(target_dir.to_str().unwrap())
+            .args(extra_args)
+            .output()
+            .expect("Failed to execute process");
+        assert!(output.status.success());
+        fs::read_to_string(target_dir.join("main.txt")).unwrap()
+    };
+
+    assert_eq!(
+        run(&temp_dir.path().join("strip"), &[]),
+        "Start.\nHeader \u{feff}here.\nEnd.\n"
+    );
+    assert_eq!(
+        run(&temp_dir.path().join("keep"), &["--bom", "keep"]),
+        "\u{feff}Start.\n\u{feff}Header \u{feff}here.\nEnd.\n"
+    );
+}
+
+#[test]
+fn test_warn_duplicate_includes() {
+    let temp_dir = tempdir().unwrap();
+    let src_dir = temp_dir.path().join("src");
+    let target_dir = temp_dir.path().join("target");
+
+    fs::create_dir_all(&src_dir).unwrap();
+    fs::create_dir_all(&target_dir).unwrap();
+
+    let mut main_file = File::create(src_dir.join("main.txt")).unwrap();
+    writeln!(main_file, "--include fragment.txt").unwrap();
+    writeln!(main_file, "This is the main file.").unwrap();
+    writeln!(main_file, "--include ./fragment.txt").unwrap();
+
+    let mut fragment_file = File::create(src_dir.join("fragment.txt")).unwrap();
+    writeln!(fragment_file, "This is the fragment.").unwrap();
+
+    let output = Command::new("cargo")
+        .arg("run")
+        .arg("--")
+        .arg("--src")
+        .arg(src_dir.to_str().unwrap())
+        .arg("--target")
+        .arg(target_dir.to_str().unwrap())
+        .arg("--warn-duplicate-includes")
+        .output()
+        .expect("Failed to execute process");
+
+    assert!(output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("fragment.txt\" more than once (lines 1, 3)"));
+
+    // The warning doesn't change the output
+    let output_content = fs::read_to_string(target_dir.join("main.txt")).unwrap();
+    assert_eq!(
+        output_content.matches("This is the fragment.").count(),
+        2,
+        "{}",
+        output_content
+    );
+}
+
+#[test]
+fn test_dump_directives() {
+    let temp_dir = tempdir().unwrap();
+    let src_dir = temp_dir.path().join("src");
+    let target_dir = temp_dir.path().join("target");
+
+    fs::create_dir_all(&src_dir).unwrap();
+
+    let main_file_path = src_dir.join("main.txt");
+    let mut main_file = File::create(&main_file_path).unwrap();
+    writeln!(main_file, "This is the main file.").unwrap();
+    writeln!(main_file, "--include include.txt").unwrap();
+    writeln!(main_file, "--include missing.txt").unwrap();
+    writeln!(main_file, "--include other.1:SYNOPSIS").unwrap();
+
+    let mut include_file = File::create(src_dir.join("include.txt")).unwrap();
+    writeln!(include_file, "This is the included file.").unwrap();
+
+    let output = Command::new("cargo")
+        .arg("run")
+        .arg("--")
+        .arg("--target")
+        .arg(target_dir.to_str().unwrap())
+        .arg("--dump-directives")
+        .arg(main_file_path.to_str().unwrap())
+        .output()
+        .expect("Failed to execute process");
+
+    assert!(output.status.success());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let resolved = src_dir.join("include.txt");
+    assert!(stdout.contains(&format!(
+        "line 2: include.txt -> {} (found)",
+        resolved.display()
+    )));
+    assert!(stdout.contains("line 3: missing.txt -> "));
+    assert!(stdout.contains("(not-found)"));
+    assert!(stdout.contains("line 4: other.1 section=SYNOPSIS -> "));
+    // Nothing is processed in this mode
+    assert!(!target_dir.exists());
+}
+
+#[test]
+fn test_watch_rebuilds_on_prepend_change() {
+    let temp_dir = tempdir().unwrap();
+    let src_dir = temp_dir.path().join("src");
+    let target_dir = temp_dir.path().join("target");
+    let wrapper_dir = temp_dir.path().join("wrappers");
+
+    fs::create_dir_all(&src_dir).unwrap();
+    fs::create_dir_all(&target_dir).unwrap();
+    fs::create_dir_all(&wrapper_dir).unwrap();
+
+    let mut first_file = File::create(src_dir.join("first.txt")).unwrap();
+    writeln!(first_file, "This is the first file.").unwrap();
+    let mut second_file = File::create(src_dir.join("second.txt")).unwrap();
+    writeln!(second_file, "This is the second file.").unwrap();
+
+    let prepend_path = wrapper_dir.join("header.txt");
+    let mut prepend_file = File::create(&prepend_path).unwrap();
+    writeln!(prepend_file, "Old header").unwrap();
+
+    let mut child = Command::new("cargo")
+        .arg("run")
+        .arg("--")
+        .arg("--src")
+        .arg(src_dir.to_str().unwrap())
+        .arg("--target")
+        .arg(target_dir.to_str().unwrap())
+        .arg("--prepend")
+        .arg(prepend_path.to_str().unwrap())
+        .arg("--watch")
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .expect("Failed to start process");
+
+    // Wait for the initial pass
+    let first_output = target_dir.join("first.txt");
+    let second_output = target_dir.join("second.txt");
+    let mut counter = 0;
+    while counter < 50
+        && !fs::read_to_string(&second_output)
+            .unwrap_or_default()
+            .contains("Old header")
+    {
+        thread::sleep(Duration::from_millis(100));
+        counter += 1;
+    }
+    thread::sleep(Duration::from_millis(100));
+
+    let mut prepend_file = File::create(&prepend_path).unwrap();
+    writeln!(prepend_file, "New header").unwrap();
+    prepend_file.flush().unwrap();
+
+    counter = 0;
+    while counter < 50
+        && !(fs::read_to_string(&first_output)
+            .unwrap()
+            .contains("New header")
+            && fs::read_to_string(&second_output)
+                .unwrap()
+                .contains("New header"))
+    {
+        thread::sleep(Duration::from_millis(100));
+        counter += 1;
+    }
+
+    child.kill().expect("Failed to kill process");
+    child.wait().expect("Failed to wait for process");
+
+    assert_eq!(
+        fs::read_to_string(&first_output).unwrap(),
+        "New header\nThis is the first file.\n"
+    );
+    assert_eq!(
+        fs::read_to_string(&second_output).unwrap(),
+        "New header\nThis is the second file.\n"
+    );
+}
+
+#[test]
+fn test_nested_includes() {
+    let temp_dir = tempdir().unwrap();
+    let src_dir = temp_dir.path().join("src");
+    let target_dir = temp_dir.path().join("target");
+
+    fs::create_dir_all(src_dir.join("shared/snippets")).unwrap();
+    fs::create_dir_all(&target_dir).unwrap();
+
+    let mut main_file = File::create(src_dir.join("main.txt")).unwrap();
+    writeln!(main_file, "--include shared/header.txt").unwrap();
+    writeln!(main_file, "This is the main file.").unwrap();
+
+    // Each level includes the next relative to its own directory
+    let mut header_file = File::create(src_dir.join("shared/header.txt")).unwrap();
+    writeln!(header_file, "This is the header.").unwrap();
+    writeln!(header_file, "--include snippets/snippet.txt").unwrap();
+
+    let mut snippet_file = File::create(src_dir.join("shared/snippets/snippet.txt")).unwrap();
+    writeln!(snippet_file, "This is the snippet.").unwrap();
+    writeln!(snippet_file, "--include ../deepest.txt").unwrap();
+
+    let mut deepest_file = File::create(src_dir.join("shared/deepest.txt")).unwrap();
+    writeln!(deepest_file, "This is the deepest file.").unwrap();
+
+    let output = Command::new("cargo")
+        .arg("run")
+        .arg("--")
+        .arg("--src")
+        .arg(src_dir.to_str().unwrap())
+        .arg("--target")
+        .arg(target_dir.to_str().unwrap())
+        .output()
+        .expect("Failed to execute process");
+
+    assert!(output.status.success());
+
+    let output_content = fs::read_to_string(target_dir.join("main.txt")).unwrap();
+    assert!(output_content.contains("This is the header."));
+    assert!(output_content.contains("This is the snippet."));
+    assert!(output_content.contains("This is the deepest file."));
+    assert!(output_content.contains("This is the main file."));
+    assert!(!output_content.contains("--include"));
+}
+
+#[test]
+fn test_include_cycle_is_broken() {
+    let temp_dir = tempdir().unwrap();
+    let src_dir = temp_dir.path().join("src");
+    let target_dir = temp_dir.path().join("target");
+
+    fs::create_dir_all(&src_dir).unwrap();
+    fs::create_dir_all(&target_dir).unwrap();
+
+    let mut a_file = File::create(src_dir.join("a.txt")).unwrap();
+    writeln!(a_file, "This is a.").unwrap();
+    writeln!(a_file, "--include b.txt").unwrap();
+
+    let mut b_file = File::create(src_dir.join("b.txt")).unwrap();
+    writeln!(b_file, "This is b.").unwrap();
+    writeln!(b_file, "--include a.txt").unwrap();
+
+    let output = Command::new("cargo")
+        .arg("run")
+        .arg("--")
+        .arg("--src")
+        .arg(src_dir.to_str().unwrap())
+        .arg("--target")
+        .arg(target_dir.to_str().unwrap())
+        .output()
+        .expect("Failed to execute process");
+
+    assert!(output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("include cycle, skipping: ") && stderr.contains("a.txt -> "),
+        "{}",
+        stderr
+    );
+
+    let output_content = fs::read_to_string(target_dir.join("a.txt")).unwrap();
+    assert_eq!(output_content, "This is a.\nThis is b.\n--include a.txt\n");
+}
+
+#[test]
+fn test_max_depth_limits_nested_includes() {
+    let temp_dir = tempdir().unwrap();
+    let src_dir = temp_dir.path().join("src");
+    let target_dir = temp_dir.path().join("target");
+
+    fs::create_dir_all(&src_dir).unwrap();
+    fs::create_dir_all(&target_dir).unwrap();
+
+    let mut main_file = File::create(src_dir.join("main.txt")).unwrap();
+    writeln!(main_file, "This is the main file.").unwrap();
+    writeln!(main_file, "--include level1.txt").unwrap();
+    for level in 1..4 {
+        let mut level_file = File::create(src_dir.join(format!("level{}.txt", level))).unwrap();
+        writeln!(level_file, "This is level {}.", level).unwrap();
+        writeln!(level_file, "--include level{}.txt", level + 1).unwrap();
+    }
+    let mut level_file = File::create(src_dir.join("level4.txt")).unwrap();
+    writeln!(level_file, "This is level 4.").unwrap();
+
+    let output = Command::new("cargo")
+        .arg("run")
+        .arg("--")
+        .arg("--src")
+        .arg(src_dir.to_str().unwrap())
+        .arg("--target")
+        .arg(target_dir.to_str().unwrap())
+        .arg("--max-depth")
+        .arg("2")
+        .output()
+        .expect("Failed to execute process");
+
+    assert!(output.status.success());
+
+    let output_content = fs::read_to_string(target_dir.join("main.txt")).unwrap();
+    assert!(output_content.contains("This is level 1."));
+    assert!(output_content.contains("This is level 2."));
+    assert!(output_content.contains("--include level3.txt"));
+    assert!(!output_content.contains("This is level 3."));
+    assert!(!output_content.contains("This is level 4."));
+}
+
+#[test]
+fn test_include_byte_range() {
+    let temp_dir = tempdir().unwrap();
+    let src_dir = temp_dir.path().join("src");
+    let target_dir = temp_dir.path().join("target");
+
+    fs::create_dir_all(&src_dir).unwrap();
+    fs::create_dir_all(&target_dir).unwrap();
+
+    // Binary around the text, so only the selected bytes can be included
+    let mut data = vec![0u8, 1, 2, 0xff];
+    data.extend_from_slice(b"Hello, world!\n");
+    data.extend_from_slice(&[0xfe, 0]);
+    fs::write(src_dir.join("data.bin"), &data).unwrap();
+    fs::write(
+        src_dir.join("range.txt"),
+        "Start\n--include data.bin@4+14\nEnd\n",
+    )
+    .unwrap();
+    fs::write(
+        src_dir.join("out_of_range.txt"),
+        "--include data.bin@10+100\n",
+    )
+    .unwrap();
+
+    let output = Command::new("cargo")
+        .arg("run")
+        .arg("--")
+        .arg("--src")
+        .arg(src_dir.to_str().unwrap())
+        .arg("--target")
+        .arg(target_dir.to_str().unwrap())
+        .output()
+        .expect("Failed to execute process");
+
+    assert!(output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("byte range 10+100 is outside data.bin"),
+        "{}",
+        stderr
+    );
+
+    let range_content = fs::read_to_string(target_dir.join("range.txt")).unwrap();
+    assert_eq!(range_content, "Start\nHello, world!\nEnd\n");
+
+    // An out of range request leaves the directive as it is
+    let out_of_range_content = fs::read_to_string(target_dir.join("out_of_range.txt")).unwrap();
+    assert_eq!(out_of_range_content, "--include data.bin@10+100\n");
+}
+
+#[test]
+fn test_include_byte_range_of_raw_bytes() {
+    let temp_dir = tempdir().unwrap();
+    let src_dir = temp_dir.path().join("src");
+    let target_dir = temp_dir.path().join("target");
+
+    fs::create_dir_all(&src_dir).unwrap();
+    fs::create_dir_all(&target_dir).unwrap();
+
+    // Starts like a UTF-16 byte order mark, and isn't UTF-8
+    let slice = [0xff, 0xfe, b'A', 0, 0x80, b'\r', b'\n'];
+    let mut data = b"head".to_vec();
+    data.extend_from_slice(&slice);
+    fs::write(src_dir.join("data.bin"), &data).unwrap();
+    fs::write(
+        src_dir.join("raw.txt"),
+        "Start\n--include data.bin@4+7\nEnd\n",
+    )
+    .unwrap();
+    // Indented, the bytes would have to be text
+    fs::write(src_dir.join("indented.txt"), "  --include data.bin@4+7\n").unwrap();
+
+    let output = Command::new("cargo")
+        .arg("run")
+        .arg("--")
+        .arg("--src")
+        .arg(src_dir.to_str().unwrap())
+        .arg("--target")
+        .arg(target_dir.to_str().unwrap())
+        .arg("--line-endings")
+        .arg("lf")
+        .output()
+        .expect("Failed to execute process");
+
+    assert!(output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("binary data in include: data.bin"),
+        "{}",
+        stderr
+    );
+
+    // The bytes are written as they are, line ending and all
+    let mut expected = b"Start\n".to_vec();
+    expected.extend_from_slice(&slice);
+    expected.extend_from_slice(b"End\n");
+    assert_eq!(fs::read(target_dir.join("raw.txt")).unwrap(), expected);
+
+    let indented_content = fs::read_to_string(target_dir.join("indented.txt")).unwrap();
+    assert_eq!(indented_content, "  --include data.bin@4+7\n");
+}
+
+#[test]
+fn test_include_line_range() {
+    let temp_dir = tempdir().unwrap();
+    let src_dir = temp_dir.path().join("src");
+    let target_dir = temp_dir.path().join("target");
+
+    fs::create_dir_all(&src_dir).unwrap();
+    fs::create_dir_all(&target_dir).unwrap();
+
+    let mut sample_file = File::create(src_dir.join("sample.rs")).unwrap();
+    for line in 1..=5 {
+        writeln!(sample_file, "line {}", line).unwrap();
+    }
+
+    let mut range_file = File::create(src_dir.join("range.txt")).unwrap();
+    writeln!(range_file, "--include sample.rs:2-3").unwrap();
+
+    let mut open_file = File::create(src_dir.join("open.txt")).unwrap();
+    writeln!(open_file, "--include sample.rs:4-").unwrap();
+
+    let mut out_of_range_file = File::create(src_dir.join("out_of_range.txt")).unwrap();
+    writeln!(out_of_range_file, "--include sample.rs:4-10").unwrap();
+
+    let output = Command::new("cargo")
+        .arg("run")
+        .arg("--")
+        .arg("--src")
+        .arg(src_dir.to_str().unwrap())
+        .arg("--target")
+        .arg(target_dir.to_str().unwrap())
+        .arg("-v")
+        .output()
+        .expect("Failed to execute process");
+
+    assert!(output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("invalid line range \"4-10\""));
+
+    let range_content = fs::read_to_string(target_dir.join("range.txt")).unwrap();
+    assert_eq!(range_content, "line 2\nline 3\n");
+
+    let open_content = fs::read_to_string(target_dir.join("open.txt")).unwrap();
+    assert_eq!(open_content, "line 4\nline 5\n");
+
+    // An out of range request falls back to the whole file
+    let out_of_range_content = fs::read_to_string(target_dir.join("out_of_range.txt")).unwrap();
+    assert_eq!(
+        out_of_range_content,
+        "line 1\nline 2\nline 3\nline 4\nline 5\n"
+    );
+}
+
+#[test]
+fn test_quoted_include_paths() {
+    let temp_dir = tempdir().unwrap();
+    let src_dir = temp_dir.path().join("src");
+    let target_dir = temp_dir.path().join("target");
+
+    fs::create_dir_all(src_dir.join("my docs")).unwrap();
+    fs::write(
+        src_dir.join("my docs/header file.txt"),
+        "line 1\nline 2\nline 3\n",
+    )
+    .unwrap();
+    fs::write(
+        src_dir.join("double.txt"),
+        "--include \"my docs/header file.txt\"\n",
+    )
+    .unwrap();
+    fs::write(
+        src_dir.join("single.txt"),
+        "--include 'my docs/header file.txt' # the shared header\n",
+    )
+    .unwrap();
+    fs::write(
+        src_dir.join("range.txt"),
+        "--include \"my docs/header file.txt\":2-3\n",
+    )
+    .unwrap();
+    fs::write(
+        src_dir.join("unterminated.txt"),
+        "--include \"my docs/header file.txt\n",
+    )
+    .unwrap();
+
+    let output = Command::new("cargo")
+        .arg("run")
+        .arg("--")
+        .arg("--src")
+        .arg(src_dir.to_str().unwrap())
+        .arg("--target")
+        .arg(target_dir.to_str().unwrap())
+        .output()
+        .expect("Failed to execute process");
+
+    assert!(output.status.success());
+    let read = |name: &str| fs::read_to_string(target_dir.join(name)).unwrap();
+    assert_eq!(read("double.txt"), "line 1\nline 2\nline 3\n");
+    assert_eq!(read("single.txt"), "line 1\nline 2\nline 3\n");
+    assert_eq!(read("range.txt"), "line 2\nline 3\n");
+
+    // An unterminated quote is taken as part of the path, which doesn't exist
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("include not found"), "{}", stderr);
+    assert_eq!(
+        read("unterminated.txt"),
+        "--include \"my docs/header file.txt\n"
+    );
+}
+
+#[test]
+fn test_include_named_region() {
+    let temp_dir = tempdir().unwrap();
+    let src_dir = temp_dir.path().join("src");
+    let target_dir = temp_dir.path().join("target");
+
+    fs::create_dir_all(&src_dir).unwrap();
+    fs::create_dir_all(&target_dir).unwrap();
+
+    let mut utils_file = File::create(src_dir.join("utils.rs")).unwrap();
+    writeln!(utils_file, "fn before() {{}}").unwrap();
+    writeln!(utils_file, "// region: setup").unwrap();
+    writeln!(utils_file, "fn setup() {{}}").unwrap();
+    writeln!(utils_file, "// region: inner").unwrap();
+    writeln!(utils_file, "fn inner() {{}}").unwrap();
+    writeln!(utils_file, "// region: overlap").unwrap();
+    writeln!(utils_file, "// endregion: inner").unwrap();
+    writeln!(utils_file, "fn overlapping() {{}}").unwrap();
+    writeln!(utils_file, "// endregion: setup").unwrap();
+    writeln!(utils_file, "fn after_setup() {{}}").unwrap();
+    writeln!(utils_file, "// endregion: overlap").unwrap();
+
+    let mut main_file = File::create(src_dir.join("main.md")).unwrap();
+    writeln!(main_file, "Setup:").unwrap();
+    writeln!(main_file, "--include utils.rs#setup").unwrap();
+    writeln!(main_file, "Inner:").unwrap();
+    writeln!(main_file, "--include utils.rs#inner").unwrap();
+    writeln!(main_file, "Overlap:").unwrap();
+    writeln!(main_file, "--include utils.rs#overlap").unwrap();
+    writeln!(main_file, "Missing:").unwrap();
+    writeln!(main_file, "--include utils.rs#missing").unwrap();
+
+    let output = Command::new("cargo")
+        .arg("run")
+        .arg("--")
+        .arg("--src")
+        .arg(src_dir.to_str().unwrap())
+        .arg("--target")
+        .arg(target_dir.to_str().unwrap())
+        .arg("-v")
+        .output()
+        .expect("Failed to execute process");
+
+    assert!(output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("region \"missing\" not found"));
+
+    let output_content = fs::read_to_string(target_dir.join("main.md")).unwrap();
+    assert_eq!(
+        output_content,
+        "Setup:\n\
+         fn setup() {}\n\
+         fn inner() {}\n\
+         fn overlapping() {}\n\
+         Inner:\n\
+         fn inner() {}\n\
+         Overlap:\n\
+         fn overlapping() {}\n\
+         fn after_setup() {}\n\
+         Missing:\n\
+         --include utils.rs#missing\n"
+    );
+}
+
+#[test]
+fn test_fenced_includes() {
+    let temp_dir = tempdir().unwrap();
+    let src_dir = temp_dir.path().join("src");
+    let target_dir = temp_dir.path().join("target");
+
+    fs::create_dir_all(&src_dir).unwrap();
+    fs::create_dir_all(&target_dir).unwrap();
+
+    let mut sample_file = File::create(src_dir.join("sample.rs")).unwrap();
+    writeln!(sample_file, "fn one() {{}}").unwrap();
+    writeln!(sample_file, "fn two() {{}}").unwrap();
+    writeln!(sample_file, "fn three() {{}}").unwrap();
+
+    let mut main_file = File::create(src_dir.join("main.md")).unwrap();
+    writeln!(main_file, "Explicit:").unwrap();
+    writeln!(main_file, "--include --fence=rs sample.rs").unwrap();
+    writeln!(main_file, "Inferred:").unwrap();
+    writeln!(main_file, "--include --fence sample.rs").unwrap();
+    writeln!(main_file, "Range:").unwrap();
+    writeln!(main_file, "  --include --fence sample.rs:2-3").unwrap();
+
+    let output = Command::new("cargo")
+        .arg("run")
+        .arg("--")
+        .arg("--src")
+        .arg(src_dir.to_str().unwrap())
+        .arg("--target")
+        .arg(target_dir.to_str().unwrap())
+        .output()
+        .expect("Failed to execute process");
+
+    assert!(output.status.success());
+
+    let output_content = fs::read_to_string(target_dir.join("main.md")).unwrap();
+    assert_eq!(
+        output_content,
+        "Explicit:\n\
+         ```rs\n\
+         fn one() {}\n\
+         fn two() {}\n\
+         fn three() {}\n\
+         ```\n\
+         Inferred:\n\
+         ```rust\n\
+         fn one() {}\n\
+         fn two() {}\n\
+         fn three() {}\n\
+         ```\n\
+         Range:\n  \
+         ```rust\n  \
+         fn two() {}\n  \
+         fn three() {}\n  \
+         ```\n"
+    );
+}
+
+#[test]
+fn test_multiple_source_directories() {
+    let temp_dir = tempdir().unwrap();
+    let docs_dir = temp_dir.path().join("docs");
+    let shared_dir = temp_dir.path().join("shared");
+    let target_dir = temp_dir.path().join("target");
+
+    fs::create_dir_all(docs_dir.join("guide")).unwrap();
+    fs::create_dir_all(&shared_dir).unwrap();
+
+    let mut index_file = File::create(docs_dir.join("index.txt")).unwrap();
+    writeln!(index_file, "--include guide/intro.txt").unwrap();
+    writeln!(index_file, "This is the index.").unwrap();
+
+    let mut intro_file = File::create(docs_dir.join("guide/intro.txt")).unwrap();
+    writeln!(intro_file, "This is the intro.").unwrap();
+
+    // Includes resolve relative to the including file, so shared files can reach into docs
+    let mut footer_file = File::create(shared_dir.join("footer.txt")).unwrap();
+    writeln!(footer_file, "--include ../docs/guide/intro.txt").unwrap();
+    writeln!(footer_file, "This is the footer.").unwrap();
+
+    let output = Command::new("cargo")
+        .arg("run")
+        .arg("--")
+        .arg("--src")
+        .arg(docs_dir.to_str().unwrap())
+        .arg("--src")
+        .arg(shared_dir.to_str().unwrap())
+        .arg("--target")
+        .arg(target_dir.to_str().unwrap())
+        .output()
+        .expect("Failed to execute process");
+
+    assert!(output.status.success());
+
+    let index_content = fs::read_to_string(target_dir.join("index.txt")).unwrap();
+    assert_eq!(index_content, "This is the intro.\nThis is the index.\n");
+
+    assert!(target_dir.join("guide/intro.txt").exists());
+
+    let footer_content = fs::read_to_string(target_dir.join("footer.txt")).unwrap();
+    assert_eq!(footer_content, "This is the intro.\nThis is the footer.\n");
+}
+
+#[test]
+fn test_strict_fails_on_missing_include() {
+    let temp_dir = tempdir().unwrap();
+    let src_dir = temp_dir.path().join("src");
+    let target_dir = temp_dir.path().join("target");
+
+    fs::create_dir_all(&src_dir).unwrap();
+
+    let mut main_file = File::create(src_dir.join("main.txt")).unwrap();
+    writeln!(main_file, "--include missing.txt").unwrap();
+    writeln!(main_file, "This is the main file.").unwrap();
+
+    let run = |strict: bool| {
+        let mut command = Command::new("cargo");
+        command
+            .arg("run")
+            .arg("--")
+            .arg("--src")
+            .arg(src_dir.to_str().unwrap())
+            .arg("--target")
+            .arg(target_dir.to_str().unwrap());
+        if strict {
+            command.arg("--strict");
+        }
+        command.output().expect("Failed to execute process")
+    };
+
+    let output = run(false);
+    assert!(output.status.success());
+    let output_content = fs::read_to_string(target_dir.join("main.txt")).unwrap();
+    assert!(output_content.contains("--include missing.txt"));
+
+    let output = run(true);
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("missing.txt"));
+    assert!(stderr.contains("main.txt"));
+    // The error says where the directive is
+    assert!(stderr.contains("main.txt:1: "), "{}", stderr);
+}
+
+#[test]
+fn test_failed_files_give_non_zero_exit_code() {
+    let temp_dir = tempdir().unwrap();
+    let src_dir = temp_dir.path().join("src");
+    let target_dir = temp_dir.path().join("target");
+
+    fs::create_dir_all(&src_dir).unwrap();
+    fs::create_dir_all(&target_dir).unwrap();
+
+    fs::write(src_dir.join("good.txt"), "Good.\n").unwrap();
+    fs::write(src_dir.join("bad.txt"), "Bad.\n").unwrap();
+    // A directory where the output should go can't be replaced, which fails even when running as
+    // root, unlike an unreadable source file
+    fs::create_dir_all(target_dir.join("bad.txt").join("blocker")).unwrap();
+
+    let output = Command::new("cargo")
+        .arg("run")
+        .arg("--")
+        .arg("--src")
+        .arg(src_dir.to_str().unwrap())
+        .arg("--target")
+        .arg(target_dir.to_str().unwrap())
+        .arg("--summary")
+        .output()
+        .expect("Failed to execute process");
+
+    assert_eq!(output.status.code(), Some(1));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("bad.txt"));
+    assert!(stderr.contains("1 file(s) could not be processed"));
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains(", 1 failed"));
+
+    // The other files are still processed
+    assert_eq!(
+        fs::read_to_string(target_dir.join("good.txt")).unwrap(),
+        "Good.\n"
+    );
+}
+
+#[test]
+fn test_exit_codes() {
+    let temp_dir = tempdir().unwrap();
+
+    let run = |name: &str, files: &[(&str, &str)], extra_args: &[&str]| {
+        let src_dir = temp_dir.path().join(name);
+        fs::create_dir_all(&src_dir).unwrap();
+        for (file, content) in files {
+            fs::write(src_dir.join(file), content).unwrap();
+        }
+        let output = Command::new("cargo")
+            .arg("run")
+            .arg("--")
+            .arg("--src")
+            .arg(src_dir.to_str().unwrap())
+            .arg("--target")
+            .arg(temp_dir.path().join(format!("{}_target", name)))
+            .args(extra_args)
+            .output()
+            .expect("Failed to execute process");
+        output.status.code()
+    };
+
+    let missing = [("main.txt", "--include missing.txt\n")];
+    assert_eq!(run("missing", &missing, &["--strict"]), Some(2));
+    assert_eq!(run("check", &missing, &["--check"]), Some(2));
+    // Without --strict a missing include is only a warning
+    assert_eq!(run("lenient", &missing, &[]), Some(0));
+    assert_eq!(run("warnings", &missing, &["--fail-on-warning"]), Some(5));
+
+    let cycle = [
+        ("a.txt", "--include b.txt\n"),
+        ("b.txt", "--include a.txt\n"),
+    ];
+    assert_eq!(run("cycle", &cycle, &["--strict"]), Some(3));
+
+    assert_eq!(run("usage", &[], &["--jobs", "none"]), Some(4));
+    assert_eq!(run("help", &[], &["--help"]), Some(0));
+}
+
+#[test]
+fn test_errors_are_shown_as_messages() {
+    let temp_dir = tempdir().unwrap();
+
+    let run = |name: &str, files: &[(&str, &str)], extra_args: &[&str]| {
+        let src_dir = temp_dir.path().join(name);
+        fs::create_dir_all(&src_dir).unwrap();
+        for (file, content) in files {
+            fs::write(src_dir.join(file), content).unwrap();
+        }
+        let output = Command::new("cargo")
+            .arg("run")
+            .arg("--")
+            .arg("--src")
+            .arg(src_dir.to_str().unwrap())
+            .arg("--target")
+            .arg(temp_dir.path().join(format!("{}_target", name)))
+            .args(extra_args)
+            .output()
+            .expect("Failed to execute process");
+        String::from_utf8_lossy(&output.stderr).into_owned()
+    };
+    let last_line = |stderr: &str| stderr.lines().last().unwrap_or("").to_string();
+
+    let missing = [("main.txt", "--include missing.txt\n")];
+    assert_eq!(
+        last_line(&run("missing", &missing, &["--strict"])),
+        "Error: 1 file(s) have includes that could not be resolved"
+    );
+    assert_eq!(
+        last_line(&run("check", &missing, &["--check"])),
+        "Error: 1 broken include(s) found"
+    );
+    assert_eq!(
+        last_line(&run("warnings", &missing, &["--fail-on-warning"])),
+        "Error: 1 warning(s) reported"
+    );
+    let unmatched = [("main.txt", "--if FOO\nText.\n")];
+    assert_eq!(
+        last_line(&run("unmatched", &unmatched, &[])),
+        "Error: 1 file(s) could not be processed"
+    );
+}
+
+#[test]
+fn test_escaped_include_is_literal() {
+    let temp_dir = tempdir().unwrap();
+    let src_dir = temp_dir.path().join("src");
+    let target_dir = temp_dir.path().join("target");
+
+    fs::create_dir_all(&src_dir).unwrap();
+
+    let mut include_file = File::create(src_dir.join("include.txt")).unwrap();
+    writeln!(include_file, "This should not be included.").unwrap();
+
+    let mut docs_file = File::create(src_dir.join("docs.txt")).unwrap();
+    writeln!(docs_file, "To include a file, write:").unwrap();
+    writeln!(docs_file, "\\--include include.txt").unwrap();
+    writeln!(docs_file, "A backslash elsewhere \\ is left alone.").unwrap();
+    writeln!(docs_file, "\\ --include include.txt").unwrap();
+
+    let output = Command::new("cargo")
+        .arg("run")
+        .arg("--")
+        .arg("--src")
+        .arg(src_dir.to_str().unwrap())
+        .arg("--target")
+        .arg(target_dir.to_str().unwrap())
+        .output()
+        .expect("Failed to execute process");
+
+    assert!(output.status.success());
+
+    let output_content = fs::read_to_string(target_dir.join("docs.txt")).unwrap();
+    assert_eq!(
+        output_content,
+        "To include a file, write:\n\
+         --include include.txt\n\
+         A backslash elsewhere \\ is left alone.\n\
+         \\ --include include.txt\n"
+    );
+}
+
+#[test]
+fn test_include_prefix_per_extension() {
+    let temp_dir = tempdir().unwrap();
+    let src_dir = temp_dir.path().join("src");
+    let target_dir = temp_dir.path().join("target");
+
+    fs::create_dir_all(&src_dir).unwrap();
+    fs::create_dir_all(&target_dir).unwrap();
+
+    fs::write(src_dir.join("header.txt"), "Header\n").unwrap();
+    fs::write(
+        src_dir.join("main.rs"),
+        "//include header.txt\n--include header.txt\n",
+    )
+    .unwrap();
+    fs::write(
+        src_dir.join("index.html"),
+        "<!--include--> header.txt\n//include header.txt\n",
+    )
+    .unwrap();
+    fs::write(
+        src_dir.join("notes.txt"),
+        "--include header.txt\n//include header.txt\n",
+    )
+    .unwrap();
+
+    let output = Command::new("cargo")
+        .arg("run")
+        .arg("--")
+        .arg("--src")
+        .arg(src_dir.to_str().unwrap())
+        .arg("--target")
+        .arg(target_dir.to_str().unwrap())
+        .arg("--include-for")
+        .arg("rs=//include")
+        .arg("--include-for")
+        .arg("html=<!--include-->")
+        .output()
+        .expect("Failed to execute process");
+
+    assert!(output.status.success());
+
+    let rust_content = fs::read_to_string(target_dir.join("main.rs")).unwrap();
+    assert_eq!(rust_content, "Header\n--include header.txt\n");
+
+    let html_content = fs::read_to_string(target_dir.join("index.html")).unwrap();
+    assert_eq!(html_content, "Header\n//include header.txt\n");
+
+    // Other extensions use the global prefix
+    let text_content = fs::read_to_string(target_dir.join("notes.txt")).unwrap();
+    assert_eq!(text_content, "Header\n//include header.txt\n");
+}
+
+#[test]
+fn test_multiple_include_prefixes() {
+    let temp_dir = tempdir().unwrap();
+    let src_dir = temp_dir.path().join("src");
+    let target_dir = temp_dir.path().join("target");
+
+    fs::create_dir_all(&src_dir).unwrap();
+    fs::create_dir_all(&target_dir).unwrap();
+
+    fs::write(src_dir.join("header.txt"), "Header\n").unwrap();
+    fs::write(src_dir.join("footer.txt"), "Footer\n").unwrap();
+    fs::write(
+        src_dir.join("page.txt"),
+        "#include header.txt\nBody\n@include footer.txt\n--include header.txt\n",
+    )
+    .unwrap();
+
+    let output = Command::new("cargo")
+        .arg("run")
+        .arg("--")
+        .arg("--src")
+        .arg(src_dir.to_str().unwrap())
+        .arg("--target")
+        .arg(target_dir.to_str().unwrap())
+        .arg("--include")
+        .arg("#include")
+        .arg("--include")
+        .arg("@include")
+        .output()
+        .expect("Failed to execute process");
+
+    assert!(output.status.success());
+
+    // Both prefixes are expanded, and the default is replaced rather than added to
+    let content = fs::read_to_string(target_dir.join("page.txt")).unwrap();
+    assert_eq!(content, "Header\nBody\nFooter\n--include header.txt\n");
+}
+
+#[test]
+fn test_include_suffix() {
+    let temp_dir = tempdir().unwrap();
+    let src_dir = temp_dir.path().join("src");
+    let target_dir = temp_dir.path().join("target");
+
+    fs::create_dir_all(&src_dir).unwrap();
+    fs::create_dir_all(&target_dir).unwrap();
+
+    fs::write(src_dir.join("header.html"), "<header></header>\n").unwrap();
+    fs::write(src_dir.join("footer.html"), "<footer></footer>\n").unwrap();
+    fs::write(
+        src_dir.join("index.html"),
+        "<!--include header.html-->\n<main></main>\n  <!--include footer.html -->\n",
+    )
+    .unwrap();
+
+    let output = Command::new("cargo")
+        .arg("run")
+        .arg("--")
+        .arg("--src")
+        .arg(src_dir.to_str().unwrap())
+        .arg("--target")
+        .arg(target_dir.to_str().unwrap())
+        .arg("--include")
+        .arg("<!--include")
+        .arg("--include-suffix")
+        .arg("-->")
+        .output()
+        .expect("Failed to execute process");
+
+    assert!(output.status.success());
+
+    let output_content = fs::read_to_string(target_dir.join("index.html")).unwrap();
+    assert_eq!(
+        output_content,
+        "<header></header>\n<main></main>\n  <footer></footer>\n"
+    );
+}
+
+#[test]
+fn test_variable_substitution() {
+    let temp_dir = tempdir().unwrap();
+    let src_dir = temp_dir.path().join("src");
+    let target_dir = temp_dir.path().join("target");
+
+    fs::create_dir_all(&src_dir).unwrap();
+    fs::create_dir_all(&target_dir).unwrap();
+
+    fs::write(
+        src_dir.join("footer.txt"),
+        "{{PRODUCT}} {{VERSION}} footer\n",
+    )
+    .unwrap();
+    fs::write(
+        src_dir.join("main.txt"),
+        "Welcome to {{PRODUCT}}!\nBuilt by {{AUTHOR}}.\n--include footer.txt\n",
+    )
+    .unwrap();
+
+    let output = Command::new("cargo")
+        .arg("run")
+        .arg("--")
+        .arg("--src")
+        .arg(src_dir.to_str().unwrap())
+        .arg("--target")
+        .arg(target_dir.to_str().unwrap())
+        .arg("--define")
+        .arg("PRODUCT=Acme")
+        .arg("--define")
+        .arg("VERSION=2.1")
+        .arg("--warn-undefined-variables")
+        .output()
+        .expect("Failed to execute process");
+
+    assert!(output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("Variable \"AUTHOR\" is not defined"));
+
+    let output_content = fs::read_to_string(target_dir.join("main.txt")).unwrap();
+    assert_eq!(
+        output_content,
+        "Welcome to Acme!\nBuilt by {{AUTHOR}}.\nAcme 2.1 footer\n"
+    );
+}
+
+#[test]
+fn test_undefined_variable_is_warned_about_once() {
+    let temp_dir = tempdir().unwrap();
+    let src_dir = temp_dir.path().join("src");
+    let target_dir = temp_dir.path().join("target");
+
+    fs::create_dir_all(&src_dir).unwrap();
+
+    fs::write(src_dir.join("main.txt"), "Hello {{WHO}}\n").unwrap();
+
+    let output = Command::new("cargo")
+        .arg("run")
+        .arg("--")
+        .arg("--src")
+        .arg(src_dir.to_str().unwrap())
+        .arg("--target")
+        .arg(target_dir.to_str().unwrap())
+        .arg("--warn-undefined-variables")
+        .arg("--fail-on-warning")
+        .output()
+        .expect("Failed to execute process");
+
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert_eq!(
+        stderr.matches("Variable \"WHO\" is not defined").count(),
+        1,
+        "{}",
+        stderr
+    );
+    assert!(stderr.contains("1 warning(s) reported"), "{}", stderr);
+}
+
+#[test]
+fn test_passes() {
+    let temp_dir = tempdir().unwrap();
+    let src_dir = temp_dir.path().join("src");
+
+    fs::create_dir_all(&src_dir).unwrap();
+
+    fs::write(src_dir.join("en.txt"), "Hello {{NAME}}.\n").unwrap();
+    fs::write(
+        src_dir.join("main.txt"),
+        "--include {{LANG}}.txt\n--include en.txt\n{{NAME}} was here.\n",
+    )
+    .unwrap();
+
+    let run = |target: &str, passes: &str| {
+        let target_dir = temp_dir.path().join(target);
+        let output = Command::new("cargo")
+            .arg("run")
+            .arg("--")
+            .arg("--src")
+            .arg(src_dir.to_str().unwrap())
+            .arg("--target")
+            .arg(target_dir.to_str().unwrap())
+            .arg("--define")
+            .arg("LANG=en")
+            .arg("--define")
+            .arg("NAME=Ann")
+            .arg("--passes")
+            .arg(passes)
+            .output()
+            .expect("Failed to execute process");
+        assert!(output.status.success());
+        let stderr = String::from_utf8_lossy(&output.stderr).to_string();
+        (
+            fs::read_to_string(target_dir.join("main.txt")).unwrap(),
+            stderr,
+        )
+    };
+
+    // The placeholder gives the include path only when it is substituted first
+    let (content, stderr) = run("substitute_first", "substitute,include");
+    assert_eq!(content, "Hello Ann.\nHello Ann.\nAnn was here.\n");
+    assert!(!stderr.contains("include not found"), "{}", stderr);
+
+    // Otherwise the directive is left as it is, and only then substituted
+    let (content, stderr) = run("include_first", "include,substitute");
+    assert_eq!(content, "--include en.txt\nHello Ann.\nAnn was here.\n");
+    assert!(
+        stderr.contains("include not found: {{LANG}}.txt"),
+        "{}",
+        stderr
+    );
+
+    let output = Command::new("cargo")
+        .arg("run")
+        .arg("--")
+        .arg("--src")
+        .arg(src_dir.to_str().unwrap())
+        .arg("--passes")
+        .arg("include")
+        .output()
+        .expect("Failed to execute process");
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("unsupported pass order"));
+}
+
+#[test]
+fn test_conditional_includes() {
+    let temp_dir = tempdir().unwrap();
+    let src_dir = temp_dir.path().join("src");
+
+    fs::create_dir_all(&src_dir).unwrap();
+
+    fs::write(src_dir.join("extra.txt"), "Extra.\n").unwrap();
+    fs::write(src_dir.join("beta.txt"), "Beta.\n").unwrap();
+    fs::write(src_dir.join("always.txt"), "Always.\n").unwrap();
+    fs::write(
+        src_dir.join("main.txt"),
+        "Start.\n\
+         --include-if FEATURE_X extra.txt\n\
+         --include-if CHANNEL beta.txt\n\
+         --include always.txt\n\
+         End.\n",
+    )
+    .unwrap();
+
+    let run = |target: &str, extra_args: &[&str]| {
+        let target_dir = temp_dir.path().join(target);
+        let output = Command::new("cargo")
+            .arg("run")
+            .arg("--")
+            .arg("--src")
+            .arg(src_dir.to_str().unwrap())
+            .arg("--target")
+            .arg(target_dir.to_str().unwrap())
+            .args(extra_args)
+            .output()
+            .expect("Failed to execute process");
+        assert!(output.status.success());
+        fs::read_to_string(target_dir.join("main.txt")).unwrap()
+    };
+
+    // Flags can be set with --feature or as a variable with --define
+    assert_eq!(
+        run(
+            "with_flags",
+            &["--feature", "FEATURE_X", "--define", "CHANNEL=beta"]
+        ),
+        "Start.\nExtra.\nBeta.\nAlways.\nEnd.\n"
+    );
+    assert_eq!(run("without_flags", &[]), "Start.\nAlways.\nEnd.\n");
+}
+
+#[test]
+fn test_conditional_regions() {
+    let temp_dir = tempdir().unwrap();
+    let src_dir = temp_dir.path().join("src");
+
+    fs::create_dir_all(&src_dir).unwrap();
+
+    fs::write(src_dir.join("extra.txt"), "Extra.\n").unwrap();
+    fs::write(
+        src_dir.join("main.txt"),
+        "Start.\n\
+         --if FEATURE_X\n\
+         X.\n\
+         --include extra.txt\n\
+         \x20\x20--if FEATURE_Y\n\
+         X and Y.\n\
+         \x20\x20--endif\n\
+         --endif\n\
+         --if FEATURE_Y\n\
+         Y.\n\
+         --endif\n\
+         End.\n",
+    )
+    .unwrap();
+
+    let run = |target: &str, extra_args: &[&str]| {
+        let target_dir = temp_dir.path().join(target);
+        let output = Command::new("cargo")
+            .arg("run")
+            .arg("--")
+            .arg("--src")
+            .arg(src_dir.to_str().unwrap())
+            .arg("--target")
+            .arg(target_dir.to_str().unwrap())
+            .args(extra_args)
+            .output()
+            .expect("Failed to execute process");
+        assert!(output.status.success());
+        fs::read_to_string(target_dir.join("main.txt")).unwrap()
+    };
+
+    // A kept region keeps its includes, and a region nested in a dropped one is dropped too
+    assert_eq!(
+        run("x", &["--feature", "FEATURE_X"]),
+        "Start.\nX.\nExtra.\nEnd.\n"
+    );
+    assert_eq!(run("y", &["--feature", "FEATURE_Y"]), "Start.\nY.\nEnd.\n");
+    assert_eq!(
+        run(
+            "both",
+            &["--feature", "FEATURE_X", "--feature", "FEATURE_Y"]
+        ),
+        "Start.\nX.\nExtra.\nX and Y.\nY.\nEnd.\n"
+    );
+    assert_eq!(run("neither", &[]), "Start.\nEnd.\n");
+}
+
+#[test]
+fn test_unterminated_conditional_region() {
+    let temp_dir = tempdir().unwrap();
+    let src_dir = temp_dir.path().join("src");
+    let target_dir = temp_dir.path().join("target");
+
+    fs::create_dir_all(&src_dir).unwrap();
+
+    fs::write(src_dir.join("part.txt"), "#if FEATURE_X\nPart.\n").unwrap();
+    fs::write(
+        src_dir.join("main.txt"),
+        "Start.\n--include part.txt\nEnd.\n",
+    )
+    .unwrap();
+
+    let output = Command::new("cargo")
+        .arg("run")
+        .arg("--")
+        .arg("--src")
+        .arg(src_dir.to_str().unwrap())
+        .arg("--target")
+        .arg(target_dir.to_str().unwrap())
+        .arg("--if-marker")
+        .arg("#if")
+        .arg("--endif-marker")
+        .arg("#endif")
+        .output()
+        .expect("Failed to execute process");
+
+    // The error names the file and line of the --if, and the rest of the file isn't dropped
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("Unmatched \"#if FEATURE_X\" on line 1"),
+        "{}",
+        stderr
+    );
+    assert!(stderr.contains("part.txt"), "{}", stderr);
+    assert!(!target_dir.join("main.txt").exists());
+}
+
+#[test]
+fn test_list_includes() {
+    let temp_dir = tempdir().unwrap();
+    let src_dir = temp_dir.path().join("src");
+    let target_dir = temp_dir.path().join("target");
+
+    fs::create_dir_all(src_dir.join("shared")).unwrap();
+    fs::create_dir_all(src_dir.join("guide")).unwrap();
+
+    fs::write(src_dir.join("shared/header.txt"), "Header.\n").unwrap();
+    fs::write(src_dir.join("shared/footer.txt"), "Footer.\n").unwrap();
+    fs::write(
+        src_dir.join("guide/intro.txt"),
+        "--include ../shared/header.txt\nIntro.\n",
+    )
+    .unwrap();
+    fs::write(
+        src_dir.join("index.txt"),
+        "--include guide/intro.txt\nIndex.\n--include shared/footer.txt\n",
+    )
+    .unwrap();
+    fs::write(src_dir.join("plain.txt"), "Nothing included.\n").unwrap();
+
+    let list = |extra_args: &[&str]| {
+        let output = Command::new("cargo")
+            .arg("run")
+            .arg("--")
+            .arg("--src")
+            .arg(src_dir.to_str().unwrap())
+            .arg("--target")
+            .arg(target_dir.to_str().unwrap())
+            .arg("--list-includes")
+            .args(extra_args)
+            .output()
+            .expect("Failed to execute process");
+        assert!(output.status.success());
+        String::from_utf8(output.stdout).unwrap()
+    };
+
+    // Nested includes are listed for the outer file too
+    assert_eq!(
+        list(&[]),
+        "guide/intro.txt\n\
+         \x20   shared/header.txt\n\
+         index.txt\n\
+         \x20   guide/intro.txt\n\
+         \x20   shared/footer.txt\n\
+         \x20   shared/header.txt\n"
+    );
+    assert_eq!(
+        list(&["--reverse"]),
+        "guide/intro.txt\n\
+         \x20   index.txt\n\
+         shared/footer.txt\n\
+         \x20   index.txt\n\
+         shared/header.txt\n\
+         \x20   guide/intro.txt\n\
+         \x20   index.txt\n"
+    );
+    assert!(!target_dir.exists());
+}
+
+#[test]
+fn test_check_reports_broken_includes() {
+    let temp_dir = tempdir().unwrap();
+    let src_dir = temp_dir.path().join("src");
+    let target_dir = temp_dir.path().join("target");
+
+    fs::create_dir_all(src_dir.join("shared")).unwrap();
+    fs::create_dir_all(src_dir.join("guide")).unwrap();
+
+    fs::write(src_dir.join("shared/header.txt"), "Header.\n").unwrap();
+    fs::write(
+        src_dir.join("logo.png"),
+        [0x89, b'P', b'N', b'G', 0xff, 0xfe],
+    )
+    .unwrap();
+    fs::write(
+        src_dir.join("guide/intro.txt"),
+        "--include ../shared/header.txt\n--include ../shared/gone.txt\n--include ../logo.png\n",
+    )
+    .unwrap();
+    // The same missing include twice, and a nested include whose own include is missing
+    fs::write(
+        src_dir.join("index.txt"),
+        "--include missing.txt\n--include shared/header.txt\n--include missing.txt\n--include guide/intro.txt\n",
+    )
+    .unwrap();
+    fs::write(src_dir.join("plain.txt"), "Nothing included.\n").unwrap();
+
+    let output = Command::new("cargo")
+        .arg("run")
+        .arg("--")
+        .arg("--src")
+        .arg(src_dir.to_str().unwrap())
+        .arg("--target")
+        .arg(target_dir.to_str().unwrap())
+        .arg("--check")
+        .output()
+        .expect("Failed to execute process");
+
+    assert!(!output.status.success());
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "guide/intro.txt\n\
+         \x20   binary: logo.png\n\
+         \x20   missing: shared/gone.txt\n\
+         index.txt\n\
+         \x20   missing: missing.txt\n"
+    );
+    assert!(!target_dir.exists());
+
+    // A tree without broken includes passes
+    fs::remove_file(src_dir.join("index.txt")).unwrap();
+    fs::remove_file(src_dir.join("guide/intro.txt")).unwrap();
+    let output = Command::new("cargo")
+        .arg("run")
+        .arg("--")
+        .arg("--src")
+        .arg(src_dir.to_str().unwrap())
+        .arg("--target")
+        .arg(target_dir.to_str().unwrap())
+        .arg("--check")
+        .output()
+        .expect("Failed to execute process");
+    assert!(output.status.success());
+    assert!(output.stdout.is_empty());
+}
+
+#[test]
+fn test_include_base() {
+    let temp_dir = tempdir().unwrap();
+    let src_dir = temp_dir.path().join("src");
+    let base_dir = temp_dir.path().join("base");
+
+    fs::create_dir_all(src_dir.join("pages")).unwrap();
+    fs::create_dir_all(&base_dir).unwrap();
+
+    fs::write(src_dir.join("pages/part.txt"), "Next to the page.\n").unwrap();
+    fs::write(base_dir.join("part.txt"), "From the base.\n").unwrap();
+    fs::write(base_dir.join("absolute.txt"), "Absolute.\n").unwrap();
+    fs::write(
+        src_dir.join("pages/page.txt"),
+        format!(
+            "--include part.txt\n--include {}\n",
+            base_dir.join("absolute.txt").display()
+        ),
+    )
+    .unwrap();
+
+    let run = |target: &str, extra_args: &[&str]| {
+        let target_dir = temp_dir.path().join(target);
+        let output = Command::new("cargo")
+            .arg("run")
+            .arg("--")
+            .arg("--src")
+            .arg(src_dir.to_str().unwrap())
+            .arg("--target")
+            .arg(target_dir.to_str().unwrap())
+            .args(extra_args)
+            .output()
+            .expect("Failed to execute process");
+        assert!(output.status.success());
+        fs::read_to_string(target_dir.join("pages/page.txt")).unwrap()
+    };
+
+    assert_eq!(run("default", &[]), "Next to the page.\nAbsolute.\n");
+    assert_eq!(
+        run("with_base", &["--include-base", base_dir.to_str().unwrap()]),
+        "From the base.\nAbsolute.\n"
+    );
+}
+
+#[cfg(unix)]
+#[test]
+fn test_permission_denied() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let temp_dir = tempdir().unwrap();
+    let src_dir = temp_dir.path().join("src");
+    let target_dir = temp_dir.path().join("target");
+
+    fs::create_dir_all(&src_dir).unwrap();
+    fs::write(src_dir.join("main.txt"), "Main.\n").unwrap();
+    let locked = src_dir.join("locked.txt");
+    fs::write(&locked, "Locked.\n").unwrap();
+    fs::set_permissions(&locked, fs::Permissions::from_mode(0o000)).unwrap();
+    if File::open(&locked).is_ok() {
+        // Running as root, which can read it anyway
+        return;
+    }
+
+    let run = |strict: bool| {
+        let mut command = Command::new("cargo");
+        command
+            .arg("run")
+            .arg("--")
+            .arg("--src")
+            .arg(src_dir.to_str().unwrap())
+            .arg("--target")
+            .arg(target_dir.to_str().unwrap());
+        if strict {
+            command.arg("--strict");
+        }
+        command.output().expect("Failed to execute process")
+    };
+
+    let output = run(false);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains(&format!(
+            "Permission denied reading {:?}. Make sure the current user can read it, e.g. with `chmod u+r",
+            locked
+        )),
+        "{}",
+        stderr
+    );
+    // The other files are still processed
+    assert!(target_dir.join("main.txt").exists());
+
+    fs::remove_dir_all(&target_dir).unwrap();
+    let output = run(true);
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("Stopped at the first error"), "{}", stderr);
+}
+
+#[cfg(unix)]
+#[test]
+fn test_follow_symlinks() {
+    let temp_dir = tempdir().unwrap();
+    let src_dir = temp_dir.path().join("src");
+    let shared_dir = temp_dir.path().join("shared");
+
+    fs::create_dir_all(&src_dir).unwrap();
+    fs::create_dir_all(&shared_dir).unwrap();
+
+    fs::write(shared_dir.join("part.txt"), "Part.\n").unwrap();
+    fs::write(shared_dir.join("note.txt"), "--include part.txt\nShared.\n").unwrap();
+    fs::write(src_dir.join("main.txt"), "Main.\n").unwrap();
+    std::os::unix::fs::symlink(&shared_dir, src_dir.join("linked")).unwrap();
+
+    let run = |target: &str, extra_args: &[&str]| {
+        let target_dir = temp_dir.path().join(target);
+        let output = Command::new("cargo")
+            .arg("run")
+            .arg("--")
+            .arg("--src")
+            .arg(src_dir.to_str().unwrap())
+            .arg("--target")
+            .arg(target_dir.to_str().unwrap())
+            .args(extra_args)
+            .output()
+            .expect("Failed to execute process");
+        (output, target_dir)
+    };
+
+    let (output, target_dir) = run("default", &[]);
+    assert!(output.status.success());
+    assert!(target_dir.join("main.txt").exists());
+    assert!(!target_dir.join("linked").exists());
+
+    // Files under the link are written under the link's name
+    let (output, target_dir) = run("following", &["--follow-symlinks"]);
+    assert!(output.status.success());
+    assert_eq!(
+        fs::read_to_string(target_dir.join("linked/note.txt")).unwrap(),
+        "Part.\nShared.\n"
+    );
+
+    // A link back to a directory containing it is reported rather than followed forever
+    std::os::unix::fs::symlink(&src_dir, shared_dir.join("back")).unwrap();
+    let (output, _) = run("looping", &["--follow-symlinks"]);
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("Symlink loop"), "{}", stderr);
+}
+
+#[test]
+fn test_sandbox() {
+    let temp_dir = tempdir().unwrap();
+    let src_dir = temp_dir.path().join("src");
+
+    fs::create_dir_all(src_dir.join("pages")).unwrap();
+
+    fs::write(temp_dir.path().join("secret.txt"), "Secret.\n").unwrap();
+    fs::write(src_dir.join("shared.txt"), "Shared.\n").unwrap();
+    fs::write(
+        src_dir.join("pages/page.txt"),
+        "--include ../shared.txt\n--include ../../secret.txt\n",
+    )
+    .unwrap();
+
+    let run = |target: &str, extra_args: &[&str]| {
+        let target_dir = temp_dir.path().join(target);
+        let output = Command::new("cargo")
+            .arg("run")
+            .arg("--")
+            .arg("--src")
+            .arg(src_dir.to_str().unwrap())
+            .arg("--target")
+            .arg(target_dir.to_str().unwrap())
+            .args(extra_args)
+            .output()
+            .expect("Failed to execute process");
+        assert!(output.status.success());
+        (
+            fs::read_to_string(target_dir.join("pages/page.txt")).unwrap(),
+            String::from_utf8_lossy(&output.stderr).to_string(),
+        )
+    };
+
+    let (content, _) = run("default", &[]);
+    assert_eq!(content, "Shared.\nSecret.\n");
+
+    // Going up within the source directory is still allowed
+    let (content, stderr) = run("sandboxed", &["--sandbox"]);
+    assert_eq!(content, "Shared.\n--include ../../secret.txt\n");
+    assert!(
+        stderr.contains("is outside the source directory"),
+        "{}",
+        stderr
+    );
+}
+
+#[test]
+fn test_no_progress_bar_when_output_is_piped() {
+    let temp_dir = tempdir().unwrap();
+    let src_dir = temp_dir.path().join("src");
+    let target_dir = temp_dir.path().join("target");
+
+    fs::create_dir_all(&src_dir).unwrap();
+    for i in 0..50 {
+        fs::write(src_dir.join(format!("file{}.txt", i)), "Text.\n").unwrap();
+    }
+
+    let output = Command::new("cargo")
+        .arg("run")
+        .arg("--")
+        .arg("--src")
+        .arg(src_dir.to_str().unwrap())
+        .arg("--target")
+        .arg(target_dir.to_str().unwrap())
+        .arg("-v")
+        .output()
+        .expect("Failed to execute process");
+
+    assert!(output.status.success());
+    for captured in [&output.stdout, &output.stderr] {
+        let captured = String::from_utf8_lossy(captured);
+        assert!(!captured.contains('\r'), "{:?}", captured);
+        assert!(!captured.contains("\x1b[2K"), "{:?}", captured);
+    }
+    assert!(target_dir.join("file49.txt").exists());
+}
+
+#[test]
+fn test_dedupe_includes() {
+    let temp_dir = tempdir().unwrap();
+    let src_dir = temp_dir.path().join("src");
+
+    fs::create_dir_all(&src_dir).unwrap();
+
+    fs::write(src_dir.join("boilerplate.txt"), "Boilerplate.\n").unwrap();
+    fs::write(
+        src_dir.join("section.txt"),
+        "Section.\n--include boilerplate.txt\n",
+    )
+    .unwrap();
+    fs::write(
+        src_dir.join("page.txt"),
+        "--include boilerplate.txt\nPage.\n--include boilerplate.txt\n--include section.txt\nEnd.\n",
+    )
+    .unwrap();
+
+    let run = |target: &str, extra_args: &[&str]| {
+        let target_dir = temp_dir.path().join(target);
+        let output = Command::new("cargo")
+            .arg("run")
+            .arg("--")
+            .arg("--src")
+            .arg(src_dir.to_str().unwrap())
+            .arg("--target")
+            .arg(target_dir.to_str().unwrap())
+            .args(extra_args)
+            .output()
+            .expect("Failed to execute process");
+        assert!(output.status.success());
+        (
+            fs::read_to_string(target_dir.join("page.txt")).unwrap(),
+            fs::read_to_string(target_dir.join("section.txt")).unwrap(),
+        )
+    };
+
+    let (page, _) = run("default", &[]);
+    assert_eq!(
+        page,
+        "Boilerplate.\nPage.\nBoilerplate.\nSection.\nBoilerplate.\nEnd.\n"
+    );
+
+    // Repeats are left out within each file, including through nested includes, but every file
+    // still gets its own copy
+    let (page, section) = run("deduped", &["--dedupe-includes"]);
+    assert_eq!(page, "Boilerplate.\nPage.\nSection.\nEnd.\n");
+    assert_eq!(section, "Section.\nBoilerplate.\n");
+}
+
+#[test]
+fn test_include_once() {
+    let temp_dir = tempdir().unwrap();
+    let src_dir = temp_dir.path().join("src");
+    let target_dir = temp_dir.path().join("target");
+
+    fs::create_dir_all(&src_dir).unwrap();
+
+    fs::write(
+        src_dir.join("styles.css"),
+        "--include-once\n.note { color: red; }\n",
+    )
+    .unwrap();
+    fs::write(
+        src_dir.join("note.html"),
+        "--include styles.css\n<p class=\"note\">Note.</p>\n",
+    )
+    .unwrap();
+    fs::write(
+        src_dir.join("page.html"),
+        "--include styles.css\n<h1>Page</h1>\n--include note.html\n--include note.html\n",
+    )
+    .unwrap();
+
+    let output = Command::new("cargo")
+        .arg("run")
+        .arg("--")
+        .arg("--src")
+        .arg(src_dir.to_str().unwrap())
+        .arg("--target")
+        .arg(target_dir.to_str().unwrap())
+        .output()
+        .expect("Failed to execute process");
+
+    assert!(output.status.success());
+    // Pulled in directly and through each note, but written once, without the marker
+    assert_eq!(
+        fs::read_to_string(target_dir.join("page.html")).unwrap(),
+        ".note { color: red; }\n<h1>Page</h1>\n<p class=\"note\">Note.</p>\n<p class=\"note\">Note.</p>\n"
+    );
+    // Each output gets its own copy
+    assert_eq!(
+        fs::read_to_string(target_dir.join("note.html")).unwrap(),
+        ".note { color: red; }\n<p class=\"note\">Note.</p>\n"
+    );
+    assert_eq!(
+        fs::read_to_string(target_dir.join("styles.css")).unwrap(),
+        ".note { color: red; }\n"
+    );
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(!stderr.contains("-once"), "{}", stderr);
+}
+
+#[test]
+fn test_include_warnings_give_the_line_number() {
+    let temp_dir = tempdir().unwrap();
+    let src_dir = temp_dir.path().join("src");
+    let target_dir = temp_dir.path().join("target");
+
+    fs::create_dir_all(&src_dir).unwrap();
+
+    let mut header = "Header line.\n".repeat(41);
+    header.push_str("--include missing.txt\n");
+    fs::write(src_dir.join("header.txt"), header).unwrap();
+    fs::write(
+        src_dir.join("main.txt"),
+        "Main.\n\n--include header.txt\n--include gone.txt\n",
+    )
+    .unwrap();
+
+    let output = Command::new("cargo")
+        .arg("run")
+        .arg("--")
+        .arg("--src")
+        .arg(src_dir.to_str().unwrap())
+        .arg("--target")
+        .arg(target_dir.to_str().unwrap())
+        .output()
+        .expect("Failed to execute process");
+
+    assert!(output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("header.txt:42: include not found: missing.txt"),
+        "{}",
+        stderr
+    );
+    assert!(
+        stderr.contains("main.txt:4: include not found: gone.txt"),
+        "{}",
+        stderr
+    );
+}
+
+/// The raw value of `key` in a single line JSON object, e.g. `"warning"` or `12`
+fn json_field<'a>(object: &'a str, key: &str) -> &'a str {
+    let start = object.find(&format!("\"{}\": ", key)).unwrap() + key.len() + 4;
+    let rest = &object[start..];
+    let end = if let Some(string) = rest.strip_prefix('"') {
+        let mut escaped = false;
+        1 + string
+            .char_indices()
+            .find(|&(_, c)| {
+                let is_end = c == '"' && !escaped;
+                escaped = c == '\\' && !escaped;
+                is_end
+            })
+            .unwrap()
+            .0
+            + 1
+    } else {
+        rest.find([',', '}']).unwrap()
+    };
+    &rest[..end]
+}
+
+#[test]
+fn test_json_diagnostics() {
+    let temp_dir = tempdir().unwrap();
+    let src_dir = temp_dir.path().join("src");
+    let target_dir = temp_dir.path().join("target");
+
+    fs::create_dir_all(&src_dir).unwrap();
+
+    fs::write(
+        src_dir.join("main.txt"),
+        "Main.\n--include missing.txt\n--include data.bin\n--include loop.txt\n",
+    )
+    .unwrap();
+    fs::write(src_dir.join("data.bin"), [0u8, 159, 146, 150, 0]).unwrap();
+    fs::write(src_dir.join("loop.txt"), "Loop.\n--include loop.txt\n").unwrap();
+
+    let output = Command::new("cargo")
+        .arg("run")
+        .arg("--")
+        .arg("--src")
+        .arg(src_dir.to_str().unwrap())
+        .arg("--target")
+        .arg(target_dir.to_str().unwrap())
+        .arg("--format")
+        .arg("json")
+        .output()
+        .expect("Failed to execute process");
+
+    assert!(output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    let diagnostics: Vec<&str> = stderr
+        .lines()
+        .filter(|line| line.starts_with('{'))
+        .collect();
+    assert!(!diagnostics.is_empty(), "{}", stderr);
+    let expected = [
+        (
+            "main.txt",
+            "2",
+            "missing.txt",
+            "include not found: missing.txt",
+        ),
+        (
+            "main.txt",
+            "3",
+            "data.bin",
+            "binary data in include: data.bin",
+        ),
+        ("loop.txt", "2", "loop.txt", "include cycle, skipping: "),
+    ];
+    for (file, line, include, message) in expected {
+        assert!(
+            diagnostics.iter().any(|diagnostic| {
+                json_field(diagnostic, "severity") == "\"warning\""
+                    && json_field(diagnostic, "file").ends_with(&format!("{}\"", file))
+                    && json_field(diagnostic, "line") == line
+                    && json_field(diagnostic, "include") == format!("\"{}\"", include)
+                    && json_field(diagnostic, "message").starts_with(&format!("\"{}", message))
+            }),
+            "no diagnostic for {} line {}:\n{}",
+            file,
+            line,
+            stderr
+        );
+    }
+}
+
+#[test]
+fn test_directive_with_trailing_comment() {
+    let temp_dir = tempdir().unwrap();
+    let src_dir = temp_dir.path().join("src");
+    let target_dir = temp_dir.path().join("target");
+
+    fs::create_dir_all(&src_dir).unwrap();
+
+    fs::write(src_dir.join("header.txt"), "Legal header.\n").unwrap();
+    fs::write(
+        src_dir.join("main.txt"),
+        "--include header.txt   # shared legal header\nMain.\n",
+    )
+    .unwrap();
+
+    let output = Command::new("cargo")
+        .arg("run")
+        .arg("--")
+        .arg("--src")
+        .arg(src_dir.to_str().unwrap())
+        .arg("--target")
+        .arg(target_dir.to_str().unwrap())
+        .output()
+        .expect("Failed to execute process");
+
+    assert!(output.status.success());
+    assert_eq!(
+        fs::read_to_string(target_dir.join("main.txt")).unwrap(),
+        "Legal header.\nMain.\n"
+    );
+}
+
+#[test]
+fn test_strip_lines() {
+    let temp_dir = tempdir().unwrap();
+    let src_dir = temp_dir.path().join("src");
+    let target_dir = temp_dir.path().join("target");
+
+    fs::create_dir_all(&src_dir).unwrap();
+
+    fs::write(
+        src_dir.join("header.txt"),
+        "# Header\n//!build shared by every page\n",
+    )
+    .unwrap();
+    fs::write(
+        src_dir.join("page.md.in"),
+        "//!build regenerate after editing the header\n--include header.txt\nBefore.\n  //!build TODO: check the numbers\n@@draft\nAfter.\n",
+    )
+    .unwrap();
+
+    let output = Command::new("cargo")
+        .arg("run")
+        .arg("--")
+        .arg("--src")
+        .arg(src_dir.to_str().unwrap())
+        .arg("--target")
+        .arg(target_dir.to_str().unwrap())
+        .arg("--strip-lines")
+        .arg("//!build")
+        .arg("--strip-lines")
+        .arg("@@")
+        .output()
+        .expect("Failed to execute process");
+
+    assert!(output.status.success());
+    // Stripped from included files too, and indented lines count
+    assert_eq!(
+        fs::read_to_string(target_dir.join("page.md.in")).unwrap(),
+        "# Header\nBefore.\nAfter.\n"
+    );
+}
+
+#[test]
+fn test_directive_with_hash_in_file_name() {
+    let temp_dir = tempdir().unwrap();
+    let src_dir = temp_dir.path().join("src");
+    let target_dir = temp_dir.path().join("target");
+
+    fs::create_dir_all(&src_dir).unwrap();
+
+    fs::create_dir_all(src_dir.join("C#")).unwrap();
+    fs::write(src_dir.join("C#").join("intro.txt"), "C# intro.\n").unwrap();
+    fs::write(
+        src_dir.join("main.txt"),
+        "--include C#/intro.txt\n--include C#/intro.txt # again\n",
+    )
+    .unwrap();
+
+    let output = Command::new("cargo")
+        .arg("run")
+        .arg("--")
+        .arg("--src")
+        .arg(src_dir.to_str().unwrap())
+        .arg("--target")
+        .arg(target_dir.to_str().unwrap())
+        .output()
+        .expect("Failed to execute process");
+
+    assert!(output.status.success());
+    assert_eq!(
+        fs::read_to_string(target_dir.join("main.txt")).unwrap(),
+        "C# intro.\nC# intro.\n"
+    );
+}
+
+#[test]
+fn test_selftest() {
+    let output = Command::new("cargo")
+        .arg("run")
+        .arg("--")
+        .arg("--selftest")
+        .output()
+        .expect("Failed to execute process");
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(
+        output.status.success(),
+        "{}{}",
+        stdout,
+        String::from_utf8_lossy(&output.stderr)
+    );
+    assert!(stdout.contains("Self-test passed"), "{}", stdout);
+    assert!(stdout.contains("remote-includes: "), "{}", stdout);
+}
+
+#[test]
+fn test_response_files() {
+    let temp_dir = tempdir().unwrap();
+    let src_dir = temp_dir.path().join("src");
+    let target_dir = temp_dir.path().join("target");
+
+    fs::create_dir_all(&src_dir).unwrap();
+
+    fs::write(src_dir.join("header.txt"), "Header for {{PRODUCT}}.\n").unwrap();
+    fs::write(
+        src_dir.join("main.rs"),
+        "//include header.txt\nfn main() {}\n",
+    )
+    .unwrap();
+    fs::write(
+        src_dir.join("main.txt"),
+        "Welcome to {{PRODUCT}} {{VERSION}}!\n",
+    )
+    .unwrap();
+
+    let defines = temp_dir.path().join("defines.txt");
+    fs::write(
+        &defines,
+        "--define \"PRODUCT=Acme Cloud\"\n--define VERSION=2.1\n",
+    )
+    .unwrap();
+    let args = temp_dir.path().join("args.txt");
+    fs::write(
+        &args,
+        format!(
+            "--src {}\n--target {}\n--include-for rs=//include\n@{}\n",
+            src_dir.display(),
+            target_dir.display(),
+            defines.display()
+        ),
+    )
+    .unwrap();
+
+    let output = Command::new("cargo")
+        .arg("run")
+        .arg("--")
+        .arg(format!("@{}", args.display()))
+        .output()
+        .expect("Failed to execute process");
+
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    assert_eq!(
+        fs::read_to_string(target_dir.join("main.txt")).unwrap(),
+        "Welcome to Acme Cloud 2.1!\n"
+    );
+    assert_eq!(
+        fs::read_to_string(target_dir.join("main.rs")).unwrap(),
+        "Header for Acme Cloud.\nfn main() {}\n"
+    );
+
+    // A response file that names itself is an error rather than endless recursion
+    fs::write(
+        &defines,
+        format!("--define VERSION=2.1\n@{}\n", args.display()),
+    )
+    .unwrap();
+    let output = Command::new("cargo")
+        .arg("run")
+        .arg("--")
+        .arg(format!("@{}", args.display()))
+        .output()
+        .expect("Failed to execute process");
+
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("Response file loop"), "{}", stderr);
+}
+
+#[test]
+fn test_annotate_includes() {
+    let temp_dir = tempdir().unwrap();
+    let src_dir = temp_dir.path().join("src");
+    let target_dir = temp_dir.path().join("target");
+
+    fs::create_dir_all(src_dir.join("parts")).unwrap();
+
+    fs::write(
+        src_dir.join("page.html"),
+        "<body>\n--include parts/header.html\n</body>\n",
+    )
+    .unwrap();
+    fs::write(
+        src_dir.join("parts/header.html"),
+        "<h1>Title</h1>\n--include nav.html\n",
+    )
+    .unwrap();
+    fs::write(src_dir.join("parts/nav.html"), "<nav></nav>\n").unwrap();
+    fs::write(src_dir.join("notes.txt"), "--include parts/nav.html\n").unwrap();
+
+    let output = Command::new("cargo")
+        .arg("run")
+        .arg("--")
+        .arg("--src")
+        .arg(src_dir.to_str().unwrap())
+        .arg("--target")
+        .arg(target_dir.to_str().unwrap())
+        .arg("--annotate")
+        .output()
+        .expect("Failed to execute process");
+
+    assert!(output.status.success());
+    assert_eq!(
+        fs::read_to_string(target_dir.join("page.html")).unwrap(),
+        "<body>\n\
+         <!-- begin include: parts/header.html -->\n\
+         <h1>Title</h1>\n\
+         <!-- begin include: nav.html -->\n\
+         <nav></nav>\n\
+         <!-- end include: nav.html -->\n\
+         <!-- end include: parts/header.html -->\n\
+         </body>\n"
+    );
+    assert_eq!(
+        fs::read_to_string(target_dir.join("notes.txt")).unwrap(),
+        "# begin include: parts/nav.html\n<nav></nav>\n# end include: parts/nav.html\n"
+    );
+
+    let output = Command::new("cargo")
+        .arg("run")
+        .arg("--")
+        .arg("--src")
+        .arg(src_dir.to_str().unwrap())
+        .arg("--target")
+        .arg(target_dir.to_str().unwrap())
+        .arg("--annotate")
+        .arg("--annotate-style")
+        .arg("/* {} */")
+        .output()
+        .expect("Failed to execute process");
+
+    assert!(output.status.success());
+    assert_eq!(
+        fs::read_to_string(target_dir.join("notes.txt")).unwrap(),
+        "/* begin include: parts/nav.html */\n<nav></nav>\n/* end include: parts/nav.html */\n"
+    );
+}
+
+#[test]
+fn test_indent_styles() {
+    let temp_dir = tempdir().unwrap();
+    let src_dir = temp_dir.path().join("src");
+
+    fs::create_dir_all(&src_dir).unwrap();
+
+    fs::write(src_dir.join("body.inc"), "first\nsecond\n").unwrap();
+    fs::write(src_dir.join("tabs.txt"), "\t\t--include body.inc\n").unwrap();
+    fs::write(src_dir.join("spaces.txt"), "      --include body.inc\n").unwrap();
+
+    let cases = [
+        (
+            "preserve",
+            "\t\tfirst\n\t\tsecond\n",
+            "      first\n      second\n",
+        ),
+        (
+            "spaces",
+            "    first\n    second\n",
+            "      first\n      second\n",
+        ),
+        (
+            "tabs",
+            "\t\tfirst\n\t\tsecond\n",
+            "\t\t\tfirst\n\t\t\tsecond\n",
+        ),
+    ];
+    for (indent, tabs, spaces) in cases {
+        let target_dir = temp_dir.path().join(indent);
+        let output = Command::new("cargo")
+            .arg("run")
+            .arg("--")
+            .arg("--src")
+            .arg(src_dir.to_str().unwrap())
+            .arg("--target")
+            .arg(target_dir.to_str().unwrap())
+            .arg("--indent")
+            .arg(indent)
+            .arg("--tab-width")
+            .arg("2")
+            .output()
+            .expect("Failed to execute process");
+
+        assert!(output.status.success());
+        assert_eq!(
+            fs::read_to_string(target_dir.join("tabs.txt")).unwrap(),
+            tabs,
+            "--indent {}",
+            indent
+        );
+        assert_eq!(
+            fs::read_to_string(target_dir.join("spaces.txt")).unwrap(),
+            spaces,
+            "--indent {}",
+            indent
+        );
+    }
+}
+
+#[test]
+fn test_fail_on_warning() {
+    let temp_dir = tempdir().unwrap();
+    let src_dir = temp_dir.path().join("src");
+    let target_dir = temp_dir.path().join("target");
+
+    fs::create_dir_all(&src_dir).unwrap();
+
+    fs::write(src_dir.join("fine.txt"), "Nothing to include.\n").unwrap();
+    fs::write(src_dir.join("main.txt"), "Main.\n--include missing.txt\n").unwrap();
+
+    let run = |extra_args: &[&str]| {
+        Command::new("cargo")
+            .arg("run")
+            .arg("--")
+            .arg("--src")
+            .arg(src_dir.to_str().unwrap())
+            .arg("--target")
+            .arg(target_dir.to_str().unwrap())
+            .args(extra_args)
+            .output()
+            .expect("Failed to execute process")
+    };
+
+    let output = run(&[]);
+    assert!(output.status.success());
+
+    let output = run(&["--fail-on-warning"]);
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("include not found: missing.txt"),
+        "{}",
+        stderr
+    );
+    assert!(stderr.contains("1 warning(s) reported"), "{}", stderr);
+    // Unlike --strict, the file is still written
+    assert_eq!(
+        fs::read_to_string(target_dir.join("main.txt")).unwrap(),
+        "Main.\n--include missing.txt\n"
+    );
+
+    let output = run(&["--fail-on-warning", "--format", "json"]);
+    assert!(!output.status.success());
+}
+
+#[test]
+fn test_max_include_size() {
+    let temp_dir = tempdir().unwrap();
+    let src_dir = temp_dir.path().join("src");
+    let target_dir = temp_dir.path().join("target");
+
+    fs::create_dir_all(&src_dir).unwrap();
+
+    fs::write(src_dir.join("small.inc"), "123456789\n").unwrap();
+    fs::write(src_dir.join("large.inc"), "1234567890\n").unwrap();
+    fs::write(
+        src_dir.join("main.txt"),
+        "--include small.inc\n--include large.inc\n",
+    )
+    .unwrap();
+
+    let output = Command::new("cargo")
+        .arg("run")
+        .arg("--")
+        .arg("--src")
+        .arg(src_dir.to_str().unwrap())
+        .arg("--target")
+        .arg(target_dir.to_str().unwrap())
+        .arg("--max-include-size")
+        .arg("10")
+        .output()
+        .expect("Failed to execute process");
+
+    assert!(output.status.success());
+    assert_eq!(
+        fs::read_to_string(target_dir.join("main.txt")).unwrap(),
+        "123456789\n--include large.inc\n"
+    );
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("large.inc is 11 bytes, over the maximum include size of 10 bytes"),
+        "{}",
+        stderr
+    );
+}
+
+#[test]
+fn test_max_line_length() {
+    let temp_dir = tempdir().unwrap();
+    let src_dir = temp_dir.path().join("src");
+    let target_dir = temp_dir.path().join("target");
+
+    fs::create_dir_all(&src_dir).unwrap();
+
+    // One 8MB line, e.g. minified JavaScript, followed by a directive that isn't expanded
+    let mut huge = "x".repeat(8 * 1024 * 1024);
+    huge.push_str("\n--include small.txt\n");
+    fs::write(src_dir.join("bundle.js"), &huge).unwrap();
+    fs::write(src_dir.join("small.txt"), "Small.\n").unwrap();
+    fs::write(src_dir.join("main.txt"), "--include small.txt\n").unwrap();
+
+    let output = Command::new("cargo")
+        .arg("run")
+        .arg("--")
+        .arg("--src")
+        .arg(src_dir.to_str().unwrap())
+        .arg("--target")
+        .arg(target_dir.to_str().unwrap())
+        .arg("--max-line-length")
+        .arg("1000000")
+        .output()
+        .expect("Failed to execute process");
+
+    assert!(output.status.success());
+    assert_eq!(
+        fs::read(target_dir.join("bundle.js")).unwrap(),
+        huge.as_bytes()
+    );
+    assert_eq!(
+        fs::read_to_string(target_dir.join("main.txt")).unwrap(),
+        "Small.\n"
+    );
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert_eq!(
+        stderr
+            .matches("has a line longer than 1000000 bytes")
+            .count(),
+        1,
+        "{}",
+        stderr
+    );
+}
+
+#[test]
+fn test_in_place() {
+    let temp_dir = tempdir().unwrap();
+    let src_dir = temp_dir.path().join("src");
+
+    fs::create_dir_all(src_dir.join("parts")).unwrap();
+    fs::write(src_dir.join("parts/header.txt"), "Header.\n").unwrap();
+    fs::write(
+        src_dir.join("main.txt"),
+        "--include parts/header.txt\nMain.\n",
+    )
+    .unwrap();
+
+    let run = || {
+        Command::new("cargo")
+            .arg("run")
+            .arg("--")
+            .arg("--src")
+            .arg(src_dir.to_str().unwrap())
+            .arg("--in-place")
+            .arg("--backup")
+            .output()
+            .expect("Failed to execute process")
+    };
+
+    let output = run();
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    assert_eq!(
+        fs::read_to_string(src_dir.join("main.txt")).unwrap(),
+        "Header.\nMain.\n"
+    );
+    assert_eq!(
+        fs::read_to_string(src_dir.join("main.txt.bak")).unwrap(),
+        "--include parts/header.txt\nMain.\n"
+    );
+
+    // Backups aren't processed themselves, so they aren't backed up in turn
+    let output = run();
+    assert!(output.status.success());
+    assert!(!src_dir.join("main.txt.bak.bak").exists());
+}
+
+#[test]
+fn test_max_walk_depth() {
+    let temp_dir = tempdir().unwrap();
+    let src_dir = temp_dir.path().join("src");
+
+    fs::create_dir_all(src_dir.join("guide/advanced")).unwrap();
+    fs::write(
+        src_dir.join("top.txt"),
+        "--include guide/advanced/deep.txt\n",
+    )
+    .unwrap();
+    fs::write(src_dir.join("guide/middle.txt"), "Middle.\n").unwrap();
+    fs::write(src_dir.join("guide/advanced/deep.txt"), "Deep.\n").unwrap();
+
+    let run = |target_dir: &Path, extra_args: &[&str]| {
+        let output = Command::new("cargo")
+            .arg("run")
+            .arg("--")
+            .arg("--src")
+            .arg(src_dir.to_str().unwrap())
+            .arg("--target")
+            .arg(target_dir.to_str().unwrap())
+            .args(extra_args)
+            .output()
+            .expect("Failed to execute process");
+        assert!(output.status.success());
+    };
+
+    // The target is inside the source, and is still left out however deep the walk goes
+    let target_dir = src_dir.join("out");
+    for _ in 0..2 {
+        run(&target_dir, &["--max-walk-depth", "2"]);
+    }
+    // Files below the limit can still be included
+    assert_eq!(
+        fs::read_to_string(target_dir.join("top.txt")).unwrap(),
+        "Deep.\n"
+    );
+    assert!(target_dir.join("guide/middle.txt").exists());
+    assert!(!target_dir.join("guide/advanced").exists());
+    assert!(!target_dir.join("out").exists());
+
+    let target_dir = temp_dir.path().join("flat");
+    run(&target_dir, &["--no-recursive", "--preserve-empty-dirs"]);
+    assert!(target_dir.join("top.txt").exists());
+    assert!(!target_dir.join("guide").exists());
+}
+
+#[test]
+fn test_preserve_empty_dirs() {
+    let temp_dir = tempdir().unwrap();
+    let src_dir = temp_dir.path().join("src");
+
+    fs::create_dir_all(src_dir.join("assets/fonts")).unwrap();
+    fs::create_dir_all(src_dir.join("build/cache")).unwrap();
+    fs::write(src_dir.join(".gitignore"), "build/\n").unwrap();
+    fs::write(src_dir.join("main.txt"), "Main.\n").unwrap();
+
+    let run = |target_dir: &Path, extra_args: &[&str]| {
+        Command::new("cargo")
+            .arg("run")
+            .arg("--")
+            .arg("--src")
+            .arg(src_dir.to_str().unwrap())
+            .arg("--target")
+            .arg(target_dir.to_str().unwrap())
+            .arg("--respect-gitignore")
+            .args(extra_args)
+            .output()
+            .expect("Failed to execute process")
+    };
+
+    let target_dir = temp_dir.path().join("without");
+    let output = run(&target_dir, &[]);
+    assert!(output.status.success());
+    assert!(target_dir.join("main.txt").exists());
+    assert!(!target_dir.join("assets").exists());
+
+    let target_dir = temp_dir.path().join("with");
+    let output = run(&target_dir, &["--preserve-empty-dirs"]);
+    assert!(output.status.success());
+    assert!(target_dir.join("assets/fonts").is_dir());
+    assert!(!target_dir.join("build").exists());
+
+    // A target inside the source isn't mirrored into itself
+    let target_dir = src_dir.join("out");
+    let output = run(&target_dir, &["--preserve-empty-dirs"]);
+    assert!(output.status.success());
+    assert!(target_dir.join("assets/fonts").is_dir());
+    assert!(!target_dir.join("out").exists());
+}
+
+#[test]
+fn test_flatten() {
+    let temp_dir = tempdir().unwrap();
+    let src_dir = temp_dir.path().join("src");
+    let target_dir = temp_dir.path().join("target");
+
+    fs::create_dir_all(src_dir.join("docs/guide")).unwrap();
+    fs::write(src_dir.join("index.md"), "Index.\n").unwrap();
+    fs::write(
+        src_dir.join("docs/guide/intro.md"),
+        "Intro.\n--include ../shared.md\n",
+    )
+    .unwrap();
+    fs::write(src_dir.join("docs/shared.md"), "Shared.\n").unwrap();
+    fs::write(src_dir.join("docs__shared.md"), "Clash.\n").unwrap();
+
+    let output = Command::new("cargo")
+        .arg("run")
+        .arg("--")
+        .arg("--src")
+        .arg(src_dir.to_str().unwrap())
+        .arg("--target")
+        .arg(target_dir.to_str().unwrap())
+        .arg("--flatten")
+        .arg("__")
+        .output()
+        .expect("Failed to execute process");
+
+    assert!(output.status.success());
+    assert_eq!(
+        fs::read_to_string(target_dir.join("docs__guide__intro.md")).unwrap(),
+        "Intro.\nShared.\n"
+    );
+    assert_eq!(
+        fs::read_to_string(target_dir.join("index.md")).unwrap(),
+        "Index.\n"
+    );
+    assert!(target_dir.join("docs__shared.md").is_file());
+    for entry in fs::read_dir(&target_dir).unwrap() {
+        assert!(!entry.unwrap().file_type().unwrap().is_dir());
+    }
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("both flatten to"), "{}", stderr);
+
+    // Cleaning keeps flattened outputs whose sources still exist
+    fs::write(target_dir.join("stale.md"), "Stale.\n").unwrap();
+    let output = Command::new("cargo")
+        .arg("run")
+        .arg("--")
+        .arg("--src")
+        .arg(src_dir.to_str().unwrap())
+        .arg("--target")
+        .arg(target_dir.to_str().unwrap())
+        .arg("--flatten")
+        .arg("__")
+        .arg("--clean")
+        .output()
+        .expect("Failed to execute process");
+
+    assert!(output.status.success());
+    assert!(target_dir.join("docs__guide__intro.md").is_file());
+    assert!(!target_dir.join("stale.md").exists());
+}
+
+#[test]
+fn test_only() {
+    let temp_dir = tempdir().unwrap();
+    let src_dir = temp_dir.path().join("src");
+    let target_dir = temp_dir.path().join("target");
+
+    fs::create_dir_all(src_dir.join("guide/advanced")).unwrap();
+    fs::create_dir_all(src_dir.join("shared")).unwrap();
+    fs::write(src_dir.join("index.md"), "Index.\n").unwrap();
+    fs::write(
+        src_dir.join("guide/intro.md"),
+        "Intro.\n--include ../shared/note.md\n",
+    )
+    .unwrap();
+    fs::write(src_dir.join("guide/advanced/tips.md"), "Tips.\n").unwrap();
+    fs::write(src_dir.join("shared/note.md"), "Note.\n").unwrap();
+
+    let output = Command::new("cargo")
+        .arg("run")
+        .arg("--")
+        .arg("--src")
+        .arg(src_dir.to_str().unwrap())
+        .arg("--target")
+        .arg(target_dir.to_str().unwrap())
+        .arg("--only")
+        .arg("guide/**")
+        .output()
+        .expect("Failed to execute process");
+    assert!(output.status.success());
+
+    // Includes outside the pattern are still expanded into the matching files
+    assert_eq!(
+        fs::read_to_string(target_dir.join("guide/intro.md")).unwrap(),
+        "Intro.\nNote.\n"
+    );
+    assert!(target_dir.join("guide/advanced/tips.md").exists());
+    assert!(!target_dir.join("index.md").exists());
+    assert!(!target_dir.join("shared").exists());
+}
+
+#[test]
+fn test_only_with_many_stars() {
+    let temp_dir = tempdir().unwrap();
+    let src_dir = temp_dir.path().join("src");
+    let target_dir = temp_dir.path().join("target");
+    let long_name = format!("{}.md", "a".repeat(40));
+
+    fs::create_dir_all(&src_dir).unwrap();
+    fs::write(src_dir.join(&long_name), "Long.\n").unwrap();
+    fs::write(src_dir.join("b.md"), "B.\n").unwrap();
+
+    // Matching this by backtracking would try every way of spreading the stars over the name
+    let output = Command::new("cargo")
+        .arg("run")
+        .arg("--")
+        .arg("--src")
+        .arg(src_dir.to_str().unwrap())
+        .arg("--target")
+        .arg(target_dir.to_str().unwrap())
+        .arg("--only")
+        .arg(format!("{}b.md", "a*".repeat(15)))
+        .arg("--only")
+        .arg("b.md")
+        .output()
+        .expect("Failed to execute process");
+    assert!(output.status.success());
+    assert!(target_dir.join("b.md").exists());
+    assert!(!target_dir.join(&long_name).exists());
+
+    let output = Command::new("cargo")
+        .arg("run")
+        .arg("--")
+        .arg("--src")
+        .arg(src_dir.to_str().unwrap())
+        .arg("--target")
+        .arg(target_dir.to_str().unwrap())
+        .arg("--only")
+        .arg("[ab.md")
+        .output()
+        .expect("Failed to execute process");
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("invalid glob"));
+}
+
+#[test]
+fn test_displayed_paths_use_forward_slashes() {
+    let temp_dir = tempdir().unwrap();
+    let src_dir = temp_dir.path().join("src");
+    let target_dir = temp_dir.path().join("target");
+
+    fs::create_dir_all(src_dir.join("docs").join("guide")).unwrap();
+    fs::write(
+        src_dir.join("docs").join("guide").join("intro.md"),
+        "Intro.\n--include ../missing.md\n",
+    )
+    .unwrap();
+
+    let output = Command::new("cargo")
+        .arg("run")
+        .arg("--")
+        .arg("--src")
+        .arg(src_dir.to_str().unwrap())
+        .arg("--target")
+        .arg(target_dir.to_str().unwrap())
+        .arg("--dry-run")
+        .output()
+        .expect("Failed to execute process");
+    assert!(output.status.success());
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stdout.contains("docs/guide/intro.md"), "{}", stdout);
+    assert!(stderr.contains("docs/guide/intro.md:2"), "{}", stderr);
+    assert!(!stdout.contains('\\'), "{}", stdout);
+    assert!(!stderr.contains('\\'), "{}", stderr);
+}
+
+#[test]
+fn test_case_insensitive_includes() {
+    let temp_dir = tempdir().unwrap();
+    let src_dir = temp_dir.path().join("src");
+
+    fs::create_dir_all(src_dir.join("parts")).unwrap();
+    fs::write(src_dir.join("parts/header.txt"), "Header.\n").unwrap();
+    fs::write(
+        src_dir.join("main.txt"),
+        "--include Parts/Header.txt\nMain.\n",
+    )
+    .unwrap();
+
+    let run = |target_dir: &Path, extra_args: &[&str]| {
+        Command::new("cargo")
+            .arg("run")
+            .arg("--")
+            .arg("--src")
+            .arg(src_dir.to_str().unwrap())
+            .arg("--target")
+            .arg(target_dir.to_str().unwrap())
+            .args(extra_args)
+            .output()
+            .expect("Failed to execute process")
+    };
+
+    // Only meaningful where the file system itself tells the names apart
+    if !src_dir.join("PARTS/HEADER.TXT").exists() {
+        let target_dir = temp_dir.path().join("exact");
+        let output = run(&target_dir, &[]);
+        assert!(output.status.success());
+        assert_eq!(
+            fs::read_to_string(target_dir.join("main.txt")).unwrap(),
+            "--include Parts/Header.txt\nMain.\n"
+        );
+    }
+
+    let target_dir = temp_dir.path().join("ignoring-case");
+    let output = run(&target_dir, &["--case-insensitive", "-v"]);
+    assert!(output.status.success());
+    assert_eq!(
+        fs::read_to_string(target_dir.join("main.txt")).unwrap(),
+        "Header.\nMain.\n"
+    );
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("ignoring case"), "{}", stdout);
+}
+
+#[test]
+fn test_on_error_policies() {
+    let temp_dir = tempdir().unwrap();
+    let src_dir = temp_dir.path().join("src");
+
+    fs::create_dir_all(&src_dir).unwrap();
+    fs::write(src_dir.join("broken.txt"), "--include missing.txt\n").unwrap();
+    for name in ["a.txt", "b.txt", "c.txt"] {
+        fs::write(src_dir.join(name), "Fine.\n").unwrap();
+    }
+
+    let run = |target_dir: &Path, policy: &str| {
+        Command::new("cargo")
+            .arg("run")
+            .arg("--")
+            .arg("--src")
+            .arg(src_dir.to_str().unwrap())
+            .arg("--target")
+            .arg(target_dir.to_str().unwrap())
+            .arg("--on-error")
+            .arg(policy)
+            .arg("--jobs")
+            .arg("1")
+            .output()
+            .expect("Failed to execute process")
+    };
+
+    // The missing include is a warning, and every file is written
+    let target_dir = temp_dir.path().join("continue");
+    let output = run(&target_dir, "continue");
+    assert!(output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("include not found: missing.txt"));
+    assert_eq!(
+        fs::read_to_string(target_dir.join("broken.txt")).unwrap(),
+        "--include missing.txt\n"
+    );
+    for name in ["a.txt", "b.txt", "c.txt"] {
+        assert!(target_dir.join(name).exists());
+    }
+
+    // The missing include is an error, and nothing is written for the broken file
+    let target_dir = temp_dir.path().join("abort");
+    let output = run(&target_dir, "abort");
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("Include file not found"), "{}", stderr);
+    assert!(stderr.contains("Stopped at the first error"), "{}", stderr);
+    assert!(!target_dir.join("broken.txt").exists());
+}
+
+#[test]
+fn test_fail_fast() {
+    let temp_dir = tempdir().unwrap();
+    let src_dir = temp_dir.path().join("src");
+
+    fs::create_dir_all(&src_dir).unwrap();
+    fs::write(src_dir.join("b.txt"), "--include missing.txt\n").unwrap();
+    for name in ["a.txt", "c.txt", "d.txt"] {
+        fs::write(src_dir.join(name), "Fine.\n").unwrap();
+    }
+
+    let run = |target_dir: &Path, extra_args: &[&str]| {
+        Command::new("cargo")
+            .arg("run")
+            .arg("--")
+            .arg("--src")
+            .arg(src_dir.to_str().unwrap())
+            .arg("--target")
+            .arg(target_dir.to_str().unwrap())
+            .arg("--strict")
+            .arg("--jobs")
+            .arg("1")
+            .args(extra_args)
+            .output()
+            .expect("Failed to execute process")
+    };
+
+    // The rest of the batch is still processed, and the run fails at the end
+    let target_dir = temp_dir.path().join("complete");
+    let output = run(&target_dir, &[]);
+    assert!(!output.status.success());
+    for name in ["a.txt", "c.txt", "d.txt"] {
+        assert!(target_dir.join(name).exists(), "{}", name);
+    }
+
+    // Files are processed in sorted order, so the failure is in the middle
+    let target_dir = temp_dir.path().join("fail-fast");
+    let output = run(&target_dir, &["--fail-fast"]);
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("Stopped at the first error, 2 file(s) were not processed"),
+        "{}",
+        stderr
+    );
+    assert!(target_dir.join("a.txt").exists());
+    for name in ["b.txt", "c.txt", "d.txt"] {
+        assert!(!target_dir.join(name).exists(), "{}", name);
+    }
+}
+
+#[test]
+fn test_warnings_without_verbose() {
+    let temp_dir = tempdir().unwrap();
+    let src_dir = temp_dir.path().join("src");
+    let target_dir = temp_dir.path().join("target");
+
+    fs::create_dir_all(&src_dir).unwrap();
+    fs::write(src_dir.join("part.txt"), "Part.\n").unwrap();
+    fs::write(src_dir.join("image.bin"), [0, 159, 146, 150]).unwrap();
+    fs::write(
+        src_dir.join("main.txt"),
+        "--include part.txt\n--include missing.txt\n--include image.bin\n",
+    )
+    .unwrap();
+
+    let output = Command::new("cargo")
+        .arg("run")
+        .arg("--")
+        .arg("--src")
+        .arg(src_dir.to_str().unwrap())
+        .arg("--target")
+        .arg(target_dir.to_str().unwrap())
+        .output()
+        .expect("Failed to execute process");
+
+    assert!(output.status.success());
+    // Warnings are shown without -v, but what was written and included is not
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("main.txt:2: include not found: missing.txt"),
+        "{}",
+        stderr
+    );
+    assert!(
+        stderr.contains("main.txt:3: binary data in include: image.bin"),
+        "{}",
+        stderr
+    );
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    for info in ["Input ", " includes "] {
+        assert!(!stdout.contains(info), "{}", stdout);
+        assert!(!stderr.contains(info), "{}", stderr);
+    }
+}
+
+#[test]
+fn test_indented_includes() {
+    let temp_dir = tempdir().unwrap();
+    let src_dir = temp_dir.path().join("src");
+    let target_dir = temp_dir.path().join("target");
+
+    fs::create_dir_all(&src_dir).unwrap();
+
+    let mut single_file = File::create(src_dir.join("single.txt")).unwrap();
+    writeln!(single_file, "let x = 1;").unwrap();
+
+    let mut multi_file = File::create(src_dir.join("multi.txt")).unwrap();
+    writeln!(multi_file, "fn main() {{").unwrap();
+    writeln!(multi_file, "    println!(\"hello\");").unwrap();
+    writeln!(multi_file).unwrap();
+    writeln!(multi_file, "}}").unwrap();
+
+    let mut main_file = File::create(src_dir.join("main.md")).unwrap();
+    writeln!(main_file, "Single:").unwrap();
+    writeln!(main_file, "    --include single.txt").unwrap();
+    writeln!(main_file, "Multi:").unwrap();
+    writeln!(main_file, "\t--include multi.txt").unwrap();
+
+    let output = Command::new("cargo")
+        .arg("run")
+        .arg("--")
+        .arg("--src")
+        .arg(src_dir.to_str().unwrap())
+        .arg("--target")
+        .arg(target_dir.to_str().unwrap())
+        .output()
+        .expect("Failed to execute process");
+
+    assert!(output.status.success());
+
+    let output_content = fs::read_to_string(target_dir.join("main.md")).unwrap();
+    assert_eq!(
+        output_content,
+        "Single:\n    let x = 1;\nMulti:\n\tfn main() {\n\t    println!(\"hello\");\n\n\t}\n"
+    );
+}
+
+#[test]
+fn test_parallel_processing_of_many_files() {
+    let temp_dir = tempdir().unwrap();
+    let src_dir = temp_dir.path().join("src");
+    let target_dir = temp_dir.path().join("target");
+
+    fs::create_dir_all(src_dir.join("nested")).unwrap();
+
+    let mut shared_file = File::create(src_dir.join("shared.txt")).unwrap();
+    writeln!(shared_file, "Shared content.").unwrap();
+
+    for i in 0..200 {
+        let dir = if i % 2 == 0 {
+            src_dir.clone()
+        } else {
+            src_dir.join("nested")
+        };
+        let include = if i % 2 == 0 {
+            "shared.txt"
+        } else {
+            "../shared.txt"
+        };
+        let mut file = File::create(dir.join(format!("file{}.txt", i))).unwrap();
+        writeln!(file, "--include {}", include).unwrap();
+        writeln!(file, "This is file {}.", i).unwrap();
+    }
+
+    let output = Command::new("cargo")
+        .arg("run")
+        .arg("--")
+        .arg("--src")
+        .arg(src_dir.to_str().unwrap())
+        .arg("--target")
+        .arg(target_dir.to_str().unwrap())
+        .arg("--jobs")
+        .arg("4")
+        .output()
+        .expect("Failed to execute process");
+
+    assert!(output.status.success());
+
+    for i in 0..200 {
+        let dir = if i % 2 == 0 {
+            target_dir.clone()
+        } else {
+            target_dir.join("nested")
+        };
+        let output_content = fs::read_to_string(dir.join(format!("file{}.txt", i))).unwrap();
+        assert_eq!(
+            output_content,
+            format!("Shared content.\nThis is file {}.\n", i)
+        );
+    }
+}
+
+#[test]
+fn test_graph_export() {
+    let temp_dir = tempdir().unwrap();
+    let src_dir = temp_dir.path().join("src");
+    let target_dir = temp_dir.path().join("target");
+
+    fs::create_dir_all(src_dir.join("shared")).unwrap();
+
+    let mut main_file = File::create(src_dir.join("main.txt")).unwrap();
+    writeln!(main_file, "--include shared/header.txt").unwrap();
+    writeln!(main_file, "--include footer.txt").unwrap();
+
+    let mut header_file = File::create(src_dir.join("shared/header.txt")).unwrap();
+    writeln!(header_file, "--include snippet.txt").unwrap();
+
+    let mut snippet_file = File::create(src_dir.join("shared/snippet.txt")).unwrap();
+    writeln!(snippet_file, "This is the snippet.").unwrap();
+
+    let mut footer_file = File::create(src_dir.join("footer.txt")).unwrap();
+    writeln!(footer_file, "This is the footer.").unwrap();
+
+    let run = |graph_path: &Path| {
+        let output = Command::new("cargo")
+            .arg("run")
+            .arg("--")
+            .arg("--src")
+            .arg(src_dir.to_str().unwrap())
+            .arg("--target")
+            .arg(target_dir.to_str().unwrap())
+            .arg("--graph")
+            .arg(graph_path.to_str().unwrap())
+            .output()
+            .expect("Failed to execute process");
+        assert!(output.status.success());
+        fs::read_to_string(graph_path).unwrap()
+    };
+
+    let dot = run(&temp_dir.path().join("includes.dot"));
+    assert!(dot.starts_with("digraph includes {\n"));
+    assert!(dot.contains("\"main.txt\" -> \"shared/header.txt\";"));
+    assert!(dot.contains("\"main.txt\" -> \"footer.txt\";"));
+    // Nested includes are attributed to the top level file that pulls them in
+    assert!(dot.contains("\"main.txt\" -> \"shared/snippet.txt\";"));
+    assert!(dot.contains("\"shared/header.txt\" -> \"shared/snippet.txt\";"));
+
+    let json = run(&temp_dir.path().join("includes.json"));
+    assert!(json.contains("{\"from\": \"main.txt\", \"to\": \"footer.txt\"}"));
+}
+
+#[test]
+fn test_manifest_export() {
+    let temp_dir = tempdir().unwrap();
+    let src_dir = temp_dir.path().join("src");
+    let target_dir = temp_dir.path().join("target");
+    let manifest_path = temp_dir.path().join("manifest.json");
+
+    fs::create_dir_all(src_dir.join("docs")).unwrap();
+    fs::create_dir_all(&target_dir).unwrap();
+
+    fs::write(src_dir.join("header.txt"), "Header\n").unwrap();
+    fs::write(
+        src_dir.join("docs/main.txt"),
+        "--include ../header.txt\nMain\n",
+    )
+    .unwrap();
+    fs::write(src_dir.join("binary.bin"), [0u8, 159, 146, 150]).unwrap();
+
+    let output = Command::new("cargo")
+        .arg("run")
+        .arg("--")
+        .arg("-v")
+        .arg("--src")
+        .arg(src_dir.to_str().unwrap())
+        .arg("--target")
+        .arg(target_dir.to_str().unwrap())
+        .arg("--manifest")
+        .arg(manifest_path.to_str().unwrap())
+        .output()
+        .expect("Failed to execute process");
+
+    assert!(output.status.success());
+
+    let manifest = fs::read_to_string(&manifest_path).unwrap();
+    assert_eq!(
+        manifest,
+        "[\n\
+         \x20 {\"source\": \"binary.bin\", \"output\": \"binary.bin\", \"includes\": [], \"bytes\": 4},\n\
+         \x20 {\"source\": \"docs/main.txt\", \"output\": \"docs/main.txt\", \"includes\": [\"header.txt\"], \"bytes\": 12},\n\
+         \x20 {\"source\": \"header.txt\", \"output\": \"header.txt\", \"includes\": [], \"bytes\": 7}\n\
+         ]\n"
+    );
+}
+
+#[test]
+fn test_expect_manifest() {
+    let temp_dir = tempdir().unwrap();
+    let src_dir = temp_dir.path().join("src");
+    let target_dir = temp_dir.path().join("target");
+    let manifest_path = temp_dir.path().join("expected.json");
+
+    fs::create_dir_all(&src_dir).unwrap();
+    fs::write(src_dir.join("header.txt"), "Header\n").unwrap();
+    fs::write(src_dir.join("main.txt"), "--include header.txt\nMain\n").unwrap();
+
+    let run = |extra_args: &[&str]| {
+        Command::new("cargo")
+            .arg("run")
+            .arg("--")
+            .arg("--src")
+            .arg(src_dir.to_str().unwrap())
+            .arg("--target")
+            .arg(target_dir.to_str().unwrap())
+            .arg("--expect-manifest")
+            .arg(manifest_path.to_str().unwrap())
+            .args(extra_args)
+            .output()
+            .expect("Failed to execute process")
+    };
+
+    let output = run(&["--update-manifest"]);
+    assert!(output.status.success());
+    let output = run(&[]);
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+
+    // A new output and a changed one
+    fs::write(src_dir.join("extra.txt"), "Extra\n").unwrap();
+    fs::write(src_dir.join("header.txt"), "New header\n").unwrap();
+    let output = run(&[]);
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("Output added: extra.txt"), "{}", stderr);
+    assert!(stderr.contains("Output changed: header.txt"), "{}", stderr);
+    assert!(stderr.contains("Output changed: main.txt"), "{}", stderr);
+    assert!(
+        stderr.contains("1 added, 0 removed, 2 changed"),
+        "{}",
+        stderr
+    );
+
+    // Accepting the changes makes the next run pass, until an output goes away
+    assert!(run(&["--update-manifest"]).status.success());
+    assert!(run(&[]).status.success());
+    fs::remove_file(src_dir.join("extra.txt")).unwrap();
+    let output = run(&[]);
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("Output removed: extra.txt"), "{}", stderr);
+}
+
+#[test]
+fn test_stdout_output() {
+    let temp_dir = tempdir().unwrap();
+    let src_dir = temp_dir.path().join("src");
+
+    fs::create_dir_all(&src_dir).unwrap();
+
+    let mut main_file = File::create(src_dir.join("main.txt")).unwrap();
+    writeln!(main_file, "--include include.txt").unwrap();
+    writeln!(main_file, "This is the main file.").unwrap();
+
+    let mut include_file = File::create(src_dir.join("include.txt")).unwrap();
+    writeln!(include_file, "This is the included file.").unwrap();
+
+    let output = Command::new("cargo")
+        .arg("run")
+        .arg("-q")
+        .arg("--")
+        .arg("--src")
+        .arg(src_dir.join("main.txt").to_str().unwrap())
+        .arg("--stdout")
+        .output()
+        .expect("Failed to execute process");
+
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout),
+        "This is the included file.\nThis is the main file.\n"
+    );
+    // Nothing is written next to the source file
+    assert_eq!(fs::read_dir(&src_dir).unwrap().count(), 2);
+
+    let output = Command::new("cargo")
+        .arg("run")
+        .arg("--")
+        .arg("--src")
+        .arg(src_dir.join("main.txt").to_str().unwrap())
+        .arg("--stdout")
+        .arg("--watch")
+        .output()
+        .expect("Failed to execute process");
+    assert!(!output.status.success());
+}
+
+#[test]
+fn test_stdin_input() {
+    let temp_dir = tempdir().unwrap();
+    let base_dir = temp_dir.path().join("base");
+
+    fs::create_dir_all(&base_dir).unwrap();
+
+    let mut include_file = File::create(base_dir.join("include.txt")).unwrap();
+    writeln!(include_file, "This is the included file.").unwrap();
+
+    let mut child = Command::new("cargo")
+        .arg("run")
+        .arg("-q")
+        .arg("--")
+        .arg("--stdin")
+        .arg("--base")
+        .arg(base_dir.to_str().unwrap())
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .expect("Failed to execute process");
+
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(b"--include include.txt\nThis came from stdin.\n")
+        .unwrap();
+    let output = child.wait_with_output().unwrap();
+
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout),
+        "This is the included file.\nThis came from stdin.\n"
+    );
+}
+
+#[test]
+fn test_respect_gitignore() {
+    let temp_dir = tempdir().unwrap();
+    let src_dir = temp_dir.path().join("src");
+    let target_dir = temp_dir.path().join("target");
+
+    fs::create_dir_all(src_dir.join("build")).unwrap();
+    fs::create_dir_all(src_dir.join("docs")).unwrap();
+
+    fs::write(src_dir.join(".gitignore"), "build/\n*.log\n").unwrap();
+    fs::write(src_dir.join("docs/.gitignore"), "!keep.log\n").unwrap();
+    fs::write(src_dir.join("build/output.txt"), "Build output.\n").unwrap();
+    fs::write(src_dir.join("debug.log"), "Debug log.\n").unwrap();
+    fs::write(src_dir.join("docs/keep.log"), "Kept log.\n").unwrap();
+    fs::write(src_dir.join("main.txt"), "This is the main file.\n").unwrap();
+
+    let run = |respect_gitignore: bool| {
+        let mut command = Command::new("cargo");
+        command
+            .arg("run")
+            .arg("--")
+            .arg("--src")
+            .arg(src_dir.to_str().unwrap())
+            .arg("--target")
+            .arg(target_dir.to_str().unwrap());
+        if respect_gitignore {
+            command.arg("--respect-gitignore");
+        }
+        let output = command.output().expect("Failed to execute process");
+        assert!(output.status.success());
+    };
+
+    run(true);
+    assert!(target_dir.join("main.txt").exists());
+    assert!(target_dir.join("docs/keep.log").exists());
+    assert!(!target_dir.join("build").exists());
+    assert!(!target_dir.join("debug.log").exists());
+
+    run(false);
+    assert!(target_dir.join("build/output.txt").exists());
+    assert!(target_dir.join("debug.log").exists());
+}
+
+#[test]
+fn test_respect_gitignore_reads_git_excludes() {
+    let temp_dir = tempdir().unwrap();
+    let src_dir = temp_dir.path().join("src");
+    let target_dir = temp_dir.path().join("target");
+    let home_dir = temp_dir.path().join("home");
+
+    fs::create_dir_all(src_dir.join(".git/info")).unwrap();
+    fs::create_dir_all(&home_dir).unwrap();
+
+    // The excludes file named in the user's git config, and the repository's own excludes, which
+    // a .gitignore overrides
+    fs::write(
+        home_dir.join(".gitconfig"),
+        format!(
+            "[core]\n\texcludesFile = {}\n",
+            home_dir
+                .join("excludes")
+                .to_str()
+                .unwrap()
+                .replace('\\', "/")
+        ),
+    )
+    .unwrap();
+    fs::write(home_dir.join("excludes"), "*.secret\n").unwrap();
+    fs::write(src_dir.join(".git/info/exclude"), "*.draft\n").unwrap();
+    fs::write(src_dir.join(".gitignore"), "!ready.draft\n").unwrap();
+    fs::write(src_dir.join("main.txt"), "Main.\n").unwrap();
+    fs::write(src_dir.join("token.secret"), "hunter2\n").unwrap();
+    fs::write(src_dir.join("notes.draft"), "Notes.\n").unwrap();
+    fs::write(src_dir.join("ready.draft"), "Ready.\n").unwrap();
+
+    let output = Command::new("cargo")
+        .arg("run")
+        .arg("--")
+        .arg("--src")
+        .arg(src_dir.to_str().unwrap())
+        .arg("--target")
+        .arg(target_dir.to_str().unwrap())
+        .arg("--respect-gitignore")
+        .env("HOME", &home_dir)
+        .env_remove("XDG_CONFIG_HOME")
+        .output()
+        .expect("Failed to execute process");
+
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    assert!(target_dir.join("main.txt").exists());
+    assert!(target_dir.join("ready.draft").exists());
+    assert!(!target_dir.join("token.secret").exists());
+    assert!(!target_dir.join("notes.draft").exists());
+    assert!(!target_dir.join(".git").exists());
+}
+
+#[test]
+fn test_clean_removes_stale_target_files() {
+    let temp_dir = tempdir().unwrap();
+    let src_dir = temp_dir.path().join("src");
+    let target_dir = temp_dir.path().join("target");
+
+    fs::create_dir_all(src_dir.join("old")).unwrap();
+
+    fs::write(src_dir.join("main.txt"), "This is the main file.\n").unwrap();
+    fs::write(
+        src_dir.join("old/stale.txt"),
+        "This file will be deleted.\n",
+    )
+    .unwrap();
+
+    let run = |clean: bool| {
+        let mut command = Command::new("cargo");
+        command
+            .arg("run")
+            .arg("--")
+            .arg("--src")
+            .arg(src_dir.to_str().unwrap())
+            .arg("--target")
+            .arg(target_dir.to_str().unwrap());
+        if clean {
+            command.arg("--clean");
+        }
+        let output = command.output().expect("Failed to execute process");
+        assert!(output.status.success());
+    };
+
+    run(false);
+    assert!(target_dir.join("old/stale.txt").exists());
+
+    fs::remove_dir_all(src_dir.join("old")).unwrap();
+    run(false);
+    assert!(target_dir.join("old/stale.txt").exists());
+
+    run(true);
+    assert!(!target_dir.join("old").exists());
+    assert!(target_dir.join("main.txt").exists());
+    assert!(src_dir.join("main.txt").exists());
+}
+
+#[test]
+fn test_mirror_prunes_target() {
+    let temp_dir = tempdir().unwrap();
+    let src_dir = temp_dir.path().join("src");
+    let target_dir = temp_dir.path().join("target");
+
+    fs::create_dir_all(src_dir.join("old")).unwrap();
+
+    fs::write(src_dir.join("main.txt"), "This is the main file.\n").unwrap();
+    fs::write(src_dir.join("page.md.in"), "A page.\n").unwrap();
+    fs::write(src_dir.join("image.bin"), [0u8, 159, 146, 150]).unwrap();
+    fs::write(
+        src_dir.join("old/stale.txt"),
+        "This file will be deleted.\n",
+    )
+    .unwrap();
+
+    let run = |extra_args: &[&str]| {
+        let output = Command::new("cargo")
+            .arg("run")
+            .arg("--")
+            .arg("--src")
+            .arg(src_dir.to_str().unwrap())
+            .arg("--target")
+            .arg(target_dir.to_str().unwrap())
+            .args(extra_args)
+            .output()
+            .expect("Failed to execute process");
+        assert!(output.status.success());
+    };
+
+    run(&[]);
+    assert!(target_dir.join("old/stale.txt").exists());
+    assert!(target_dir.join("page.md.in").exists());
+    assert!(target_dir.join("image.bin").exists());
+
+    fs::remove_dir_all(src_dir.join("old")).unwrap();
+    fs::write(target_dir.join("extra.txt"), "Not from any source.\n").unwrap();
+    run(&["--mirror", "--rename", ".md.in:.md", "--no-copy-binaries"]);
+
+    assert!(!target_dir.join("old").exists());
+    assert!(!target_dir.join("extra.txt").exists());
+    // The output from before the rename and the binary that is no longer copied are pruned too
+    assert!(!target_dir.join("page.md.in").exists());
+    assert!(!target_dir.join("image.bin").exists());
+    assert!(target_dir.join("page.md").exists());
+    assert!(target_dir.join("main.txt").exists());
+    assert!(src_dir.join("image.bin").exists());
+}
+
+#[test]
+fn test_hash_cache_keeps_the_hashes_of_files_not_checked() {
+    let temp_dir = tempdir().unwrap();
+    let src_dir = temp_dir.path().join("src");
+    let target_dir = temp_dir.path().join("target");
+
+    fs::create_dir_all(&src_dir).unwrap();
+
+    fs::write(src_dir.join("main.txt"), "Main.\n").unwrap();
+    fs::write(src_dir.join("other.txt"), "Other.\n").unwrap();
+
+    let run = |extra_args: &[&str]| {
+        let output = Command::new("cargo")
+            .arg("run")
+            .arg("--")
+            .arg("--src")
+            .arg(src_dir.to_str().unwrap())
+            .arg("--target")
+            .arg(target_dir.to_str().unwrap())
+            .arg("--hash-cache")
+            .args(extra_args)
+            .output()
+            .expect("Failed to execute process");
+        assert!(output.status.success());
+    };
+    let hashes = || fs::read_to_string(target_dir.join(".simple-include-hashes.json")).unwrap();
+
+    run(&[]);
+    assert!(hashes().contains("other.txt"));
+
+    fs::write(src_dir.join("main.txt"), "Changed.\n").unwrap();
+    run(&["--only", "main.txt"]);
+    assert!(hashes().contains("main.txt"));
+    assert!(hashes().contains("other.txt"));
+
+    // other.txt is still known to be unchanged, so its output is left alone
+    fs::write(target_dir.join("other.txt"), "Old.\n").unwrap();
+    run(&[]);
+    assert_eq!(
+        fs::read_to_string(target_dir.join("other.txt")).unwrap(),
+        "Old.\n"
+    );
+}
+
+#[test]
+fn test_since_processes_recently_modified_files() {
+    let temp_dir = tempdir().unwrap();
+    let src_dir = temp_dir.path().join("src");
+    let target_dir = temp_dir.path().join("target");
+
+    fs::create_dir_all(&src_dir).unwrap();
+
+    fs::write(src_dir.join("main.txt"), "--include include.txt\nMain.\n").unwrap();
+    fs::write(src_dir.join("other.txt"), "Other.\n").unwrap();
+    fs::write(src_dir.join("include.txt"), "Included.\n").unwrap();
+
+    let run = |extra_args: &[&str]| {
+        let output = Command::new("cargo")
+            .arg("run")
+            .arg("--")
+            .arg("--src")
+            .arg(src_dir.to_str().unwrap())
+            .arg("--target")
+            .arg(target_dir.to_str().unwrap())
+            .args(extra_args)
+            .output()
+            .expect("Failed to execute process");
+        assert!(output.status.success());
+    };
+    let outputs = ["main.txt", "other.txt", "include.txt"];
+    // Marks every output, so the ones written again can be told apart
+    let mark_outputs = || {
+        for file in outputs {
+            fs::write(target_dir.join(file), "Old.\n").unwrap();
+        }
+    };
+    let read = |file: &str| fs::read_to_string(target_dir.join(file)).unwrap();
+
+    // --incremental leaves the cache that says main.txt includes include.txt
+    run(&["--incremental"]);
+    for file in outputs {
+        File::options()
+            .write(true)
+            .open(src_dir.join(file))
+            .unwrap()
+            .set_modified(SystemTime::now() - Duration::from_secs(2 * 3600))
+            .unwrap();
+    }
+
+    mark_outputs();
+    fs::write(src_dir.join("include.txt"), "Changed.\n").unwrap();
+    run(&["--since", "1h"]);
+    assert_eq!(read("include.txt"), "Changed.\n");
+    assert_eq!(read("main.txt"), "Changed.\nMain.\n");
+    assert_eq!(read("other.txt"), "Old.\n");
+
+    mark_outputs();
+    run(&["--since", "2000-01-01T00:00:00Z"]);
+    assert_eq!(read("other.txt"), "Other.\n");
+}
+
+#[test]
+fn test_incremental_skips_up_to_date_files() {
+    let temp_dir = tempdir().unwrap();
+    let src_dir = temp_dir.path().join("src");
+    let target_dir = temp_dir.path().join("target");
+
+    fs::create_dir_all(&src_dir).unwrap();
+
+    fs::write(src_dir.join("main.txt"), "--include include.txt\nMain.\n").unwrap();
+    fs::write(src_dir.join("other.txt"), "Other.\n").unwrap();
+    fs::write(src_dir.join("include.txt"), "Included.\n").unwrap();
+
+    let run = || {
+        let output = Command::new("cargo")
+            .arg("run")
+            .arg("--")
+            .arg("--src")
+            .arg(src_dir.to_str().unwrap())
+            .arg("--target")
+            .arg(target_dir.to_str().unwrap())
+            .arg("--incremental")
+            .output()
+            .expect("Failed to execute process");
+        assert!(output.status.success());
+    };
+    let modified = |path: &Path| fs::metadata(path).unwrap().modified().unwrap();
+    // Move the outputs' modification times back so a rebuild is visible even on coarse clocks
+    let backdate = |path: &Path| {
+        let earlier = SystemTime::now() - Duration::from_secs(60);
+        File::options()
+            .write(true)
+            .open(path)
+            .unwrap()
+            .set_modified(earlier)
+            .unwrap();
+    };
+
+    run();
+    let main_output = target_dir.join("main.txt");
+    let other_output = target_dir.join("other.txt");
+    assert_eq!(
+        fs::read_to_string(&main_output).unwrap(),
+        "Included.\nMain.\n"
+    );
+
+    // Sources are older than the outputs, so nothing is rebuilt
+    for file in ["main.txt", "other.txt", "include.txt"] {
+        File::options()
+            .write(true)
+            .open(src_dir.join(file))
+            .unwrap()
+            .set_modified(SystemTime::now() - Duration::from_secs(120))
+            .unwrap();
+    }
+    backdate(&main_output);
+    backdate(&other_output);
+    let main_modified = modified(&main_output);
+    let other_modified = modified(&other_output);
+    run();
+    assert_eq!(modified(&main_output), main_modified);
+    assert_eq!(modified(&other_output), other_modified);
+
+    // A changed include rebuilds the file that includes it, but nothing else
+    fs::write(src_dir.join("include.txt"), "Changed.\n").unwrap();
+    run();
+    assert_ne!(modified(&main_output), main_modified);
+    assert_eq!(
+        fs::read_to_string(&main_output).unwrap(),
+        "Changed.\nMain.\n"
+    );
+    assert_eq!(modified(&other_output), other_modified);
+}
+
+#[test]
+fn test_hash_cache_ignores_modification_times() {
+    let temp_dir = tempdir().unwrap();
+    let src_dir = temp_dir.path().join("src");
+    let target_dir = temp_dir.path().join("target");
+
+    fs::create_dir_all(&src_dir).unwrap();
+
+    fs::write(src_dir.join("main.txt"), "--include include.txt\nMain.\n").unwrap();
+    fs::write(src_dir.join("include.txt"), "Included.\n").unwrap();
+
+    let run = || {
+        let output = Command::new("cargo")
+            .arg("run")
+            .arg("--")
+            .arg("--src")
+            .arg(src_dir.to_str().unwrap())
+            .arg("--target")
+            .arg(target_dir.to_str().unwrap())
+            .arg("--hash-cache")
+            .output()
+            .expect("Failed to execute process");
+        assert!(output.status.success());
+    };
+    let set_modified = |path: &Path, time: SystemTime| {
+        File::options()
+            .write(true)
+            .open(path)
+            .unwrap()
+            .set_modified(time)
+            .unwrap();
+    };
+    let modified = |path: &Path| fs::metadata(path).unwrap().modified().unwrap();
+
+    run();
+    let main_output = target_dir.join("main.txt");
+    assert_eq!(
+        fs::read_to_string(&main_output).unwrap(),
+        "Included.\nMain.\n"
+    );
+    assert!(target_dir.join(".simple-include-hashes.json").exists());
+
+    // Like a fresh checkout: the sources are newer than the outputs, but their content is the same
+    let earlier = SystemTime::now() - Duration::from_secs(120);
+    set_modified(&main_output, earlier);
+    let main_modified = modified(&main_output);
+    for file in ["main.txt", "include.txt"] {
+        set_modified(
+            &src_dir.join(file),
+            SystemTime::now() + Duration::from_secs(60),
+        );
+    }
+    run();
+    assert_eq!(modified(&main_output), main_modified);
+
+    // Changed content is rebuilt, whatever the modification times say
+    fs::write(src_dir.join("include.txt"), "Changed.\n").unwrap();
+    set_modified(
+        &src_dir.join("include.txt"),
+        earlier - Duration::from_secs(60),
+    );
+    run();
+    assert_ne!(modified(&main_output), main_modified);
+    assert_eq!(
+        fs::read_to_string(&main_output).unwrap(),
+        "Changed.\nMain.\n"
+    );
+}
+
+#[test]
+fn test_include_cache_is_reused() {
+    let temp_dir = tempdir().unwrap();
+    let src_dir = temp_dir.path().join("src");
+    let target_dir = temp_dir.path().join("target");
+
+    fs::create_dir_all(&src_dir).unwrap();
+
+    fs::write(src_dir.join("main.txt"), "--include include.txt\nMain.\n").unwrap();
+    fs::write(src_dir.join("include.txt"), "Included.\n").unwrap();
+
+    let run = || {
+        let output = Command::new("cargo")
+            .arg("run")
+            .arg("--")
+            .arg("--src")
+            .arg(src_dir.to_str().unwrap())
+            .arg("--target")
+            .arg(target_dir.to_str().unwrap())
+            .arg("--incremental")
+            .arg("--verbose")
+            .output()
+            .expect("Failed to execute process");
+        assert!(output.status.success());
+        String::from_utf8_lossy(&output.stdout).to_string()
+    };
+
+    let stdout = run();
+    assert!(!stdout.contains("Loaded the include cache"));
+    let cache = fs::read_to_string(target_dir.join(".simple-include-cache.json")).unwrap();
+    assert!(cache.contains("include.txt"));
+    assert!(cache.contains("main.txt"));
+
+    // Make the include newer than the output
+    let main_output = target_dir.join("main.txt");
+    File::options()
+        .write(true)
+        .open(&main_output)
+        .unwrap()
+        .set_modified(SystemTime::now() - Duration::from_secs(60))
+        .unwrap();
+    fs::write(src_dir.join("include.txt"), "Changed.\n").unwrap();
+
+    let stdout = run();
+    assert!(stdout.contains("Loaded the include cache"));
+    assert_eq!(
+        fs::read_to_string(&main_output).unwrap(),
+        "Changed.\nMain.\n"
+    );
+}
+
+#[test]
+fn test_watch_debounces_rapid_writes() {
+    let temp_dir = tempdir().unwrap();
+    let src_dir = temp_dir.path().join("src");
+    let target_dir = temp_dir.path().join("target");
+
+    fs::create_dir_all(&src_dir).unwrap();
+    fs::create_dir_all(&target_dir).unwrap();
+
+    let main_path = src_dir.join("main.txt");
+    fs::write(&main_path, "Version 0\n").unwrap();
+
+    let mut child = Command::new("cargo")
+        .arg("run")
+        .arg("--")
+        .arg("--src")
+        .arg(src_dir.to_str().unwrap())
+        .arg("--target")
+        .arg(target_dir.to_str().unwrap())
+        .arg("--watch")
+        .arg("-vv")
+        .arg("--debounce")
+        .arg("300")
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("Failed to start process");
+
+    // Wait for the initial pass
+    let main_output = target_dir.join("main.txt");
+    let mut counter = 0;
+    while counter < 50 && !main_output.exists() {
+        thread::sleep(Duration::from_millis(100));
+        counter += 1;
+    }
+    thread::sleep(Duration::from_millis(200));
+
+    for version in 1..=5 {
+        fs::write(&main_path, format!("Version {}\n", version)).unwrap();
+        thread::sleep(Duration::from_millis(10));
+    }
+
+    counter = 0;
+    while counter < 50 && fs::read_to_string(&main_output).unwrap() != "Version 5\n" {
+        thread::sleep(Duration::from_millis(100));
+        counter += 1;
+    }
+    // Give any further batches time to run
+    thread::sleep(Duration::from_millis(500));
+
+    child.kill().expect("Failed to kill process");
+    let output = child
+        .wait_with_output()
+        .expect("Failed to wait for process");
+
+    assert_eq!(fs::read_to_string(&main_output).unwrap(), "Version 5\n");
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let changes = stdout
+        .lines()
+        .filter(|line| line.starts_with("File changed") && line.contains("main.txt"))
+        .count();
+    assert_eq!(changes, 1, "{}", stdout);
+    assert!(!stdout.contains("Error"), "{}", stdout);
+}
+
+#[test]
+fn test_watch_flushes_a_batch_that_keeps_growing() {
+    let temp_dir = tempdir().unwrap();
+    let src_dir = temp_dir.path().join("src");
+    let target_dir = temp_dir.path().join("target");
+
+    fs::create_dir_all(&src_dir).unwrap();
+    fs::create_dir_all(&target_dir).unwrap();
+
+    let main_path = src_dir.join("main.txt");
+    fs::write(&main_path, "Version 0\n").unwrap();
+
+    let mut child = Command::new("cargo")
+        .arg("run")
+        .arg("--")
+        .arg("--src")
+        .arg(src_dir.to_str().unwrap())
+        .arg("--target")
+        .arg(target_dir.to_str().unwrap())
+        .arg("--watch")
+        .arg("--debounce")
+        .arg("200")
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .expect("Failed to start process");
+
+    // Wait for the initial pass
+    let main_output = target_dir.join("main.txt");
+    let mut counter = 0;
+    while counter < 50 && !main_output.exists() {
+        thread::sleep(Duration::from_millis(100));
+        counter += 1;
+    }
+    thread::sleep(Duration::from_millis(200));
+
+    // Keep writing well inside the debounce window, for far longer than ten of them
+    let mut regenerated = false;
+    for version in 1..=160 {
+        fs::write(&main_path, format!("Version {}\n", version)).unwrap();
+        thread::sleep(Duration::from_millis(50));
+        if fs::read_to_string(&main_output).unwrap() != "Version 0\n" {
+            regenerated = true;
+            break;
+        }
+    }
+
+    child.kill().expect("Failed to kill process");
+    child.wait().expect("Failed to wait for process");
+
+    assert!(
+        regenerated,
+        "output was not regenerated while changes kept arriving"
+    );
+}
+
+#[test]
+fn test_watch_processes_each_file_once_per_batch() {
+    let temp_dir = tempdir().unwrap();
+    let src_dir = temp_dir.path().join("src");
+    let target_dir = temp_dir.path().join("target");
+
+    fs::create_dir_all(&src_dir).unwrap();
+    fs::create_dir_all(&target_dir).unwrap();
+
+    fs::write(src_dir.join("a.txt"), "A.\n").unwrap();
+    fs::write(src_dir.join("b.txt"), "B.\n").unwrap();
+    fs::write(
+        src_dir.join("main.txt"),
+        "--include a.txt\n--include b.txt\nMain.\n",
+    )
+    .unwrap();
+
+    let mut child = Command::new("cargo")
+        .arg("run")
+        .arg("--")
+        .arg("--src")
+        .arg(src_dir.to_str().unwrap())
+        .arg("--target")
+        .arg(target_dir.to_str().unwrap())
+        .arg("--watch")
+        .arg("-vv")
+        .arg("--debounce")
+        .arg("300")
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .expect("Failed to start process");
+
+    let main_output = target_dir.join("main.txt");
+    let mut counter = 0;
+    while counter < 50 && !main_output.exists() {
+        thread::sleep(Duration::from_millis(100));
+        counter += 1;
+    }
+    thread::sleep(Duration::from_millis(200));
+
+    // Both includes and the file itself change, some more than once, in one batch
+    fs::write(src_dir.join("a.txt"), "A1.\n").unwrap();
+    fs::write(src_dir.join("b.txt"), "B1.\n").unwrap();
+    fs::write(
+        src_dir.join("main.txt"),
+        "--include a.txt\n--include b.txt\nMain 1.\n",
+    )
+    .unwrap();
+    fs::write(src_dir.join("a.txt"), "A2.\n").unwrap();
+
+    let expected = "A2.\nB1.\nMain 1.\n";
+    counter = 0;
+    while counter < 50 && fs::read_to_string(&main_output).unwrap() != expected {
+        thread::sleep(Duration::from_millis(100));
+        counter += 1;
+    }
+    thread::sleep(Duration::from_millis(500));
+
+    child.kill().expect("Failed to kill process");
+    let output = child
+        .wait_with_output()
+        .expect("Failed to wait for process");
+
+    assert_eq!(fs::read_to_string(&main_output).unwrap(), expected);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    // The first pass and the rescan once watching has started come before any change is seen
+    let (_, after_change) = stdout.split_once("File changed").unwrap();
+    let writes = after_change
+        .lines()
+        .filter(|line| line.starts_with("Input") && line.contains("main.txt"))
+        .count();
+    assert_eq!(writes, 1, "{}", stdout);
+}
+
+#[test]
+fn test_watch_reads_files_written_in_chunks() {
+    let temp_dir = tempdir().unwrap();
+    let src_dir = temp_dir.path().join("src");
+    let target_dir = temp_dir.path().join("target");
+
+    fs::create_dir_all(&src_dir).unwrap();
+    fs::create_dir_all(&target_dir).unwrap();
+
+    fs::write(src_dir.join("part.txt"), "Part.\n").unwrap();
+    fs::write(src_dir.join("main.txt"), "--include part.txt\nMain.\n").unwrap();
+
+    let mut child = Command::new("cargo")
+        .arg("run")
+        .arg("--")
+        .arg("--src")
+        .arg(src_dir.to_str().unwrap())
+        .arg("--target")
+        .arg(target_dir.to_str().unwrap())
+        .arg("--watch")
+        .arg("--debounce")
+        .arg("1")
+        .arg("--read-retries")
+        .arg("5")
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .expect("Failed to start process");
+
+    let main_output = target_dir.join("main.txt");
+    let mut counter = 0;
+    while counter < 50 && !main_output.exists() {
+        thread::sleep(Duration::from_millis(100));
+        counter += 1;
+    }
+    thread::sleep(Duration::from_millis(200));
+
+    // Truncated, then written in two chunks, like an editor saving
+    let mut part = File::create(src_dir.join("part.txt")).unwrap();
+    part.write_all(b"First line.\n").unwrap();
+    part.flush().unwrap();
+    thread::sleep(Duration::from_millis(5));
+    part.write_all(b"Second line.\n").unwrap();
+    drop(part);
+
+    let expected = "First line.\nSecond line.\nMain.\n";
+    counter = 0;
+    while counter < 50 && fs::read_to_string(&main_output).unwrap() != expected {
+        thread::sleep(Duration::from_millis(100));
+        counter += 1;
+    }
+    child.kill().expect("Failed to kill process");
+    child.wait().expect("Failed to wait for process");
+
+    assert_eq!(fs::read_to_string(&main_output).unwrap(), expected);
+}
+
+#[test]
+fn test_watch_handles_bursts_of_changes() {
+    let temp_dir = tempdir().unwrap();
+    let src_dir = temp_dir.path().join("src");
+    let target_dir = temp_dir.path().join("target");
+
+    const FILES: usize = 2000;
+    for dir in 0..20 {
+        fs::create_dir_all(src_dir.join(format!("dir{}", dir))).unwrap();
+    }
+    let file = |i: usize| format!("dir{}/file{}.txt", i % 20, i);
+    for i in 0..FILES {
+        fs::write(src_dir.join(file(i)), "Before.\n").unwrap();
+    }
+
+    let mut child = Command::new("cargo")
+        .arg("run")
+        .arg("--")
+        .arg("--src")
+        .arg(src_dir.to_str().unwrap())
+        .arg("--target")
+        .arg(target_dir.to_str().unwrap())
+        .arg("--watch")
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .expect("Failed to start process");
+
+    let last_output = target_dir.join(file(FILES - 1));
+    let mut counter = 0;
+    while counter < 100 && !last_output.exists() {
+        thread::sleep(Duration::from_millis(100));
+        counter += 1;
+    }
+    thread::sleep(Duration::from_millis(500));
+
+    // Every file changes at once, more than fit in the watcher's queue
+    for i in 0..FILES {
+        fs::write(src_dir.join(file(i)), format!("After {}.\n", i)).unwrap();
+    }
+
+    let all_updated = || {
+        (0..FILES).all(|i| {
+            fs::read_to_string(target_dir.join(file(i))).ok() == Some(format!("After {}.\n", i))
+        })
+    };
+    counter = 0;
+    while counter < 100 && !all_updated() {
+        thread::sleep(Duration::from_millis(100));
+        counter += 1;
+    }
+    child.kill().expect("Failed to kill process");
+    child.wait().expect("Failed to wait for process");
+
+    assert!(all_updated());
+}
+
+#[test]
+fn test_watch_on_change() {
+    let temp_dir = tempdir().unwrap();
+    let src_dir = temp_dir.path().join("src");
+    let target_dir = temp_dir.path().join("target");
+    let marker = temp_dir.path().join("changed.log");
+
+    fs::create_dir_all(&src_dir).unwrap();
+    fs::write(src_dir.join("main.txt"), "Before.\n").unwrap();
+
+    let mut child = Command::new("cargo")
+        .arg("run")
+        .arg("--")
+        .arg("--src")
+        .arg(src_dir.to_str().unwrap())
+        .arg("--target")
+        .arg(target_dir.to_str().unwrap())
+        .arg("--watch")
+        .arg("--on-change")
+        .arg(format!(
+            "echo \"$SI_CHANGED_FILE\" >> '{}'",
+            marker.display()
+        ))
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .expect("Failed to start process");
+
+    while !target_dir.join("main.txt").exists() {
+        thread::sleep(Duration::from_millis(100));
+    }
+    thread::sleep(Duration::from_millis(200));
+    // The first pass isn't a change, so the command isn't run for it
+    assert!(!marker.exists());
+
+    fs::write(src_dir.join("main.txt"), "After.\n").unwrap();
+    let mut logged = String::new();
+    for _ in 0..50 {
+        logged = fs::read_to_string(&marker).unwrap_or_default();
+        if !logged.is_empty() {
+            break;
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+    child.kill().expect("Failed to kill process");
+    child.wait().expect("Failed to wait for process");
+
+    assert_eq!(logged.lines().next(), Some("main.txt"));
+    assert_eq!(
+        fs::read_to_string(target_dir.join("main.txt")).unwrap(),
+        "After.\n"
+    );
+}
+
+#[test]
+fn test_watch_processes_new_files() {
+    let temp_dir = tempdir().unwrap();
+    let src_dir = temp_dir.path().join("src");
+    let target_dir = temp_dir.path().join("target");
+
+    fs::create_dir_all(&src_dir).unwrap();
+    fs::create_dir_all(&target_dir).unwrap();
+
+    fs::write(src_dir.join("include.txt"), "Included.\n").unwrap();
+    fs::write(src_dir.join("main.txt"), "--include later.txt\nMain.\n").unwrap();
+
+    let mut child = Command::new("cargo")
+        .arg("run")
+        .arg("--")
+        .arg("--src")
+        .arg(src_dir.to_str().unwrap())
+        .arg("--target")
+        .arg(target_dir.to_str().unwrap())
+        .arg("--watch")
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .expect("Failed to start process");
+
+    // Wait for the initial pass
+    let main_output = target_dir.join("main.txt");
+    let mut counter = 0;
+    while counter < 50 && !main_output.exists() {
+        thread::sleep(Duration::from_millis(100));
+        counter += 1;
+    }
+    thread::sleep(Duration::from_millis(200));
+
+    // A new file that includes an existing one
+    fs::write(src_dir.join("new.txt"), "--include include.txt\nNew.\n").unwrap();
+    // A new file that an existing file already tries to include
+    fs::write(src_dir.join("later.txt"), "Later.\n").unwrap();
+
+    let new_output = target_dir.join("new.txt");
+    counter = 0;
+    while counter < 50
+        && !(fs::read_to_string(&new_output).unwrap_or_default() == "Included.\nNew.\n"
+            && fs::read_to_string(&main_output).unwrap() == "Later.\nMain.\n")
+    {
+        thread::sleep(Duration::from_millis(100));
+        counter += 1;
+    }
+
+    child.kill().expect("Failed to kill process");
+    child.wait().expect("Failed to wait for process");
+
+    assert_eq!(
+        fs::read_to_string(&new_output).unwrap(),
+        "Included.\nNew.\n"
+    );
+    assert_eq!(fs::read_to_string(&main_output).unwrap(), "Later.\nMain.\n");
+}
+
+#[test]
+fn test_watch_picks_up_changes_made_during_startup() {
+    let temp_dir = tempdir().unwrap();
+    let src_dir = temp_dir.path().join("src");
+    let target_dir = temp_dir.path().join("target");
+
+    fs::create_dir_all(&src_dir).unwrap();
+    fs::create_dir_all(&target_dir).unwrap();
+
+    fs::write(src_dir.join("include.txt"), "Included.\n").unwrap();
+    fs::write(src_dir.join("main.txt"), "--include include.txt\nMain.\n").unwrap();
+
+    let mut child = Command::new("cargo")
+        .arg("run")
+        .arg("--")
+        .arg("--src")
+        .arg(src_dir.to_str().unwrap())
+        .arg("--target")
+        .arg(target_dir.to_str().unwrap())
+        .arg("--watch")
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .expect("Failed to start process");
+
+    // Change the include as soon as the first pass has written the output, before the watcher
+    // is likely to be set up
+    let main_output = target_dir.join("main.txt");
+    let mut counter = 0;
+    while counter < 500 && !main_output.exists() {
+        thread::sleep(Duration::from_millis(10));
+        counter += 1;
+    }
+    fs::write(src_dir.join("include.txt"), "Changed.\n").unwrap();
+
+    counter = 0;
+    while counter < 50 && fs::read_to_string(&main_output).unwrap() != "Changed.\nMain.\n" {
+        thread::sleep(Duration::from_millis(100));
+        counter += 1;
+    }
+
+    child.kill().expect("Failed to kill process");
+    child.wait().expect("Failed to wait for process");
+
+    assert_eq!(
+        fs::read_to_string(&main_output).unwrap(),
+        "Changed.\nMain.\n"
+    );
+}
+
+#[test]
+fn test_watch_rebuilds_transitive_dependents() {
+    let temp_dir = tempdir().unwrap();
+    let src_dir = temp_dir.path().join("src");
+    let target_dir = temp_dir.path().join("target");
+
+    fs::create_dir_all(&src_dir).unwrap();
+    fs::create_dir_all(&target_dir).unwrap();
+
+    fs::write(src_dir.join("c.txt"), "C.\n").unwrap();
+    fs::write(src_dir.join("b.txt"), "--include c.txt\nB.\n").unwrap();
+    fs::write(src_dir.join("a.txt"), "--include b.txt\nA.\n").unwrap();
+    fs::write(src_dir.join("d.txt"), "D.\n").unwrap();
+
+    let mut child = Command::new("cargo")
+        .arg("run")
+        .arg("--")
+        .arg("--src")
+        .arg(src_dir.to_str().unwrap())
+        .arg("--target")
+        .arg(target_dir.to_str().unwrap())
+        .arg("--watch")
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .expect("Failed to start process");
+
+    let wait_for = |file: &str, expected: &str| {
+        let output = target_dir.join(file);
+        let mut counter = 0;
+        while counter < 50 && fs::read_to_string(&output).unwrap_or_default() != expected {
+            thread::sleep(Duration::from_millis(100));
+            counter += 1;
+        }
+        fs::read_to_string(&output).unwrap_or_default()
+    };
+
+    assert_eq!(wait_for("a.txt", "C.\nB.\nA.\n"), "C.\nB.\nA.\n");
+    thread::sleep(Duration::from_millis(500));
+
+    fs::write(src_dir.join("c.txt"), "Changed C.\n").unwrap();
+    let b = wait_for("b.txt", "Changed C.\nB.\n");
+    let a = wait_for("a.txt", "Changed C.\nB.\nA.\n");
+
+    // A new nested include is followed too, although a.txt itself didn't change
+    fs::write(
+        src_dir.join("b.txt"),
+        "--include c.txt\n--include d.txt\nB.\n",
+    )
+    .unwrap();
+    wait_for("a.txt", "Changed C.\nD.\nB.\nA.\n");
+    thread::sleep(Duration::from_millis(300));
+    fs::write(src_dir.join("d.txt"), "Changed D.\n").unwrap();
+    let nested = wait_for("a.txt", "Changed C.\nChanged D.\nB.\nA.\n");
+
+    child.kill().expect("Failed to kill process");
+    child.wait().expect("Failed to wait for process");
+
+    assert_eq!(b, "Changed C.\nB.\n");
+    assert_eq!(a, "Changed C.\nB.\nA.\n");
+    assert_eq!(nested, "Changed C.\nChanged D.\nB.\nA.\n");
+}
+
+#[cfg(unix)]
+#[test]
+fn test_watch_rebuilds_every_includer_of_a_shared_file() {
+    let temp_dir = tempdir().unwrap();
+    let src_dir = temp_dir.path().join("src");
+    let target_dir = temp_dir.path().join("target");
+
+    fs::create_dir_all(src_dir.join("shared")).unwrap();
+    fs::create_dir_all(src_dir.join("guide/intro")).unwrap();
+    fs::create_dir_all(src_dir.join("api")).unwrap();
+    std::os::unix::fs::symlink(src_dir.join("shared"), src_dir.join("common")).unwrap();
+
+    fs::write(src_dir.join("shared/footer.txt"), "Footer.\n").unwrap();
+    // The same file, spelled three ways
+    fs::write(
+        src_dir.join("guide/intro/page.txt"),
+        "--include ../../shared/footer.txt\n",
+    )
+    .unwrap();
+    fs::write(
+        src_dir.join("api/page.txt"),
+        "--include ../shared/./footer.txt\n",
+    )
+    .unwrap();
+    fs::write(
+        src_dir.join("api/linked.txt"),
+        "--include ../common/footer.txt\n",
+    )
+    .unwrap();
+
+    let mut child = Command::new("cargo")
+        .arg("run")
+        .arg("--")
+        .arg("--src")
+        .arg(src_dir.to_str().unwrap())
+        .arg("--target")
+        .arg(target_dir.to_str().unwrap())
+        .arg("--watch")
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .expect("Failed to start process");
+
+    let wait_for = |file: &str, expected: &str| {
+        let output = target_dir.join(file);
+        let mut counter = 0;
+        while counter < 50 && fs::read_to_string(&output).unwrap_or_default() != expected {
+            thread::sleep(Duration::from_millis(100));
+            counter += 1;
+        }
+        fs::read_to_string(&output).unwrap_or_default()
+    };
+
+    assert_eq!(wait_for("api/linked.txt", "Footer.\n"), "Footer.\n");
+    thread::sleep(Duration::from_millis(500));
+
+    fs::write(src_dir.join("shared/footer.txt"), "New footer.\n").unwrap();
+    let guide = wait_for("guide/intro/page.txt", "New footer.\n");
+    let api = wait_for("api/page.txt", "New footer.\n");
+    let linked = wait_for("api/linked.txt", "New footer.\n");
+
+    child.kill().expect("Failed to kill process");
+    child.wait().expect("Failed to wait for process");
+
+    assert_eq!(guide, "New footer.\n");
+    assert_eq!(api, "New footer.\n");
+    assert_eq!(linked, "New footer.\n");
+}
+
+#[test]
+fn test_watch_rebuild_all_on_include_change() {
+    let temp_dir = tempdir().unwrap();
+    let src_dir = temp_dir.path().join("src");
+    let target_dir = temp_dir.path().join("target");
+
+    fs::create_dir_all(&src_dir).unwrap();
+    fs::write(src_dir.join("header.txt"), "Header.\n").unwrap();
+    fs::write(src_dir.join("a.txt"), "--include header.txt\nA.\n").unwrap();
+    fs::write(src_dir.join("b.txt"), "--include header.txt\nB.\n").unwrap();
+    let notes = temp_dir.path().join("notes.txt");
+    fs::write(&notes, "Notes.\n").unwrap();
+
+    let output = Command::new("cargo")
+        .arg("run")
+        .arg("--")
+        .arg("--src")
+        .arg(src_dir.to_str().unwrap())
+        .arg("--target")
+        .arg(target_dir.to_str().unwrap())
+        .arg("--incremental")
+        .output()
+        .expect("Failed to execute process");
+    assert!(output.status.success());
+
+    // A stale cache that has b.txt including something else, so the header's only known includer
+    // is a.txt and b.txt is skipped as up to date
+    let canonical = |path: &Path| fs::canonicalize(path).unwrap().display().to_string();
+    fs::write(
+        target_dir.join(".simple-include-cache.json"),
+        format!(
+            "{{\n  {:?}: [{:?}],\n  {:?}: [{:?}]\n}}\n",
+            canonical(&src_dir.join("header.txt")),
+            canonical(&src_dir.join("a.txt")),
+            canonical(&notes),
+            canonical(&src_dir.join("b.txt")),
+        ),
+    )
+    .unwrap();
+    // Its output shows when the watcher has started
+    fs::write(src_dir.join("c.txt"), "C.\n").unwrap();
+
+    let mut child = Command::new("cargo")
+        .arg("run")
+        .arg("--")
+        .arg("--src")
+        .arg(src_dir.to_str().unwrap())
+        .arg("--target")
+        .arg(target_dir.to_str().unwrap())
+        .arg("--incremental")
+        .arg("--watch")
+        .arg("--rebuild-all-on-include-change")
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .expect("Failed to start process");
+
+    let wait_for = |file: &str, expected: &str| {
+        let output = target_dir.join(file);
+        let mut counter = 0;
+        while counter < 50 && fs::read_to_string(&output).unwrap_or_default() != expected {
+            thread::sleep(Duration::from_millis(100));
+            counter += 1;
+        }
+        fs::read_to_string(&output).unwrap_or_default()
+    };
+
+    assert_eq!(wait_for("c.txt", "C.\n"), "C.\n");
+    thread::sleep(Duration::from_millis(500));
+
+    fs::write(src_dir.join("header.txt"), "New header.\n").unwrap();
+    let a = wait_for("a.txt", "New header.\nA.\n");
+    let b = wait_for("b.txt", "New header.\nB.\n");
+
+    child.kill().expect("Failed to kill process");
+    child.wait().expect("Failed to wait for process");
+
+    assert_eq!(a, "New header.\nA.\n");
+    assert_eq!(b, "New header.\nB.\n");
+}
+
+#[test]
+fn test_watch_external_includes() {
+    let temp_dir = tempdir().unwrap();
+    let src_dir = temp_dir.path().join("src");
+    let target_dir = temp_dir.path().join("target");
+    let external_dir = temp_dir.path().join("external");
+
+    fs::create_dir_all(&src_dir).unwrap();
+    fs::create_dir_all(&target_dir).unwrap();
+    fs::create_dir_all(&external_dir).unwrap();
+
+    let external_file = external_dir.join("motd.txt");
+    fs::write(&external_file, "Welcome.\n").unwrap();
+    fs::write(
+        src_dir.join("main.txt"),
+        format!("--include {}\nMain.\n", external_file.display()),
+    )
+    .unwrap();
+
+    let mut child = Command::new("cargo")
+        .arg("run")
+        .arg("--")
+        .arg("--src")
+        .arg(src_dir.to_str().unwrap())
+        .arg("--target")
+        .arg(target_dir.to_str().unwrap())
+        .arg("--watch")
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .expect("Failed to start process");
+
+    let main_output = target_dir.join("main.txt");
+    let wait_for = |expected: &str| {
+        let mut counter = 0;
+        while counter < 50 && fs::read_to_string(&main_output).unwrap_or_default() != expected {
+            thread::sleep(Duration::from_millis(100));
+            counter += 1;
+        }
+    };
+    wait_for("Welcome.\nMain.\n");
+    // Give the watcher time to be set up
+    thread::sleep(Duration::from_millis(500));
+
+    fs::write(&external_file, "Changed.\n").unwrap();
+    wait_for("Changed.\nMain.\n");
+
+    child.kill().expect("Failed to kill process");
+    child.wait().expect("Failed to wait for process");
+
+    assert_eq!(
+        fs::read_to_string(&main_output).unwrap(),
+        "Changed.\nMain.\n"
+    );
+}
+
+#[cfg(unix)]
+#[test]
+fn test_watch_exits_cleanly_on_interrupt() {
+    let temp_dir = tempdir().unwrap();
+    let src_dir = temp_dir.path().join("src");
+    let target_dir = temp_dir.path().join("target");
+
+    fs::create_dir_all(&src_dir).unwrap();
+    fs::create_dir_all(&target_dir).unwrap();
+
+    fs::write(src_dir.join("include.txt"), "Included.\n").unwrap();
+    fs::write(src_dir.join("main.txt"), "--include include.txt\nMain.\n").unwrap();
+
+    let mut child = Command::new("cargo")
+        .arg("run")
+        .arg("--")
+        .arg("--src")
+        .arg(src_dir.to_str().unwrap())
+        .arg("--target")
+        .arg(target_dir.to_str().unwrap())
+        .arg("--watch")
+        .arg("-v")
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .expect("Failed to start process");
+
+    // Wait for the watcher to start
+    let stdout = BufReader::new(child.stdout.take().unwrap());
+    let mut lines = stdout.lines();
+    for line in lines.by_ref() {
+        if line.unwrap().contains("Watching for changes") {
+            break;
+        }
+    }
+    thread::sleep(Duration::from_millis(200));
+
+    let kill = Command::new("kill")
+        .arg("-INT")
+        .arg(child.id().to_string())
+        .status()
+        .unwrap();
+    assert!(kill.success());
+
+    let mut counter = 0;
+    let status = loop {
+        if let Some(status) = child.try_wait().unwrap() {
+            break Some(status);
+        }
+        if counter == 50 {
+            break None;
+        }
+        thread::sleep(Duration::from_millis(100));
+        counter += 1;
+    };
+    let Some(status) = status else {
+        child.kill().expect("Failed to kill process");
+        child.wait().expect("Failed to wait for process");
+        panic!("The watcher did not exit after an interrupt");
+    };
+
+    assert!(status.success());
+    let rest: Vec<String> = lines.map(|line| line.unwrap()).collect();
+    assert!(rest.iter().any(|line| line.contains("Shutting down")));
+    assert_eq!(
+        fs::read_to_string(target_dir.join("main.txt")).unwrap(),
+        "Included.\nMain.\n"
+    );
+}
+
+#[cfg(unix)]
+#[test]
+fn test_watch_exits_on_a_second_interrupt() {
+    use std::os::unix::process::ExitStatusExt;
+
+    let temp_dir = tempdir().unwrap();
+    let src_dir = temp_dir.path().join("src");
+    let target_dir = temp_dir.path().join("target");
+    let started = temp_dir.path().join("started");
+
+    fs::create_dir_all(&src_dir).unwrap();
+    fs::create_dir_all(&target_dir).unwrap();
+
+    let main_path = src_dir.join("main.txt");
+    fs::write(&main_path, "Main.\n").unwrap();
+
+    // A hook that hangs, so the first interrupt can't finish the batch it is waiting for
+    let mut child = Command::new("cargo")
+        .arg("run")
+        .arg("--")
+        .arg("--src")
+        .arg(src_dir.to_str().unwrap())
+        .arg("--target")
+        .arg(target_dir.to_str().unwrap())
+        .arg("--watch")
+        .arg("-v")
+        .arg("--on-change")
+        .arg(format!("touch '{}'; sleep 10", started.display()))
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .expect("Failed to start process");
+
+    // Wait for the watcher to start
+    let stdout = BufReader::new(child.stdout.take().unwrap());
+    let mut lines = stdout.lines();
+    for line in lines.by_ref() {
+        if line.unwrap().contains("Watching for changes") {
+            break;
+        }
+    }
+    thread::sleep(Duration::from_millis(200));
+
+    fs::write(&main_path, "Changed.\n").unwrap();
+    let mut counter = 0;
+    while counter < 50 && !started.exists() {
+        thread::sleep(Duration::from_millis(100));
+        counter += 1;
+    }
+    assert!(started.exists(), "The --on-change command did not run");
+
+    for _ in 0..2 {
+        let kill = Command::new("kill")
+            .arg("-INT")
+            .arg(child.id().to_string())
+            .status()
+            .unwrap();
+        assert!(kill.success());
+        thread::sleep(Duration::from_millis(200));
+    }
+
+    let mut counter = 0;
+    let status = loop {
+        if let Some(status) = child.try_wait().unwrap() {
+            break Some(status);
+        }
+        if counter == 30 {
+            break None;
+        }
+        thread::sleep(Duration::from_millis(100));
+        counter += 1;
+    };
+    let Some(status) = status else {
+        child.kill().expect("Failed to kill process");
+        child.wait().expect("Failed to wait for process");
+        panic!("The watcher did not exit after a second interrupt");
+    };
+
+    assert_eq!(status.code(), Some(130), "{:?}", status.signal());
+}
+
+#[test]
+fn test_source_and_target_overlap() {
+    let temp_dir = tempdir().unwrap();
+    let src_dir = temp_dir.path().join("src");
+
+    fs::create_dir_all(src_dir.join("build")).unwrap();
+    fs::write(src_dir.join("main.txt"), "Main.\n").unwrap();
+
+    let run = |src_dir: &Path, target_dir: &Path| {
+        Command::new("cargo")
+            .arg("run")
+            .arg("--")
+            .arg("--src")
+            .arg(src_dir.to_str().unwrap())
+            .arg("--target")
+            .arg(target_dir.to_str().unwrap())
+            .output()
+            .expect("Failed to execute process")
+    };
+
+    // A target nested deeper in the source is left out of the sources, so a second run
+    // doesn't pick up the first run's output
+    let target_dir = src_dir.join("build/out");
+    for _ in 0..2 {
+        let output = run(&src_dir, &target_dir);
+        assert!(output.status.success());
+    }
+    assert_eq!(
+        fs::read_to_string(target_dir.join("main.txt")).unwrap(),
+        "Main.\n"
+    );
+    assert!(!target_dir.join("build").exists());
+
+    // The same directory as source and target would overwrite the sources
+    let output = run(&src_dir, &src_dir.join("."));
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("are both"), "{}", stderr);
+
+    // A source inside the target would have its own output read back as sources
+    let output = run(&src_dir.join("build"), &src_dir);
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("is inside the target directory"),
+        "{}",
+        stderr
+    );
+    assert!(!src_dir.join("out").exists());
+}
+
+#[test]
+fn test_clean_with_target_inside_source() {
+    let temp_dir = tempdir().unwrap();
+    let src_dir = temp_dir.path().join("src");
+    let target_dir = src_dir.join("target");
+
+    fs::create_dir_all(&target_dir).unwrap();
+
+    fs::write(src_dir.join("main.txt"), "This is the main file.\n").unwrap();
+    fs::write(target_dir.join("stale.txt"), "This file has no source.\n").unwrap();
+
+    let output = Command::new("cargo")
+        .arg("run")
+        .arg("--")
+        .arg("--src")
+        .arg(src_dir.to_str().unwrap())
+        .arg("--target")
+        .arg(target_dir.to_str().unwrap())
+        .arg("--clean")
+        .output()
+        .expect("Failed to execute process");
+    assert!(output.status.success());
+
+    assert!(!target_dir.join("stale.txt").exists());
+    assert!(target_dir.join("main.txt").exists());
+    assert!(src_dir.join("main.txt").exists());
+}
+
+#[test]
+fn test_watch_timestamps() {
+    let temp_dir = tempdir().unwrap();
+    let src_dir = temp_dir.path().join("src");
+    let target_dir = temp_dir.path().join("target");
+
+    fs::create_dir_all(&src_dir).unwrap();
+    fs::write(src_dir.join("main.txt"), "Before.\n").unwrap();
+
+    let mut child = Command::new("cargo")
+        .arg("run")
+        .arg("--")
+        .arg("--src")
+        .arg(src_dir.to_str().unwrap())
+        .arg("--target")
+        .arg(target_dir.to_str().unwrap())
+        .arg("--watch")
+        .arg("--timestamps")
+        // Two hours ahead of UTC all year round
+        .env("TZ", "XST-2")
+        .arg("-vv")
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .expect("Failed to start process");
+
+    let stdout = BufReader::new(child.stdout.take().unwrap());
+    let mut lines = stdout.lines().map(|line| line.unwrap());
+    let mut seen = Vec::new();
+    for line in lines.by_ref() {
+        if line.contains("Watching for changes") {
+            seen.push(line);
+            break;
+        }
+    }
+    thread::sleep(Duration::from_millis(200));
+    fs::write(src_dir.join("main.txt"), "After.\n").unwrap();
+    for line in lines.by_ref() {
+        let done = line.contains("File changed");
+        seen.push(line);
+        if done {
+            break;
+        }
+    }
+    child.kill().expect("Failed to kill process");
+    child.wait().expect("Failed to wait for process");
+
+    // e.g. 2024-05-01T11:30:15.042+02:00
+    assert!(seen.len() >= 2, "{:?}", seen);
+    for line in &seen {
+        let (timestamp, _) = line.split_once(' ').unwrap();
+        let (date, time) = timestamp.split_once('T').unwrap();
+        let date: Vec<u32> = date.split('-').map(|n| n.parse().unwrap()).collect();
+        assert!(date[0] >= 2024 && (1..=12).contains(&date[1]) && (1..=31).contains(&date[2]));
+        let (time, offset) = time.split_at(12);
+        assert_eq!(offset, "+02:00", "{}", line);
+        let time: Vec<f64> = time.split(':').map(|n| n.parse().unwrap()).collect();
+        assert!(
+            time[0] < 24.0 && time[1] < 60.0 && time[2] < 60.0,
+            "{}",
+            line
+        );
+    }
+}
+
+#[test]
+fn test_watch_handles_renames() {
+    let temp_dir = tempdir().unwrap();
+    let src_dir = temp_dir.path().join("src");
+    let target_dir = temp_dir.path().join("target");
+
+    fs::create_dir_all(src_dir.join("old_dir")).unwrap();
+    fs::create_dir_all(&target_dir).unwrap();
+
+    fs::write(src_dir.join("include.txt"), "Included.\n").unwrap();
+    fs::write(src_dir.join("old.txt"), "--include include.txt\nOld.\n").unwrap();
+    fs::write(src_dir.join("old_dir/nested.txt"), "Nested.\n").unwrap();
+
+    let mut child = Command::new("cargo")
+        .arg("run")
+        .arg("--")
+        .arg("--src")
+        .arg(src_dir.to_str().unwrap())
+        .arg("--target")
+        .arg(target_dir.to_str().unwrap())
+        .arg("--watch")
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .expect("Failed to start process");
+
+    // Wait for the initial pass
+    let mut counter = 0;
+    while counter < 50 && !target_dir.join("old_dir/nested.txt").exists() {
+        thread::sleep(Duration::from_millis(100));
+        counter += 1;
+    }
+    thread::sleep(Duration::from_millis(200));
+
+    fs::rename(src_dir.join("old.txt"), src_dir.join("new.txt")).unwrap();
+    fs::rename(src_dir.join("old_dir"), src_dir.join("new_dir")).unwrap();
+
+    let done = || {
+        !target_dir.join("old.txt").exists()
+            && !target_dir.join("old_dir").exists()
+            && target_dir.join("new.txt").exists()
+            && target_dir.join("new_dir/nested.txt").exists()
+    };
+    counter = 0;
+    while counter < 50 && !done() {
+        thread::sleep(Duration::from_millis(100));
+        counter += 1;
+    }
+
+    child.kill().expect("Failed to kill process");
+    child.wait().expect("Failed to wait for process");
+
+    assert!(!target_dir.join("old.txt").exists());
+    assert!(!target_dir.join("old_dir").exists());
+    assert_eq!(
+        fs::read_to_string(target_dir.join("new.txt")).unwrap(),
+        "Included.\nOld.\n"
+    );
+    assert_eq!(
+        fs::read_to_string(target_dir.join("new_dir/nested.txt")).unwrap(),
+        "Nested.\n"
+    );
+}
+
+#[test]
+fn test_watch_with_polling() {
+    let temp_dir = tempdir().unwrap();
+    let src_dir = temp_dir.path().join("src");
+    let target_dir = temp_dir.path().join("target");
+
+    fs::create_dir_all(&src_dir).unwrap();
+    fs::create_dir_all(&target_dir).unwrap();
+
+    let main_path = src_dir.join("main.txt");
+    fs::write(&main_path, "Before.\n").unwrap();
+
+    let mut child = Command::new("cargo")
+        .arg("run")
+        .arg("--")
+        .arg("--src")
+        .arg(src_dir.to_str().unwrap())
+        .arg("--target")
+        .arg(target_dir.to_str().unwrap())
+        .arg("--watch")
+        .arg("--poll")
+        .arg("100")
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .expect("Failed to start process");
+
+    // Wait for the initial pass
+    let main_output = target_dir.join("main.txt");
+    let mut counter = 0;
+    while counter < 50 && !main_output.exists() {
+        thread::sleep(Duration::from_millis(100));
+        counter += 1;
+    }
+    thread::sleep(Duration::from_millis(300));
+
+    fs::write(&main_path, "After, with more text.\n").unwrap();
+
+    counter = 0;
+    while counter < 50 && fs::read_to_string(&main_output).unwrap() != "After, with more text.\n" {
+        thread::sleep(Duration::from_millis(100));
+        counter += 1;
+    }
+
+    child.kill().expect("Failed to kill process");
+    child.wait().expect("Failed to wait for process");
+
+    assert_eq!(
+        fs::read_to_string(&main_output).unwrap(),
+        "After, with more text.\n"
+    );
+}
+
+#[cfg(unix)]
+#[test]
+fn test_permissions_are_preserved() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let temp_dir = tempdir().unwrap();
+    let src_dir = temp_dir.path().join("src");
+    let target_dir = temp_dir.path().join("target");
+
+    fs::create_dir_all(&src_dir).unwrap();
+
+    fs::write(src_dir.join("header.sh"), "set -e\n").unwrap();
+    let script_path = src_dir.join("build.sh");
+    fs::write(&script_path, "#!/bin/sh\n--include header.sh\necho built\n").unwrap();
+    fs::set_permissions(&script_path, fs::Permissions::from_mode(0o755)).unwrap();
+    let binary_path = src_dir.join("tool.bin");
+    fs::write(&binary_path, [0, 159, 146, 150]).unwrap();
+    fs::set_permissions(&binary_path, fs::Permissions::from_mode(0o755)).unwrap();
+
+    for _ in 0..2 {
+        let output = Command::new("cargo")
+            .arg("run")
+            .arg("--")
+            .arg("--src")
+            .arg(src_dir.to_str().unwrap())
+            .arg("--target")
+            .arg(target_dir.to_str().unwrap())
+            .arg("--verbose")
+            .output()
+            .expect("Failed to execute process");
+        assert!(output.status.success());
+    }
+
+    let mode = |path: &Path| fs::metadata(path).unwrap().permissions().mode() & 0o777;
+    assert_eq!(mode(&target_dir.join("build.sh")), 0o755);
+    assert_eq!(mode(&target_dir.join("tool.bin")), 0o755);
+    assert_eq!(
+        fs::read_to_string(target_dir.join("build.sh")).unwrap(),
+        "#!/bin/sh\nset -e\necho built\n"
+    );
+}
+
+#[test]
+fn test_line_endings() {
+    let temp_dir = tempdir().unwrap();
+    let src_dir = temp_dir.path().join("src");
+
+    fs::create_dir_all(&src_dir).unwrap();
+
+    fs::write(
+        src_dir.join("main.txt"),
+        "First\r\n--include include.txt\r\nLast\r\n",
+    )
+    .unwrap();
+    fs::write(src_dir.join("include.txt"), "Unix\nWindows\r\n").unwrap();
+
+    for (mode, expected) in [
+        ("preserve", "First\r\nUnix\nWindows\r\nLast\r\n"),
+        ("lf", "First\nUnix\nWindows\nLast\n"),
+        ("crlf", "First\r\nUnix\r\nWindows\r\nLast\r\n"),
+    ] {
+        let target_dir = temp_dir.path().join(mode);
+        let output = Command::new("cargo")
+            .arg("run")
+            .arg("--")
+            .arg("--src")
+            .arg(src_dir.to_str().unwrap())
+            .arg("--target")
+            .arg(target_dir.to_str().unwrap())
+            .arg("--line-endings")
+            .arg(mode)
+            .output()
+            .expect("Failed to execute process");
+        assert!(output.status.success());
+
+        assert_eq!(
+            fs::read_to_string(target_dir.join("main.txt")).unwrap(),
+            expected,
+            "--line-endings {}",
+            mode
+        );
+    }
+}
+
+#[test]
+fn test_trailing_newlines() {
+    let temp_dir = tempdir().unwrap();
+    let src_dir = temp_dir.path().join("src");
+
+    fs::create_dir_all(&src_dir).unwrap();
+
+    fs::write(src_dir.join("with.txt"), "--include multi.txt\nLast line\n").unwrap();
+    fs::write(
+        src_dir.join("without.txt"),
+        "--include multi.txt\nLast line",
+    )
+    .unwrap();
+    fs::write(
+        src_dir.join("ends_in_include.txt"),
+        "First line\n--include bare.txt",
+    )
+    .unwrap();
+    fs::write(
+        src_dir.join("between.txt"),
+        "--include multi.txt\n--include bare.txt\n--include crlf.txt\nAfter\n",
+    )
+    .unwrap();
+    // Included content joins the surrounding lines whether or not it ends in a newline, with no
+    // blank line added after it
+    fs::write(src_dir.join("multi.txt"), "One\nTwo\n").unwrap();
+    fs::write(src_dir.join("bare.txt"), "No newline").unwrap();
+    fs::write(src_dir.join("crlf.txt"), "Windows\r\n").unwrap();
+
+    let run = |target_dir: &Path, no_trailing_newline: bool| {
+        let mut command = Command::new("cargo");
+        command
+            .arg("run")
+            .arg("--")
+            .arg("--src")
+            .arg(src_dir.to_str().unwrap())
+            .arg("--target")
+            .arg(target_dir.to_str().unwrap());
+        if no_trailing_newline {
+            command.arg("--no-trailing-newline");
+        }
+        let output = command.output().expect("Failed to execute process");
+        assert!(output.status.success());
+    };
+
+    let target_dir = temp_dir.path().join("target");
+    run(&target_dir, false);
+    assert_eq!(
+        fs::read(target_dir.join("with.txt")).unwrap(),
+        b"One\nTwo\nLast line\n"
+    );
+    assert_eq!(
+        fs::read(target_dir.join("without.txt")).unwrap(),
+        b"One\nTwo\nLast line"
+    );
+    assert_eq!(
+        fs::read(target_dir.join("ends_in_include.txt")).unwrap(),
+        b"First line\nNo newline"
+    );
+    assert_eq!(
+        fs::read(target_dir.join("between.txt")).unwrap(),
+        b"One\nTwo\nNo newline\nWindows\r\nAfter\n"
+    );
+
+    let target_dir = temp_dir.path().join("target_no_newline");
+    run(&target_dir, true);
+    assert_eq!(
+        fs::read(target_dir.join("with.txt")).unwrap(),
+        b"One\nTwo\nLast line"
+    );
+    assert_eq!(
+        fs::read(target_dir.join("without.txt")).unwrap(),
+        b"One\nTwo\nLast line"
+    );
+}
+
+#[test]
+fn test_rename_extensions() {
+    let temp_dir = tempdir().unwrap();
+    let src_dir = temp_dir.path().join("src");
+    let target_dir = temp_dir.path().join("target");
+
+    fs::create_dir_all(src_dir.join("docs")).unwrap();
+    fs::create_dir_all(&target_dir).unwrap();
+
+    fs::write(src_dir.join("header.txt"), "Header\n").unwrap();
+    fs::write(
+        src_dir.join("docs/a.md.in"),
+        "--include ../header.txt\nBody\n",
+    )
+    .unwrap();
+
+    let output = Command::new("cargo")
+        .arg("run")
+        .arg("--")
+        .arg("--src")
+        .arg(src_dir.to_str().unwrap())
+        .arg("--target")
+        .arg(target_dir.to_str().unwrap())
+        .arg("--rename")
+        .arg(".md.in:.md")
+        .arg("--clean")
+        .output()
+        .expect("Failed to execute process");
+
+    assert!(output.status.success());
+
+    let output_content = fs::read_to_string(target_dir.join("docs/a.md")).unwrap();
+    assert_eq!(output_content, "Header\nBody\n");
+    assert!(!target_dir.join("docs/a.md.in").exists());
+    // Files that don't match keep their name
+    assert!(target_dir.join("header.txt").exists());
+}
+
+#[test]
+fn test_target_prefix_and_suffix() {
+    let temp_dir = tempdir().unwrap();
+    let src_dir = temp_dir.path().join("src");
+    let target_dir = temp_dir.path().join("target");
+
+    fs::create_dir_all(src_dir.join("docs")).unwrap();
+    fs::write(src_dir.join("header.txt"), "Header\n").unwrap();
+    fs::write(src_dir.join("README"), "Readme\n").unwrap();
+    fs::write(
+        src_dir.join("docs/a.md.in"),
+        "--include ../header.txt\nBody\n",
+    )
+    .unwrap();
+
+    let run = || {
+        let output = Command::new("cargo")
+            .arg("run")
+            .arg("--")
+            .arg("--src")
+            .arg(src_dir.to_str().unwrap())
+            .arg("--target")
+            .arg(target_dir.to_str().unwrap())
+            .arg("--rename")
+            .arg(".md.in:.md")
+            .arg("--target-prefix")
+            .arg("gen-")
+            .arg("--target-suffix")
+            .arg(".generated")
+            .arg("--mirror")
+            .output()
+            .expect("Failed to execute process");
+        assert!(output.status.success());
+    };
+
+    // The prefix and suffix are added to the renamed file name
+    run();
+    assert_eq!(
+        fs::read_to_string(target_dir.join("docs/gen-a.generated.md")).unwrap(),
+        "Header\nBody\n"
+    );
+    assert!(target_dir.join("gen-header.generated.txt").exists());
+    // A name without an extension gets the suffix at the end
+    assert!(target_dir.join("gen-README.generated").exists());
+
+    // Mirroring keeps the decorated outputs, as they still have sources, and removes the rest
+    fs::write(target_dir.join("stray.txt"), "Stray\n").unwrap();
+    run();
+    assert!(target_dir.join("docs/gen-a.generated.md").exists());
+    assert!(target_dir.join("gen-header.generated.txt").exists());
+    assert!(!target_dir.join("stray.txt").exists());
+}
+
+#[test]
+fn test_preserve_mtime() {
+    let temp_dir = tempdir().unwrap();
+    let src_dir = temp_dir.path().join("src");
+    let target_dir = temp_dir.path().join("target");
+
+    fs::create_dir_all(&src_dir).unwrap();
+    fs::create_dir_all(&target_dir).unwrap();
+
+    let set_mtime = |path: &Path, secs: u64| {
+        File::options()
+            .write(true)
+            .open(path)
+            .unwrap()
+            .set_modified(SystemTime::UNIX_EPOCH + Duration::from_secs(secs))
+            .unwrap();
+    };
+    fs::write(src_dir.join("plain.txt"), "Plain.\n").unwrap();
+    set_mtime(&src_dir.join("plain.txt"), 1_000_000);
+    fs::write(src_dir.join("include.txt"), "Included.\n").unwrap();
+    set_mtime(&src_dir.join("include.txt"), 3_000_000);
+    fs::write(src_dir.join("main.txt"), "--include include.txt\nMain.\n").unwrap();
+    set_mtime(&src_dir.join("main.txt"), 2_000_000);
+
+    let output = Command::new("cargo")
+        .arg("run")
+        .arg("--")
+        .arg("--src")
+        .arg(src_dir.to_str().unwrap())
+        .arg("--target")
+        .arg(target_dir.to_str().unwrap())
+        .arg("--preserve-mtime")
+        .output()
+        .expect("Failed to execute process");
+
+    assert!(output.status.success());
+
+    let mtime = |path: &Path| fs::metadata(path).unwrap().modified().unwrap();
+    assert_eq!(
+        mtime(&target_dir.join("plain.txt")),
+        SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000)
+    );
+    // A file with includes gets the newest time of the file and its includes
+    assert_eq!(
+        mtime(&target_dir.join("main.txt")),
+        SystemTime::UNIX_EPOCH + Duration::from_secs(3_000_000)
+    );
+}
+
+#[test]
+fn test_outputs_are_written_without_temporary_files() {
+    let temp_dir = tempdir().unwrap();
+    let src_dir = temp_dir.path().join("src");
+    let target_dir = temp_dir.path().join("target");
+
+    fs::create_dir_all(src_dir.join("sub")).unwrap();
+    fs::create_dir_all(&target_dir).unwrap();
+
+    let body = "A line of the included file.\n".repeat(10_000);
+    fs::write(src_dir.join("include.txt"), &body).unwrap();
+    fs::write(
+        src_dir.join("sub/main.txt"),
+        "--include ../include.txt\nMain.\n",
+    )
+    .unwrap();
+    fs::write(src_dir.join("binary.bin"), [0u8, 159, 146, 150]).unwrap();
+    // An existing output is replaced
+    fs::create_dir_all(target_dir.join("sub")).unwrap();
+    fs::write(target_dir.join("sub/main.txt"), "Old.\n").unwrap();
+
+    let output = Command::new("cargo")
+        .arg("run")
+        .arg("--")
+        .arg("-v")
+        .arg("--src")
+        .arg(src_dir.to_str().unwrap())
+        .arg("--target")
+        .arg(target_dir.to_str().unwrap())
+        .output()
+        .expect("Failed to execute process");
+
+    assert!(output.status.success());
+
+    assert_eq!(
+        fs::read_to_string(target_dir.join("sub/main.txt")).unwrap(),
+        format!("{}Main.\n", body)
+    );
+    assert_eq!(
+        fs::read(target_dir.join("binary.bin")).unwrap(),
+        [0u8, 159, 146, 150]
+    );
+    let residue: Vec<_> = walkdir::WalkDir::new(&target_dir)
+        .into_iter()
+        .map(|entry| entry.unwrap().into_path())
+        .filter(|path| path.to_string_lossy().ends_with(".tmp"))
+        .collect();
+    assert!(residue.is_empty(), "Temporary files left: {:?}", residue);
+}
+
+#[test]
+fn test_config_file() {
+    let temp_dir = tempdir().unwrap();
+    let src_dir = temp_dir.path().join("docs");
+
+    fs::create_dir_all(&src_dir).unwrap();
+
+    let mut main_file = File::create(src_dir.join("main.txt")).unwrap();
+    writeln!(main_file, "#include include.txt").unwrap();
+    writeln!(main_file, "This is the main file.").unwrap();
+
+    let mut include_file = File::create(src_dir.join("include.txt")).unwrap();
+    writeln!(include_file, "This is the included file.").unwrap();
+
+    let mut config_file = File::create(temp_dir.path().join("simple-include.toml")).unwrap();
+    writeln!(config_file, "# Settings for the test").unwrap();
+    writeln!(config_file, "src = \"docs\"").unwrap();
+    writeln!(config_file, "target = 'out'").unwrap();
+    writeln!(config_file, "include = \"#include\"").unwrap();
+    writeln!(config_file, "max-depth = 8").unwrap();
+    writeln!(config_file, "verbose = false").unwrap();
+    writeln!(config_file, "colour = true").unwrap();
+
+    let manifest = Path::new(env!("CARGO_MANIFEST_DIR")).join("Cargo.toml");
+    let output = Command::new("cargo")
+        .arg("run")
+        .arg("-q")
+        .arg("--manifest-path")
+        .arg(&manifest)
+        .current_dir(temp_dir.path())
+        .output()
+        .expect("Failed to execute process");
+
+    assert!(output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("Unknown key \"colour\""));
+    assert_eq!(
+        fs::read_to_string(temp_dir.path().join("out/main.txt")).unwrap(),
+        "This is the included file.\nThis is the main file.\n"
+    );
+
+    // Command line options override the config file
+    let output = Command::new("cargo")
+        .arg("run")
+        .arg("-q")
+        .arg("--manifest-path")
+        .arg(&manifest)
+        .arg("--")
+        .arg("--target")
+        .arg("other")
+        .current_dir(temp_dir.path())
+        .output()
+        .expect("Failed to execute process");
+
+    assert!(output.status.success());
+    assert!(temp_dir.path().join("other/main.txt").exists());
+}
+
+#[test]
+fn test_print_config() {
+    let temp_dir = tempdir().unwrap();
+    let src_dir = temp_dir.path().join("docs");
+
+    fs::create_dir_all(&src_dir).unwrap();
+    fs::write(src_dir.join("main.txt"), "Main.\n").unwrap();
+    fs::write(
+        temp_dir.path().join("simple-include.toml"),
+        "src = \"docs\"\ntarget = \"out\"\nmax-depth = 8\ninclude = \"#include\"\nverbose = 2\n",
+    )
+    .unwrap();
+
+    let manifest = Path::new(env!("CARGO_MANIFEST_DIR")).join("Cargo.toml");
+    let output = Command::new("cargo")
+        .arg("run")
+        .arg("-q")
+        .arg("--manifest-path")
+        .arg(&manifest)
+        .arg("--")
+        .arg("--max-depth")
+        .arg("3")
+        .arg("--print-config")
+        .current_dir(temp_dir.path())
+        .output()
+        .expect("Failed to execute process");
+
+    assert!(output.status.success());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let settings: Vec<&str> = stdout.lines().collect();
+    // The command line wins over the config file, which wins over the defaults
+    assert!(settings.contains(&"max_depth = 3"), "{}", stdout);
+    assert!(settings.contains(&"include = [\"#include\"]"), "{}", stdout);
+    assert!(settings.contains(&"src = [\"docs\"]"), "{}", stdout);
+    assert!(settings.contains(&"verbose = 2"), "{}", stdout);
+    assert!(
+        settings.contains(&"line_endings = \"preserve\""),
+        "{}",
+        stdout
+    );
+    // Nothing is processed
+    assert!(!temp_dir.path().join("out").exists());
+}
+
+#[test]
+fn test_environment_variables_in_include_paths() {
+    let temp_dir = tempdir().unwrap();
+    let src_dir = temp_dir.path().join("src");
+    let target_dir = temp_dir.path().join("target");
+    let shared_dir = temp_dir.path().join("shared");
+    let home_dir = temp_dir.path().join("home");
+
+    fs::create_dir_all(&src_dir).unwrap();
+    fs::create_dir_all(&shared_dir).unwrap();
+    fs::create_dir_all(&home_dir).unwrap();
+
+    let mut main_file = File::create(src_dir.join("main.txt")).unwrap();
+    writeln!(main_file, "--include $SHARED_DOCS/header.txt").unwrap();
+    writeln!(main_file, "--include ${{SHARED_DOCS}}/header.txt").unwrap();
+    writeln!(main_file, "--include $SIMPLE_INCLUDE_UNDEFINED/header.txt").unwrap();
+    writeln!(main_file, "--include ~/footer.txt").unwrap();
+
+    let mut header_file = File::create(shared_dir.join("header.txt")).unwrap();
+    writeln!(header_file, "Shared header").unwrap();
+
+    let mut footer_file = File::create(home_dir.join("footer.txt")).unwrap();
+    writeln!(footer_file, "Footer from home").unwrap();
+
+    let output = Command::new("cargo")
+        .arg("run")
+        .arg("--")
+        .arg("--src")
+        .arg(src_dir.to_str().unwrap())
+        .arg("--target")
+        .arg(target_dir.to_str().unwrap())
+        .arg("--verbose")
+        .env("SHARED_DOCS", &shared_dir)
+        .env("HOME", &home_dir)
+        .env_remove("SIMPLE_INCLUDE_UNDEFINED")
+        .output()
+        .expect("Failed to execute process");
+
+    assert!(output.status.success());
+    assert_eq!(
+        fs::read_to_string(target_dir.join("main.txt")).unwrap(),
+        "Shared header\nShared header\n--include $SIMPLE_INCLUDE_UNDEFINED/header.txt\nFooter from home\n"
+    );
+    assert!(String::from_utf8_lossy(&output.stderr)
+        .contains("environment variable \"SIMPLE_INCLUDE_UNDEFINED\" is not defined"));
+}
+
+#[test]
+fn test_root_relative_includes() {
+    let temp_dir = tempdir().unwrap();
+    let src_dir = temp_dir.path().join("src");
+    let target_dir = temp_dir.path().join("target");
+
+    fs::create_dir_all(src_dir.join("shared")).unwrap();
+    fs::create_dir_all(src_dir.join("a/b/c")).unwrap();
+
+    let mut header_file = File::create(src_dir.join("shared/header.txt")).unwrap();
+    writeln!(header_file, "Shared header").unwrap();
+
+    for dir in ["", "a", "a/b/c"] {
+        let mut file = File::create(src_dir.join(dir).join("page.txt")).unwrap();
+        writeln!(file, "--include @/shared/header.txt").unwrap();
+        writeln!(file, "Page").unwrap();
+    }
+    // Ordinary relative includes still resolve against the including file
+    let mut relative_file = File::create(src_dir.join("a/relative.txt")).unwrap();
+    writeln!(relative_file, "--include ../shared/header.txt").unwrap();
+
+    let status = Command::new("cargo")
+        .arg("run")
+        .arg("--")
+        .arg("--src")
+        .arg(src_dir.to_str().unwrap())
+        .arg("--target")
+        .arg(target_dir.to_str().unwrap())
+        .status()
+        .expect("Failed to execute process");
+
+    assert!(status.success());
+    for dir in ["", "a", "a/b/c"] {
+        assert_eq!(
+            fs::read_to_string(target_dir.join(dir).join("page.txt")).unwrap(),
+            "Shared header\nPage\n"
+        );
+    }
+    assert_eq!(
+        fs::read_to_string(target_dir.join("a/relative.txt")).unwrap(),
+        "Shared header\n"
+    );
+}
+
+#[test]
+fn test_glob_includes() {
+    let temp_dir = tempdir().unwrap();
+    let src_dir = temp_dir.path().join("src");
+    let target_dir = temp_dir.path().join("target");
+    let fragments_dir = temp_dir.path().join("fragments");
+
+    fs::create_dir_all(&src_dir).unwrap();
+    fs::create_dir_all(fragments_dir.join("old")).unwrap();
+
+    let mut main_file = File::create(src_dir.join("CHANGELOG.md")).unwrap();
+    writeln!(main_file, "# Changelog").unwrap();
+    writeln!(main_file, "--include ../fragments/*.md").unwrap();
+    writeln!(main_file, "--include ../fragments/*.txt").unwrap();
+
+    // Created out of order to check the matches are sorted. The last one has no trailing newline.
+    fs::write(fragments_dir.join("2.md"), "- Second\n").unwrap();
+    fs::write(fragments_dir.join("1.md"), "- First\n").unwrap();
+    fs::write(fragments_dir.join("3.md"), "- Third").unwrap();
+    fs::write(fragments_dir.join("old/0.md"), "- Not matched\n").unwrap();
+
+    let output = Command::new("cargo")
+        .arg("run")
+        .arg("--")
+        .arg("--src")
+        .arg(src_dir.to_str().unwrap())
+        .arg("--target")
+        .arg(target_dir.to_str().unwrap())
+        .arg("--verbose")
+        .output()
+        .expect("Failed to execute process");
+
+    assert!(output.status.success());
+    assert_eq!(
+        fs::read_to_string(target_dir.join("CHANGELOG.md")).unwrap(),
+        "# Changelog\n- First\n- Second\n- Third\n--include ../fragments/*.txt\n"
+    );
+    assert!(String::from_utf8_lossy(&output.stderr).contains("no files match"));
+}
+
+#[test]
+fn test_directory_includes() {
+    let temp_dir = tempdir().unwrap();
+    let src_dir = temp_dir.path().join("src");
+    let target_dir = temp_dir.path().join("target");
+    let chapters_dir = temp_dir.path().join("chapters");
+
+    fs::create_dir_all(&src_dir).unwrap();
+    fs::create_dir_all(chapters_dir.join("appendix")).unwrap();
+
+    fs::write(src_dir.join("book.md"), "# Book\n--include ../chapters/\n").unwrap();
+    fs::write(
+        src_dir.join("full.md"),
+        "# Book\n--include ../chapters/**\n",
+    )
+    .unwrap();
+    // Created out of order to check the files are sorted
+    fs::write(chapters_dir.join("02.md"), "Chapter two.\n").unwrap();
+    fs::write(chapters_dir.join("01.md"), "Chapter one.\n").unwrap();
+    fs::write(chapters_dir.join("cover.png"), [0u8, 159, 146, 150]).unwrap();
+    fs::write(chapters_dir.join("appendix/a.md"), "Appendix A.\n").unwrap();
+
+    let output = Command::new("cargo")
+        .arg("run")
+        .arg("--")
+        .arg("--src")
+        .arg(src_dir.to_str().unwrap())
+        .arg("--target")
+        .arg(target_dir.to_str().unwrap())
+        .output()
+        .expect("Failed to execute process");
+
+    assert!(output.status.success());
+    // Only the files directly in the directory, and not the binary one
+    assert_eq!(
+        fs::read_to_string(target_dir.join("book.md")).unwrap(),
+        "# Book\nChapter one.\nChapter two.\n"
+    );
+    // Everything under it with a trailing /**
+    assert_eq!(
+        fs::read_to_string(target_dir.join("full.md")).unwrap(),
+        "# Book\nChapter one.\nChapter two.\nAppendix A.\n"
+    );
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("cover.png in ../chapters/ is a binary file"),
+        "{}",
+        stderr
+    );
+}
+
+#[cfg(feature = "remote-includes")]
+#[test]
+fn test_remote_includes() {
+    use std::io::Read;
+    use std::net::TcpListener;
+
+    let temp_dir = tempdir().unwrap();
+    let src_dir = temp_dir.path().join("src");
+    let target_dir = temp_dir.path().join("target");
+
+    fs::create_dir_all(&src_dir).unwrap();
+
+    // Serves the header, then closes so the second include can't be fetched
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let server = thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut request = [0u8; 1024];
+        let n = stream.read(&mut request).unwrap();
+        assert!(String::from_utf8_lossy(&request[..n]).starts_with("GET /header.txt HTTP/1.1"));
+        let body = "// Licensed under MIT\n";
+        write!(
+            stream,
+            "HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\nContent-Length: {}\r\n\r\n{}",
+            body.len(),
+            body
+        )
+        .unwrap();
+    });
+
+    let mut main_file = File::create(src_dir.join("main.rs")).unwrap();
+    writeln!(main_file, "--include http://127.0.0.1:{}/header.txt", port).unwrap();
+    writeln!(main_file, "--include http://127.0.0.1:{}/missing.txt", port).unwrap();
+    writeln!(main_file, "fn main() {{}}").unwrap();
+
+    let output = Command::new("cargo")
+        .arg("run")
+        .arg("--features")
+        .arg("remote-includes")
+        .arg("--")
+        .arg("--src")
+        .arg(src_dir.to_str().unwrap())
+        .arg("--target")
+        .arg(target_dir.to_str().unwrap())
+        .arg("--fetch-timeout")
+        .arg("5")
+        .output()
+        .expect("Failed to execute process");
+    server.join().unwrap();
+
+    assert!(output.status.success());
+    assert_eq!(
+        fs::read_to_string(target_dir.join("main.rs")).unwrap(),
+        format!(
+            "// Licensed under MIT\n--include http://127.0.0.1:{}/missing.txt\nfn main() {{}}\n",
+            port
+        )
+    );
+    assert!(String::from_utf8_lossy(&output.stderr).contains("could not fetch"));
+}
+
+#[cfg(feature = "remote-includes")]
+#[test]
+fn test_remote_includes_over_https() {
+    use rustls::pki_types::{PrivateKeyDer, PrivatePkcs8KeyDer};
+    use std::io::Read;
+    use std::net::TcpListener;
+    use std::sync::Arc;
+
+    let temp_dir = tempdir().unwrap();
+    let src_dir = temp_dir.path().join("src");
+    let target_dir = temp_dir.path().join("target");
+
+    fs::create_dir_all(&src_dir).unwrap();
+
+    // A self-signed certificate, trusted through SSL_CERT_FILE as an internal CA would be
+    let certified = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+    let cert_file = temp_dir.path().join("ca.pem");
+    fs::write(&cert_file, certified.cert.pem()).unwrap();
+    let key = PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(
+        certified.signing_key.serialize_der(),
+    ));
+    let config = rustls::ServerConfig::builder_with_provider(Arc::new(
+        rustls::crypto::ring::default_provider(),
+    ))
+    .with_safe_default_protocol_versions()
+    .unwrap()
+    .with_no_client_auth()
+    .with_single_cert(vec![certified.cert.der().clone()], key)
+    .unwrap();
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let server = thread::spawn(move || {
+        let (stream, _) = listener.accept().unwrap();
+        let connection = rustls::ServerConnection::new(Arc::new(config)).unwrap();
+        let mut stream = rustls::StreamOwned::new(connection, stream);
+        let mut request = Vec::new();
+        let mut buffer = [0u8; 1024];
+        while !request.windows(4).any(|window| window == b"\r\n\r\n") {
+            let n = stream.read(&mut buffer).unwrap();
+            assert_ne!(n, 0);
+            request.extend_from_slice(&buffer[..n]);
+        }
+        assert!(String::from_utf8_lossy(&request).starts_with("GET /header.txt HTTP/1.1"));
+        let body = "// Licensed under MIT\n";
+        write!(
+            stream,
+            "HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            body.len(),
+            body
+        )
+        .unwrap();
+        stream.conn.send_close_notify();
+        stream.flush().unwrap();
+    });
+
+    let mut main_file = File::create(src_dir.join("main.rs")).unwrap();
+    writeln!(main_file, "--include https://localhost:{}/header.txt", port).unwrap();
+    writeln!(main_file, "fn main() {{}}").unwrap();
+
+    let output = Command::new("cargo")
+        .arg("run")
+        .arg("--features")
+        .arg("remote-includes")
+        .arg("--")
+        .arg("--src")
+        .arg(src_dir.to_str().unwrap())
+        .arg("--target")
+        .arg(target_dir.to_str().unwrap())
+        .arg("--fetch-timeout")
+        .arg("5")
+        .env("SSL_CERT_FILE", &cert_file)
+        .output()
+        .expect("Failed to execute process");
+    server.join().unwrap();
+
+    assert!(output.status.success());
+    assert_eq!(
+        fs::read_to_string(target_dir.join("main.rs")).unwrap(),
+        "// Licensed under MIT\nfn main() {}\n",
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+}
+
+#[cfg(feature = "exec-includes")]
+#[test]
+fn test_exec_includes() {
+    let temp_dir = tempdir().unwrap();
+    let src_dir = temp_dir.path().join("src");
+
+    fs::create_dir_all(&src_dir).unwrap();
+    fs::write(
+        src_dir.join("main.txt"),
+        "Start.\n--include !echo hello\n--include !exit 3\nEnd.\n",
+    )
+    .unwrap();
+
+    let run = |target_dir: &Path, extra_args: &[&str]| {
+        Command::new("cargo")
+            .arg("run")
+            .arg("--features")
+            .arg("exec-includes")
+            .arg("--")
+            .arg("--src")
+            .arg(src_dir.to_str().unwrap())
+            .arg("--target")
+            .arg(target_dir.to_str().unwrap())
+            .args(extra_args)
+            .output()
+            .expect("Failed to execute process")
+    };
+
+    // Without --allow-exec, command includes are treated as file names, which don't exist
+    let target_dir = temp_dir.path().join("without");
+    let output = run(&target_dir, &[]);
+    assert!(output.status.success());
+    assert_eq!(
+        fs::read_to_string(target_dir.join("main.txt")).unwrap(),
+        "Start.\n--include !echo hello\n--include !exit 3\nEnd.\n"
+    );
+
+    // A command that fails is left as it is, with a warning
+    let target_dir = temp_dir.path().join("with");
+    let output = run(&target_dir, &["--allow-exec"]);
+    assert!(output.status.success());
+    let expanded = fs::read_to_string(target_dir.join("main.txt")).unwrap();
+    assert_eq!(
+        expanded.replace("\r\n", "\n"),
+        "Start.\nhello\n--include !exit 3\nEnd.\n"
+    );
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("\"exit 3\" failed"), "{}", stderr);
+}
+
+#[cfg(all(feature = "exec-includes", unix))]
+#[test]
+fn test_exec_include_times_out_on_a_background_process() {
+    use std::time::Instant;
+
+    let temp_dir = tempdir().unwrap();
+    let src_dir = temp_dir.path().join("src");
+    let target_dir = temp_dir.path().join("target");
+
+    fs::create_dir_all(&src_dir).unwrap();
+    // The command exits straight away, but the sleep it leaves behind holds its output open
+    fs::write(
+        src_dir.join("main.txt"),
+        "Start.\n--include !echo hello; sleep 20 &\nEnd.\n",
+    )
+    .unwrap();
+
+    let started = Instant::now();
+    let output = Command::new("cargo")
+        .arg("run")
+        .arg("--features")
+        .arg("exec-includes")
+        .arg("--")
+        .arg("--src")
+        .arg(src_dir.to_str().unwrap())
+        .arg("--target")
+        .arg(target_dir.to_str().unwrap())
+        .arg("--allow-exec")
+        .arg("--exec-timeout")
+        .arg("1")
+        .output()
+        .expect("Failed to execute process");
+
+    assert!(started.elapsed() < Duration::from_secs(15));
+    assert!(output.status.success());
+    assert_eq!(
+        fs::read_to_string(target_dir.join("main.txt")).unwrap(),
+        "Start.\n--include !echo hello; sleep 20 &\nEnd.\n"
+    );
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("timed out"), "{}", stderr);
+}
+
+#[test]
+fn test_dry_run() {
+    let temp_dir = tempdir().unwrap();
+    let src_dir = temp_dir.path().join("src");
+    let target_dir = temp_dir.path().join("target");
+
+    fs::create_dir_all(src_dir.join("sub")).unwrap();
+    fs::create_dir_all(&target_dir).unwrap();
+
+    let mut main_file = File::create(src_dir.join("main.txt")).unwrap();
+    writeln!(main_file, "--include sub/include.txt").unwrap();
+    writeln!(main_file, "This is the main file.").unwrap();
+
+    let mut include_file = File::create(src_dir.join("sub/include.txt")).unwrap();
+    writeln!(include_file, "This is the included file.").unwrap();
+
+    let output = Command::new("cargo")
+        .arg("run")
+        .arg("--")
+        .arg("--src")
+        .arg(src_dir.to_str().unwrap())
+        .arg("--target")
+        .arg(target_dir.to_str().unwrap())
+        .arg("--dry-run")
+        .arg("--verbose")
+        .output()
+        .expect("Failed to execute process");
+
+    assert!(output.status.success());
+    assert_eq!(fs::read_dir(&target_dir).unwrap().count(), 0);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains(&format!(
+        "Would write {:?} (50 bytes)",
+        target_dir.join("main.txt")
+    )));
+    assert!(stdout.contains(&format!(
+        "Would write {:?} (27 bytes)",
+        target_dir.join("sub/include.txt")
+    )));
+
+    // The target directory isn't created either
+    let missing_target_dir = temp_dir.path().join("missing");
+    let output = Command::new("cargo")
+        .arg("run")
+        .arg("--")
+        .arg("--src")
+        .arg(src_dir.to_str().unwrap())
+        .arg("--target")
+        .arg(missing_target_dir.to_str().unwrap())
+        .arg("--dry-run")
+        .output()
+        .expect("Failed to execute process");
+
+    assert!(output.status.success());
+    assert!(!missing_target_dir.exists());
+    assert!(String::from_utf8_lossy(&output.stdout).contains(&format!(
+        "Would write {:?}",
+        missing_target_dir.join("main.txt")
+    )));
+}
+
+#[test]
+fn test_diff() {
+    let temp_dir = tempdir().unwrap();
+    let src_dir = temp_dir.path().join("src");
+    let shared_dir = temp_dir.path().join("shared");
+    let target_dir = temp_dir.path().join("target");
+
+    fs::create_dir_all(&src_dir).unwrap();
+    fs::create_dir_all(&shared_dir).unwrap();
+    fs::write(shared_dir.join("header.txt"), "Header v1\n").unwrap();
+    fs::write(
+        src_dir.join("page.txt"),
+        "--include ../shared/header.txt\nPage.\n",
+    )
+    .unwrap();
+    fs::write(src_dir.join("other.txt"), "Other.\n").unwrap();
+
+    let run = |diff: bool| {
+        let mut command = Command::new("cargo");
+        command
+            .arg("run")
+            .arg("--")
+            .arg("--src")
+            .arg(src_dir.to_str().unwrap())
+            .arg("--target")
+            .arg(target_dir.to_str().unwrap());
+        if diff {
+            command.arg("--diff");
+        }
+        command.output().expect("Failed to execute process")
+    };
+
+    assert!(run(false).status.success());
+    // Nothing would change yet
+    let output = run(true);
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "");
+
+    fs::write(shared_dir.join("header.txt"), "Header v2\n").unwrap();
+    let output = run(true);
+    assert!(output.status.success());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("page.txt"), "{}", stdout);
+    assert!(
+        stdout.contains("\n-Header v1\n+Header v2\n Page.\n"),
+        "{}",
+        stdout
+    );
+    assert!(!stdout.contains("other.txt"), "{}", stdout);
+
+    // Nothing is written
+    assert_eq!(
+        fs::read_to_string(target_dir.join("page.txt")).unwrap(),
+        "Header v1\nPage.\n"
+    );
+}
+
+#[test]
+fn test_summary() {
+    let temp_dir = tempdir().unwrap();
+    let src_dir = temp_dir.path().join("src");
+    let target_dir = temp_dir.path().join("target");
+
+    fs::create_dir_all(&src_dir).unwrap();
+
+    let mut main_file = File::create(src_dir.join("main.txt")).unwrap();
+    writeln!(main_file, "--include a.txt").unwrap();
+    writeln!(main_file, "--include missing.txt").unwrap();
+
+    let mut a_file = File::create(src_dir.join("a.txt")).unwrap();
+    writeln!(a_file, "A").unwrap();
+    writeln!(a_file, "--include b.txt").unwrap();
+
+    let mut b_file = File::create(src_dir.join("b.txt")).unwrap();
+    writeln!(b_file, "B").unwrap();
+
+    let output = Command::new("cargo")
+        .arg("run")
+        .arg("--")
+        .arg("--src")
+        .arg(src_dir.to_str().unwrap())
+        .arg("--target")
+        .arg(target_dir.to_str().unwrap())
+        .arg("--summary")
+        .output()
+        .expect("Failed to execute process");
+
+    assert!(output.status.success());
+    // main.txt expands a.txt and the nested b.txt, a.txt expands b.txt
+    let bytes: u64 = ["main.txt", "a.txt", "b.txt"]
+        .iter()
+        .map(|file| fs::metadata(target_dir.join(file)).unwrap().len())
+        .sum();
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout),
+        format!(
+            "Processed 3 files: 3 includes expanded, 1 skipped (missing or binary), {} bytes written\n",
+            bytes
+        )
+    );
+}
+
+#[test]
+fn test_quiet_only_prints_errors() {
+    let temp_dir = tempdir().unwrap();
+    let src_dir = temp_dir.path().join("src");
+    let target_dir = temp_dir.path().join("target");
+
+    fs::create_dir_all(&src_dir).unwrap();
+
+    fs::write(
+        src_dir.join("main.txt"),
+        "--include good.txt\n--include good.txt\n",
+    )
+    .unwrap();
+    fs::write(src_dir.join("good.txt"), "Good.\n").unwrap();
+    fs::write(src_dir.join("bad.txt"), "--include missing.txt\n").unwrap();
+
+    let run = |flag: &str| {
+        Command::new("cargo")
+            .arg("run")
+            .arg("-q")
+            .arg("--")
+            .arg("--src")
+            .arg(src_dir.to_str().unwrap())
+            .arg("--target")
+            .arg(target_dir.to_str().unwrap())
+            .arg("--strict")
+            .arg("--warn-duplicate-includes")
+            .arg(flag)
+            .output()
+            .expect("Failed to execute process")
+    };
+
+    let output = run("-v");
+    assert!(!output.status.success());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("Input"), "{}", stdout);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("more than once"), "{}", stderr);
+    assert!(stderr.contains("missing.txt"), "{}", stderr);
+
+    let output = run("--quiet");
+    assert!(!output.status.success());
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "");
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(!stderr.contains("more than once"), "{}", stderr);
+    assert!(stderr.contains("missing.txt"), "{}", stderr);
+}
+
+#[test]
+fn test_color() {
+    let temp_dir = tempdir().unwrap();
+    let src_dir = temp_dir.path().join("src");
+    let target_dir = temp_dir.path().join("target");
+
+    fs::create_dir_all(&src_dir).unwrap();
+
+    fs::write(
+        src_dir.join("main.txt"),
+        "--include include.txt\n--include missing.txt\n",
+    )
+    .unwrap();
+    fs::write(src_dir.join("include.txt"), "Included.\n").unwrap();
+
+    let run = |color: &str| {
+        Command::new("cargo")
+            .arg("run")
+            .arg("-q")
+            .arg("--")
+            .arg("--src")
+            .arg(src_dir.to_str().unwrap())
+            .arg("--target")
+            .arg(target_dir.to_str().unwrap())
+            .arg("-v")
+            .arg("--color")
+            .arg(color)
+            .output()
+            .expect("Failed to execute process")
+    };
+
+    let output = run("never");
+    assert!(output.status.success());
+    assert!(String::from_utf8_lossy(&output.stdout).contains("Input"));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("missing.txt"));
+    assert!(!output.stdout.contains(&0x1b));
+    assert!(!output.stderr.contains(&0x1b));
+
+    let output = run("always");
+    assert!(output.status.success());
+    assert!(output.stdout.contains(&0x1b));
+    assert!(output.stderr.contains(&0x1b));
+}
+
+#[cfg(feature = "archive-includes")]
+#[test]
+fn test_archive_includes() {
+    let temp_dir = tempdir().unwrap();
+    let src_dir = temp_dir.path().join("src");
+    let target_dir = temp_dir.path().join("target");
+
+    // A zip with a single stored member, built by hand
+    fn crc32(data: &[u8]) -> u32 {
+        let mut crc = !0u32;
+        for &byte in data {
+            crc ^= u32::from(byte);
+            for _ in 0..8 {
+                crc = if crc & 1 != 0 {
+                    (crc >> 1) ^ 0xedb8_8320
+                } else {
+                    crc >> 1
+                };
+            }
+        }
+        !crc
+    }
+    let name = b"header.txt";
+    let content = b"Header from the archive\n";
+    let mut sizes = Vec::new();
+    sizes.extend_from_slice(&crc32(content).to_le_bytes());
+    sizes.extend_from_slice(&(content.len() as u32).to_le_bytes());
+    sizes.extend_from_slice(&(content.len() as u32).to_le_bytes());
+    let mut zip = Vec::new();
+    zip.extend_from_slice(b"PK\x03\x04\x14\x00\x00\x00\x00\x00\x00\x00\x00\x00");
+    zip.extend_from_slice(&sizes);
+    zip.extend_from_slice(&(name.len() as u16).to_le_bytes());
+    zip.extend_from_slice(&[0, 0]);
+    zip.extend_from_slice(name);
+    zip.extend_from_slice(content);
+    let central_directory = zip.len() as u32;
+    zip.extend_from_slice(b"PK\x01\x02\x14\x00\x14\x00\x00\x00\x00\x00\x00\x00\x00\x00");
+    zip.extend_from_slice(&sizes);
+    zip.extend_from_slice(&(name.len() as u16).to_le_bytes());
+    zip.extend_from_slice(&[0; 12]);
+    zip.extend_from_slice(&0u32.to_le_bytes());
+    zip.extend_from_slice(name);
+    let central_directory_size = zip.len() as u32 - central_directory;
+    zip.extend_from_slice(b"PK\x05\x06\x00\x00\x00\x00\x01\x00\x01\x00");
+    zip.extend_from_slice(&central_directory_size.to_le_bytes());
+    zip.extend_from_slice(&central_directory.to_le_bytes());
+    zip.extend_from_slice(&[0, 0]);
+
+    fs::create_dir_all(&src_dir).unwrap();
+    fs::write(src_dir.join("snippets.zip"), zip).unwrap();
+    fs::write(src_dir.join("broken.zip"), "not an archive").unwrap();
+    fs::write(
+        src_dir.join("main.txt"),
+        "Start.\n--include snippets.zip!header.txt\n--include snippets.zip!missing.txt\n--include broken.zip!header.txt\nEnd.\n",
+    )
+    .unwrap();
+
+    let output = Command::new("cargo")
+        .arg("run")
+        .arg("--features")
+        .arg("archive-includes")
+        .arg("--")
+        .arg("--src")
+        .arg(src_dir.to_str().unwrap())
+        .arg("--target")
+        .arg(target_dir.to_str().unwrap())
+        .output()
+        .expect("Failed to execute process");
+    assert!(output.status.success());
+
+    // Missing members and corrupt archives are left as they are, with a warning
+    assert_eq!(
+        fs::read_to_string(target_dir.join("main.txt")).unwrap(),
+        "Start.\nHeader from the archive\n--include snippets.zip!missing.txt\n--include broken.zip!header.txt\nEnd.\n"
+    );
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("\"missing.txt\" is not in the archive"));
+    assert!(stderr.contains("corrupt archive"));
+}
+
+#[cfg(feature = "archive-includes")]
+#[test]
+fn test_malformed_and_oversized_archives() {
+    use flate2::write::GzEncoder;
+    use std::io::Cursor;
+    use zip::write::SimpleFileOptions;
+
+    let temp_dir = tempdir().unwrap();
+    let src_dir = temp_dir.path().join("src");
+    let target_dir = temp_dir.path().join("target");
+
+    fs::create_dir_all(&src_dir).unwrap();
+
+    // A megabyte of zeros compresses to almost nothing, as in a zip bomb
+    let big = vec![0u8; 1024 * 1024];
+    let zip = |content: &[u8]| {
+        let mut writer = zip::ZipWriter::new(Cursor::new(Vec::new()));
+        writer
+            .start_file("big.txt", SimpleFileOptions::default())
+            .unwrap();
+        writer.write_all(content).unwrap();
+        writer.finish().unwrap().into_inner()
+    };
+    let tar_gz = |content: &[u8]| {
+        let mut builder = tar::Builder::new(GzEncoder::new(Vec::new(), Default::default()));
+        let mut header = tar::Header::new_gnu();
+        header.set_size(content.len() as u64);
+        header.set_mode(0o644);
+        header.set_cksum();
+        builder
+            .append_data(&mut header, "big.txt", content)
+            .unwrap();
+        builder.into_inner().unwrap().finish().unwrap()
+    };
+
+    fs::write(src_dir.join("bomb.zip"), zip(&big)).unwrap();
+    fs::write(src_dir.join("bomb.tar.gz"), tar_gz(&big)).unwrap();
+    let small = zip(b"Small.\n");
+    fs::write(src_dir.join("truncated.zip"), &small[..small.len() / 2]).unwrap();
+    // Garbage in place of the deflated data, which the inflater or checksum must catch
+    let mut garbled = zip(&big);
+    let data_start = 30 + "big.txt".len();
+    garbled[data_start..data_start + 16].fill(0xff);
+    fs::write(src_dir.join("garbled.zip"), garbled).unwrap();
+    let tar = tar_gz(b"Small.\n");
+    fs::write(src_dir.join("truncated.tar.gz"), &tar[..tar.len() / 2]).unwrap();
+    fs::write(
+        src_dir.join("main.txt"),
+        "--include bomb.zip!big.txt\n--include bomb.tar.gz!big.txt\n--include truncated.zip!big.txt\n--include garbled.zip!big.txt\n--include truncated.tar.gz!big.txt\nEnd.\n",
+    )
+    .unwrap();
+
+    let output = Command::new("cargo")
+        .arg("run")
+        .arg("--features")
+        .arg("archive-includes")
+        .arg("--")
+        .arg("--src")
+        .arg(src_dir.to_str().unwrap())
+        .arg("--target")
+        .arg(target_dir.to_str().unwrap())
+        .arg("--max-include-size")
+        .arg("65536")
+        .output()
+        .expect("Failed to execute process");
+    assert!(output.status.success());
+
+    // Every include is left as it is, with a warning
+    assert_eq!(
+        fs::read_to_string(target_dir.join("main.txt")).unwrap(),
+        "--include bomb.zip!big.txt\n--include bomb.tar.gz!big.txt\n--include truncated.zip!big.txt\n--include garbled.zip!big.txt\n--include truncated.tar.gz!big.txt\nEnd.\n"
+    );
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert_eq!(
+        stderr
+            .matches("is over the maximum size of 65536 bytes when decompressed")
+            .count(),
+        2,
+        "{}",
+        stderr
+    );
+    assert_eq!(stderr.matches("corrupt archive").count(), 3, "{}", stderr);
+}
+
+#[test]
+fn test_watch_exits_on_its_own() {
+    let temp_dir = tempdir().unwrap();
+    let src_dir = temp_dir.path().join("src");
+    let target_dir = temp_dir.path().join("target");
+
+    fs::create_dir_all(&src_dir).unwrap();
+    fs::write(src_dir.join("a.txt"), "A.\n").unwrap();
+
+    let spawn = |extra_args: &[&str]| {
+        Command::new("cargo")
+            .arg("run")
+            .arg("--")
+            .arg("--src")
+            .arg(src_dir.to_str().unwrap())
+            .arg("--target")
+            .arg(target_dir.to_str().unwrap())
+            .arg("--watch")
+            .args(extra_args)
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .expect("Failed to start process")
+    };
+    // Waits up to ten seconds for the process to exit by itself
+    let wait_for_exit = |child: &mut std::process::Child| {
+        for _ in 0..100 {
+            if let Some(status) = child.try_wait().unwrap() {
+                return Some(status);
+            }
+            thread::sleep(Duration::from_millis(100));
+        }
+        child.kill().expect("Failed to kill process");
+        child.wait().expect("Failed to wait for process");
+        None
+    };
+
+    // --watch-events exits after handling the first change
+    let mut child = spawn(&["--watch-events", "1"]);
+    let output = target_dir.join("a.txt");
+    let mut counter = 0;
+    while counter < 50 && !output.exists() {
+        thread::sleep(Duration::from_millis(100));
+        counter += 1;
+    }
+    thread::sleep(Duration::from_millis(500));
+    fs::write(src_dir.join("a.txt"), "New A.\n").unwrap();
+    let status = wait_for_exit(&mut child).expect("Watch did not exit after one change");
+    assert!(status.success());
+    assert_eq!(fs::read_to_string(&output).unwrap(), "New A.\n");
+
+    // --watch-timeout exits once nothing has changed for a while
+    let mut child = spawn(&["--watch-timeout", "1"]);
+    let status = wait_for_exit(&mut child).expect("Watch did not exit when idle");
+    assert!(status.success());
+}
+
+#[test]
+fn test_report_unused() {
+    let temp_dir = tempdir().unwrap();
+    let src_dir = temp_dir.path().join("src");
+    let target_dir = temp_dir.path().join("target");
+
+    fs::create_dir_all(src_dir.join("snippets")).unwrap();
+    fs::write(src_dir.join("snippets/used.txt"), "Used.\n").unwrap();
+    fs::write(src_dir.join("snippets/unused.txt"), "Unused.\n").unwrap();
+    fs::write(
+        src_dir.join("main.txt"),
+        "--include snippets/used.txt\nBody.\n",
+    )
+    .unwrap();
+
+    let run = |extra_args: &[&str]| {
+        Command::new("cargo")
+            .arg("run")
+            .arg("--")
+            .arg("--src")
+            .arg(src_dir.to_str().unwrap())
+            .arg("--target")
+            .arg(target_dir.to_str().unwrap())
+            .arg("--report-unused")
+            .args(extra_args)
+            .output()
+            .expect("Failed to execute process")
+    };
+
+    // Only the snippets are candidates, so main.txt isn't reported
+    let output = run(&["--unused-glob", "snippets/**"]);
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout),
+        "snippets/unused.txt\n"
+    );
+    // Nothing is written
+    assert!(!target_dir.exists());
+
+    // Without a glob every file nothing includes is reported
+    let output = run(&[]);
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout),
+        "main.txt\nsnippets/unused.txt\n"
+    );
+}
+
+#[test]
+fn test_include_path() {
+    let temp_dir = tempdir().unwrap();
+    let src_dir = temp_dir.path().join("src");
+    let target_dir = temp_dir.path().join("target");
+    let first_dir = temp_dir.path().join("first");
+    let second_dir = temp_dir.path().join("second");
+
+    fs::create_dir_all(&src_dir).unwrap();
+    fs::create_dir_all(&first_dir).unwrap();
+    fs::create_dir_all(&second_dir).unwrap();
+    fs::write(src_dir.join("local.txt"), "Local.\n").unwrap();
+    fs::write(first_dir.join("local.txt"), "Shadowed.\n").unwrap();
+    fs::write(first_dir.join("shared.txt"), "First.\n").unwrap();
+    fs::write(second_dir.join("shared.txt"), "Second.\n").unwrap();
+    fs::write(second_dir.join("only.txt"), "Only in second.\n").unwrap();
+    fs::write(
+        src_dir.join("main.txt"),
+        "--include local.txt\n--include shared.txt\n--include only.txt\n--include missing.txt\n",
+    )
+    .unwrap();
+
+    let output = Command::new("cargo")
+        .arg("run")
+        .arg("--")
+        .arg("--src")
+        .arg(src_dir.to_str().unwrap())
+        .arg("--target")
+        .arg(target_dir.to_str().unwrap())
+        .arg("--include-path")
+        .arg(first_dir.to_str().unwrap())
+        .arg("--include-path")
+        .arg(second_dir.to_str().unwrap())
+        .output()
+        .expect("Failed to execute process");
+    assert!(output.status.success());
+
+    // A file next to the includer wins, then the search paths are tried in order
+    assert_eq!(
+        fs::read_to_string(target_dir.join("main.txt")).unwrap(),
+        "Local.\nFirst.\nOnly in second.\n--include missing.txt\n"
+    );
+    assert!(String::from_utf8_lossy(&output.stderr).contains("include not found: missing.txt"));
+}
+
+#[test]
+fn test_note_unexpected_include_targets() {
+    let temp_dir = tempdir().unwrap();
+    let src_dir = temp_dir.path().join("src");
+    let target_dir = temp_dir.path().join("target");
+
+    fs::create_dir_all(src_dir.join("docs")).unwrap();
+    fs::create_dir_all(src_dir.join("shared")).unwrap();
+    fs::write(src_dir.join("self.txt"), "Self.\n--include self.txt\n").unwrap();
+    // The include goes to the other intro.txt, which may not be what was meant
+    fs::write(
+        src_dir.join("docs/intro.txt"),
+        "--include ../shared/intro.txt\nIntro.\n",
+    )
+    .unwrap();
+    fs::write(src_dir.join("shared/intro.txt"), "Shared.\n").unwrap();
+    fs::write(src_dir.join("docs/plain.txt"), "--include intro.txt\n").unwrap();
+
+    let run = |extra_args: &[&str]| {
+        let output = Command::new("cargo")
+            .arg("run")
+            .arg("--")
+            .arg("--src")
+            .arg(src_dir.to_str().unwrap())
+            .arg("--target")
+            .arg(target_dir.to_str().unwrap())
+            .args(extra_args)
+            .output()
+            .expect("Failed to execute process");
+        assert!(output.status.success());
+        String::from_utf8_lossy(&output.stdout).into_owned()
+    };
+
+    let stdout = run(&["-v"]);
+    assert!(stdout.contains("self.txt includes itself"), "{}", stdout);
+    assert!(
+        stdout.contains("../shared/intro.txt resolves to "),
+        "{}",
+        stdout
+    );
+    // An include of the file next to the includer is what you'd expect
+    assert!(!stdout.contains("plain.txt:1:"), "{}", stdout);
+
+    // The notes are advisory, so they only show in verbose mode
+    let stdout = run(&[]);
+    assert!(!stdout.contains("includes itself"));
+    assert!(!stdout.contains("resolves to"));
+}
+
+#[test]
+fn test_root_marker() {
+    let temp_dir = tempdir().unwrap();
+    let project = temp_dir.path().join("project");
+    let run_dir = project.join("docs/guide");
+    let target_dir = temp_dir.path().join("target");
+
+    fs::create_dir_all(project.join("shared")).unwrap();
+    fs::create_dir_all(&run_dir).unwrap();
+    fs::write(project.join(".si-root"), "").unwrap();
+    fs::write(project.join("shared/header.txt"), "Header.\n").unwrap();
+    fs::write(
+        run_dir.join("main.txt"),
+        "--include @/shared/header.txt\nBody.\n",
+    )
+    .unwrap();
+
+    let manifest = Path::new(env!("CARGO_MANIFEST_DIR")).join("Cargo.toml");
+    let run = |extra_args: &[&str]| {
+        let output = Command::new("cargo")
+            .arg("run")
+            .arg("-q")
+            .arg("--manifest-path")
+            .arg(&manifest)
+            .arg("--")
+            .arg("--src")
+            .arg(".")
+            .arg("--target")
+            .arg(target_dir.to_str().unwrap())
+            .args(extra_args)
+            .current_dir(&run_dir)
+            .output()
+            .expect("Failed to execute process");
+        assert!(output.status.success());
+        fs::read_to_string(target_dir.join("main.txt")).unwrap()
+    };
+
+    // Without the marker, root-relative includes resolve against the directory it is run from
+    assert_eq!(run(&[]), "--include @/shared/header.txt\nBody.\n");
+    // With it, they resolve against the directory two levels up that holds the marker
+    assert_eq!(run(&["--root-marker", ".si-root"]), "Header.\nBody.\n");
+    // A marker that isn't found leaves the usual root
+    assert_eq!(
+        run(&["--root-marker", ".no-such-marker"]),
+        "--include @/shared/header.txt\nBody.\n"
+    );
+}
+
+#[test]
+fn test_entry_and_out() {
+    let temp_dir = tempdir().unwrap();
+    let src_dir = temp_dir.path().join("src");
+    let out = temp_dir.path().join("dist/bundle.txt");
+
+    fs::create_dir_all(src_dir.join("snippets")).unwrap();
+    fs::write(src_dir.join("snippets/one.txt"), "One.\n").unwrap();
+    fs::write(
+        src_dir.join("snippets/two.txt"),
+        "Two.\n--include three.txt\n",
+    )
+    .unwrap();
+    fs::write(src_dir.join("snippets/three.txt"), "Three.\n").unwrap();
+    fs::write(
+        src_dir.join("main.txt"),
+        "Start.\n--include snippets/one.txt\n--include snippets/two.txt\nEnd.\n",
+    )
+    .unwrap();
+    fs::write(src_dir.join("other.txt"), "Not part of the bundle.\n").unwrap();
+
+    let output = Command::new("cargo")
+        .arg("run")
+        .arg("--")
+        .arg("--entry")
+        .arg(src_dir.join("main.txt").to_str().unwrap())
+        .arg("--out")
+        .arg(out.to_str().unwrap())
+        .output()
+        .expect("Failed to execute process");
+    assert!(output.status.success());
+
+    // Only the entry point is written, with its includes expanded all the way down
+    assert_eq!(
+        fs::read_to_string(&out).unwrap(),
+        "Start.\nOne.\nTwo.\nThree.\nEnd.\n"
+    );
+    assert_eq!(fs::read_dir(out.parent().unwrap()).unwrap().count(), 1);
+
+    // --out is needed to know where to write
+    let output = Command::new("cargo")
+        .arg("run")
+        .arg("--")
+        .arg("--entry")
+        .arg(src_dir.join("main.txt").to_str().unwrap())
+        .output()
+        .expect("Failed to execute process");
+    assert!(!output.status.success());
+}
+
+#[test]
+fn test_include_directory_as_file() {
+    let temp_dir = tempdir().unwrap();
+    let src_dir = temp_dir.path().join("src");
+    let target_dir = temp_dir.path().join("target");
+
+    fs::create_dir_all(src_dir.join("parts")).unwrap();
+    fs::write(src_dir.join("parts/one.txt"), "One.\n").unwrap();
+    fs::write(src_dir.join("main.txt"), "--include parts\nEnd.\n").unwrap();
+
+    let output = Command::new("cargo")
+        .arg("run")
+        .arg("--")
+        .arg("--src")
+        .arg(src_dir.to_str().unwrap())
+        .arg("--target")
+        .arg(target_dir.to_str().unwrap())
+        .arg("--no-directory-includes")
+        .output()
+        .expect("Failed to execute process");
+    assert!(output.status.success());
+
+    // The directive is left as it is, with a warning saying why rather than a read error
+    assert_eq!(
+        fs::read_to_string(target_dir.join("main.txt")).unwrap(),
+        "--include parts\nEnd.\n"
+    );
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("include target parts is a directory, not a file, skipping"),
+        "{}",
+        stderr
+    );
+
+    let output = Command::new("cargo")
+        .arg("run")
+        .arg("--")
+        .arg("--dump-directives")
+        .arg(src_dir.join("main.txt").to_str().unwrap())
+        .output()
+        .expect("Failed to execute process");
+    assert!(output.status.success());
+    assert!(String::from_utf8_lossy(&output.stdout).contains("(directory, 1 file)"));
+}
+
+#[test]
+fn test_max_total_output() {
+    let temp_dir = tempdir().unwrap();
+    let src_dir = temp_dir.path().join("src");
+    let target_dir = temp_dir.path().join("target");
+
+    fs::create_dir_all(&src_dir).unwrap();
+    fs::write(src_dir.join("chunk.txt"), "x".repeat(99) + "\n").unwrap();
+    for name in ["a.txt", "b.txt", "c.txt", "d.txt"] {
+        fs::write(src_dir.join(name), "--include chunk.txt\n").unwrap();
+    }
+
+    let output = Command::new("cargo")
+        .arg("run")
+        .arg("--")
+        .arg("--src")
+        .arg(src_dir.to_str().unwrap())
+        .arg("--target")
+        .arg(target_dir.to_str().unwrap())
+        .arg("--jobs")
+        .arg("1")
+        .arg("--max-total-output")
+        .arg("150")
+        .output()
+        .expect("Failed to execute process");
+
+    // Every output is 100 bytes, so the second one goes over the limit and the rest aren't written
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains(
+            "Stopped after writing 200 bytes, over the --max-total-output limit of 150 bytes"
+        ),
+        "{}",
+        stderr
+    );
+    assert_eq!(fs::read_dir(&target_dir).unwrap().count(), 2);
+}
+
+#[test]
+fn test_include_filters() {
+    let temp_dir = tempdir().unwrap();
+    let src_dir = temp_dir.path().join("src");
+    let target_dir = temp_dir.path().join("target");
+
+    fs::create_dir_all(&src_dir).unwrap();
+    fs::create_dir_all(&target_dir).unwrap();
+
+    fs::write(src_dir.join("key.pem"), "secret\n").unwrap();
+    fs::write(src_dir.join("snippet.txt"), "one\n\ntwo\n").unwrap();
+    fs::write(
+        src_dir.join("main.txt"),
+        "--include key.pem |base64\n\
+         --include snippet.txt |indent=4\n\
+         --include key.pem |trim|upper\n\
+         --include key.pem |shout\n",
+    )
+    .unwrap();
+
+    let output = Command::new("cargo")
+        .arg("run")
+        .arg("--")
+        .arg("--src")
+        .arg(src_dir.to_str().unwrap())
+        .arg("--target")
+        .arg(target_dir.to_str().unwrap())
+        .output()
+        .expect("Failed to execute process");
+
+    assert!(output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("unknown filter \"shout\""), "{}", stderr);
+
+    let output_content = fs::read_to_string(target_dir.join("main.txt")).unwrap();
+    assert_eq!(
+        output_content,
+        "c2VjcmV0Cg==\n    one\n\n    two\nSECRET\nsecret\n"
+    );
+}
+
+#[test]
+fn test_include_with_arguments() {
+    let temp_dir = tempdir().unwrap();
+    let src_dir = temp_dir.path().join("src");
+    let target_dir = temp_dir.path().join("target");
+
+    fs::create_dir_all(src_dir.join("my docs")).unwrap();
+
+    fs::write(
+        src_dir.join("sample.rs"),
+        "// sample\nfn main() {\n}\n// end\n",
+    )
+    .unwrap();
+    fs::write(src_dir.join("my docs/note.txt"), "note\n").unwrap();
+    fs::write(
+        src_dir.join("main.md"),
+        "--include(path=\"sample.rs\", lines=\"2-3\", fence=\"rust\")\n\
+         --include( filters = 'upper|indent=2' , path = 'my docs/note.txt' )\n\
+         --include(path=\"sample.rs\", lines=2-3)\n\
+         --include(path=\"sample.rs\", colour=\"red\")\n\
+         --include(path=\"sample.rs\"\n",
+    )
+    .unwrap();
+
+    let output = Command::new("cargo")
+        .arg("run")
+        .arg("--")
+        .arg("--src")
+        .arg(src_dir.to_str().unwrap())
+        .arg("--target")
+        .arg(target_dir.to_str().unwrap())
+        .output()
+        .expect("Failed to execute process");
+
+    assert!(output.status.success());
+    // Malformed directives are left as they are, with a warning for each
+    assert_eq!(
+        fs::read_to_string(target_dir.join("main.md")).unwrap(),
+        "```rust\nfn main() {\n}\n```\n  NOTE\n\
+         --include(path=\"sample.rs\", lines=2-3)\n\
+         --include(path=\"sample.rs\", colour=\"red\")\n\
+         --include(path=\"sample.rs\"\n"
+    );
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("main.md:3: malformed include directive, leaving it as it is: the value of lines must be quoted"),
+        "{}",
+        stderr
+    );
+    assert!(
+        stderr.contains("main.md:4: malformed include directive, leaving it as it is: unknown argument \"colour\""),
+        "{}",
+        stderr
+    );
+    assert!(
+        stderr.contains("main.md:5: malformed include directive, leaving it as it is: missing closing parenthesis"),
+        "{}",
+        stderr
+    );
+}
+
+#[test]
+fn test_watch_events_json() {
+    let temp_dir = tempdir().unwrap();
+    let src_dir = temp_dir.path().join("src");
+    let target_dir = temp_dir.path().join("target");
+
+    fs::create_dir_all(&src_dir).unwrap();
+    fs::write(src_dir.join("main.txt"), "--include snippet.txt\n").unwrap();
+    fs::write(src_dir.join("snippet.txt"), "Old.\n").unwrap();
+
+    let mut child = Command::new("cargo")
+        .arg("run")
+        .arg("--")
+        .arg("--src")
+        .arg(src_dir.to_str().unwrap())
+        .arg("--target")
+        .arg(target_dir.to_str().unwrap())
+        .arg("--watch")
+        .arg("--watch-events")
+        .arg("1")
+        .arg("--watch-events-json")
+        .arg("-v")
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("Failed to start process");
+
+    let output = target_dir.join("main.txt");
+    let mut counter = 0;
+    while counter < 50 && !output.exists() {
+        thread::sleep(Duration::from_millis(100));
+        counter += 1;
+    }
+    thread::sleep(Duration::from_millis(500));
+    fs::write(src_dir.join("snippet.txt"), "New.\n").unwrap();
+
+    let mut counter = 0;
+    while counter < 100 && child.try_wait().unwrap().is_none() {
+        thread::sleep(Duration::from_millis(100));
+        counter += 1;
+    }
+    if child.try_wait().unwrap().is_none() {
+        child.kill().expect("Failed to kill process");
+    }
+    let result = child
+        .wait_with_output()
+        .expect("Failed to wait for process");
+    assert!(result.status.success());
+    assert_eq!(fs::read_to_string(&output).unwrap(), "New.\n");
+
+    // Only the event is on stdout, the -v messages go to stderr
+    let stdout = String::from_utf8_lossy(&result.stdout);
+    let lines: Vec<&str> = stdout.lines().collect();
+    assert_eq!(lines.len(), 1, "{}", stdout);
+    let event = lines[0];
+    assert!(
+        event.starts_with("{\"event\": \"modified\", \"source\": \""),
+        "{}",
+        event
+    );
+    assert!(
+        event.contains("/src/snippet.txt\", \"target\": \""),
+        "{}",
+        event
+    );
+    assert!(
+        event.contains("/target/snippet.txt\", \"rebuilt\": [\""),
+        "{}",
+        event
+    );
+    assert!(event.ends_with("/target/main.txt\"]}"), "{}", event);
+    let stderr = String::from_utf8_lossy(&result.stderr);
+    assert!(stderr.contains("Watching for changes"), "{}", stderr);
+}
+
+#[test]
+fn test_watch_target_inside_source() {
+    let temp_dir = tempdir().unwrap();
+    let src_dir = temp_dir.path().join("src");
+    let target_dir = src_dir.join("out");
+
+    fs::create_dir_all(&src_dir).unwrap();
+    fs::write(src_dir.join("main.txt"), "--include snippet.txt\n").unwrap();
+    fs::write(src_dir.join("snippet.txt"), "Old.\n").unwrap();
+
+    // Exits once nothing has changed for two seconds, which never happens if writing the
+    // outputs sets off another rebuild
+    let mut child = Command::new("cargo")
+        .arg("run")
+        .arg("--")
+        .arg("--src")
+        .arg(src_dir.to_str().unwrap())
+        .arg("--target")
+        .arg(target_dir.to_str().unwrap())
+        .arg("--watch")
+        .arg("--watch-timeout")
+        .arg("2")
+        .arg("--watch-events-json")
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("Failed to start process");
+
+    let output = target_dir.join("main.txt");
+    let mut counter = 0;
+    while counter < 50 && !output.exists() {
+        thread::sleep(Duration::from_millis(100));
+        counter += 1;
+    }
+    thread::sleep(Duration::from_millis(500));
+    fs::write(src_dir.join("snippet.txt"), "New.\n").unwrap();
+
+    let mut counter = 0;
+    while counter < 150 && child.try_wait().unwrap().is_none() {
+        thread::sleep(Duration::from_millis(100));
+        counter += 1;
+    }
+    let exited = child.try_wait().unwrap().is_some();
+    if !exited {
+        child.kill().expect("Failed to kill process");
+    }
+    let result = child
+        .wait_with_output()
+        .expect("Failed to wait for process");
+    let stdout = String::from_utf8_lossy(&result.stdout);
+    assert!(exited, "still rebuilding: {}", stdout);
+    assert!(result.status.success());
+    assert_eq!(fs::read_to_string(&output).unwrap(), "New.\n");
+
+    // Only the edit was handled, not the outputs written for it
+    let lines: Vec<&str> = stdout.lines().collect();
+    assert_eq!(lines.len(), 1, "{}", stdout);
+    assert!(lines[0].contains("/src/snippet.txt\""), "{}", stdout);
+}
+
+#[test]
+fn test_watch_ignore() {
+    let temp_dir = tempdir().unwrap();
+    let src_dir = temp_dir.path().join("src");
+    let target_dir = temp_dir.path().join("target");
+
+    fs::create_dir_all(src_dir.join("docs")).unwrap();
+    fs::write(src_dir.join("main.txt"), "Main.\n").unwrap();
+
+    let mut child = Command::new("cargo")
+        .arg("run")
+        .arg("--")
+        .arg("--src")
+        .arg(src_dir.to_str().unwrap())
+        .arg("--target")
+        .arg(target_dir.to_str().unwrap())
+        .arg("--watch")
+        .arg("--watch-timeout")
+        .arg("2")
+        .arg("--watch-ignore")
+        .arg("**/*.swp")
+        .arg("--watch-ignore")
+        .arg(".git/**")
+        .arg("-vv")
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("Failed to start process");
+
+    let output = target_dir.join("main.txt");
+    let mut counter = 0;
+    while counter < 50 && !output.exists() {
+        thread::sleep(Duration::from_millis(100));
+        counter += 1;
+    }
+    thread::sleep(Duration::from_millis(500));
+    // An editor's swap files and a lock file, coming and going
+    fs::write(src_dir.join(".main.txt.swp"), "swap").unwrap();
+    fs::write(src_dir.join("docs/.guide.txt.swp"), "swap").unwrap();
+    fs::create_dir_all(src_dir.join(".git")).unwrap();
+    fs::write(src_dir.join(".git/index.lock"), "").unwrap();
+    fs::remove_file(src_dir.join(".git/index.lock")).unwrap();
+
+    let mut counter = 0;
+    while counter < 100 && child.try_wait().unwrap().is_none() {
+        thread::sleep(Duration::from_millis(100));
+        counter += 1;
+    }
+    if child.try_wait().unwrap().is_none() {
+        child.kill().expect("Failed to kill process");
+    }
+    let result = child
+        .wait_with_output()
+        .expect("Failed to wait for process");
+    assert!(result.status.success());
+
+    assert!(!target_dir.join(".main.txt.swp").exists());
+    assert!(!target_dir.join("docs/.guide.txt.swp").exists());
+    let stdout = String::from_utf8_lossy(&result.stdout);
+    assert!(!stdout.contains(".swp"), "{}", stdout);
+    assert!(!stdout.contains("index.lock"), "{}", stdout);
+    assert!(stdout.contains("No changes for 2 seconds"), "{}", stdout);
+}
+
+#[test]
+fn test_includeignore_blocks_includes() {
+    let temp_dir = tempdir().unwrap();
+    let src_dir = temp_dir.path().join("src");
+    let target_dir = temp_dir.path().join("target");
+
+    fs::create_dir_all(src_dir.join("secrets")).unwrap();
+    fs::create_dir_all(src_dir.join("docs")).unwrap();
+    fs::write(src_dir.join(".includeignore"), "secrets/\n*.pem\n").unwrap();
+    fs::write(src_dir.join("secrets/token.txt"), "hunter2\n").unwrap();
+    fs::write(src_dir.join("key.pem"), "PRIVATE KEY\n").unwrap();
+    fs::write(src_dir.join("snippet.txt"), "Snippet.\n").unwrap();
+    fs::write(
+        src_dir.join("docs/main.txt"),
+        "--include ../secrets/token.txt\n--include ../key.pem\n--include ../snippet.txt\n",
+    )
+    .unwrap();
+
+    let output = Command::new("cargo")
+        .arg("run")
+        .arg("--")
+        .arg("--src")
+        .arg(src_dir.to_str().unwrap())
+        .arg("--target")
+        .arg(target_dir.to_str().unwrap())
+        .output()
+        .expect("Failed to execute process");
+
+    assert!(output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("include blocked by .includeignore: ../secrets/token.txt"),
+        "{}",
+        stderr
+    );
+    assert!(
+        stderr.contains("include blocked by .includeignore: ../key.pem"),
+        "{}",
+        stderr
+    );
+
+    let output_content = fs::read_to_string(target_dir.join("docs/main.txt")).unwrap();
+    assert_eq!(
+        output_content,
+        "--include ../secrets/token.txt\n--include ../key.pem\nSnippet.\n"
+    );
+}
+
+#[test]
+fn test_interactive_overwrite() {
+    let temp_dir = tempdir().unwrap();
+    let src_dir = temp_dir.path().join("src");
+    let target_dir = temp_dir.path().join("target");
+
+    fs::create_dir_all(&src_dir).unwrap();
+    fs::create_dir_all(&target_dir).unwrap();
+    fs::write(src_dir.join("notes.txt"), "Generated.\n").unwrap();
+    fs::write(src_dir.join("new.txt"), "New.\n").unwrap();
+    fs::write(target_dir.join("notes.txt"), "Hand-written.\n").unwrap();
+
+    let run = |answers: &str| {
+        let mut child = Command::new("cargo")
+            .arg("run")
+            .arg("--")
+            .arg("--src")
+            .arg(src_dir.to_str().unwrap())
+            .arg("--target")
+            .arg(target_dir.to_str().unwrap())
+            .arg("--interactive")
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .expect("Failed to start process");
+        child
+            .stdin
+            .take()
+            .unwrap()
+            .write_all(answers.as_bytes())
+            .unwrap();
+        child
+            .wait_with_output()
+            .expect("Failed to wait for process")
+    };
+
+    // Declining leaves the existing file alone, and new files are written without asking
+    let output = run("n\n");
+    assert!(output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert_eq!(stderr.matches("Overwrite ").count(), 1, "{}", stderr);
+    assert_eq!(
+        fs::read_to_string(target_dir.join("notes.txt")).unwrap(),
+        "Hand-written.\n"
+    );
+    assert_eq!(
+        fs::read_to_string(target_dir.join("new.txt")).unwrap(),
+        "New.\n"
+    );
+
+    // With nothing on stdin, files are overwritten as without --interactive
+    let output = run("");
+    assert!(output.status.success());
+    assert_eq!(
+        fs::read_to_string(target_dir.join("notes.txt")).unwrap(),
+        "Generated.\n"
+    );
+}
+
+#[test]
+fn test_directory_configs() {
+    let temp_dir = tempdir().unwrap();
+    let src_dir = temp_dir.path().join("src");
+    let target_dir = temp_dir.path().join("target");
+
+    fs::create_dir_all(src_dir.join("rust/nested")).unwrap();
+    fs::create_dir_all(src_dir.join("web")).unwrap();
+    fs::write(src_dir.join("snippet.txt"), "Snippet.\n").unwrap();
+    fs::write(src_dir.join("rust/.si-config"), "include = \"//include\"\n").unwrap();
+    fs::write(
+        src_dir.join("rust/nested/.si-config"),
+        "# The nearest config wins\ninclude = [\"//!include\"]\n",
+    )
+    .unwrap();
+    fs::write(
+        src_dir.join("web/.si-config"),
+        "include = \"<!--include\"\ninclude-suffix = \"-->\"\n",
+    )
+    .unwrap();
+    fs::write(
+        src_dir.join("main.txt"),
+        "--include snippet.txt\n//include snippet.txt\n",
+    )
+    .unwrap();
+    fs::write(
+        src_dir.join("rust/lib.rs"),
+        "//include ../snippet.txt\n--include ../snippet.txt\n",
+    )
+    .unwrap();
+    fs::write(
+        src_dir.join("rust/nested/mod.rs"),
+        "//!include ../../snippet.txt\n//include ../../snippet.txt\n",
+    )
+    .unwrap();
+    fs::write(
+        src_dir.join("web/index.html"),
+        "<!--include ../snippet.txt-->\n--include ../snippet.txt\n",
+    )
+    .unwrap();
+
+    let output = Command::new("cargo")
+        .arg("run")
+        .arg("--")
+        .arg("--src")
+        .arg(src_dir.to_str().unwrap())
+        .arg("--target")
+        .arg(target_dir.to_str().unwrap())
+        .output()
+        .expect("Failed to execute process");
+
+    assert!(output.status.success());
+    let read = |path: &str| fs::read_to_string(target_dir.join(path)).unwrap();
+    assert_eq!(read("main.txt"), "Snippet.\n//include snippet.txt\n");
+    assert_eq!(read("rust/lib.rs"), "Snippet.\n--include ../snippet.txt\n");
+    assert_eq!(
+        read("rust/nested/mod.rs"),
+        "Snippet.\n//include ../../snippet.txt\n"
+    );
+    assert_eq!(
+        read("web/index.html"),
+        "Snippet.\n--include ../snippet.txt\n"
+    );
+    // The configs themselves aren't copied
+    assert!(!target_dir.join("rust/.si-config").exists());
+}
+
+#[test]
+fn test_stats_json() {
+    let temp_dir = tempdir().unwrap();
+    let src_dir = temp_dir.path().join("src");
+    let target_dir = temp_dir.path().join("target");
+    let stats_path = temp_dir.path().join("stats.json");
+
+    fs::create_dir_all(src_dir.join("docs")).unwrap();
+    fs::write(src_dir.join("snippet.txt"), "Snippet.\n").unwrap();
+    fs::write(src_dir.join("short.txt"), "Short.\n").unwrap();
+    fs::write(
+        src_dir.join("docs/guide.txt"),
+        "A longer guide.\n--include ../snippet.txt\n--include ../snippet.txt\n",
+    )
+    .unwrap();
+
+    let output = Command::new("cargo")
+        .arg("run")
+        .arg("--")
+        .arg("--src")
+        .arg(src_dir.to_str().unwrap())
+        .arg("--target")
+        .arg(target_dir.to_str().unwrap())
+        .arg("--stats-json")
+        .arg(stats_path.to_str().unwrap())
+        .output()
+        .expect("Failed to execute process");
+
+    assert!(output.status.success());
+    let stats = fs::read_to_string(&stats_path).unwrap();
+    assert!(stats.contains("\"files\": 3,"), "{}", stats);
+    assert!(stats.contains("\"includes\": 2,"), "{}", stats);
+    assert!(stats.contains("\"skipped\": 0,"), "{}", stats);
+    assert!(stats.contains("\"bytes_written\": 50,"), "{}", stats);
+    assert!(
+        stats.contains("\"largest_file\": {\"path\": \"docs/guide.txt\", \"bytes\": 34}"),
+        "{}",
+        stats
+    );
+    // Every time is a non-negative number of seconds
+    for key in [
+        "read_seconds",
+        "write_seconds",
+        "setup_seconds",
+        "process_seconds",
+        "finish_seconds",
+        "wall_seconds",
+    ] {
+        let start = stats.find(&format!("\"{}\": ", key)).unwrap() + key.len() + 4;
+        let value: String = stats[start..]
+            .chars()
+            .take_while(|c| c.is_ascii_digit() || *c == '.')
+            .collect();
+        let seconds: f64 = value.parse().unwrap_or(-1.0);
+        assert!(seconds >= 0.0, "{} in {}", key, stats);
+    }
+}
+
+#[test]
+fn test_large_include_is_streamed() {
+    let temp_dir = tempdir().unwrap();
+    let src_dir = temp_dir.path().join("src");
+    let target_dir = temp_dir.path().join("target");
+
+    fs::create_dir_all(src_dir.join("parts")).unwrap();
+
+    // Over the size at which includes are streamed, with mixed line endings, multi-byte
+    // characters, a nested include and no line ending at the end
+    let mut large = String::new();
+    for i in 0..100_000 {
+        large.push_str(&format!(
+            "Line {} of the large include, with ünïcödé ✓\r\n",
+            i
+        ));
+    }
+    large.push_str("--include small.txt\n");
+    large.push_str("The end");
+    fs::write(src_dir.join("parts/large.txt"), &large).unwrap();
+    fs::write(src_dir.join("parts/small.txt"), "Small.\n").unwrap();
+    fs::write(
+        src_dir.join("main.txt"),
+        "Before\n--include parts/large.txt\nAfter\n",
+    )
+    .unwrap();
+
+    let output = Command::new("cargo")
+        .arg("run")
+        .arg("--")
+        .arg("--src")
+        .arg(src_dir.to_str().unwrap())
+        .arg("--target")
+        .arg(target_dir.to_str().unwrap())
+        .output()
+        .expect("Failed to execute process");
+
+    assert!(output.status.success());
+    let expected = format!(
+        "Before\n{}\nAfter\n",
+        large.replace("--include small.txt\n", "Small.\n")
+    );
+    assert!(expected.len() > 4 * 1024 * 1024);
+    assert!(
+        fs::read(target_dir.join("main.txt")).unwrap() == expected.as_bytes(),
+        "the large include was not inlined exactly"
+    );
+}
+
+#[test]
+fn test_watch_burst_regenerates_all() {
+    let temp_dir = tempdir().unwrap();
+    let src_dir = temp_dir.path().join("src");
+    let target_dir = temp_dir.path().join("target");
+
+    fs::create_dir_all(&src_dir).unwrap();
+    fs::write(src_dir.join("shared.txt"), "Shared.\n").unwrap();
+    for i in 0..30 {
+        fs::write(
+            src_dir.join(format!("page{}.txt", i)),
+            "Old.\n--include shared.txt\n",
+        )
+        .unwrap();
+    }
+
+    let mut child = Command::new("cargo")
+        .arg("run")
+        .arg("--")
+        .arg("--src")
+        .arg(src_dir.to_str().unwrap())
+        .arg("--target")
+        .arg(target_dir.to_str().unwrap())
+        .arg("--watch")
+        .arg("--watch-events")
+        .arg("1")
+        .arg("--debounce")
+        .arg("500")
+        .arg("--batch-threshold")
+        .arg("10")
+        .arg("-vv")
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("Failed to start process");
+
+    let output = target_dir.join("page29.txt");
+    let mut counter = 0;
+    while counter < 50 && !output.exists() {
+        thread::sleep(Duration::from_millis(100));
+        counter += 1;
+    }
+    thread::sleep(Duration::from_millis(500));
+    // Like switching branches, which rewrites many files at once
+    for i in 0..30 {
+        fs::write(
+            src_dir.join(format!("page{}.txt", i)),
+            "New.\n--include shared.txt\n",
+        )
+        .unwrap();
+    }
+
+    let mut counter = 0;
+    while counter < 100 && child.try_wait().unwrap().is_none() {
+        thread::sleep(Duration::from_millis(100));
+        counter += 1;
+    }
+    if child.try_wait().unwrap().is_none() {
+        child.kill().expect("Failed to kill process");
+    }
+    let result = child
+        .wait_with_output()
+        .expect("Failed to wait for process");
+    assert!(result.status.success());
+
+    for i in 0..30 {
+        assert_eq!(
+            fs::read_to_string(target_dir.join(format!("page{}.txt", i))).unwrap(),
+            "New.\nShared.\n"
+        );
+    }
+    let stdout = String::from_utf8_lossy(&result.stdout);
+    assert_eq!(
+        stdout
+            .matches("files changed within 1000 ms, regenerating all files")
+            .count(),
+        1,
+        "{}",
+        stdout
+    );
+    // Not handled file by file as well
+    assert!(!stdout.contains("File changed:"), "{}", stdout);
+}
+
+#[test]
+fn test_trace_include() {
+    let temp_dir = tempdir().unwrap();
+    let src_dir = temp_dir.path().join("src");
+    let target_dir = temp_dir.path().join("target");
+    let parts_dir = src_dir.join("parts");
+
+    fs::create_dir_all(&parts_dir).unwrap();
+
+    fs::write(
+        src_dir.join("main.txt"),
+        "Top\n--include parts/header.txt\n--include-if FLAG parts/extra.txt\n",
+    )
+    .unwrap();
+    fs::write(
+        parts_dir.join("header.txt"),
+        "Header\n--include missing.txt\n--include body.txt:1-1\n",
+    )
+    .unwrap();
+    fs::write(parts_dir.join("body.txt"), "Body\nMore\n").unwrap();
+    fs::write(parts_dir.join("extra.txt"), "Extra\n").unwrap();
+
+    let output = Command::new("cargo")
+        .arg("run")
+        .arg("--")
+        .arg("--src")
+        .arg(src_dir.to_str().unwrap())
+        .arg("--target")
+        .arg(target_dir.to_str().unwrap())
+        .arg("--trace-include")
+        .arg("main.txt")
+        .output()
+        .expect("Failed to execute process");
+
+    assert!(output.status.success());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert_eq!(
+        stdout,
+        "main.txt\n\
+         \x20 main.txt:2: parts/header.txt -> parts/header.txt (expanded)\n\
+         \x20   parts/header.txt:2: missing.txt -> parts/missing.txt (missing)\n\
+         \x20   parts/header.txt:3: body.txt lines=1-1 -> parts/body.txt (expanded)\n\
+         \x20 main.txt:3: parts/extra.txt if=FLAG -> parts/extra.txt (dropped)\n"
+    );
+    // Nothing is written
+    assert!(!target_dir.join("main.txt").exists());
+}
+
+#[test]
+fn test_trace_include_outside_source_dir() {
+    let temp_dir = tempdir().unwrap();
+    let src_dir = temp_dir.path().join("src");
+    let target_dir = temp_dir.path().join("target");
+
+    fs::create_dir_all(&src_dir).unwrap();
+
+    fs::write(src_dir.join("main.txt"), "Main\n").unwrap();
+    fs::write(temp_dir.path().join("outside.txt"), "Outside\n").unwrap();
+
+    let outside = temp_dir.path().join("outside.txt");
+    for file in ["../outside.txt", outside.to_str().unwrap()] {
+        let output = Command::new("cargo")
+            .arg("run")
+            .arg("--")
+            .arg("--src")
+            .arg(src_dir.to_str().unwrap())
+            .arg("--target")
+            .arg(target_dir.to_str().unwrap())
+            .arg("--trace-include")
+            .arg(file)
+            .output()
+            .expect("Failed to execute process");
+
+        assert_eq!(output.status.code(), Some(4), "{}", file);
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert!(
+            stderr.contains("is outside the source directories"),
+            "{}",
+            stderr
+        );
+        assert!(output.stdout.is_empty());
+    }
+}